@@ -4,12 +4,15 @@
 //! Uses cluster bitmaps and allocation strategies for optimal performance.
 
 use alloc::{vec, vec::Vec, collections::BTreeMap};
-use lib_kernel::kprintln;
-use crate::{FilesystemResult, FilesystemError, ide_read_sectors, ide_write_sectors};
+use crate::kprintln;
+use crate::{FilesystemResult, FilesystemError, block_device::SharedBlockDevice};
 
 pub const CLUSTER_SIZE: u32 = 4096; // 4KB clusters
 pub const SECTORS_PER_CLUSTER: u32 = CLUSTER_SIZE / 512;
 
+/// Start cluster of a sparse run, which has no clusters behind it
+pub const SPARSE_CLUSTER: u64 = u64::MAX;
+
 /// Cluster run representing a contiguous range of clusters
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ClusterRun {
@@ -25,12 +28,31 @@ impl ClusterRun {
         }
     }
 
+    /// Run of `cluster_count` unallocated clusters that read as zeros
+    pub fn sparse(cluster_count: u64) -> Self {
+        Self::new(SPARSE_CLUSTER, cluster_count)
+    }
+
+    /// Whether the run is a hole rather than allocated clusters
+    pub fn is_sparse(&self) -> bool {
+        self.start_cluster == SPARSE_CLUSTER
+    }
+
+    /// `count` clusters of this run starting `skip` clusters in; part of a hole is a hole
+    pub fn slice(&self, skip: u64, count: u64) -> Self {
+        if self.is_sparse() {
+            Self::sparse(count)
+        } else {
+            Self::new(self.start_cluster + skip, count)
+        }
+    }
+
     pub fn end_cluster(&self) -> u64 {
         self.start_cluster + self.cluster_count - 1
     }
 
     pub fn contains_cluster(&self, cluster: u64) -> bool {
-        cluster >= self.start_cluster && cluster <= self.end_cluster()
+        cluster >= self.start_cluster && cluster - self.start_cluster < self.cluster_count
     }
 
     pub fn overlaps(&self, other: &ClusterRun) -> bool {
@@ -38,6 +60,9 @@ impl ClusterRun {
     }
 
     pub fn can_merge(&self, other: &ClusterRun) -> bool {
+        if self.is_sparse() || other.is_sparse() {
+            return false;
+        }
         self.end_cluster() + 1 == other.start_cluster || other.end_cluster() + 1 == self.start_cluster
     }
 
@@ -172,7 +197,7 @@ pub enum AllocationStrategy {
 
 /// Free space manager using cluster bitmap
 pub struct ClusterBitmap {
-    drive: u8,
+    device: SharedBlockDevice,
     bitmap_start_sector: u64,
     bitmap_size_sectors: u64,
     total_clusters: u64,
@@ -182,14 +207,14 @@ pub struct ClusterBitmap {
 }
 
 impl ClusterBitmap {
-    pub fn new(drive: u8, bitmap_start_sector: u64, total_clusters: u64) -> Self {
+    pub fn new(device: SharedBlockDevice, bitmap_start_sector: u64, total_clusters: u64) -> Self {
         let bits_needed = total_clusters;
         let bytes_needed = (bits_needed + 7) / 8;
         let bitmap_size_sectors = (bytes_needed + 511) / 512;
         let clusters_per_sector = 512 * 8; // 512 bytes * 8 bits per byte
 
         Self {
-            drive,
+            device,
             bitmap_start_sector,
             bitmap_size_sectors,
             total_clusters,
@@ -200,7 +225,7 @@ impl ClusterBitmap {
     }
 
     pub fn load_bitmap(&mut self) -> FilesystemResult<()> {
-        kprintln!("Loading cluster bitmap from drive {}", self.drive);
+        kprintln!("Loading cluster bitmap");
         if self.cached_bitmap.is_none() {
             // Allocate bitmap in smaller chunks to avoid early allocator failures
             // Read 2 sectors (1KB) at a time to keep allocations very small
@@ -215,12 +240,7 @@ impl ClusterBitmap {
                 let sectors_to_read = CHUNK_SIZE_SECTORS.min(self.bitmap_size_sectors - sectors_read);
                 let mut chunk = vec![0u8; (sectors_to_read * 512) as usize];
                 
-                ide_read_sectors(
-                    self.drive,
-                    sectors_to_read as u8,
-                    (self.bitmap_start_sector + sectors_read) as u32,
-                    &mut chunk,
-                )?;
+                self.device.read_sectors(self.bitmap_start_sector + sectors_read, &mut chunk)?;
                 
                 bitmap_data.extend_from_slice(&chunk);
                 sectors_read += sectors_to_read;
@@ -233,15 +253,39 @@ impl ClusterBitmap {
         Ok(())
     }
 
+    /// Cover `total_clusters`, keeping the bitmap at `bitmap_start_sector`
+    ///
+    /// Clusters added past the old end are free. The bitmap is rewritten in
+    /// full on the next flush; the caller makes sure the region at
+    /// `bitmap_start_sector` is large enough.
+    pub fn resize(&mut self, bitmap_start_sector: u64, total_clusters: u64) -> FilesystemResult<()> {
+        self.load_bitmap()?;
+        let bitmap_size_sectors = total_clusters.div_ceil(8).div_ceil(512);
+        if let Some(ref mut bitmap) = self.cached_bitmap {
+            // Bits past the old end were never in use, whatever the disk held there
+            for cluster in self.total_clusters..(bitmap.len() as u64 * 8).min(total_clusters) {
+                bitmap[(cluster / 8) as usize] &= !(1 << (cluster % 8));
+            }
+            bitmap.resize(bitmap_size_sectors as usize * 512, 0);
+        }
+
+        self.bitmap_start_sector = bitmap_start_sector;
+        self.bitmap_size_sectors = bitmap_size_sectors;
+        self.total_clusters = total_clusters;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Drop the cached bitmap so the next access reloads it from disk
+    pub fn invalidate(&mut self) {
+        self.cached_bitmap = None;
+        self.dirty = false;
+    }
+
     pub fn flush_bitmap(&mut self) -> FilesystemResult<()> {
         if self.dirty {
             if let Some(ref bitmap) = self.cached_bitmap {
-                ide_write_sectors(
-                    self.drive,
-                    self.bitmap_size_sectors as u8,
-                    self.bitmap_start_sector as u32,
-                    bitmap,
-                )?;
+                self.device.write_sectors(self.bitmap_start_sector, bitmap)?;
                 self.dirty = false;
             }
         }
@@ -563,40 +607,13 @@ impl ClusterAllocator {
         Ok(free_clusters * CLUSTER_SIZE as u64)
     }
 
-    pub fn defragment_file(&mut self, file_record_number: u64) -> FilesystemResult<()> {
-        // Get current runs
-        if let Some(current_runs) = self.allocated_runs.get(&file_record_number).cloned() {
-            let total_clusters = current_runs.total_clusters;
-
-            // Try to allocate contiguous space
-            if let Some(new_run) = self.bitmap.find_free_clusters(total_clusters, AllocationStrategy::FirstFit)? {
-                // Mark new space as used
-                self.bitmap.mark_run_used(&new_run)?;
-
-                // Free old space
-                for old_run in &current_runs.runs {
-                    self.bitmap.mark_run_free(old_run)?;
-                }
-
-                // Update tracking
-                let mut new_run_list = RunList::new();
-                new_run_list.add_run(new_run);
-                self.allocated_runs.insert(file_record_number, new_run_list);
-
-                self.bitmap.flush_bitmap()?;
-            }
-        }
-
-        Ok(())
-    }
-
     pub fn read_cluster(&self, cluster: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
         if buffer.len() < CLUSTER_SIZE as usize {
             return Err(FilesystemError::InvalidParameter);
         }
 
         let sector_start = cluster * SECTORS_PER_CLUSTER as u64;
-        ide_read_sectors(self.bitmap.drive, SECTORS_PER_CLUSTER as u8, sector_start as u32, buffer)?;
+        self.bitmap.device.read_sectors(sector_start, &mut buffer[..CLUSTER_SIZE as usize])?;
         Ok(())
     }
 
@@ -606,7 +623,7 @@ impl ClusterAllocator {
         }
 
         let sector_start = cluster * SECTORS_PER_CLUSTER as u64;
-        ide_write_sectors(self.bitmap.drive, SECTORS_PER_CLUSTER as u8, sector_start as u32, buffer)?;
+        self.bitmap.device.write_sectors(sector_start, &buffer[..CLUSTER_SIZE as usize])?;
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,260 @@
+//! Block Device Abstraction
+//!
+//! Decouples the filesystem from the storage driver underneath it. Every layer
+//! of galleon2 talks to a [`BlockDevice`] instead of calling the IDE driver
+//! directly, so a volume can live on an IDE disk, an AHCI disk, a partition of
+//! either, or a RAM-backed image used by the host-side unit tests.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+use crate::{FilesystemResult, FilesystemError};
+#[cfg(feature = "kernel")]
+use crate::{ide_read_sectors, ide_write_sectors, return_drive_size_bytes};
+
+/// Sector size every on-disk structure of galleon2 is laid out in
+pub const SECTOR_SIZE: usize = 512;
+
+/// Sector-addressed storage that a galleon2 volume can live on.
+///
+/// Buffers passed to [`read_sectors`](BlockDevice::read_sectors) and
+/// [`write_sectors`](BlockDevice::write_sectors) must be a whole number of
+/// sectors long; the number of sectors transferred is derived from the buffer
+/// length. Methods take `&self` so one device can be shared between the MFT,
+/// journal, bitmap and index managers of a mounted filesystem.
+pub trait BlockDevice: Send + Sync {
+    /// Size of one sector in bytes
+    fn sector_size(&self) -> usize;
+
+    /// Number of addressable sectors
+    fn sector_count(&self) -> u64;
+
+    /// Read `buffer.len() / sector_size()` sectors starting at `start_sector`
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()>;
+
+    /// Write `buffer.len() / sector_size()` sectors starting at `start_sector`
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()>;
+
+    /// Make all previously written sectors durable
+    fn flush(&self) -> FilesystemResult<()>;
+
+    /// Make previously written sectors in `start_sector..start_sector + sector_count` durable
+    ///
+    /// Devices that buffer writes can use this to push out one range ahead of
+    /// the rest; by default the whole device is flushed.
+    fn flush_sectors(&self, start_sector: u64, sector_count: u64) -> FilesystemResult<()> {
+        let _ = (start_sector, sector_count);
+        self.flush()
+    }
+
+    /// Total capacity in bytes
+    fn capacity_bytes(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+}
+
+/// Block device handle shared by all managers of a mounted filesystem
+pub type SharedBlockDevice = Arc<dyn BlockDevice>;
+
+/// Validate a transfer against the device geometry and return its sector count
+pub(crate) fn check_transfer(device: &dyn BlockDevice, start_sector: u64, len: usize) -> FilesystemResult<u64> {
+    let sector_size = device.sector_size();
+    if len == 0 || !len.is_multiple_of(sector_size) {
+        return Err(FilesystemError::InvalidParameter);
+    }
+
+    let sectors = (len / sector_size) as u64;
+    match start_sector.checked_add(sectors) {
+        Some(end) if end <= device.sector_count() => Ok(sectors),
+        _ => Err(FilesystemError::OutOfRange),
+    }
+}
+
+/// Block device backed by a drive on the legacy IDE controller
+#[cfg(feature = "kernel")]
+pub struct IdeBlockDevice {
+    drive: u8,
+    sector_count: u64,
+}
+
+#[cfg(feature = "kernel")]
+impl IdeBlockDevice {
+    /// Largest transfer a single IDE command can move
+    const MAX_SECTORS_PER_COMMAND: u64 = 255;
+
+    /// Open an IDE drive (0-3), querying its size from the controller
+    pub fn new(drive: u8) -> FilesystemResult<Self> {
+        if drive > 3 {
+            return Err(FilesystemError::InvalidParameter);
+        }
+
+        let size_bytes = return_drive_size_bytes(drive)?;
+        if size_bytes == 0 {
+            return Err(FilesystemError::DriveNotFound);
+        }
+
+        Ok(Self {
+            drive,
+            sector_count: size_bytes / SECTOR_SIZE as u64,
+        })
+    }
+
+    /// Drive number on the IDE controller
+    pub fn drive(&self) -> u8 {
+        self.drive
+    }
+}
+
+#[cfg(feature = "kernel")]
+impl BlockDevice for IdeBlockDevice {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+
+        // IDE commands carry an 8-bit sector count and a 28-bit LBA
+        for (i, chunk) in buffer.chunks_mut(Self::MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE).enumerate() {
+            let lba = start_sector + i as u64 * Self::MAX_SECTORS_PER_COMMAND;
+            let lba = u32::try_from(lba).map_err(|_| FilesystemError::OutOfRange)?;
+            ide_read_sectors(self.drive, (chunk.len() / SECTOR_SIZE) as u8, lba, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks(Self::MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE).enumerate() {
+            let lba = start_sector + i as u64 * Self::MAX_SECTORS_PER_COMMAND;
+            let lba = u32::try_from(lba).map_err(|_| FilesystemError::OutOfRange)?;
+            ide_write_sectors(self.drive, (chunk.len() / SECTOR_SIZE) as u8, lba, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> FilesystemResult<()> {
+        // The IDE driver uses PIO and completes every write before returning
+        Ok(())
+    }
+}
+
+/// RAM-backed block device, used for disk images and host-side tests
+pub struct MemoryBlockDevice {
+    data: Mutex<Vec<u8>>,
+    sector_size: usize,
+}
+
+impl MemoryBlockDevice {
+    /// Create a zero-filled device of `size_bytes`, rounded down to whole sectors
+    pub fn new(size_bytes: usize) -> Self {
+        let size_bytes = size_bytes - size_bytes % SECTOR_SIZE;
+        Self {
+            data: Mutex::new(vec![0u8; size_bytes]),
+            sector_size: SECTOR_SIZE,
+        }
+    }
+
+    /// Wrap an existing image; trailing bytes that do not fill a sector are dropped
+    pub fn from_image(mut image: Vec<u8>) -> Self {
+        let whole = image.len() - image.len() % SECTOR_SIZE;
+        image.truncate(whole);
+        Self {
+            data: Mutex::new(image),
+            sector_size: SECTOR_SIZE,
+        }
+    }
+
+    /// Copy of the current device contents
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+
+    /// Consume the device and return its backing image
+    pub fn into_image(self) -> Vec<u8> {
+        self.data.into_inner()
+    }
+}
+
+impl BlockDevice for MemoryBlockDevice {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.data.lock().len() / self.sector_size) as u64
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+        let offset = start_sector as usize * self.sector_size;
+        let data = self.data.lock();
+        buffer.copy_from_slice(&data[offset..offset + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+        let offset = start_sector as usize * self.sector_size;
+        let mut data = self.data.lock();
+        data[offset..offset + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn flush(&self) -> FilesystemResult<()> {
+        Ok(())
+    }
+}
+
+/// A contiguous range of sectors on another device, e.g. one partition
+pub struct PartitionBlockDevice {
+    inner: SharedBlockDevice,
+    start_sector: u64,
+    sector_count: u64,
+}
+
+impl PartitionBlockDevice {
+    /// Expose `sector_count` sectors of `inner` starting at `start_sector`
+    pub fn new(inner: SharedBlockDevice, start_sector: u64, sector_count: u64) -> FilesystemResult<Self> {
+        match start_sector.checked_add(sector_count) {
+            Some(end) if end <= inner.sector_count() => Ok(Self {
+                inner,
+                start_sector,
+                sector_count,
+            }),
+            _ => Err(FilesystemError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for PartitionBlockDevice {
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+        self.inner.read_sectors(self.start_sector + start_sector, buffer)
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+        self.inner.write_sectors(self.start_sector + start_sector, buffer)
+    }
+
+    fn flush(&self) -> FilesystemResult<()> {
+        self.inner.flush()
+    }
+
+    fn flush_sectors(&self, start_sector: u64, sector_count: u64) -> FilesystemResult<()> {
+        self.inner.flush_sectors(self.start_sector + start_sector, sector_count)
+    }
+}
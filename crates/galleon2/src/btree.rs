@@ -4,17 +4,23 @@
 //! Uses index nodes for internal navigation and leaf nodes for actual directory entries.
 
 use alloc::{vec, vec::Vec, string::{String, ToString}};
+use core::ops::Range;
 use crate::{
     FilesystemResult, FilesystemError,
+    checksum::{crc32c, crc32c_excluding},
     mft::FileRecordNumber,
     file_record::FileName,
-    ide_read_sectors, ide_write_sectors,
+    block_device::SharedBlockDevice,
+    allocation::{ClusterAllocator, AllocationStrategy},
 };
-use lib_kernel::kprintln;
+use crate::kprintln;
 
 pub const INDEX_NODE_SIZE: usize = 4096; // 4KB index nodes
 pub const INDEX_ENTRY_HEADER_SIZE: usize = 16;
 
+/// CRC32C of the node, taken with this field zeroed
+const INDEX_CHECKSUM_FIELD: Range<usize> = 20..24;
+
 /// Index entry flags
 #[derive(Debug, Clone, Copy)]
 pub struct IndexEntryFlags {
@@ -61,7 +67,7 @@ impl IndexEntry {
     pub fn new_leaf(file_record_number: FileRecordNumber, file_name: FileName) -> Self {
         let key = file_name.name.clone();
         let key_length = key.len() as u16;
-        let entry_length = INDEX_ENTRY_HEADER_SIZE as u16 + key_length + file_name.serialize().len() as u16;
+        let entry_length = (INDEX_ENTRY_HEADER_SIZE as u16 + key_length + file_name.serialize().len() as u16 + 7) & !7;
 
         Self {
             file_record_number,
@@ -76,7 +82,7 @@ impl IndexEntry {
 
     pub fn new_internal(key: String, sub_node_vcn: u64) -> Self {
         let key_length = key.len() as u16;
-        let entry_length = INDEX_ENTRY_HEADER_SIZE as u16 + key_length + 8; // +8 for VCN
+        let entry_length = (INDEX_ENTRY_HEADER_SIZE as u16 + key_length + 8 + 7) & !7; // +8 for VCN
 
         let mut flags = IndexEntryFlags::new();
         flags.has_sub_node = true;
@@ -92,6 +98,15 @@ impl IndexEntry {
         }
     }
 
+    /// End marker of an internal node, pointing at the rightmost child
+    pub fn new_internal_end(sub_node_vcn: u64) -> Self {
+        let mut entry = Self::new_end_marker();
+        entry.flags.has_sub_node = true;
+        entry.entry_length += 8;
+        entry.sub_node_vcn = Some(sub_node_vcn);
+        entry
+    }
+
     pub fn new_end_marker() -> Self {
         let mut flags = IndexEntryFlags::new();
         flags.is_last_entry = true;
@@ -115,7 +130,7 @@ impl IndexEntry {
         data.extend_from_slice(&self.entry_length.to_le_bytes());
         data.extend_from_slice(&self.key_length.to_le_bytes());
         data.push(self.flags.to_u8());
-        data.push(0); // Padding
+        data.extend_from_slice(&[0u8; 3]); // Padding to INDEX_ENTRY_HEADER_SIZE
 
         // Key (file name)
         data.extend_from_slice(self.key.as_bytes());
@@ -343,28 +358,53 @@ impl IndexNode {
     }
 
     pub fn split(&mut self) -> IndexNode {
-        let mid_point = self.entries.len() / 2;
+        let vcn = self.vcn + 1;
+        self.split_with_separator(vcn).1
+    }
 
-        // Create new node with second half of entries
-        let mut new_node = if self.header.is_leaf() {
-            IndexNode::new_leaf(self.vcn + 1)
+    /// Move the upper half of the entries into a new node at `right_vcn`.
+    ///
+    /// Returns the separator key for the parent: every key remaining in this
+    /// node sorts at or below it, every key in the new node above it.
+    pub fn split_with_separator(&mut self, right_vcn: u64) -> (String, IndexNode) {
+        let mut right = if self.header.is_leaf() {
+            IndexNode::new_leaf(right_vcn)
         } else {
-            IndexNode::new_internal(self.vcn + 1)
+            IndexNode::new_internal(right_vcn)
         };
 
         // Move entries from mid_point onwards to new node (except end marker)
         let end_marker_pos = self.entries.len() - 1;
-        if mid_point < end_marker_pos {
-            let moved_entries: Vec<_> = self.entries.drain(mid_point..end_marker_pos).collect();
-            for entry in moved_entries {
-                new_node.entries.insert(new_node.entries.len() - 1, entry);
-            }
-        }
+        let mid_point = (self.entries.len() / 2).max(1).min(end_marker_pos);
+        let moved_entries: Vec<_> = self.entries.drain(mid_point..end_marker_pos).collect();
+        let right_end = right.entries.len() - 1;
+        right.entries.splice(right_end..right_end, moved_entries);
+
+        let separator = if self.header.is_leaf() {
+            self.entries[mid_point - 1].key.clone()
+        } else {
+            // The rightmost child moves with the upper half; the last
+            // remaining separator becomes this node's end marker
+            let end_marker = self.entries.pop().unwrap();
+            right.entries.pop();
+            right.entries.push(end_marker);
+
+            let last = self.entries.pop().unwrap();
+            self.entries.push(IndexEntry::new_internal_end(last.sub_node_vcn.unwrap_or(0)));
+            last.key
+        };
 
         self.update_header();
-        new_node.update_header();
+        right.update_header();
 
-        new_node
+        (separator, right)
+    }
+
+    /// Child of an internal node whose subtree may contain `key`
+    pub fn child_for(&self, key: &str) -> Option<u64> {
+        self.entries.iter()
+            .find(|e| e.flags.is_last_entry || key <= e.key.as_str())
+            .and_then(|e| e.sub_node_vcn)
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -384,6 +424,8 @@ impl IndexNode {
             }
         }
 
+        let checksum = crc32c(&data);
+        data[INDEX_CHECKSUM_FIELD].copy_from_slice(&checksum.to_le_bytes());
         data
     }
 
@@ -393,6 +435,10 @@ impl IndexNode {
         }
 
         let header = IndexHeader::deserialize(data)?;
+        let stored = u32::from_le_bytes(data[INDEX_CHECKSUM_FIELD].try_into().unwrap());
+        if crc32c_excluding(&data[..INDEX_NODE_SIZE], INDEX_CHECKSUM_FIELD) != stored {
+            return Err(FilesystemError::ChecksumMismatch);
+        }
         let mut entries = Vec::new();
 
         let mut offset = header.entries_offset as usize;
@@ -416,22 +462,41 @@ impl IndexNode {
 
 /// B+ Tree manager for directory indexing
 pub struct BTreeManager {
-    drive: u8,
+    device: SharedBlockDevice,
     root_vcn: u64,
     index_allocation_start: u64, // Starting cluster for index allocation
     cluster_size: u32,
 }
 
 impl BTreeManager {
-    pub fn new(drive: u8, root_vcn: u64, index_allocation_start: u64, cluster_size: u32) -> Self {
+    pub fn new(device: SharedBlockDevice, root_vcn: u64, index_allocation_start: u64, cluster_size: u32) -> Self {
         Self {
-            drive,
+            device,
             root_vcn,
             index_allocation_start,
             cluster_size,
         }
     }
 
+    /// Manager for another directory's tree in the same index allocation area
+    pub fn for_directory(&self, root_vcn: u64) -> Self {
+        Self::new(self.device.clone(), root_vcn, self.index_allocation_start, self.cluster_size)
+    }
+
+    /// VCN of this tree's root node
+    pub fn root_vcn(&self) -> u64 {
+        self.root_vcn
+    }
+
+    /// Allocate and write an empty root node for a new directory, returning its VCN
+    pub fn create_root(&self, allocator: &mut ClusterAllocator) -> FilesystemResult<u64> {
+        let vcn = self.allocate_vcn(allocator)?;
+        let mut root = IndexNode::new_leaf(vcn);
+        root.update_header();
+        self.write_node(&root)?;
+        Ok(vcn)
+    }
+
     pub fn read_node(&self, vcn: u64) -> FilesystemResult<IndexNode> {
         let cluster_num = self.index_allocation_start + vcn;
         let sector_start = cluster_num * (self.cluster_size / 512) as u64;
@@ -441,7 +506,7 @@ impl BTreeManager {
                   vcn, cluster_num, sector_start, sectors_per_node);
 
         let mut node_data = vec![0u8; INDEX_NODE_SIZE];
-        ide_read_sectors(self.drive, sectors_per_node as u8, sector_start as u32, &mut node_data)?;
+        self.device.read_sectors(sector_start, &mut node_data)?;
 
         kprintln!("First 16 bytes of node data: {:02x?}", &node_data[0..16]);
         
@@ -459,7 +524,7 @@ impl BTreeManager {
         let node_data = node.serialize();
         kprintln!("First 16 bytes being written: {:02x?}", &node_data[0..16]);
         
-        ide_write_sectors(self.drive, sectors_per_node as u8, sector_start as u32, &node_data)?;
+        self.device.write_sectors(sector_start, &node_data)?;
         kprintln!("B-tree node written successfully");
         Ok(())
     }
@@ -479,43 +544,26 @@ impl BTreeManager {
                 }
             } else {
                 // Internal node - find child to descend to
-                let mut found_child = None;
-                for entry in &node.entries {
-                    if entry.flags.is_last_entry {
-                        // Last entry points to rightmost child
-                        if let Some(vcn) = entry.sub_node_vcn {
-                            found_child = Some(vcn);
-                        }
-                        break;
-                    } else if key <= &entry.key {
-                        if let Some(vcn) = entry.sub_node_vcn {
-                            found_child = Some(vcn);
-                            break;
-                        }
-                    }
-                }
-
-                if let Some(vcn) = found_child {
-                    current_vcn = vcn;
-                } else {
-                    return Ok(None);
+                match node.child_for(key) {
+                    Some(vcn) => current_vcn = vcn,
+                    None => return Ok(None),
                 }
             }
         }
     }
 
-    pub fn insert(&mut self, key: String, file_record_number: FileRecordNumber, file_name: FileName) -> FilesystemResult<()> {
+    pub fn insert(&mut self, allocator: &mut ClusterAllocator, key: String, file_record_number: FileRecordNumber, file_name: FileName) -> FilesystemResult<()> {
         let root = self.read_node(self.root_vcn)?;
 
         if root.is_full() {
-            // Split root and create new root
-            self.split_root()?;
+            // Split root; the root keeps its VCN so directory records stay valid
+            self.split_root(allocator, root)?;
         }
 
-        self.insert_recursive(self.root_vcn, key, file_record_number, file_name)
+        self.insert_recursive(allocator, self.root_vcn, key, file_record_number, file_name)
     }
 
-    fn insert_recursive(&self, vcn: u64, key: String, file_record_number: FileRecordNumber, file_name: FileName) -> FilesystemResult<()> {
+    fn insert_recursive(&self, allocator: &mut ClusterAllocator, vcn: u64, key: String, file_record_number: FileRecordNumber, file_name: FileName) -> FilesystemResult<()> {
         let mut node = self.read_node(vcn)?;
 
         if node.header.is_leaf() {
@@ -523,82 +571,75 @@ impl BTreeManager {
             let entry = IndexEntry::new_leaf(file_record_number, file_name);
             node.insert_entry(entry);
             self.write_node(&node)?;
-        } else {
-            // Find child to insert into
-            let mut child_vcn = None;
-            for entry in &node.entries {
-                if entry.flags.is_last_entry {
-                    if let Some(vcn) = entry.sub_node_vcn {
-                        child_vcn = Some(vcn);
-                    }
-                    break;
-                } else if key <= entry.key {
-                    if let Some(vcn) = entry.sub_node_vcn {
-                        child_vcn = Some(vcn);
-                        break;
-                    }
-                }
-            }
+            return Ok(());
+        }
 
-            if let Some(child_vcn) = child_vcn {
-                self.insert_recursive(child_vcn, key, file_record_number, file_name)?;
+        // Find child to insert into
+        let mut child_vcn = node.child_for(&key).ok_or(FilesystemError::InvalidParameter)?;
 
-                // Check if child split and handle promotion
-                let child = self.read_node(child_vcn)?;
-                if child.is_full() {
-                    // Child needs to be split
-                    // This would involve more complex split handling
-                }
-            }
+        // Split a full child before descending so the split never has to
+        // propagate back up through a full parent
+        let child = self.read_node(child_vcn)?;
+        if child.is_full() {
+            self.split_child(allocator, &mut node, child)?;
+            child_vcn = node.child_for(&key).ok_or(FilesystemError::InvalidParameter)?;
         }
 
-        Ok(())
+        self.insert_recursive(allocator, child_vcn, key, file_record_number, file_name)
     }
 
-    fn split_root(&mut self) -> FilesystemResult<()> {
-        let old_root = self.read_node(self.root_vcn)?;
-
-        // Create new root
-        let new_root_vcn = self.allocate_vcn()?;
-        let mut new_root = IndexNode::new_internal(new_root_vcn);
-
-        // Split old root
-        let mut old_root_copy = old_root.clone();
-        let new_node = old_root_copy.split();
-        let new_node_vcn = self.allocate_vcn()?;
+    fn split_root(&self, allocator: &mut ClusterAllocator, old_root: IndexNode) -> FilesystemResult<()> {
+        // Move the old root's contents into two fresh children
+        let left_vcn = self.allocate_vcn(allocator)?;
+        let right_vcn = self.allocate_vcn(allocator)?;
 
-        // Add entries to new root pointing to split nodes
-        let left_entry = IndexEntry::new_internal(
-            old_root_copy.entries[0].key.clone(),
-            old_root_copy.vcn,
-        );
-        let right_entry = IndexEntry::new_internal(
-            new_node.entries[0].key.clone(),
-            new_node_vcn,
-        );
+        let mut left = old_root;
+        left.vcn = left_vcn;
+        let (separator, right) = left.split_with_separator(right_vcn);
 
-        new_root.insert_entry(left_entry);
-        new_root.insert_entry(right_entry);
+        let mut new_root = IndexNode::new_internal(self.root_vcn);
+        new_root.entries = vec![
+            IndexEntry::new_internal(separator, left_vcn),
+            IndexEntry::new_internal_end(right_vcn),
+        ];
+        new_root.update_header();
 
         // Write all nodes
-        self.write_node(&old_root_copy)?;
-        self.write_node(&new_node)?;
+        self.write_node(&left)?;
+        self.write_node(&right)?;
         self.write_node(&new_root)?;
 
-        // Update root VCN
-        self.root_vcn = new_root_vcn;
+        Ok(())
+    }
+
+    fn split_child(&self, allocator: &mut ClusterAllocator, parent: &mut IndexNode, mut child: IndexNode) -> FilesystemResult<()> {
+        let right_vcn = self.allocate_vcn(allocator)?;
+        let (separator, right) = child.split_with_separator(right_vcn);
+
+        // The parent entry that covered the whole child now covers the upper
+        // half; a new separator entry in front of it covers the lower half
+        let pos = parent.entries.iter()
+            .position(|e| e.sub_node_vcn == Some(child.vcn))
+            .ok_or(FilesystemError::InvalidParameter)?;
+        parent.entries[pos].sub_node_vcn = Some(right_vcn);
+        parent.entries.insert(pos, IndexEntry::new_internal(separator, child.vcn));
+        parent.update_header();
+
+        self.write_node(&child)?;
+        self.write_node(&right)?;
+        self.write_node(parent)?;
 
         Ok(())
     }
 
-    fn allocate_vcn(&self) -> FilesystemResult<u64> {
-        // Simplified VCN allocation
-        static mut NEXT_VCN: u64 = 1;
-        unsafe {
-            let vcn = NEXT_VCN;
-            NEXT_VCN += 1;
-            Ok(vcn)
-        }
+    fn allocate_vcn(&self, allocator: &mut ClusterAllocator) -> FilesystemResult<u64> {
+        // Index nodes are ordinary clusters addressed relative to the index area
+        let run = allocator.bitmap.find_free_clusters(1, AllocationStrategy::FirstFit)?
+            .filter(|run| run.start_cluster >= self.index_allocation_start)
+            .ok_or(FilesystemError::InsufficientSpace)?;
+        allocator.bitmap.mark_run_used(&run)?;
+        allocator.bitmap.flush_bitmap()?;
+        Ok(run.start_cluster - self.index_allocation_start)
     }
 
     pub fn list_directory(&self) -> FilesystemResult<Vec<(String, FileRecordNumber)>> {
@@ -629,35 +670,128 @@ impl BTreeManager {
         Ok(())
     }
 
-    pub fn delete(&mut self, key: &str) -> FilesystemResult<bool> {
-        self.delete_recursive(self.root_vcn, key)
+    /// Remove `key` from the tree, rebalancing underflowing nodes on the way back up
+    pub fn delete(&mut self, allocator: &mut ClusterAllocator, key: &str) -> FilesystemResult<bool> {
+        if !self.delete_recursive(allocator, self.root_vcn, key)? {
+            return Ok(false);
+        }
+
+        // Shrink the tree while the root is an internal node with a single child.
+        // The child's contents move up so the root keeps its VCN.
+        loop {
+            let root = self.read_node(self.root_vcn)?;
+            if root.header.is_leaf() || root.entries.len() > 1 {
+                break;
+            }
+
+            let child_vcn = root.entries[0].sub_node_vcn.ok_or(FilesystemError::InvalidParameter)?;
+            let mut new_root = self.read_node(child_vcn)?;
+            new_root.vcn = self.root_vcn;
+            self.write_node(&new_root)?;
+            self.free_vcn(allocator, child_vcn)?;
+        }
+
+        Ok(true)
     }
 
-    fn delete_recursive(&self, vcn: u64, key: &str) -> FilesystemResult<bool> {
+    fn delete_recursive(&self, allocator: &mut ClusterAllocator, vcn: u64, key: &str) -> FilesystemResult<bool> {
         let mut node = self.read_node(vcn)?;
 
         if node.header.is_leaf() {
             // Remove from leaf if found
             if node.remove_entry(key).is_some() {
                 self.write_node(&node)?;
-                Ok(true)
-            } else {
-                Ok(false)
+                return Ok(true);
             }
+            return Ok(false);
+        }
+
+        // Find child containing the key
+        let child_vcn = match node.child_for(key) {
+            Some(vcn) => vcn,
+            None => return Ok(false),
+        };
+        if !self.delete_recursive(allocator, child_vcn, key)? {
+            return Ok(false);
+        }
+
+        let child = self.read_node(child_vcn)?;
+        if child.is_underflow() && node.entries.len() > 1 {
+            self.rebalance(allocator, &mut node, child)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Fix an underflowing child of `parent` by merging it with a sibling, or
+    /// by redistributing entries between them when the merged node would be full
+    fn rebalance(&self, allocator: &mut ClusterAllocator, parent: &mut IndexNode, child: IndexNode) -> FilesystemResult<()> {
+        let pos = parent.entries.iter()
+            .position(|e| e.sub_node_vcn == Some(child.vcn))
+            .ok_or(FilesystemError::InvalidParameter)?;
+
+        // Pair the child with its left sibling, or its right one if it is leftmost
+        let left_pos = if pos > 0 { pos - 1 } else { pos };
+        let (left, right) = if left_pos == pos {
+            let right_vcn = parent.entries[pos + 1].sub_node_vcn.ok_or(FilesystemError::InvalidParameter)?;
+            (child, self.read_node(right_vcn)?)
         } else {
-            // Find child containing the key
+            let left_vcn = parent.entries[left_pos].sub_node_vcn.ok_or(FilesystemError::InvalidParameter)?;
+            (self.read_node(left_vcn)?, child)
+        };
+        let separator = parent.entries[left_pos].key.clone();
+
+        // Concatenate both nodes; in internal nodes the left end marker becomes
+        // an ordinary entry keyed by the parent's separator
+        let mut merged = left.clone();
+        let left_end = merged.entries.pop().ok_or(FilesystemError::InvalidParameter)?;
+        if !merged.header.is_leaf() {
+            let sub_node_vcn = left_end.sub_node_vcn.ok_or(FilesystemError::InvalidParameter)?;
+            merged.entries.push(IndexEntry::new_internal(separator, sub_node_vcn));
+        }
+        merged.entries.extend(right.entries.iter().cloned());
+        merged.update_header();
+
+        if !merged.is_full() {
+            // Merge: the right node's parent entry keeps covering the combined range
+            merged.vcn = right.vcn;
+            self.write_node(&merged)?;
+            parent.entries.remove(left_pos);
+            parent.update_header();
+            self.write_node(parent)?;
+            self.free_vcn(allocator, left.vcn)?;
+        } else {
+            // Redistribute: split the combined entries evenly across both nodes
+            let (separator, new_right) = merged.split_with_separator(right.vcn);
+            self.write_node(&merged)?;
+            self.write_node(&new_right)?;
+            parent.entries[left_pos] = IndexEntry::new_internal(separator, left.vcn);
+            parent.update_header();
+            self.write_node(parent)?;
+        }
+
+        Ok(())
+    }
+
+    /// Free every node of the tree, e.g. when its directory is removed
+    pub fn free_tree(&self, allocator: &mut ClusterAllocator) -> FilesystemResult<()> {
+        self.free_subtree(allocator, self.root_vcn)
+    }
+
+    fn free_subtree(&self, allocator: &mut ClusterAllocator, vcn: u64) -> FilesystemResult<()> {
+        let node = self.read_node(vcn)?;
+        if !node.header.is_leaf() {
             for entry in &node.entries {
-                if entry.flags.is_last_entry {
-                    if let Some(child_vcn) = entry.sub_node_vcn {
-                        return self.delete_recursive(child_vcn, key);
-                    }
-                } else if key <= &entry.key {
-                    if let Some(child_vcn) = entry.sub_node_vcn {
-                        return self.delete_recursive(child_vcn, key);
-                    }
+                if let Some(child_vcn) = entry.sub_node_vcn {
+                    self.free_subtree(allocator, child_vcn)?;
                 }
             }
-            Ok(false)
         }
+        self.free_vcn(allocator, vcn)
+    }
+
+    fn free_vcn(&self, allocator: &mut ClusterAllocator, vcn: u64) -> FilesystemResult<()> {
+        allocator.bitmap.set_cluster_free(self.index_allocation_start + vcn)?;
+        allocator.bitmap.flush_bitmap()
     }
 }
@@ -0,0 +1,238 @@
+//! Buffer Cache
+//!
+//! Keeps recently used blocks of a [`BlockDevice`] in memory. Reads of cached
+//! blocks never reach the disk and writes are held back until the block is
+//! evicted or the cache is flushed, so the bitmap, MFT and index nodes that
+//! every operation touches stay in memory between operations.
+//!
+//! Blocks are cluster sized and evicted least recently used first. A dirty
+//! block is written back when it is evicted, on [`BlockDevice::flush`], or on
+//! [`BlockDevice::flush_sectors`] for the range it lies in. The journal uses
+//! the latter to make each log record durable before the metadata write it
+//! describes enters the cache, and flushes the whole cache before it moves
+//! the start of the log past the records that protect those writes.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use spin::Mutex;
+use crate::{
+    FilesystemResult,
+    block_device::{BlockDevice, SharedBlockDevice, check_transfer},
+};
+
+/// Sectors per cached block; one cluster
+pub const BLOCK_SECTORS: u64 = 8;
+
+/// Blocks kept by default (1 MiB of 4 KiB blocks)
+pub const DEFAULT_CACHE_BLOCKS: usize = 256;
+
+/// Hit and miss counters of a [`BufferCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Block lookups served from memory
+    pub hits: u64,
+    /// Block lookups that had to read the disk
+    pub misses: u64,
+    /// Dirty blocks written back to the disk
+    pub writebacks: u64,
+    /// Blocks dropped to make room for others
+    pub evictions: u64,
+}
+
+/// A cached block
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    /// Tick of the last access; key of the block in the LRU list
+    last_used: u64,
+}
+
+struct CacheState {
+    blocks: BTreeMap<u64, CachedBlock>,
+    /// Block numbers by last access, oldest first
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+/// Write-back LRU cache in front of a block device
+pub struct BufferCache {
+    inner: SharedBlockDevice,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl BufferCache {
+    /// Cache up to `capacity` blocks of `inner`
+    pub fn new(inner: SharedBlockDevice, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity: capacity.max(1),
+            state: Mutex::new(CacheState {
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// Hit, miss and write-back counters since the cache was created
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    /// Number of cached blocks not yet written back
+    pub fn dirty_blocks(&self) -> usize {
+        self.state.lock().blocks.values().filter(|block| block.dirty).count()
+    }
+
+    /// First sector and length in bytes of a block; the last block of the
+    /// device may be short
+    fn block_span(&self, block: u64) -> (u64, usize) {
+        let start = block * BLOCK_SECTORS;
+        let sectors = BLOCK_SECTORS.min(self.inner.sector_count() - start);
+        (start, sectors as usize * self.inner.sector_size())
+    }
+
+    /// Mark `block` as most recently used
+    fn touch(state: &mut CacheState, block: u64) {
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(cached) = state.blocks.get_mut(&block) {
+            state.lru.remove(&cached.last_used);
+            cached.last_used = tick;
+            state.lru.insert(tick, block);
+        }
+    }
+
+    /// Make sure `block` is cached, reading it from disk unless `overwrite`
+    /// says the caller is about to replace all of it
+    fn load(&self, state: &mut CacheState, block: u64, overwrite: bool) -> FilesystemResult<()> {
+        if state.blocks.contains_key(&block) {
+            state.stats.hits += 1;
+            Self::touch(state, block);
+            return Ok(());
+        }
+
+        let (start, len) = self.block_span(block);
+        let mut data = vec![0u8; len];
+        if !overwrite {
+            state.stats.misses += 1;
+            self.inner.read_sectors(start, &mut data)?;
+        }
+
+        self.make_room(state)?;
+        state.tick += 1;
+        let tick = state.tick;
+        state.blocks.insert(block, CachedBlock { data, dirty: false, last_used: tick });
+        state.lru.insert(tick, block);
+        Ok(())
+    }
+
+    /// Evict least recently used blocks until there is room for one more
+    fn make_room(&self, state: &mut CacheState) -> FilesystemResult<()> {
+        while state.blocks.len() >= self.capacity {
+            let Some((&tick, &block)) = state.lru.iter().next() else {
+                break;
+            };
+            if state.blocks[&block].dirty {
+                self.write_back(state, block)?;
+            }
+            state.lru.remove(&tick);
+            state.blocks.remove(&block);
+            state.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Write a dirty block to the device and mark it clean
+    fn write_back(&self, state: &mut CacheState, block: u64) -> FilesystemResult<()> {
+        let (start, _) = self.block_span(block);
+        let cached = state.blocks.get_mut(&block).expect("write-back of uncached block");
+        self.inner.write_sectors(start, &cached.data)?;
+        cached.dirty = false;
+        state.stats.writebacks += 1;
+        Ok(())
+    }
+
+    /// Write back every dirty block overlapping `first_block..end_block`
+    fn write_back_range(&self, state: &mut CacheState, first_block: u64, end_block: u64) -> FilesystemResult<()> {
+        let dirty: Vec<u64> = state.blocks.range(first_block..end_block)
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+        for block in dirty {
+            self.write_back(state, block)?;
+        }
+        Ok(())
+    }
+
+    /// Call `f` with each block a transfer touches, along with the byte range
+    /// of the block and of the caller's buffer that overlap
+    fn for_each_block(
+        &self,
+        start_sector: u64,
+        len: usize,
+        mut f: impl FnMut(u64, core::ops::Range<usize>, core::ops::Range<usize>) -> FilesystemResult<()>,
+    ) -> FilesystemResult<()> {
+        let sector_size = self.inner.sector_size();
+        let mut done = 0;
+        while done < len {
+            let sector = start_sector + (done / sector_size) as u64;
+            let block = sector / BLOCK_SECTORS;
+            let (block_start, block_len) = self.block_span(block);
+            let within = (sector - block_start) as usize * sector_size;
+            let count = (block_len - within).min(len - done);
+            f(block, within..within + count, done..done + count)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+        let mut state = self.state.lock();
+        self.for_each_block(start_sector, buffer.len(), |block, in_block, in_buffer| {
+            self.load(&mut state, block, false)?;
+            buffer[in_buffer].copy_from_slice(&state.blocks[&block].data[in_block]);
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        check_transfer(self, start_sector, buffer.len())?;
+        let mut state = self.state.lock();
+        self.for_each_block(start_sector, buffer.len(), |block, in_block, in_buffer| {
+            let whole_block = in_block.start == 0 && in_block.end == self.block_span(block).1;
+            self.load(&mut state, block, whole_block)?;
+            let cached = state.blocks.get_mut(&block).expect("block was just loaded");
+            cached.data[in_block].copy_from_slice(&buffer[in_buffer]);
+            cached.dirty = true;
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> FilesystemResult<()> {
+        let mut state = self.state.lock();
+        self.write_back_range(&mut state, 0, u64::MAX)?;
+        self.inner.flush()
+    }
+
+    fn flush_sectors(&self, start_sector: u64, sector_count: u64) -> FilesystemResult<()> {
+        let mut state = self.state.lock();
+        let first_block = start_sector / BLOCK_SECTORS;
+        let end_block = (start_sector + sector_count).div_ceil(BLOCK_SECTORS);
+        self.write_back_range(&mut state, first_block, end_block)?;
+        self.inner.flush()
+    }
+}
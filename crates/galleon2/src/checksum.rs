@@ -0,0 +1,98 @@
+//! Metadata Checksums
+//!
+//! CRC32C (Castagnoli) checksums for MFT records, index nodes and the
+//! superblock, and the update sequence fixups that let a structure spanning
+//! several sectors tell when a write reached only some of them.
+//!
+//! Before a structure is written, the last two bytes of each 512-byte stride
+//! are saved in its update sequence array and replaced with the update
+//! sequence number. On read every stride must end in that number; a stride
+//! that does not was left over from an earlier write.
+
+use core::ops::Range;
+use crate::{FilesystemResult, FilesystemError};
+
+/// Bytes covered by each entry of an update sequence array
+pub const UPDATE_SEQUENCE_STRIDE: usize = 512;
+
+const CRC32C_POLYNOMIAL: u32 = 0x82F6_3B78; // Reflected Castagnoli polynomial
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32c_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// CRC32C of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+/// CRC32C of `data` with the bytes in `field` read as zeros, for checksums
+/// stored inside the data they cover
+pub fn crc32c_excluding(data: &[u8], field: Range<usize>) -> u32 {
+    let mut crc = crc32c_update(!0, &data[..field.start]);
+    for _ in field.clone() {
+        crc = crc32c_update(crc, &[0]);
+    }
+    !crc32c_update(crc, &data[field.end..])
+}
+
+/// Number of entries in the update sequence array of a `len`-byte block:
+/// the sequence number followed by one saved word per stride
+pub const fn update_sequence_size(len: usize) -> usize {
+    1 + len / UPDATE_SEQUENCE_STRIDE
+}
+
+/// Stamp the end of every stride of `block` with `usn`, saving the bytes it
+/// replaces in the update sequence array at `array_offset`
+pub fn apply_update_sequence(block: &mut [u8], array_offset: usize, usn: u16) {
+    block[array_offset..array_offset + 2].copy_from_slice(&usn.to_le_bytes());
+    for stride in 0..block.len() / UPDATE_SEQUENCE_STRIDE {
+        let end = (stride + 1) * UPDATE_SEQUENCE_STRIDE;
+        let saved = array_offset + 2 + stride * 2;
+        block.copy_within(end - 2..end, saved);
+        block[end - 2..end].copy_from_slice(&usn.to_le_bytes());
+    }
+}
+
+/// Check that every stride of `block` ends in the update sequence number and
+/// put back the bytes saved in the array
+///
+/// Fails with `ChecksumMismatch` if a stride does not carry the number.
+pub fn remove_update_sequence(block: &mut [u8], array_offset: usize, array_size: usize) -> FilesystemResult<()> {
+    let strides = block.len() / UPDATE_SEQUENCE_STRIDE;
+    if array_size != update_sequence_size(block.len()) || array_offset + array_size * 2 > UPDATE_SEQUENCE_STRIDE - 2 {
+        return Err(FilesystemError::InvalidParameter);
+    }
+
+    let usn = [block[array_offset], block[array_offset + 1]];
+    for stride in 0..strides {
+        let end = (stride + 1) * UPDATE_SEQUENCE_STRIDE;
+        if block[end - 2..end] != usn {
+            return Err(FilesystemError::ChecksumMismatch);
+        }
+        let saved = array_offset + 2 + stride * 2;
+        block.copy_within(saved..saved + 2, end - 2);
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Defragmenter
+//!
+//! Moves the data of fragmented files into single contiguous runs. Files are
+//! found by walking the MFT for records in use whose Data attribute is
+//! non-resident; a file whose allocated clusters form more than one run is
+//! fragmented. Sparse files are left alone, since moving their data into one
+//! run would fill their holes.
+//!
+//! Each file is moved in its own transaction: its clusters are copied into a
+//! free run, the Data attribute is pointed at the copy and the old clusters
+//! are freed. The copy goes to clusters that are free until the transaction
+//! commits, so a crash part way leaves the file on its old clusters.
+
+use alloc::vec::Vec;
+use crate::{
+    FilesystemResult,
+    allocation::AllocationStrategy,
+    galleon_fs::GalleonFilesystem,
+    journal::OperationType,
+    mft::{DataRun, FileRecordNumber, MFT_FIRST_USER_RECORD},
+};
+use crate::kprintln;
+
+/// How fragmented the files of a volume are
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FragmentationStats {
+    /// Files with data stored in clusters
+    pub files: u64,
+    /// Files whose data is split over more than one run
+    pub fragmented_files: u64,
+    /// Allocated runs over all files; equal to `files` when nothing is
+    /// fragmented, holes not counted
+    pub extents: u64,
+}
+
+/// Result of [`GalleonFilesystem::defragment`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefragReport {
+    pub before: FragmentationStats,
+    pub after: FragmentationStats,
+    /// Files moved into a single run
+    pub files_moved: u64,
+    /// Clusters copied while moving them
+    pub clusters_moved: u64,
+    /// Fragmented files left alone because they have holes or no free run
+    /// was large enough
+    pub files_skipped: u64,
+}
+
+impl GalleonFilesystem {
+    /// Fragmentation of the files on the volume
+    pub fn fragmentation(&self) -> FilesystemResult<FragmentationStats> {
+        let mut stats = FragmentationStats::default();
+        for (_, runs) in self.non_resident_files()? {
+            let extents = runs.iter().filter(|run| !run.is_sparse()).count() as u64;
+            stats.files += 1;
+            stats.extents += extents;
+            if extents > 1 {
+                stats.fragmented_files += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Move every fragmented file into a single contiguous run where one is free
+    pub fn defragment(&mut self) -> FilesystemResult<DefragReport> {
+        kprintln!("Starting filesystem defragmentation");
+        let mut report = DefragReport {
+            before: self.fragmentation()?,
+            ..DefragReport::default()
+        };
+
+        for (record, runs) in self.non_resident_files()? {
+            if runs.iter().filter(|run| !run.is_sparse()).count() <= 1 {
+                continue;
+            }
+            if runs.iter().any(|run| run.is_sparse()) {
+                report.files_skipped += 1;
+                continue;
+            }
+
+            // Best fit keeps large free runs for the files that need them
+            let clusters: u64 = runs.iter().map(|run| run.cluster_count).sum();
+            let Some(target) = self.allocator.bitmap.find_free_clusters(clusters, AllocationStrategy::BestFit)? else {
+                kprintln!("No free run of {} clusters for record {}", clusters, record);
+                report.files_skipped += 1;
+                continue;
+            };
+
+            self.transaction(OperationType::WriteData, record, |fs| {
+                fs.allocator.bitmap.mark_run_used(&target)?;
+                fs.allocator.bitmap.flush_bitmap()?;
+                for old in fs.file_manager.relocate_clusters(record, 0, target)? {
+                    fs.allocator.deallocate_clusters(record, old)?;
+                }
+                Ok(())
+            })?;
+            report.files_moved += 1;
+            report.clusters_moved += clusters;
+        }
+
+        report.after = self.fragmentation()?;
+        kprintln!("Filesystem defragmentation completed: {} of {} fragmented files moved, {} extents before, {} after",
+                  report.files_moved, report.before.fragmented_files, report.before.extents, report.after.extents);
+        Ok(report)
+    }
+
+    /// Data runs of every file in use with non-resident data
+    ///
+    /// Records that fail to read are left for the consistency checker. System
+    /// records are skipped; the MFT's own runs are addressed by record number.
+    fn non_resident_files(&self) -> FilesystemResult<Vec<(FileRecordNumber, Vec<DataRun>)>> {
+        let mut files = Vec::new();
+        for record_number in MFT_FIRST_USER_RECORD..self.mft_manager.record_capacity() {
+            let in_use = matches!(self.mft_manager.read_slot(record_number),
+                Ok(Some(record)) if record.header.get_flags().in_use);
+            if !in_use {
+                continue;
+            }
+            if let Some(runs) = self.file_manager.data_runs(record_number)?
+                && !runs.is_empty()
+            {
+                files.push((record_number, runs));
+            }
+        }
+        Ok(files)
+    }
+}
@@ -5,11 +5,18 @@
 
 use crate::{
     FilesystemResult, FilesystemError,
+    defrag::DefragReport,
+    file_record::{AtimePolicy, StandardInformation, TimeUpdate},
     galleon_fs::GalleonFilesystem,
-    mft::FileRecordNumber,
+    mft::{FileRecordNumber, MFT_RECORD_ROOT},
     types::pathbuf::PathBuf,
+    block_device::SharedBlockDevice,
 };
+#[cfg(feature = "kernel")]
+use crate::block_device::IdeBlockDevice;
 use alloc::{string::String, vec::Vec};
+#[cfg(feature = "kernel")]
+use alloc::sync::Arc;
 
 /// High-level file manager wrapping the Galleon filesystem
 pub struct FileManager {
@@ -22,22 +29,34 @@ impl FileManager {
     pub fn new(filesystem: GalleonFilesystem) -> Self {
         Self {
             filesystem,
-            current_directory: 5, // Root directory record number
+            current_directory: MFT_RECORD_ROOT,
         }
     }
 
-    /// Format a new filesystem on the specified drive
-    pub fn format_drive(drive: u8) -> FilesystemResult<Self> {
-        let filesystem = GalleonFilesystem::format(drive)?;
+    /// Format a new filesystem on the specified block device
+    pub fn format_device(device: SharedBlockDevice) -> FilesystemResult<Self> {
+        let filesystem = GalleonFilesystem::format(device)?;
         Ok(Self::new(filesystem))
     }
 
-    /// Mount an existing filesystem from the specified drive
-    pub fn mount_drive(drive: u8) -> FilesystemResult<Self> {
-        let filesystem = GalleonFilesystem::mount(drive)?;
+    /// Mount an existing filesystem from the specified block device
+    pub fn mount_device(device: SharedBlockDevice) -> FilesystemResult<Self> {
+        let filesystem = GalleonFilesystem::mount(device)?;
         Ok(Self::new(filesystem))
     }
 
+    /// Format a new filesystem on the specified IDE drive
+    #[cfg(feature = "kernel")]
+    pub fn format_drive(drive: u8) -> FilesystemResult<Self> {
+        Self::format_device(Arc::new(IdeBlockDevice::new(drive)?))
+    }
+
+    /// Mount an existing filesystem from the specified IDE drive
+    #[cfg(feature = "kernel")]
+    pub fn mount_drive(drive: u8) -> FilesystemResult<Self> {
+        Self::mount_device(Arc::new(IdeBlockDevice::new(drive)?))
+    }
+
     /// Create a new file in the current directory
     pub fn create_file(&mut self, name: String, contents: Option<String>) -> FilesystemResult<FileRecordNumber> {
         let data = contents.map(|s| s.into_bytes());
@@ -77,34 +96,53 @@ impl FileManager {
         self.filesystem.write_file(file_record, data)
     }
 
-    /// Delete a file by name
-    pub fn delete_file(&mut self, name: &str) -> FilesystemResult<()> {
-        // Find the file first
-        if let Some(file_record) = self.filesystem.find_file(name)? {
-            self.filesystem.delete_file(file_record, name)
-        } else {
-            Err(FilesystemError::InvalidParameter) // File not found
-        }
+    /// Delete a file or empty directory by path
+    pub fn delete_file(&mut self, path: &str) -> FilesystemResult<()> {
+        let path = PathBuf::from(path);
+        let (directory, name) = self.filesystem.resolve_parent(self.current_directory, &path)?;
+        self.filesystem.unlink(directory, name)
+    }
+
+    /// Create a hard link at `new_path` to the file at `existing`
+    pub fn link(&mut self, existing: &str, new_path: &str) -> FilesystemResult<()> {
+        let file_record = self.resolve_path(existing)?;
+        let path = PathBuf::from(new_path);
+        let (directory, name) = self.filesystem.resolve_parent(self.current_directory, &path)?;
+        self.filesystem.link(file_record, directory, name)
+    }
+
+    /// Number of names a file is linked under
+    pub fn get_link_count(&self, file_record: FileRecordNumber) -> FilesystemResult<u16> {
+        self.filesystem.link_count(file_record)
     }
 
     /// List files in the current directory
     pub fn list_files(&self) -> FilesystemResult<Vec<(String, FileRecordNumber)>> {
-        self.filesystem.list_directory()
+        self.filesystem.list_directory_at(self.current_directory)
     }
 
-    /// Find a file by name in the current directory
-    pub fn find_file(&self, name: &str) -> FilesystemResult<Option<FileRecordNumber>> {
-        self.filesystem.find_file(name)
+    /// Find a file by path, relative to the current directory unless absolute
+    pub fn find_file(&self, path: &str) -> FilesystemResult<Option<FileRecordNumber>> {
+        match self.resolve_path(path) {
+            Ok(file_record) => Ok(Some(file_record)),
+            Err(FilesystemError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
-    /// Change current directory (simplified - assumes directory name in current dir)
-    pub fn change_directory(&mut self, name: &str) -> FilesystemResult<()> {
-        if let Some(dir_record) = self.filesystem.find_file(name)? {
-            self.current_directory = dir_record;
-            Ok(())
-        } else {
-            Err(FilesystemError::InvalidParameter)
+    /// Resolve a path to a file record, relative to the current directory unless absolute
+    pub fn resolve_path(&self, path: &str) -> FilesystemResult<FileRecordNumber> {
+        self.filesystem.resolve_path(self.current_directory, &PathBuf::from(path))
+    }
+
+    /// Change current directory to the directory at `path`
+    pub fn change_directory(&mut self, path: &str) -> FilesystemResult<()> {
+        let dir_record = self.resolve_path(path)?;
+        if !self.filesystem.is_directory(dir_record)? {
+            return Err(FilesystemError::NotADirectory);
         }
+        self.current_directory = dir_record;
+        Ok(())
     }
 
     /// Get current directory record number
@@ -112,11 +150,9 @@ impl FileManager {
         self.current_directory
     }
 
-    /// Move to parent directory (simplified)
+    /// Move to parent directory; the root is its own parent
     pub fn move_to_parent(&mut self) -> FilesystemResult<()> {
-        // In a full implementation, we'd track parent relationships
-        // For now, just reset to root
-        self.current_directory = 5; // Root directory
+        self.current_directory = self.filesystem.parent_directory(self.current_directory)?;
         Ok(())
     }
 
@@ -126,10 +162,15 @@ impl FileManager {
     }
 
     /// Defragment the filesystem
-    pub fn defragment(&mut self) -> FilesystemResult<()> {
+    pub fn defragment(&mut self) -> FilesystemResult<DefragReport> {
         self.filesystem.defragment()
     }
 
+    /// Grow the filesystem to fill its device, returning the new cluster count
+    pub fn grow(&mut self) -> FilesystemResult<u64> {
+        self.filesystem.grow()
+    }
+
     /// Sync all changes to disk
     pub fn sync(&mut self) -> FilesystemResult<()> {
         self.filesystem.sync()
@@ -148,7 +189,7 @@ impl FileManager {
         self.create_file_binary(dest_name, data)
     }
 
-    /// Move (rename) a file
+    /// Move (rename) a file within the current directory
     pub fn move_file(&mut self, old_name: &str, new_name: String) -> FilesystemResult<()> {
         // Copy to new name
         let _new_record = self.copy_file(old_name, new_name)?;
@@ -159,10 +200,14 @@ impl FileManager {
         Ok(())
     }
 
-    /// Get file size
+    /// Get file size, counting holes
     pub fn get_file_size(&mut self, file_record: FileRecordNumber) -> FilesystemResult<u64> {
-        let data = self.read_file_binary(file_record)?;
-        Ok(data.len() as u64)
+        self.filesystem.file_size(file_record)
+    }
+
+    /// Get the disk space allocated to a file, which excludes holes
+    pub fn get_allocated_size(&self, file_record: FileRecordNumber) -> FilesystemResult<u64> {
+        self.filesystem.allocated_size(file_record)
     }
 
     /// Check if a file exists
@@ -172,72 +217,227 @@ impl FileManager {
 
     /// Append data to an existing file
     pub fn append_file_text(&mut self, file_record: FileRecordNumber, additional_content: String) -> FilesystemResult<()> {
-        let mut existing_data = self.read_file_binary(file_record)?;
-        existing_data.extend_from_slice(additional_content.as_bytes());
-        self.write_file_binary(file_record, existing_data)
+        self.append_file_binary(file_record, additional_content.into_bytes())
     }
 
     /// Append binary data to an existing file
     pub fn append_file_binary(&mut self, file_record: FileRecordNumber, additional_data: Vec<u8>) -> FilesystemResult<()> {
-        let mut existing_data = self.read_file_binary(file_record)?;
-        existing_data.extend_from_slice(&additional_data);
-        self.write_file_binary(file_record, existing_data)
+        let size = self.filesystem.file_size(file_record)?;
+        self.filesystem.write_at(file_record, size, &additional_data)?;
+        Ok(())
     }
 
     /// Truncate a file to a specific size
     pub fn truncate_file(&mut self, file_record: FileRecordNumber, new_size: usize) -> FilesystemResult<()> {
-        let mut data = self.read_file_binary(file_record)?;
-        if new_size < data.len() {
-            data.truncate(new_size);
-            self.write_file_binary(file_record, data)?;
+        if (new_size as u64) < self.filesystem.file_size(file_record)? {
+            self.filesystem.set_len(file_record, new_size as u64)?;
         }
         Ok(())
     }
+
+    /// Deallocate `len` bytes of a file from `offset`; the range reads back as zeros
+    pub fn punch_hole(&mut self, file_record: FileRecordNumber, offset: u64, len: u64) -> FilesystemResult<()> {
+        self.filesystem.punch_hole(file_record, offset, len)
+    }
+
+    /// Read a named data stream of a file
+    pub fn read_stream(&self, file_record: FileRecordNumber, stream: &str) -> FilesystemResult<Vec<u8>> {
+        self.filesystem.read_stream(file_record, stream)
+    }
+
+    /// Replace a named data stream of a file, creating it if needed
+    pub fn write_stream(&mut self, file_record: FileRecordNumber, stream: &str, data: Vec<u8>) -> FilesystemResult<()> {
+        self.filesystem.write_stream(file_record, stream, data)
+    }
+
+    /// List the named data streams of a file
+    pub fn list_streams(&self, file_record: FileRecordNumber) -> FilesystemResult<Vec<String>> {
+        self.filesystem.list_streams(file_record)
+    }
+
+    /// Remove a named data stream from a file
+    pub fn remove_stream(&mut self, file_record: FileRecordNumber, stream: &str) -> FilesystemResult<()> {
+        self.filesystem.remove_stream(file_record, stream)
+    }
+
+    /// Get an extended attribute of a file or directory
+    pub fn get_xattr(&self, file_record: FileRecordNumber, key: &str) -> FilesystemResult<Option<Vec<u8>>> {
+        self.filesystem.get_xattr(file_record, key)
+    }
+
+    /// Set an extended attribute of a file or directory
+    pub fn set_xattr(&mut self, file_record: FileRecordNumber, key: &str, value: &[u8]) -> FilesystemResult<()> {
+        self.filesystem.set_xattr(file_record, key, value)
+    }
+
+    /// List the extended attributes of a file or directory
+    pub fn list_xattrs(&self, file_record: FileRecordNumber) -> FilesystemResult<Vec<String>> {
+        self.filesystem.list_xattrs(file_record)
+    }
+
+    /// Remove an extended attribute from a file or directory
+    pub fn remove_xattr(&mut self, file_record: FileRecordNumber, key: &str) -> FilesystemResult<()> {
+        self.filesystem.remove_xattr(file_record, key)
+    }
+
+    /// Get the times, link count, owner and mode of a file or directory
+    pub fn get_metadata(&self, file_record: FileRecordNumber) -> FilesystemResult<StandardInformation> {
+        self.filesystem.stat(file_record)
+    }
+
+    /// Change the permission bits of a file or directory
+    pub fn chmod(&mut self, file_record: FileRecordNumber, permissions: u32) -> FilesystemResult<()> {
+        self.filesystem.chmod(file_record, permissions)
+    }
+
+    /// Change the owner and group of a file or directory
+    pub fn chown(&mut self, file_record: FileRecordNumber, uid: Option<u32>, gid: Option<u32>) -> FilesystemResult<()> {
+        self.filesystem.chown(file_record, uid, gid)
+    }
+
+    /// Set the access and modification times of a file or directory
+    pub fn utimens(&mut self, file_record: FileRecordNumber, access: TimeUpdate, modification: TimeUpdate) -> FilesystemResult<()> {
+        self.filesystem.utimens(file_record, access, modification)
+    }
+
+    /// Choose when reads update access times
+    pub fn set_atime_policy(&mut self, policy: AtimePolicy) {
+        self.filesystem.set_atime_policy(policy);
+    }
+
+    /// Open the file at `path` for positional reads and writes
+    pub fn open(&self, path: &str) -> FilesystemResult<FileHandle> {
+        let file_record = self.resolve_path(path)?;
+        if self.filesystem.is_directory(file_record)? {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        Ok(FileHandle::new(file_record))
+    }
+}
+
+/// Position to seek to within an open file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// Offset from the start of the file
+    Start(u64),
+    /// Offset from the end of the file
+    End(i64),
+    /// Offset from the current position
+    Current(i64),
+}
+
+/// Open file with a cursor
+///
+/// Reads and writes go through the file manager and only touch the clusters
+/// under the cursor, so large files never have to be loaded whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHandle {
+    file_record: FileRecordNumber,
+    position: u64,
+}
+
+impl FileHandle {
+    /// Handle on a file record, positioned at the start of the file
+    pub fn new(file_record: FileRecordNumber) -> Self {
+        Self { file_record, position: 0 }
+    }
+
+    /// File record this handle refers to
+    pub fn file_record(&self) -> FileRecordNumber {
+        self.file_record
+    }
+
+    /// Current cursor position
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read into `buf` at the cursor, returning the number of bytes read (0 at end of file)
+    pub fn read(&mut self, manager: &FileManager, buf: &mut [u8]) -> FilesystemResult<usize> {
+        let count = manager.filesystem.read_at(self.file_record, self.position, buf)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    /// Write `data` at the cursor, extending the file if needed
+    pub fn write(&mut self, manager: &mut FileManager, data: &[u8]) -> FilesystemResult<usize> {
+        let count = manager.filesystem.write_at(self.file_record, self.position, data)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    /// Move the cursor, returning the new position
+    ///
+    /// Seeking past the end is allowed; a later write leaves a zero-filled gap.
+    pub fn seek(&mut self, manager: &FileManager, pos: SeekFrom) -> FilesystemResult<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(delta) => (manager.filesystem.file_size(self.file_record)?, delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+        self.position = base.checked_add_signed(delta).ok_or(FilesystemError::InvalidParameter)?;
+        Ok(self.position)
+    }
+
+    /// Truncate or zero-extend the file; the cursor is left where it is
+    pub fn set_len(&self, manager: &mut FileManager, len: u64) -> FilesystemResult<()> {
+        manager.filesystem.set_len(self.file_record, len)
+    }
+
+    /// Current size of the file in bytes
+    pub fn len(&self, manager: &FileManager) -> FilesystemResult<u64> {
+        manager.filesystem.file_size(self.file_record)
+    }
 }
 
 /// Path-based file operations (convenience functions)
 impl FileManager {
-    /// Create file using path-like interface
-    pub fn create_file_at_path(&mut self, _path: PathBuf, name: String, contents: Option<String>) -> FilesystemResult<FileRecordNumber> {
-        // For now, ignore path and create in current directory
-        // Full implementation would parse path and navigate directories
-        self.create_file(name, contents)
+    /// Create a file named `name` in the directory at `path`
+    pub fn create_file_at_path(&mut self, path: PathBuf, name: String, contents: Option<String>) -> FilesystemResult<FileRecordNumber> {
+        let directory = self.filesystem.resolve_path(self.current_directory, &path)?;
+        let data = contents.map(|s| s.into_bytes());
+        self.filesystem.create_file(directory, name, data)
     }
 
-    /// List files at a specific path
-    pub fn list_files_at_path(&self, _path: PathBuf) -> FilesystemResult<Vec<(String, FileRecordNumber)>> {
-        // For now, list current directory
-        // Full implementation would navigate to path first
-        self.list_files()
+    /// List files in the directory at `path`
+    pub fn list_files_at_path(&self, path: PathBuf) -> FilesystemResult<Vec<(String, FileRecordNumber)>> {
+        let directory = self.filesystem.resolve_path(self.current_directory, &path)?;
+        self.filesystem.list_directory_at(directory)
     }
 
-    /// Delete file at path
-    pub fn delete_file_at_path(&mut self, _path: PathBuf, name: &str) -> FilesystemResult<()> {
-        // For now, delete from current directory
-        self.delete_file(name)
+    /// Delete the file named `name` from the directory at `path`
+    pub fn delete_file_at_path(&mut self, path: PathBuf, name: &str) -> FilesystemResult<()> {
+        let directory = self.filesystem.resolve_path(self.current_directory, &path)?;
+        self.filesystem.unlink(directory, name)
     }
 }
 
 // Legacy compatibility functions for the old interface
-/// Create a file using the legacy interface (compatibility)
-pub fn create_file(drive: u8, name: String, contents: Option<String>) -> FilesystemResult<()> {
+/// Create a file at `path` (resolved from the root directory) using the legacy interface
+#[cfg(feature = "kernel")]
+pub fn create_file(drive: u8, path: String, contents: Option<String>) -> FilesystemResult<()> {
     let mut manager = FileManager::mount_drive(drive)?;
-    let _file_record = manager.create_file(name, contents)?;
+    let path = PathBuf::new(path);
+    let (directory, name) = manager.filesystem.resolve_parent(MFT_RECORD_ROOT, &path)?;
+    let data = contents.map(|s| s.into_bytes());
+    let _file_record = manager.filesystem.create_file(directory, String::from(name), data)?;
     manager.sync()?;
     Ok(())
 }
 
-/// List files using legacy interface (compatibility)
-pub fn list_files(_drive: u8, _path: PathBuf) -> FilesystemResult<Vec<String>> {
-    // This function signature doesn't return filesystem results in the original
-    // We'll need to modify callers to use the new FileManager interface
-    Ok(Vec::new()) // Stub for compatibility
+/// List the names in the directory at `path` using the legacy interface
+#[cfg(feature = "kernel")]
+pub fn list_files(drive: u8, path: PathBuf) -> FilesystemResult<Vec<String>> {
+    let manager = FileManager::mount_drive(drive)?;
+    let entries = manager.list_files_at_path(path)?;
+    Ok(entries.into_iter().map(|(name, _)| name).collect())
 }
 
-/// Delete file using legacy interface (compatibility)
-pub fn delete_file(drive: u8, name: &str) -> FilesystemResult<()> {
+/// Delete the file at `path` (resolved from the root directory) using the legacy interface
+#[cfg(feature = "kernel")]
+pub fn delete_file(drive: u8, path: &str) -> FilesystemResult<()> {
     let mut manager = FileManager::mount_drive(drive)?;
-    manager.delete_file(name)?;
+    manager.delete_file(path)?;
     manager.sync()?;
     Ok(())
 }
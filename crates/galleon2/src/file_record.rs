@@ -4,14 +4,18 @@
 //! Provides advanced file management with attributes and metadata.
 
 use alloc::{vec, string::String, vec::Vec};
+use core::ops::Range;
+use spin::Mutex;
 use crate::{
     FilesystemResult, FilesystemError,
-    mft::{MftRecord, MftManager, Attribute, AttributeType, AttributeData, FileRecordNumber, RecordFlags},
-    journal::{JournalManager, OperationType},
+    mft::{MftRecord, MftManager, Attribute, AttributeType, AttributeData, DataRun, FileRecordNumber, RecordFlags, MFT_RECORD_MFT, MFT_RECORD_SIZE},
+    journal::{SharedJournal, OperationType},
+    allocation::ClusterAllocator,
+    block_device::SharedBlockDevice,
 };
-use lib_kernel::kprintln;
+use crate::kprintln;
 
-/// File timestamps
+/// File timestamps, in nanoseconds of the filesystem clock
 #[derive(Debug, Clone, Copy)]
 pub struct FileTimes {
     pub creation_time: u64,
@@ -44,6 +48,67 @@ impl FileTimes {
     pub fn update_metadata(&mut self) {
         self.mft_change_time = get_current_time();
     }
+
+    /// Whether a read at `now` should update the access time under `policy`
+    pub fn access_due(&self, policy: AtimePolicy, now: u64) -> bool {
+        match policy {
+            AtimePolicy::Strict => true,
+            AtimePolicy::Relatime => self.last_access_time <= self.last_write_time
+                || self.last_access_time <= self.mft_change_time
+                || now.saturating_sub(self.last_access_time) >= RELATIME_INTERVAL_NS,
+            AtimePolicy::Noatime => false,
+        }
+    }
+}
+
+/// When reading a file updates its access time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AtimePolicy {
+    /// On every read
+    Strict,
+    /// On the first read after a write or metadata change, and at most daily otherwise
+    #[default]
+    Relatime,
+    /// Never
+    Noatime,
+}
+
+/// Age after which `Relatime` refreshes an access time that is already current
+const RELATIME_INTERVAL_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// New value for one timestamp in [`FileRecordManager::set_times`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUpdate {
+    /// Leave the timestamp as it is
+    Omit,
+    /// Set it to the current time
+    Now,
+    /// Set it to this many nanoseconds
+    At(u64),
+}
+
+impl TimeUpdate {
+    fn apply(self, time: &mut u64, now: u64) {
+        match self {
+            TimeUpdate::Omit => {}
+            TimeUpdate::Now => *time = now,
+            TimeUpdate::At(value) => *time = value,
+        }
+    }
+}
+
+/// POSIX file type and permission bits kept in [`StandardInformation::mode`]
+pub mod mode {
+    /// Mask of the file type bits
+    pub const S_IFMT: u32 = 0o170000;
+    pub const S_IFDIR: u32 = 0o040000;
+    pub const S_IFREG: u32 = 0o100000;
+    pub const S_ISUID: u32 = 0o4000;
+    pub const S_ISGID: u32 = 0o2000;
+    /// Bits `chmod` may change: permissions, setuid, setgid and sticky
+    pub const PERMISSION_BITS: u32 = 0o7777;
+    pub const DEFAULT_FILE: u32 = S_IFREG | 0o644;
+    pub const DEFAULT_DIRECTORY: u32 = S_IFDIR | 0o755;
 }
 
 /// File attributes
@@ -141,6 +206,14 @@ pub struct StandardInformation {
     pub security_id: u32,
     pub quota_charged: u64,
     pub update_sequence_number: u64,
+    /// Directory entries referring to the record, one per FileName attribute
+    pub link_count: u16,
+    /// POSIX owner
+    pub uid: u32,
+    /// POSIX group
+    pub gid: u32,
+    /// POSIX file type and permission bits, see [`mode`]
+    pub mode: u32,
 }
 
 impl StandardInformation {
@@ -155,6 +228,10 @@ impl StandardInformation {
             security_id: 0,
             quota_charged: 0,
             update_sequence_number: 0,
+            link_count: 1,
+            uid: 0,
+            gid: 0,
+            mode: mode::DEFAULT_FILE,
         }
     }
 
@@ -169,11 +246,15 @@ impl StandardInformation {
             security_id: 0,
             quota_charged: 0,
             update_sequence_number: 0,
+            link_count: 1,
+            uid: 0,
+            gid: 0,
+            mode: mode::DEFAULT_DIRECTORY,
         }
     }
 
     pub fn serialize(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(72);
+        let mut data = Vec::with_capacity(96);
         data.extend_from_slice(&self.times.creation_time.to_le_bytes());
         data.extend_from_slice(&self.times.last_access_time.to_le_bytes());
         data.extend_from_slice(&self.times.last_write_time.to_le_bytes());
@@ -186,6 +267,12 @@ impl StandardInformation {
         data.extend_from_slice(&self.security_id.to_le_bytes());
         data.extend_from_slice(&self.quota_charged.to_le_bytes());
         data.extend_from_slice(&self.update_sequence_number.to_le_bytes());
+        data.extend_from_slice(&self.link_count.to_le_bytes());
+        data.extend_from_slice(&[0u8; 6]);
+        data.extend_from_slice(&self.uid.to_le_bytes());
+        data.extend_from_slice(&self.gid.to_le_bytes());
+        data.extend_from_slice(&self.mode.to_le_bytes());
+        data.extend_from_slice(&[0u8; 4]);
         data
     }
 
@@ -209,6 +296,21 @@ impl StandardInformation {
         let security_id = u32::from_le_bytes(data[52..56].try_into().unwrap());
         let quota_charged = u64::from_le_bytes(data[56..64].try_into().unwrap());
         let update_sequence_number = u64::from_le_bytes(data[64..72].try_into().unwrap());
+        // Records written before link counts were kept have exactly one link
+        let link_count = match data.get(72..74) {
+            Some(bytes) => u16::from_le_bytes(bytes.try_into().unwrap()),
+            None => 1,
+        };
+        // Records written before POSIX metadata was kept belong to root
+        let (uid, gid, mode) = match data.get(80..92) {
+            Some(bytes) => (
+                u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+                u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            ),
+            None if file_attributes.directory => (0, 0, mode::DEFAULT_DIRECTORY),
+            None => (0, 0, mode::DEFAULT_FILE),
+        };
 
         Ok(Self {
             times,
@@ -220,6 +322,10 @@ impl StandardInformation {
             security_id,
             quota_charged,
             update_sequence_number,
+            link_count,
+            uid,
+            gid,
+            mode,
         })
     }
 }
@@ -330,30 +436,92 @@ impl FileName {
     }
 }
 
+/// Index Root attribute of a directory
+///
+/// Points at the root node of the directory's own B+ tree in the index
+/// allocation area and records the parent directory for `..` lookups.
+#[derive(Debug, Clone, Copy)]
+pub struct IndexRoot {
+    pub root_vcn: u64,
+    pub parent_directory: FileRecordNumber,
+}
+
+impl IndexRoot {
+    pub fn new(root_vcn: u64, parent_directory: FileRecordNumber) -> Self {
+        Self {
+            root_vcn,
+            parent_directory,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(32);
+        data.extend_from_slice(b"INDX"); // Signature
+        data.extend_from_slice(&0u32.to_le_bytes()); // Entry count
+        data.extend_from_slice(&32u32.to_le_bytes()); // Index size
+        data.extend_from_slice(&0u32.to_le_bytes()); // Flags
+        data.extend_from_slice(&self.root_vcn.to_le_bytes());
+        data.extend_from_slice(&self.parent_directory.to_le_bytes());
+        data
+    }
+
+    pub fn deserialize(data: &[u8]) -> FilesystemResult<Self> {
+        if data.len() < 32 || &data[0..4] != b"INDX" {
+            return Err(FilesystemError::InvalidParameter);
+        }
+
+        Ok(Self {
+            root_vcn: u64::from_le_bytes(data[16..24].try_into().unwrap()),
+            parent_directory: u64::from_le_bytes(data[24..32].try_into().unwrap()),
+        })
+    }
+}
+
+/// Largest Data attribute kept inside the MFT record; anything bigger lives in clusters
+const RESIDENT_DATA_LIMIT: usize = 700;
+
+/// Clusters the MFT grows by once every record slot is in use
+const MFT_GROWTH_CLUSTERS: u64 = 4;
+
 /// File Record Manager - high-level file operations
 pub struct FileRecordManager {
     mft_manager: MftManager,
-    journal_manager: JournalManager,
+    journal_manager: SharedJournal,
+    /// Device for file contents; only metadata goes through the journal
+    data_device: SharedBlockDevice,
+    atime_policy: AtimePolicy,
 }
 
 impl FileRecordManager {
-    pub fn new(mft_manager: MftManager, journal_manager: JournalManager) -> Self {
+    pub fn new(mft_manager: MftManager, journal_manager: SharedJournal, data_device: SharedBlockDevice) -> Self {
         Self {
             mft_manager,
             journal_manager,
+            data_device,
+            atime_policy: AtimePolicy::default(),
         }
     }
 
+    /// When reads update access times
+    pub fn atime_policy(&self) -> AtimePolicy {
+        self.atime_policy
+    }
+
+    pub fn set_atime_policy(&mut self, policy: AtimePolicy) {
+        self.atime_policy = policy;
+    }
+
     pub fn create_file(
         &mut self,
+        allocator: &mut ClusterAllocator,
         parent_directory: FileRecordNumber,
         name: String,
         data: Option<Vec<u8>>,
     ) -> FilesystemResult<FileRecordNumber> {
-        let transaction_id = self.journal_manager.begin_transaction();
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::CreateFile, parent_directory);
 
         // Allocate new file record
-        let file_record_number = self.mft_manager.allocate_record()?;
+        let file_record_number = self.allocate_record(allocator)?;
 
         // Create MFT record
         let mut record = MftRecord::new(file_record_number);
@@ -371,39 +539,35 @@ impl FileRecordManager {
         let file_name_attr = Attribute::new_resident(AttributeType::FileName, file_name.serialize());
         record.add_attribute(file_name_attr);
 
-        // Add Data attribute
+        // Add Data attribute; large contents are written out to clusters
+        let mut large_data = None;
         if let Some(file_data) = data {
-            let data_attr = if file_data.len() <= 700 { // Resident threshold
-                Attribute::new_resident(AttributeType::Data, file_data.clone())
+            if file_data.len() <= RESIDENT_DATA_LIMIT {
+                record.add_attribute(Attribute::new_resident(AttributeType::Data, file_data));
             } else {
-                // Create non-resident data (would need cluster allocation)
-                let runs = vec![crate::allocation::ClusterRun::new(0, 1)]; // Simplified
-                Attribute::new_non_resident(AttributeType::Data, runs, file_data.len() as u64)
-            };
-            record.add_attribute(data_attr);
-
-            // Log the operation
-            self.journal_manager.log_operation(
-                transaction_id,
-                OperationType::CreateFile,
-                file_record_number,
-                Vec::new(), // No undo data for create
-                record.serialize().to_vec(),
-            )?;
+                record.add_attribute(Attribute::new_non_resident(AttributeType::Data, Vec::new(), 0));
+                large_data = Some(file_data);
+            }
         }
 
-        // Write the record
+        // Write the record; the journaled device logs its before and after images
         self.mft_manager.write_record(&record)?;
 
+        if let Some(file_data) = large_data {
+            self.write_at(allocator, file_record_number, 0, &file_data)?;
+        }
+
         // Commit the transaction
-        self.journal_manager.commit_transaction(transaction_id)?;
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
 
         Ok(file_record_number)
     }
 
     pub fn create_directory(
         &mut self,
+        allocator: &mut ClusterAllocator,
         parent_directory: FileRecordNumber,
+        index_root_vcn: u64,
         ptr: *const u8,
         len: usize,
         cap: usize,
@@ -412,9 +576,9 @@ impl FileRecordManager {
         // for _ in 1..30000 {
         //     x86_64::instructions::hlt();
         // }
-        let transaction_id = self.journal_manager.begin_transaction();
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::CreateDirectory, parent_directory);
         // Allocate new directory record
-        let dir_record_number = self.mft_manager.allocate_record()?;
+        let dir_record_number = self.allocate_record(allocator)?;
         kprintln!("[FileRecord] Allocated record {}", dir_record_number);
 
         // Create MFT record for directory
@@ -450,7 +614,7 @@ impl FileRecordManager {
 
         // Add Index Root attribute for directory entries
         kprintln!("[FileRecord] Creating IndexRoot...");
-        let index_root_data = create_empty_index_root();
+        let index_root_data = IndexRoot::new(index_root_vcn, parent_directory).serialize();
         kprintln!("[FileRecord] Creating IndexRoot attribute...");
         let index_root_attr = Attribute::new_resident(AttributeType::IndexRoot, index_root_data);
         kprintln!("[FileRecord] Adding IndexRoot attribute...");
@@ -461,39 +625,74 @@ impl FileRecordManager {
 
         // recursive_gloal_increment();
 
-        self.journal_manager.log_operation(
-            transaction_id,
-            OperationType::CreateDirectory,
-            dir_record_number,
-            Vec::new(),
-            record.serialize().to_vec(),
-        )?;
-
         // Write the record
         self.mft_manager.write_record(&record)?;
 
         // Commit the transaction
-        self.journal_manager.commit_transaction(transaction_id)?;
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
 
         Ok(dir_record_number)
     }
 
+    /// Whether the record describes a directory
+    pub fn is_directory(&self, file_record_number: FileRecordNumber) -> FilesystemResult<bool> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(record.header.get_flags().is_directory)
+    }
+
+    /// Read the Index Root of a directory record
+    pub fn read_index_root(&self, directory: FileRecordNumber) -> FilesystemResult<IndexRoot> {
+        let record = self.mft_manager.read_record(directory)?;
+        if !record.header.get_flags().is_directory {
+            return Err(FilesystemError::NotADirectory);
+        }
+
+        for attr in &record.attributes {
+            if attr.header.attr_type == AttributeType::IndexRoot as u32
+                && let AttributeData::Resident(ref data) = attr.data
+            {
+                return IndexRoot::deserialize(data);
+            }
+        }
+
+        Err(FilesystemError::InvalidParameter)
+    }
+
+    /// Directory that contains the given file or directory; for a file with
+    /// several links, the directory of its first one
+    pub fn parent_of(&self, file_record_number: FileRecordNumber) -> FilesystemResult<FileRecordNumber> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+
+        for attr in &record.attributes {
+            if let AttributeData::Resident(ref data) = attr.data {
+                if attr.header.attr_type == AttributeType::FileName as u32 {
+                    return Ok(FileName::deserialize(data)?.parent_directory);
+                }
+                if attr.header.attr_type == AttributeType::IndexRoot as u32 {
+                    return Ok(IndexRoot::deserialize(data)?.parent_directory);
+                }
+            }
+        }
+
+        Err(FilesystemError::InvalidParameter)
+    }
+
     pub fn read_file(&mut self, file_record_number: FileRecordNumber) -> FilesystemResult<Vec<u8>> {
         let record = self.mft_manager.read_record(file_record_number)?;
 
-        // Find the data attribute
+        // Find the unnamed data attribute
         for attr in &record.attributes {
-            if attr.header.attr_type == AttributeType::Data as u32 {
+            if attr.header.attr_type == AttributeType::Data as u32 && attr.name.is_empty() {
                 match &attr.data {
                     AttributeData::Resident(data) => {
                         // Update access time
                         self.update_access_time(file_record_number)?;
                         return Ok(data.clone());
                     }
-                    AttributeData::NonResident { runs: _runs, real_size, .. } => {
+                    AttributeData::NonResident { runs, real_size, .. } => {
                         // Allocate directly to avoid double allocation
-                        let file_data = vec![0u8; *real_size as usize];
-                        // Would read from clusters specified in runs
+                        let mut file_data = vec![0u8; *real_size as usize];
+                        self.read_clusters(runs, 0, &mut file_data)?;
                         self.update_access_time(file_record_number)?;
                         return Ok(file_data);
                     }
@@ -504,68 +703,363 @@ impl FileRecordManager {
         Err(FilesystemError::InvalidParameter)
     }
 
-    pub fn write_file(&mut self, file_record_number: FileRecordNumber, data: Vec<u8>) -> FilesystemResult<()> {
-        let transaction_id = self.journal_manager.begin_transaction();
+    /// Logical size of the file's data in bytes
+    pub fn file_size(&self, file_record_number: FileRecordNumber) -> FilesystemResult<u64> {
+        self.stream_size(file_record_number, "")
+    }
+
+    /// Logical size of a data stream in bytes; `""` is the file's main data
+    pub fn stream_size(&self, file_record_number: FileRecordNumber, stream: &str) -> FilesystemResult<u64> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(match Self::stream_attribute(&record, stream) {
+            Some(index) => match &record.attributes[index].data {
+                AttributeData::Resident(data) => data.len() as u64,
+                AttributeData::NonResident { real_size, .. } => *real_size,
+            },
+            None => 0,
+        })
+    }
+
+    /// Bytes of clusters allocated to the file's data; holes and data stored
+    /// in the record take none
+    pub fn allocated_size(&self, file_record_number: FileRecordNumber) -> FilesystemResult<u64> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(match Self::data_attribute(&record).map(|index| &record.attributes[index].data) {
+            Some(AttributeData::NonResident { allocated_size, .. }) => *allocated_size,
+            _ => 0,
+        })
+    }
+
+    /// Read up to `buf.len()` bytes starting at `offset`
+    ///
+    /// Only the clusters covering the requested range are read. Returns the
+    /// number of bytes read, which is short at end of file.
+    pub fn read_at(&self, file_record_number: FileRecordNumber, offset: u64, buf: &mut [u8]) -> FilesystemResult<usize> {
+        self.read_stream_at(file_record_number, "", offset, buf)
+    }
+
+    /// [`read_at`](Self::read_at) on a named data stream
+    pub fn read_stream_at(
+        &self,
+        file_record_number: FileRecordNumber,
+        stream: &str,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FilesystemResult<usize> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        let Some(index) = Self::stream_attribute(&record, stream) else {
+            return Ok(0);
+        };
+
+        match &record.attributes[index].data {
+            AttributeData::Resident(data) => {
+                if offset >= data.len() as u64 {
+                    return Ok(0);
+                }
+                let start = offset as usize;
+                let count = buf.len().min(data.len() - start);
+                buf[..count].copy_from_slice(&data[start..start + count]);
+                Ok(count)
+            }
+            AttributeData::NonResident { runs, real_size, .. } => {
+                if offset >= *real_size {
+                    return Ok(0);
+                }
+                let count = (buf.len() as u64).min(*real_size - offset) as usize;
+                self.read_clusters(runs, offset, &mut buf[..count])?;
+                Ok(count)
+            }
+        }
+    }
+
+    /// Write `data` at `offset`, growing the file if the write ends past it
+    ///
+    /// Only the clusters covering the written range are touched; a gap
+    /// between the old end of file and `offset` is left as a hole and reads
+    /// back as zeros.
+    pub fn write_at(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        offset: u64,
+        data: &[u8],
+    ) -> FilesystemResult<usize> {
+        self.write_stream_at(allocator, file_record_number, "", offset, data)
+    }
+
+    /// [`write_at`](Self::write_at) on a named data stream, creating it if needed
+    pub fn write_stream_at(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        stream: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> FilesystemResult<usize> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::WriteData, file_record_number);
 
         let mut record = self.mft_manager.read_record(file_record_number)?;
-        let old_record_data = record.serialize().to_vec();
+        let index = self.data_attribute_for_write(&mut record, stream)?;
+        let end = offset.checked_add(data.len() as u64).ok_or(FilesystemError::InvalidParameter)?;
 
-        // Find and update the data attribute
-        for attr in &mut record.attributes {
-            if attr.header.attr_type == AttributeType::Data as u32 {
-                attr.data = if data.len() <= 700 {
-                    AttributeData::Resident(data.clone())
-                } else {
-                    // Convert to non-resident or update existing runs
-                    let runs = vec![crate::allocation::ClusterRun::new(0, 1)]; // Simplified
-                    AttributeData::NonResident {
-                        runs,
-                        allocated_size: (data.len() + 4095) as u64 & !4095, // Round up to cluster
-                        real_size: data.len() as u64,
-                        initialized_size: data.len() as u64,
+        if let AttributeData::Resident(content) = &mut record.attributes[index].data {
+            if end <= RESIDENT_DATA_LIMIT as u64 {
+                if content.len() < end as usize {
+                    content.resize(end as usize, 0);
+                }
+                content[offset as usize..end as usize].copy_from_slice(data);
+            } else {
+                self.make_non_resident(allocator, file_record_number, &mut record.attributes[index])?;
+            }
+        }
+
+        if let AttributeData::NonResident { runs, allocated_size, real_size, initialized_size } = &mut record.attributes[index].data {
+            self.write_clusters(allocator, file_record_number, runs, offset, data)?;
+            *real_size = (*real_size).max(end);
+            *initialized_size = *real_size;
+            *allocated_size = Self::allocated_clusters(runs) * self.cluster_size() as u64;
+        }
+
+        self.update_write_time(&mut record)?;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+
+        Ok(data.len())
+    }
+
+    /// Truncate or extend the file to exactly `len` bytes
+    ///
+    /// Clusters past the new end are released; extended space is a hole and
+    /// reads as zeros.
+    pub fn set_len(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        len: u64,
+    ) -> FilesystemResult<()> {
+        self.set_stream_len(allocator, file_record_number, "", len)
+    }
+
+    /// [`set_len`](Self::set_len) on a named data stream, creating it if needed
+    pub fn set_stream_len(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        stream: &str,
+        len: u64,
+    ) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::WriteData, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let index = self.data_attribute_for_write(&mut record, stream)?;
+
+        if let AttributeData::Resident(content) = &mut record.attributes[index].data {
+            if len <= RESIDENT_DATA_LIMIT as u64 {
+                content.resize(len as usize, 0);
+            } else {
+                self.make_non_resident(allocator, file_record_number, &mut record.attributes[index])?;
+            }
+        }
+
+        if let AttributeData::NonResident { runs, allocated_size, real_size, initialized_size } = &mut record.attributes[index].data {
+            let cluster_size = self.cluster_size() as u64;
+            if len < *real_size {
+                Self::release_runs(allocator, file_record_number, runs, len.div_ceil(cluster_size))?;
+
+                // Keep the bytes past end of file zeroed so a later extension reads zeros
+                let tail = (len % cluster_size) as usize;
+                if tail != 0
+                    && let Some(lcn) = Self::lcn_for_vcn(runs, len / cluster_size)
+                {
+                    let mut cluster = vec![0u8; cluster_size as usize];
+                    self.read_cluster(lcn, &mut cluster)?;
+                    cluster[tail..].fill(0);
+                    self.write_cluster(lcn, &cluster)?;
+                }
+            } else if len.div_ceil(cluster_size) > Self::run_clusters(runs) {
+                let clusters = len.div_ceil(cluster_size) - Self::run_clusters(runs);
+                Self::push_run(runs, DataRun::sparse(clusters));
+            }
+            *real_size = len;
+            *initialized_size = len;
+            *allocated_size = Self::allocated_clusters(runs) * cluster_size;
+        }
+
+        self.update_write_time(&mut record)?;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+
+        Ok(())
+    }
+
+    /// Deallocate the clusters backing `offset..offset + len`, which then read as zeros
+    ///
+    /// Clusters only partly inside the range keep their allocation and are
+    /// zeroed over it. The file size does not change.
+    pub fn punch_hole(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        offset: u64,
+        len: u64,
+    ) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::WriteData, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let index = self.data_attribute_for_write(&mut record, "")?;
+        let cluster_size = self.cluster_size() as u64;
+
+        match &mut record.attributes[index].data {
+            AttributeData::Resident(content) => {
+                let end = offset.saturating_add(len).min(content.len() as u64);
+                if offset < end {
+                    content[offset as usize..end as usize].fill(0);
+                }
+            }
+            AttributeData::NonResident { runs, allocated_size, real_size, .. } => {
+                let end = offset.saturating_add(len).min(*real_size);
+                if offset < end {
+                    // The last cluster is zero past end of file, so a range
+                    // reaching end of file covers it whole
+                    let first_whole = offset.div_ceil(cluster_size);
+                    let end_whole = if end == *real_size { end.div_ceil(cluster_size) } else { end / cluster_size };
+
+                    let mut cluster = vec![0u8; cluster_size as usize];
+                    for vcn in offset / cluster_size..end.div_ceil(cluster_size) {
+                        if (first_whole..end_whole).contains(&vcn) {
+                            continue;
+                        }
+                        let Some(lcn) = Self::lcn_for_vcn(runs, vcn) else {
+                            continue;
+                        };
+                        let cluster_start = vcn * cluster_size;
+                        let from = (offset.max(cluster_start) - cluster_start) as usize;
+                        let to = (end.min(cluster_start + cluster_size) - cluster_start) as usize;
+                        self.read_cluster(lcn, &mut cluster)?;
+                        cluster[from..to].fill(0);
+                        self.write_cluster(lcn, &cluster)?;
                     }
-                };
-                break;
+
+                    if first_whole < end_whole {
+                        let (spliced, released) = Self::splice_runs(runs, first_whole, DataRun::sparse(end_whole - first_whole));
+                        *runs = spliced;
+                        for run in released.into_iter().filter(|run| !run.is_sparse()) {
+                            allocator.deallocate_clusters(file_record_number, run)?;
+                        }
+                    }
+                    *allocated_size = Self::allocated_clusters(runs) * cluster_size;
+                }
             }
         }
 
-        // Update file times
         self.update_write_time(&mut record)?;
+        self.store_record(&mut record)?;
 
-        // Log the operation
-        self.journal_manager.log_operation(
-            transaction_id,
-            OperationType::WriteData,
-            file_record_number,
-            old_record_data,
-            record.serialize().to_vec(),
-        )?;
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
 
-        // Write the updated record
-        self.mft_manager.write_record(&record)?;
+        Ok(())
+    }
+
+    /// Replace the whole contents of the file
+    ///
+    /// Existing clusters are reused in place and only the excess is freed.
+    pub fn write_file(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        data: Vec<u8>,
+    ) -> FilesystemResult<()> {
+        self.write_stream(allocator, file_record_number, "", data)
+    }
+
+    /// Replace the whole contents of a named data stream, creating it if needed
+    pub fn write_stream(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        stream: &str,
+        data: Vec<u8>,
+    ) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::WriteData, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let index = self.data_attribute_for_write(&mut record, stream)?;
+
+        if data.len() <= RESIDENT_DATA_LIMIT {
+            // Small contents move back into the record
+            if let AttributeData::NonResident { runs, .. } = &mut record.attributes[index].data {
+                Self::release_runs(allocator, file_record_number, runs, 0)?;
+            }
+            record.attributes[index].data = AttributeData::Resident(data);
+            self.update_write_time(&mut record)?;
+            self.store_record(&mut record)?;
+        } else {
+            self.set_stream_len(allocator, file_record_number, stream, data.len() as u64)?;
+            self.write_stream_at(allocator, file_record_number, stream, 0, &data)?;
+        }
 
         // Commit the transaction
-        self.journal_manager.commit_transaction(transaction_id)?;
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
 
         Ok(())
     }
 
     fn update_access_time(&mut self, file_record_number: FileRecordNumber) -> FilesystemResult<()> {
+        if self.atime_policy == AtimePolicy::Noatime {
+            return Ok(());
+        }
         let mut record = self.mft_manager.read_record(file_record_number)?;
 
-        // Update access time in Standard Information
+        // Update access time in Standard Information, if the policy asks for it
+        let now = get_current_time();
+        let mut due = false;
         for attr in &mut record.attributes {
             if attr.header.attr_type == AttributeType::StandardInformation as u32 {
                 if let AttributeData::Resident(ref mut data) = attr.data {
                     let mut std_info = StandardInformation::deserialize(data)?;
-                    std_info.times.update_access();
+                    due = std_info.times.access_due(self.atime_policy, now);
+                    std_info.times.last_access_time = now;
                     *data = std_info.serialize().to_vec();
                 }
                 break;
             }
         }
 
-        self.mft_manager.write_record(&record)?;
+        if due {
+            self.mft_manager.write_record(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Store the link count in both the record header and Standard Information
+    fn set_links(record: &mut MftRecord, links: u16) -> FilesystemResult<()> {
+        record.header.hard_link_count = links;
+        for attr in &mut record.attributes {
+            if attr.header.attr_type == AttributeType::StandardInformation as u32 {
+                if let AttributeData::Resident(ref mut data) = attr.data {
+                    let mut std_info = StandardInformation::deserialize(data)?;
+                    std_info.link_count = links;
+                    *data = std_info.serialize();
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_metadata_time(record: &mut MftRecord) -> FilesystemResult<()> {
+        for attr in &mut record.attributes {
+            if attr.header.attr_type == AttributeType::StandardInformation as u32 {
+                if let AttributeData::Resident(ref mut data) = attr.data {
+                    let mut std_info = StandardInformation::deserialize(data)?;
+                    std_info.times.update_metadata();
+                    *data = std_info.serialize();
+                }
+                break;
+            }
+        }
         Ok(())
     }
 
@@ -584,11 +1078,17 @@ impl FileRecordManager {
         Ok(())
     }
 
-    pub fn delete_file(&mut self, file_record_number: FileRecordNumber) -> FilesystemResult<()> {
-        let transaction_id = self.journal_manager.begin_transaction();
+    pub fn delete_file(&mut self, allocator: &mut ClusterAllocator, file_record_number: FileRecordNumber) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::DeleteFile, file_record_number);
 
         let record = self.mft_manager.read_record(file_record_number)?;
-        let old_record_data = record.serialize().to_vec();
+
+        // Release the clusters holding the file's data and named streams
+        for attr in &record.attributes {
+            if let AttributeData::NonResident { runs, .. } = &attr.data {
+                Self::release_runs(allocator, file_record_number, &mut runs.clone(), 0)?;
+            }
+        }
 
         // Mark record as not in use
         let mut new_record = record.clone();
@@ -596,38 +1096,742 @@ impl FileRecordManager {
         flags.in_use = false;
         new_record.header.set_flags(flags);
 
-        // Log the operation
-        self.journal_manager.log_operation(
-            transaction_id,
-            OperationType::DeleteFile,
-            file_record_number,
-            old_record_data, // For recovery
-            new_record.serialize().to_vec(),
-        )?;
-
         // Write the updated record
         self.mft_manager.write_record(&new_record)?;
 
         // Commit the transaction
-        self.journal_manager.commit_transaction(transaction_id)?;
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+
+        Ok(())
+    }
+
+    /// Data runs of a file, or `None` while its data is resident
+    pub fn data_runs(&self, file_record_number: FileRecordNumber) -> FilesystemResult<Option<Vec<DataRun>>> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(Self::data_attribute(&record).and_then(|index| match &record.attributes[index].data {
+            AttributeData::NonResident { runs, .. } => Some(runs.clone()),
+            AttributeData::Resident(_) => None,
+        }))
+    }
+
+    /// Drop every run from the first one `keep` rejects, shrinking the file to match
+    ///
+    /// Applies to each data stream. The dropped clusters are not freed; they
+    /// may belong to something else.
+    pub fn truncate_runs(
+        &mut self,
+        file_record_number: FileRecordNumber,
+        mut keep: impl FnMut(&DataRun) -> bool,
+    ) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::WriteData, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let cluster_size = self.cluster_size() as u64;
+        for attr in &mut record.attributes {
+            let AttributeData::NonResident { runs, allocated_size, real_size, initialized_size } = &mut attr.data else {
+                continue;
+            };
+            let valid = runs.iter().position(|run| !keep(run)).unwrap_or(runs.len());
+            runs.truncate(valid);
+            *allocated_size = Self::allocated_clusters(runs) * cluster_size;
+            *real_size = (*real_size).min(Self::run_clusters(runs) * cluster_size);
+            *initialized_size = *real_size;
+        }
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Copy the clusters of a file starting at `first_vcn` into `target` and
+    /// point the file at the copy
+    ///
+    /// `target` must already be allocated. Returns the physical runs that were
+    /// replaced; the caller decides whether they can be freed.
+    pub fn relocate_clusters(
+        &mut self,
+        file_record_number: FileRecordNumber,
+        first_vcn: u64,
+        target: DataRun,
+    ) -> FilesystemResult<Vec<DataRun>> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::WriteData, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let index = Self::data_attribute(&record).ok_or(FilesystemError::InvalidParameter)?;
+        let AttributeData::NonResident { runs, .. } = &mut record.attributes[index].data else {
+            return Err(FilesystemError::InvalidParameter);
+        };
+        if target.cluster_count == 0 || first_vcn + target.cluster_count > Self::run_clusters(runs) {
+            return Err(FilesystemError::InvalidParameter);
+        }
+
+        let mut cluster = vec![0u8; self.cluster_size() as usize];
+        for i in 0..target.cluster_count {
+            let lcn = Self::lcn_for_vcn(runs, first_vcn + i).ok_or(FilesystemError::InvalidParameter)?;
+            self.read_cluster(lcn, &mut cluster)?;
+            self.write_cluster(target.start_cluster + i, &cluster)?;
+        }
+
+        let (spliced, replaced) = Self::splice_runs(runs, first_vcn, target);
+        *runs = spliced;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(replaced)
+    }
+
+    /// Set the number of directory entries that refer to a record
+    pub fn set_link_count(&mut self, file_record_number: FileRecordNumber, links: u16) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::UpdateMetadata, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        Self::set_links(&mut record, links)?;
+        self.mft_manager.write_record(&record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Number of directory entries that refer to a record
+    pub fn link_count(&self, file_record_number: FileRecordNumber) -> FilesystemResult<u16> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(record.header.hard_link_count)
+    }
+
+    /// Standard Information of a record: times, link count and POSIX metadata
+    pub fn standard_information(&self, file_record_number: FileRecordNumber) -> FilesystemResult<StandardInformation> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        for attr in &record.attributes {
+            if attr.header.attr_type == AttributeType::StandardInformation as u32
+                && let AttributeData::Resident(ref data) = attr.data
+            {
+                return StandardInformation::deserialize(data);
+            }
+        }
+        Err(FilesystemError::InvalidParameter)
+    }
+
+    /// Set the permission, setuid, setgid and sticky bits; the file type is kept
+    pub fn set_mode(&mut self, file_record_number: FileRecordNumber, permissions: u32) -> FilesystemResult<()> {
+        self.update_standard_information(file_record_number, |std_info, now| {
+            std_info.mode = (std_info.mode & !mode::PERMISSION_BITS) | (permissions & mode::PERMISSION_BITS);
+            std_info.times.mft_change_time = now;
+        })
+    }
+
+    /// Change the owner and group; `None` leaves one unchanged
+    ///
+    /// As on other POSIX systems, a regular file loses its setuid and setgid
+    /// bits when either changes.
+    pub fn set_owner(&mut self, file_record_number: FileRecordNumber, uid: Option<u32>, gid: Option<u32>) -> FilesystemResult<()> {
+        self.update_standard_information(file_record_number, |std_info, now| {
+            let uid = uid.unwrap_or(std_info.uid);
+            let gid = gid.unwrap_or(std_info.gid);
+            if (uid, gid) != (std_info.uid, std_info.gid) && std_info.mode & mode::S_IFMT == mode::S_IFREG {
+                std_info.mode &= !(mode::S_ISUID | mode::S_ISGID);
+            }
+            std_info.uid = uid;
+            std_info.gid = gid;
+            std_info.times.mft_change_time = now;
+        })
+    }
+
+    /// Set the access and modification times, as `utimensat` does
+    pub fn set_times(&mut self, file_record_number: FileRecordNumber, access: TimeUpdate, modification: TimeUpdate) -> FilesystemResult<()> {
+        self.update_standard_information(file_record_number, |std_info, now| {
+            access.apply(&mut std_info.times.last_access_time, now);
+            modification.apply(&mut std_info.times.last_write_time, now);
+            std_info.times.mft_change_time = now;
+        })
+    }
+
+    /// Change a record's Standard Information in one transaction
+    fn update_standard_information(
+        &mut self,
+        file_record_number: FileRecordNumber,
+        update: impl FnOnce(&mut StandardInformation, u64),
+    ) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::SetAttribute, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let attr = record.attributes.iter_mut()
+            .find(|attr| attr.header.attr_type == AttributeType::StandardInformation as u32)
+            .ok_or(FilesystemError::InvalidParameter)?;
+        let AttributeData::Resident(ref mut data) = attr.data else {
+            return Err(FilesystemError::InvalidParameter);
+        };
+        let mut std_info = StandardInformation::deserialize(data)?;
+        update(&mut std_info, get_current_time());
+        *data = std_info.serialize();
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Names the file is linked under, one per FileName attribute
+    pub fn file_names(&self, file_record_number: FileRecordNumber) -> FilesystemResult<Vec<FileName>> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        record.attributes.iter()
+            .filter(|attr| attr.header.attr_type == AttributeType::FileName as u32)
+            .filter_map(|attr| match &attr.data {
+                AttributeData::Resident(data) => Some(FileName::deserialize(data)),
+                AttributeData::NonResident { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Record another name for a file in `parent`
+    ///
+    /// Only the file record changes; the caller adds the directory entry.
+    /// Directories cannot be linked.
+    pub fn add_link(&mut self, file_record_number: FileRecordNumber, parent: FileRecordNumber, name: &str) -> FilesystemResult<u16> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::UpdateMetadata, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        if record.header.get_flags().is_directory {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        let links = record.header.hard_link_count.checked_add(1).ok_or(FilesystemError::InvalidParameter)?;
+        let file_name = FileName::new(parent, String::from(name), false);
+        record.add_attribute(Attribute::new_resident(AttributeType::FileName, file_name.serialize()));
+        Self::set_links(&mut record, links)?;
+        Self::update_metadata_time(&mut record)?;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(links)
+    }
+
+    /// Drop the name `name` in `parent` from a file, deleting the file with
+    /// its last link
+    ///
+    /// Only the file record changes; the caller removes the directory entry.
+    /// Returns the links left.
+    pub fn remove_link(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        parent: FileRecordNumber,
+        name: &str,
+    ) -> FilesystemResult<u16> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::DeleteFile, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let links = record.header.hard_link_count.saturating_sub(1);
+        if links == 0 {
+            self.delete_file(allocator, file_record_number)?;
+        } else {
+            let position = record.attributes.iter().position(|attr| {
+                attr.header.attr_type == AttributeType::FileName as u32
+                    && matches!(&attr.data, AttributeData::Resident(data)
+                        if FileName::deserialize(data).is_ok_and(|file_name| file_name.parent_directory == parent && file_name.name == name))
+            });
+            if let Some(index) = position {
+                record.attributes.remove(index);
+            }
+            Self::set_links(&mut record, links)?;
+            Self::update_metadata_time(&mut record)?;
+            self.store_record(&mut record)?;
+        }
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(links)
+    }
+
+    /// Point a record at a new parent directory and name, leaving it with one link
+    pub fn relink(&mut self, file_record_number: FileRecordNumber, parent: FileRecordNumber, name: &str) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::MoveFile, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+
+        // Other names the file had are dropped along with their entries
+        let mut seen_name = false;
+        record.attributes.retain(|attr| {
+            let keep = attr.header.attr_type != AttributeType::FileName as u32 || !seen_name;
+            seen_name |= attr.header.attr_type == AttributeType::FileName as u32;
+            keep
+        });
+
+        for attr in &mut record.attributes {
+            if let AttributeData::Resident(ref mut data) = attr.data {
+                if attr.header.attr_type == AttributeType::FileName as u32 {
+                    let mut file_name = FileName::deserialize(data)?;
+                    file_name.parent_directory = parent;
+                    file_name.name = String::from(name);
+                    *data = file_name.serialize();
+                } else if attr.header.attr_type == AttributeType::IndexRoot as u32 {
+                    let mut index_root = IndexRoot::deserialize(data)?;
+                    index_root.parent_directory = parent;
+                    *data = index_root.serialize();
+                }
+            }
+        }
+        Self::set_links(&mut record, 1)?;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Overwrite a record slot with an unused record
+    pub fn clear_record(&mut self, file_record_number: FileRecordNumber) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::DeleteFile, file_record_number);
+        self.mft_manager.write_record(&MftRecord::new(file_record_number))?;
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Names of the file's named data streams
+    pub fn list_streams(&self, file_record_number: FileRecordNumber) -> FilesystemResult<Vec<String>> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(record.attributes.iter()
+            .filter(|attr| attr.header.attr_type == AttributeType::Data as u32 && !attr.name.is_empty())
+            .map(|attr| attr.name.clone())
+            .collect())
+    }
+
+    /// Remove a named data stream and free its clusters
+    pub fn delete_stream(
+        &mut self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        stream: &str,
+    ) -> FilesystemResult<()> {
+        if stream.is_empty() {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::WriteData, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let index = Self::stream_attribute(&record, stream).ok_or(FilesystemError::NotFound)?;
+        if let AttributeData::NonResident { runs, .. } = &mut record.attributes[index].data {
+            Self::release_runs(allocator, file_record_number, runs, 0)?;
+        }
+        record.attributes.remove(index);
+        self.update_write_time(&mut record)?;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Value of an extended attribute, or `None` if the file does not have it
+    pub fn get_xattr(&self, file_record_number: FileRecordNumber, key: &str) -> FilesystemResult<Option<Vec<u8>>> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(Self::xattr_attribute(&record, key).and_then(|index| match &record.attributes[index].data {
+            AttributeData::Resident(value) => Some(value.clone()),
+            AttributeData::NonResident { .. } => None,
+        }))
+    }
+
+    /// Set an extended attribute, replacing any previous value
+    ///
+    /// Values are stored in the file record, so they fail with
+    /// `InsufficientSpace` once the record is full.
+    pub fn set_xattr(&mut self, file_record_number: FileRecordNumber, key: &str, value: &[u8]) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::SetAttribute, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let attr = Attribute::new_resident(AttributeType::ExtendedAttribute, value.to_vec()).with_name(String::from(key));
+        match Self::xattr_attribute(&record, key) {
+            Some(index) => record.attributes[index] = attr,
+            None => record.add_attribute(attr),
+        }
+        Self::update_metadata_time(&mut record)?;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Keys of the file's extended attributes
+    pub fn list_xattrs(&self, file_record_number: FileRecordNumber) -> FilesystemResult<Vec<String>> {
+        let record = self.mft_manager.read_record(file_record_number)?;
+        Ok(record.attributes.iter()
+            .filter(|attr| attr.header.attr_type == AttributeType::ExtendedAttribute as u32)
+            .map(|attr| attr.name.clone())
+            .collect())
+    }
+
+    /// Remove an extended attribute
+    pub fn remove_xattr(&mut self, file_record_number: FileRecordNumber, key: &str) -> FilesystemResult<()> {
+        let transaction_id = self.journal_manager.lock().begin_transaction(OperationType::SetAttribute, file_record_number);
+
+        let mut record = self.mft_manager.read_record(file_record_number)?;
+        let index = Self::xattr_attribute(&record, key).ok_or(FilesystemError::NotFound)?;
+        record.attributes.remove(index);
+        Self::update_metadata_time(&mut record)?;
+        self.store_record(&mut record)?;
+
+        self.journal_manager.lock().commit_transaction(transaction_id)?;
+        Ok(())
+    }
+
+    /// Index of the unnamed Data attribute, if the record has one
+    fn data_attribute(record: &MftRecord) -> Option<usize> {
+        Self::stream_attribute(record, "")
+    }
+
+    /// Index of the Data attribute named `stream`; `""` is the unnamed one
+    fn stream_attribute(record: &MftRecord, stream: &str) -> Option<usize> {
+        record.attributes.iter().position(|attr| attr.header.attr_type == AttributeType::Data as u32 && attr.name == stream)
+    }
+
+    /// Index of the extended attribute `key`
+    fn xattr_attribute(record: &MftRecord, key: &str) -> Option<usize> {
+        record.attributes.iter().position(|attr| attr.header.attr_type == AttributeType::ExtendedAttribute as u32 && attr.name == key)
+    }
+
+    /// Index of a Data attribute, adding an empty one to a file without it
+    fn data_attribute_for_write(&self, record: &mut MftRecord, stream: &str) -> FilesystemResult<usize> {
+        if record.header.get_flags().is_directory {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        if let Some(index) = Self::stream_attribute(record, stream) {
+            return Ok(index);
+        }
+        record.add_attribute(Attribute::new_resident(AttributeType::Data, Vec::new()).with_name(String::from(stream)));
+        Ok(record.attributes.len() - 1)
+    }
+
+    /// Move resident contents out of the record into freshly allocated clusters
+    fn make_non_resident(
+        &self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        attr: &mut Attribute,
+    ) -> FilesystemResult<()> {
+        let AttributeData::Resident(content) = &attr.data else {
+            return Ok(());
+        };
+
+        let mut runs = Vec::new();
+        self.write_clusters(allocator, file_record_number, &mut runs, 0, content)?;
+        let real_size = content.len() as u64;
+        *attr = Attribute::new_non_resident(AttributeType::Data, runs, real_size).with_name(core::mem::take(&mut attr.name));
+        Ok(())
+    }
+
+    /// Write `data` at `offset` into the clusters of `runs`
+    ///
+    /// Holes the write touches are allocated, and the space between the end
+    /// of `runs` and the write becomes a hole. Newly allocated clusters start
+    /// out zeroed; bytes of existing clusters outside the written range are
+    /// preserved.
+    fn write_clusters(
+        &self,
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        runs: &mut Vec<DataRun>,
+        offset: u64,
+        data: &[u8],
+    ) -> FilesystemResult<()> {
+        let cluster_size = self.cluster_size() as u64;
+        let end = offset + data.len() as u64;
+        let first_vcn = offset / cluster_size;
+        let end_vcn = end.div_ceil(cluster_size);
+
+        let have = Self::run_clusters(runs);
+        if end_vcn > have {
+            Self::push_run(runs, DataRun::sparse(end_vcn - have));
+        }
+        let filled = Self::fill_holes(allocator, file_record_number, runs, first_vcn, end_vcn)?;
+
+        let mut cluster = vec![0u8; cluster_size as usize];
+        for vcn in first_vcn..end_vcn {
+            let lcn = Self::lcn_for_vcn(runs, vcn).ok_or(FilesystemError::InvalidParameter)?;
+            let cluster_start = vcn * cluster_size;
+            let from = offset.max(cluster_start);
+            let to = end.min(cluster_start + cluster_size);
+
+            if from == cluster_start && to == cluster_start + cluster_size {
+                // Whole cluster overwritten
+                let start = (from - offset) as usize;
+                self.write_cluster(lcn, &data[start..start + cluster_size as usize])?;
+                continue;
+            }
+
+            if filled.iter().any(|hole| hole.contains(&vcn)) {
+                cluster.fill(0);
+            } else {
+                self.read_cluster(lcn, &mut cluster)?;
+            }
+            let start = (from - offset) as usize;
+            let within = (from - cluster_start) as usize;
+            cluster[within..within + (to - from) as usize].copy_from_slice(&data[start..start + (to - from) as usize]);
+            self.write_cluster(lcn, &cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fill `buf` from the clusters of `runs` starting at byte `offset`
+    fn read_clusters(&self, runs: &[DataRun], offset: u64, buf: &mut [u8]) -> FilesystemResult<()> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut cluster = vec![0u8; cluster_size as usize];
+        let mut done = 0;
+
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = (position % cluster_size) as usize;
+            let count = (cluster_size as usize - within).min(buf.len() - done);
+
+            match Self::lcn_for_vcn(runs, position / cluster_size) {
+                Some(lcn) if count == cluster_size as usize => {
+                    self.read_cluster(lcn, &mut buf[done..done + count])?;
+                }
+                Some(lcn) => {
+                    self.read_cluster(lcn, &mut cluster)?;
+                    buf[done..done + count].copy_from_slice(&cluster[within..within + count]);
+                }
+                // Unallocated clusters read as zeros
+                None => buf[done..done + count].fill(0),
+            }
+            done += count;
+        }
 
         Ok(())
     }
+
+    /// Allocate clusters for every hole of `runs` within `first_vcn..end_vcn`
+    ///
+    /// Returns the virtual cluster ranges that were holes.
+    fn fill_holes(
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        runs: &mut Vec<DataRun>,
+        first_vcn: u64,
+        end_vcn: u64,
+    ) -> FilesystemResult<Vec<Range<u64>>> {
+        let mut holes = Vec::new();
+        let mut run_vcn = 0;
+        for run in runs.iter() {
+            let run_end = run_vcn + run.cluster_count;
+            let start = run_vcn.max(first_vcn);
+            let stop = run_end.min(end_vcn);
+            if run.is_sparse() && start < stop {
+                holes.push(start..stop);
+            }
+            run_vcn = run_end;
+        }
+
+        for hole in &holes {
+            let mut vcn = hole.start;
+            for run in Self::allocate_runs(allocator, file_record_number, hole.end - hole.start)? {
+                *runs = Self::splice_runs(runs, vcn, run).0;
+                vcn += run.cluster_count;
+            }
+        }
+        Ok(holes)
+    }
+
+    /// Allocate `clusters` clusters in as few runs as free space allows
+    ///
+    /// Falls back to smaller extents when no free run of the full length exists.
+    fn allocate_runs(
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        clusters: u64,
+    ) -> FilesystemResult<Vec<DataRun>> {
+        let mut allocated = Vec::new();
+        let mut have = 0;
+        let mut request = clusters;
+
+        while have < clusters {
+            let run = match allocator.allocate_clusters(file_record_number, request.min(clusters - have)) {
+                Ok(run) => run,
+                Err(FilesystemError::InsufficientSpace) if request > 1 => {
+                    request /= 2;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            Self::push_run(&mut allocated, run);
+            have += run.cluster_count;
+        }
+
+        Ok(allocated)
+    }
+
+    /// Append a run, merging it into the last one when both are holes or
+    /// they are physically contiguous
+    fn push_run(runs: &mut Vec<DataRun>, run: DataRun) {
+        match runs.last_mut() {
+            Some(last) if last.is_sparse() && run.is_sparse() => {
+                last.cluster_count += run.cluster_count;
+            }
+            Some(last) if !last.is_sparse() && !run.is_sparse()
+                && last.start_cluster + last.cluster_count == run.start_cluster =>
+            {
+                last.cluster_count += run.cluster_count;
+            }
+            _ => runs.push(run),
+        }
+    }
+
+    /// Map `replacement.cluster_count` clusters from `first_vcn` onto `replacement`
+    ///
+    /// Returns the new run list and the physical runs it no longer uses.
+    fn splice_runs(runs: &[DataRun], first_vcn: u64, replacement: DataRun) -> (Vec<DataRun>, Vec<DataRun>) {
+        let end_vcn = first_vcn + replacement.cluster_count;
+        let mut spliced = Vec::new();
+        let mut replaced = Vec::new();
+        let mut run_vcn = 0;
+
+        for run in runs {
+            let run_end = run_vcn + run.cluster_count;
+            if run_vcn < first_vcn {
+                let count = run_end.min(first_vcn) - run_vcn;
+                Self::push_run(&mut spliced, run.slice(0, count));
+            }
+
+            let overlap_start = run_vcn.max(first_vcn);
+            let overlap_end = run_end.min(end_vcn);
+            if overlap_start < overlap_end {
+                replaced.push(run.slice(overlap_start - run_vcn, overlap_end - overlap_start));
+                if overlap_start == first_vcn {
+                    Self::push_run(&mut spliced, replacement);
+                }
+            }
+
+            if run_end > end_vcn {
+                let start = run_vcn.max(end_vcn);
+                Self::push_run(&mut spliced, run.slice(start - run_vcn, run_end - start));
+            }
+            run_vcn = run_end;
+        }
+
+        (spliced, replaced)
+    }
+
+    /// Free every cluster of `runs` past the first `keep` clusters
+    fn release_runs(
+        allocator: &mut ClusterAllocator,
+        file_record_number: FileRecordNumber,
+        runs: &mut Vec<DataRun>,
+        keep: u64,
+    ) -> FilesystemResult<()> {
+        let mut seen = 0;
+        let mut kept = Vec::new();
+
+        for run in runs.drain(..) {
+            if seen >= keep {
+                if !run.is_sparse() {
+                    allocator.deallocate_clusters(file_record_number, run)?;
+                }
+            } else if seen + run.cluster_count > keep {
+                let retained = keep - seen;
+                kept.push(run.slice(0, retained));
+                if !run.is_sparse() {
+                    allocator.deallocate_clusters(file_record_number, run.slice(retained, run.cluster_count - retained))?;
+                }
+            } else {
+                kept.push(run);
+            }
+            seen += run.cluster_count;
+        }
+
+        *runs = kept;
+        Ok(())
+    }
+
+    /// Physical cluster backing virtual cluster `vcn`, or `None` in a hole
+    fn lcn_for_vcn(runs: &[DataRun], vcn: u64) -> Option<u64> {
+        let mut run_start = 0;
+        for run in runs {
+            if vcn < run_start + run.cluster_count {
+                return (!run.is_sparse()).then(|| run.start_cluster + (vcn - run_start));
+            }
+            run_start += run.cluster_count;
+        }
+        None
+    }
+
+    /// Virtual clusters covered by `runs`, holes included
+    fn run_clusters(runs: &[DataRun]) -> u64 {
+        runs.iter().map(|run| run.cluster_count).sum()
+    }
+
+    /// Clusters actually allocated to `runs`
+    fn allocated_clusters(runs: &[DataRun]) -> u64 {
+        runs.iter().filter(|run| !run.is_sparse()).map(|run| run.cluster_count).sum()
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.mft_manager.cluster_size
+    }
+
+    fn read_cluster(&self, lcn: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        let sectors_per_cluster = self.cluster_size() as u64 / self.data_device.sector_size() as u64;
+        self.data_device.read_sectors(lcn * sectors_per_cluster, buffer)
+    }
+
+    fn write_cluster(&self, lcn: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        let sectors_per_cluster = self.cluster_size() as u64 / self.data_device.sector_size() as u64;
+        self.data_device.write_sectors(lcn * sectors_per_cluster, buffer)
+    }
+
+    /// Find a free record, growing the MFT into new clusters when every slot is taken
+    fn allocate_record(&mut self, allocator: &mut ClusterAllocator) -> FilesystemResult<FileRecordNumber> {
+        match self.mft_manager.allocate_record() {
+            Err(FilesystemError::InsufficientSpace) => {}
+            result => return result,
+        }
+
+        let run = allocator.allocate_clusters(MFT_RECORD_MFT, MFT_GROWTH_CLUSTERS)?;
+        kprintln!("[FileRecord] Growing MFT by {} clusters at {}", run.cluster_count, run.start_cluster);
+
+        // The clusters stay free on disk until the transaction commits, so
+        // clearing them bypasses the journal
+        let zeros = vec![0u8; self.cluster_size() as usize];
+        for cluster in run.start_cluster..=run.end_cluster() {
+            self.write_cluster(cluster, &zeros)?;
+        }
+
+        if let Err(error) = self.mft_manager.extend(run) {
+            allocator.deallocate_clusters(MFT_RECORD_MFT, run)?;
+            return Err(error);
+        }
+        self.mft_manager.allocate_record()
+    }
+
+    /// Write a record whose attributes may have grown, refusing one that no longer fits
+    fn store_record(&self, record: &mut MftRecord) -> FilesystemResult<()> {
+        record.update_bytes_in_use();
+        if record.header.bytes_in_use as usize > MFT_RECORD_SIZE {
+            return Err(FilesystemError::InsufficientSpace);
+        }
+        self.mft_manager.write_record(record)
+    }
 }
 
-// Helper functions
-fn get_current_time() -> u64 {
-    // TODO: Implement proper timestamp using RTC/TSC
-    // For now, return a fixed timestamp
-    0x01D0000000000000 // Arbitrary NT timestamp
+/// Source of the current time in nanoseconds
+pub type Clock = fn() -> u64;
+
+static CLOCK: Mutex<Clock> = Mutex::new(default_clock);
+
+/// Replace the clock timestamps are taken from
+///
+/// The kernel's tick counter is used by default; builds without the kernel
+/// start at zero until a clock is installed.
+pub fn set_clock(clock: Clock) {
+    *CLOCK.lock() = clock;
 }
 
-fn create_empty_index_root() -> Vec<u8> {
-    // Create empty B+ tree index root for directory
-    let mut data = Vec::new();
-    data.extend_from_slice(b"INDX"); // Signature
-    data.extend_from_slice(&0u32.to_le_bytes()); // Entry count
-    data.extend_from_slice(&16u32.to_le_bytes()); // Index size
-    data.extend_from_slice(&0u32.to_le_bytes()); // Flags
-    data
+#[cfg(feature = "kernel")]
+fn default_clock() -> u64 {
+    lib_kernel::time::get_timestamp_ns()
+}
+
+#[cfg(not(feature = "kernel"))]
+fn default_clock() -> u64 {
+    0
+}
+
+// Helper functions
+fn get_current_time() -> u64 {
+    let clock = *CLOCK.lock();
+    clock()
 }
@@ -1,8 +1,7 @@
-use ide::{ide_write_sectors, IdeResult};
-use lib_kernel::kprintln;
+use crate::kprintln;
 
 use crate::{
-    return_drive_size_bytes, validate_super_block, 
+    block_device::BlockDevice, validate_super_block,
     write_super_block, FilesystemError, FilesystemResult
 };
 
@@ -12,21 +11,22 @@ struct AlignedZeroBuf {
     buf: [u8; 50 * 512],
 }
 
-pub fn zero_out_sectors(drive: u8, start_sector: u64, sector_count: u8) -> IdeResult<()> {
+pub fn zero_out_sectors(device: &dyn BlockDevice, start_sector: u64, sector_count: u8) -> FilesystemResult<()> {
     // Use a 4096-aligned buffer for 50 sectors (50 * 512 = 25,600 bytes)
     let zero_buf = AlignedZeroBuf { buf: [0u8; 50 * 512] };
-    let res = ide_write_sectors(drive, sector_count, start_sector as u32, &zero_buf.buf);
+    let len = (sector_count.min(50) as usize) * 512;
+    let res = device.write_sectors(start_sector, &zero_buf.buf[..len]);
     match &res {
         Ok(_) => {},
-        Err(e) => kprintln!("Failed to zero {} sectors starting at sector {} on drive {}: {:?}", sector_count, start_sector, drive, e),
+        Err(e) => kprintln!("Failed to zero {} sectors starting at sector {}: {:?}", sector_count, start_sector, e),
     }
     res
 }
 
-/// Initialize filesystem on a drive with proper error handling
-pub fn init_fs(drive: u8) -> FilesystemResult<()> {
+/// Initialize filesystem on a block device with proper error handling
+pub fn init_fs(device: &dyn BlockDevice) -> FilesystemResult<()> {
     // Get disk size with error handling
-    let disk_size_bytes = return_drive_size_bytes(drive)?;
+    let disk_size_bytes = device.capacity_bytes();
 
     if disk_size_bytes == 0 {
         kprintln!("Drive size is 0 bytes");
@@ -40,7 +40,7 @@ pub fn init_fs(drive: u8) -> FilesystemResult<()> {
     //    let sectors_remaining = disk_sectors - sector;
     //    let sectors_to_zero = if sectors_remaining >= 50 { 50 } else { sectors_remaining as u8 };
     //    
-    //    let res = zero_out_sectors(drive, sector, sectors_to_zero);
+    //    let res = zero_out_sectors(device, sector, sectors_to_zero);
     //    match res {
     //        Ok(_) => {} // already printed success inside zero_out_sectors
     //        Err(e) => {
@@ -62,11 +62,11 @@ pub fn init_fs(drive: u8) -> FilesystemResult<()> {
     }
 
     // Write super block with error handling
-    write_super_block(drive, total_blocks, block_size, 1)?;
+    write_super_block(device, total_blocks, block_size, 1)?;
 
     // Validate that the super block was written correctly
-    validate_super_block(drive)?;
+    validate_super_block(device)?;
 
-    kprintln!("Filesystem initialized successfully on drive {} with {} blocks", drive, total_blocks);
+    kprintln!("Filesystem initialized successfully with {} blocks", total_blocks);
     Ok(())
 }
\ No newline at end of file
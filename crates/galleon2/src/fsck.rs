@@ -0,0 +1,598 @@
+//! Consistency Checker
+//!
+//! Walks every structure of a mounted volume and cross-checks them against
+//! each other: the superblock layout, each MFT record, the B-tree of every
+//! directory and the cluster bitmap. Each cluster must have exactly one owner
+//! (the system area, an index node or a file's data) and be marked used in the
+//! bitmap exactly when it has one. Each index entry must point at a record in
+//! use, and each record in use must be referenced by as many entries as its
+//! link count says.
+//!
+//! In repair mode the problems are fixed through ordinary journaled
+//! transactions, in an order where each step only relies on structures the
+//! steps before it have made consistent. Files that no directory refers to
+//! are moved into `/lost+found`, named after their record number.
+
+use alloc::{collections::{BTreeMap, BTreeSet}, format, string::String, vec::Vec};
+use core::mem::ManuallyDrop;
+use crate::{
+    FilesystemError, FilesystemResult,
+    allocation::ClusterRun,
+    btree::{IndexEntry, IndexNode},
+    file_record::FileName,
+    galleon_fs::{GalleonFilesystem, GalleonSuperBlock, BACKUP_SUPER_BLOCK_SECTOR},
+    journal::OperationType,
+    mft::{AttributeData, DataRun, FileRecordNumber, MftRecord, MFT_FIRST_USER_RECORD, MFT_RECORD_MFT, MFT_RECORD_ROOT},
+    super_block::SuperBlock,
+};
+use crate::kprintln;
+
+/// Directory in the root that repair moves orphaned records into
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// What a cluster belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusterOwner {
+    /// Boot sector, MFT, journal or bitmap, including MFT and bitmap runs outside the system area
+    System,
+    /// A node of a directory's B-tree
+    Index { directory: FileRecordNumber },
+    /// Data of a file
+    Data { record: FileRecordNumber },
+}
+
+/// A problem found on the volume
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckIssue {
+    /// The on-disk superblock is unreadable or describes an impossible layout
+    BadSuperBlock(&'static str),
+    /// An MFT slot holds a record that fails to parse or validate
+    CorruptRecord { record: FileRecordNumber, error: FilesystemError },
+    /// An index node of a directory fails to parse or lies outside the volume
+    CorruptIndexNode { directory: FileRecordNumber, vcn: u64 },
+    /// An index entry points at a record that is not in use
+    DanglingEntry { directory: FileRecordNumber, name: String, record: FileRecordNumber },
+    /// A record in use that no directory refers to
+    Orphan { record: FileRecordNumber },
+    /// The number of index entries referring to a record differs from its link count
+    LinkCountMismatch { record: FileRecordNumber, links: u16, recorded: u16 },
+    /// A data run that reaches outside the data area of the volume
+    InvalidRun { record: FileRecordNumber, run: DataRun },
+    /// Clusters claimed by two owners
+    DoubleAllocated { run: ClusterRun, first: ClusterOwner, second: ClusterOwner },
+    /// Clusters in use but marked free in the bitmap
+    UnmarkedClusters(ClusterRun),
+    /// Clusters marked used in the bitmap that nothing owns
+    LeakedClusters(ClusterRun),
+}
+
+/// Result of [`GalleonFilesystem::check`]
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    /// Problems found before any repair
+    pub issues: Vec<CheckIssue>,
+    /// Problems still present after repair; the same as `issues` without repair
+    pub remaining: Vec<CheckIssue>,
+    /// Number of fixes applied
+    pub repaired: usize,
+    pub records_checked: u64,
+    pub directories_checked: u64,
+    pub clusters_in_use: u64,
+}
+
+impl CheckReport {
+    /// Whether the volume is consistent, after repair if it was requested
+    pub fn is_clean(&self) -> bool {
+        self.remaining.is_empty()
+    }
+}
+
+/// Clusters claimed so far, as non-overlapping runs keyed by first cluster
+#[derive(Default)]
+struct ClusterMap {
+    runs: BTreeMap<u64, (u64, ClusterOwner)>,
+}
+
+impl ClusterMap {
+    /// Claim `run` for `owner`, returning the parts already claimed by someone else
+    fn claim(&mut self, run: ClusterRun, owner: ClusterOwner) -> Vec<(ClusterRun, ClusterOwner)> {
+        let end = run.start_cluster + run.cluster_count;
+        let first_key = self.runs.range(..=run.start_cluster).next_back()
+            .map(|(&start, _)| start)
+            .unwrap_or(run.start_cluster);
+
+        let mut conflicts = Vec::new();
+        let mut unclaimed = Vec::new();
+        let mut cursor = run.start_cluster;
+        for (&start, &(count, first)) in self.runs.range(first_key..end) {
+            let claimed_end = start + count;
+            if claimed_end <= cursor {
+                continue;
+            }
+            if start > cursor {
+                unclaimed.push(ClusterRun::new(cursor, start - cursor));
+            }
+            let overlap_start = start.max(cursor);
+            let overlap_end = claimed_end.min(end);
+            conflicts.push((ClusterRun::new(overlap_start, overlap_end - overlap_start), first));
+            cursor = overlap_end;
+        }
+        if cursor < end {
+            unclaimed.push(ClusterRun::new(cursor, end - cursor));
+        }
+
+        for part in unclaimed {
+            self.runs.insert(part.start_cluster, (part.cluster_count, owner));
+        }
+        conflicts
+    }
+
+    fn is_claimed(&self, cluster: u64) -> bool {
+        self.runs.range(..=cluster).next_back()
+            .is_some_and(|(&start, &(count, _))| cluster < start + count)
+    }
+
+    fn clusters(&self) -> u64 {
+        self.runs.values().map(|&(count, _)| count).sum()
+    }
+}
+
+/// Everything one pass over the volume learned
+#[derive(Default)]
+struct Scan {
+    issues: Vec<CheckIssue>,
+    records_checked: u64,
+    directories_checked: u64,
+    clusters_in_use: u64,
+    /// Directories with unreadable index nodes, with the entries that could still be read
+    broken_directories: BTreeMap<FileRecordNumber, Vec<IndexEntry>>,
+}
+
+impl GalleonFilesystem {
+    /// Check the volume for inconsistencies, repairing them if `repair` is set
+    pub fn check(&mut self, repair: bool) -> FilesystemResult<CheckReport> {
+        kprintln!("Checking filesystem (repair: {})", repair);
+        let scan = self.scan()?;
+        let mut report = CheckReport {
+            issues: scan.issues.clone(),
+            ..CheckReport::default()
+        };
+
+        let scan = if repair && !scan.issues.is_empty() {
+            // Every pass works from a fresh scan of what the previous one left behind
+            report.repaired += self.repair_structure(&scan)?;
+            let scan = self.scan()?;
+            report.repaired += self.repair_references(&scan)?;
+            let scan = self.scan()?;
+            report.repaired += self.repair_allocation(&scan)?;
+            let scan = self.scan()?;
+            report.repaired += self.repair_links(&scan)?;
+            let scan = self.scan()?;
+            report.repaired += self.release_leaked(&scan)?;
+            self.scan()?
+        } else {
+            scan
+        };
+
+        report.remaining = scan.issues;
+        report.records_checked = scan.records_checked;
+        report.directories_checked = scan.directories_checked;
+        report.clusters_in_use = scan.clusters_in_use;
+        kprintln!("Check found {} issues, repaired {}, {} remaining",
+                  report.issues.len(), report.repaired, report.remaining.len());
+        Ok(report)
+    }
+
+    /// Walk the whole volume once and collect what is wrong with it
+    fn scan(&mut self) -> FilesystemResult<Scan> {
+        let mut scan = Scan::default();
+        let mut claims = ClusterMap::default();
+        let total_clusters = self.super_block.legacy_super_block.total_blocks;
+        let data_start = self.super_block.index_allocation_start;
+
+        self.check_super_block(&mut scan)?;
+        claims.claim(ClusterRun::new(0, data_start), ClusterOwner::System);
+        if self.super_block.bitmap_start_cluster >= data_start {
+            let bitmap = ClusterRun::new(self.super_block.bitmap_start_cluster, self.super_block.bitmap_size_clusters);
+            Self::record_conflicts(&mut scan, claims.claim(bitmap, ClusterOwner::System), ClusterOwner::System);
+        }
+
+        // Records in use, and the clusters holding file data
+        let mut records = BTreeMap::new();
+        for record_number in 0..self.mft_manager.record_capacity() {
+            let record = match self.mft_manager.read_slot(record_number) {
+                Ok(Some(record)) if record.header.get_flags().in_use => record,
+                Ok(_) => continue,
+                Err(error) => {
+                    scan.issues.push(CheckIssue::CorruptRecord { record: record_number, error });
+                    continue;
+                }
+            };
+            scan.records_checked += 1;
+
+            for run in Self::valid_runs(&record, data_start, total_clusters, &mut scan) {
+                let owner = if record_number == MFT_RECORD_MFT {
+                    ClusterOwner::System
+                } else {
+                    ClusterOwner::Data { record: record_number }
+                };
+                Self::record_conflicts(&mut scan, claims.claim(run, owner), owner);
+            }
+            records.insert(record_number, record);
+        }
+
+        // Every directory's tree, counting the entries that refer to each record
+        let mut links: BTreeMap<FileRecordNumber, u16> = BTreeMap::new();
+        let directories: Vec<FileRecordNumber> = records.iter()
+            .filter(|(_, record)| record.header.get_flags().is_directory)
+            .map(|(&record_number, _)| record_number)
+            .collect();
+        for directory in directories {
+            scan.directories_checked += 1;
+            let root_vcn = match self.directory_index(directory) {
+                Ok(index) => index.root_vcn(),
+                Err(error) => {
+                    scan.issues.push(CheckIssue::CorruptRecord { record: directory, error });
+                    records.remove(&directory);
+                    continue;
+                }
+            };
+
+            let mut entries = Vec::new();
+            let mut visited = BTreeSet::new();
+            let intact = self.walk_index(directory, root_vcn, &mut visited, &mut entries, &mut claims, &mut scan);
+
+            for entry in &entries {
+                if records.contains_key(&entry.file_record_number) {
+                    *links.entry(entry.file_record_number).or_insert(0) += 1;
+                } else {
+                    scan.issues.push(CheckIssue::DanglingEntry {
+                        directory,
+                        name: entry.key.clone(),
+                        record: entry.file_record_number,
+                    });
+                }
+            }
+            if !intact {
+                scan.broken_directories.insert(directory, entries);
+            }
+        }
+
+        // Link counts; system records are not linked into the namespace
+        for (&record_number, record) in records.range(MFT_FIRST_USER_RECORD..) {
+            let links = links.get(&record_number).copied().unwrap_or(0);
+            if links == 0 {
+                scan.issues.push(CheckIssue::Orphan { record: record_number });
+            } else if links != record.header.hard_link_count {
+                scan.issues.push(CheckIssue::LinkCountMismatch {
+                    record: record_number,
+                    links,
+                    recorded: record.header.hard_link_count,
+                });
+            }
+        }
+
+        // The bitmap must match the claims exactly
+        self.allocator.bitmap.invalidate();
+        let mut unmarked: Option<ClusterRun> = None;
+        let mut leaked: Option<ClusterRun> = None;
+        for cluster in 0..total_clusters {
+            let claimed = claims.is_claimed(cluster);
+            let used = !self.allocator.bitmap.is_cluster_free(cluster)?;
+            Self::extend_mismatch(&mut unmarked, claimed && !used, cluster, &mut scan, CheckIssue::UnmarkedClusters);
+            Self::extend_mismatch(&mut leaked, used && !claimed, cluster, &mut scan, CheckIssue::LeakedClusters);
+        }
+        scan.issues.extend(unmarked.map(CheckIssue::UnmarkedClusters));
+        scan.issues.extend(leaked.map(CheckIssue::LeakedClusters));
+
+        scan.clusters_in_use = claims.clusters();
+        Ok(scan)
+    }
+
+    /// Compare the on-disk superblocks with the mounted layout and check the layout itself
+    fn check_super_block(&self, scan: &mut Scan) -> FilesystemResult<()> {
+        let mut sector = [0u8; 512];
+        let mut backup = [0u8; 512];
+        self.device.read_sectors(0, &mut sector)?;
+        self.device.read_sectors(BACKUP_SUPER_BLOCK_SECTOR, &mut backup)?;
+        if backup != sector {
+            scan.issues.push(CheckIssue::BadSuperBlock("backup differs from the primary"));
+        }
+        if !SuperBlock::is_valid(&sector) {
+            scan.issues.push(CheckIssue::BadSuperBlock("invalid signature"));
+            return Ok(());
+        }
+
+        let on_disk = match GalleonSuperBlock::deserialize(&sector) {
+            Ok(super_block) => super_block,
+            Err(FilesystemError::ChecksumMismatch) => {
+                scan.issues.push(CheckIssue::BadSuperBlock("checksum mismatch"));
+                return Ok(());
+            }
+            Err(_) => {
+                scan.issues.push(CheckIssue::BadSuperBlock("unreadable layout"));
+                return Ok(());
+            }
+        };
+        let layout = &self.super_block;
+        if on_disk.mft_start_cluster != layout.mft_start_cluster
+            || on_disk.bitmap_start_cluster != layout.bitmap_start_cluster
+            || on_disk.index_allocation_start != layout.index_allocation_start
+            || on_disk.legacy_super_block.total_blocks != layout.legacy_super_block.total_blocks
+        {
+            scan.issues.push(CheckIssue::BadSuperBlock("layout differs from the mounted volume"));
+        }
+
+        // A grown volume may have moved its bitmap into the data area
+        let total_clusters = layout.legacy_super_block.total_blocks;
+        let bitmap_end = layout.bitmap_start_cluster + layout.bitmap_size_clusters;
+        let bitmap_placed = if layout.bitmap_start_cluster >= layout.index_allocation_start {
+            bitmap_end <= total_clusters
+        } else {
+            layout.journal_start_cluster + layout.journal_size_clusters <= layout.bitmap_start_cluster
+                && bitmap_end <= layout.index_allocation_start
+        };
+        let ordered = layout.mft_start_cluster < layout.mft_mirror_cluster
+            && layout.mft_mirror_cluster <= layout.journal_start_cluster
+            && bitmap_placed
+            && layout.index_allocation_start < total_clusters;
+        if !ordered {
+            scan.issues.push(CheckIssue::BadSuperBlock("regions overlap or exceed the volume"));
+        }
+        if layout.bitmap_size_clusters * 8 * (layout.cluster_size as u64) < total_clusters {
+            scan.issues.push(CheckIssue::BadSuperBlock("bitmap too small for the volume"));
+        }
+        Ok(())
+    }
+
+    /// Data runs of a record up to the first one outside the data area
+    fn valid_runs(record: &MftRecord, data_start: u64, total_clusters: u64, scan: &mut Scan) -> Vec<DataRun> {
+        let mut valid = Vec::new();
+        for attr in &record.attributes {
+            let AttributeData::NonResident { ref runs, .. } = attr.data else {
+                continue;
+            };
+            for &run in runs {
+                if run.is_sparse() {
+                    continue;
+                }
+                let in_bounds = run.cluster_count > 0
+                    && run.start_cluster >= data_start
+                    && run.start_cluster.checked_add(run.cluster_count).is_some_and(|end| end <= total_clusters);
+                if !in_bounds {
+                    scan.issues.push(CheckIssue::InvalidRun { record: record.record_number, run });
+                    break;
+                }
+                valid.push(run);
+            }
+        }
+        valid
+    }
+
+    /// Walk the subtree at `vcn`, claiming its nodes and collecting its leaf entries
+    ///
+    /// Returns false if any node of the subtree could not be read.
+    fn walk_index(
+        &self,
+        directory: FileRecordNumber,
+        vcn: u64,
+        visited: &mut BTreeSet<u64>,
+        entries: &mut Vec<IndexEntry>,
+        claims: &mut ClusterMap,
+        scan: &mut Scan,
+    ) -> bool {
+        let cluster = self.super_block.index_allocation_start + vcn;
+        let node = if cluster < self.super_block.legacy_super_block.total_blocks && visited.insert(vcn) {
+            self.btree_manager.read_node(vcn).ok()
+        } else {
+            None
+        };
+        let Some(node) = node else {
+            scan.issues.push(CheckIssue::CorruptIndexNode { directory, vcn });
+            return false;
+        };
+
+        let owner = ClusterOwner::Index { directory };
+        Self::record_conflicts(scan, claims.claim(ClusterRun::new(cluster, 1), owner), owner);
+
+        if node.header.is_leaf() {
+            entries.extend(node.entries.into_iter().filter(|entry| !entry.flags.is_last_entry));
+            return true;
+        }
+
+        let mut intact = true;
+        for entry in &node.entries {
+            if let Some(child_vcn) = entry.sub_node_vcn {
+                intact &= self.walk_index(directory, child_vcn, visited, entries, claims, scan);
+            }
+        }
+        intact
+    }
+
+    fn record_conflicts(scan: &mut Scan, conflicts: Vec<(ClusterRun, ClusterOwner)>, second: ClusterOwner) {
+        for (run, first) in conflicts {
+            scan.issues.push(CheckIssue::DoubleAllocated { run, first, second });
+        }
+    }
+
+    /// Grow the current mismatching run by `cluster`, or report it once the mismatch ends
+    fn extend_mismatch(
+        current: &mut Option<ClusterRun>,
+        mismatch: bool,
+        cluster: u64,
+        scan: &mut Scan,
+        issue: fn(ClusterRun) -> CheckIssue,
+    ) {
+        match current {
+            Some(run) if mismatch => run.cluster_count += 1,
+            None if mismatch => *current = Some(ClusterRun::new(cluster, 1)),
+            Some(_) => scan.issues.extend(current.take().map(issue)),
+            None => {}
+        }
+    }
+
+    /// Rewrite damaged superblocks, clear unreadable records and rebuild
+    /// directories with unreadable index nodes
+    fn repair_structure(&mut self, scan: &Scan) -> FilesystemResult<usize> {
+        let mut repaired = 0;
+        if scan.issues.iter().any(|issue| matches!(issue, CheckIssue::BadSuperBlock(_))) {
+            // The mounted layout came from whichever copy was intact
+            self.transaction(OperationType::UpdateMetadata, 0, |fs| fs.write_super_block())?;
+            repaired += 1;
+        }
+        for issue in &scan.issues {
+            if let CheckIssue::CorruptRecord { record, .. } = *issue
+                && record >= MFT_FIRST_USER_RECORD
+            {
+                self.transaction(OperationType::DeleteFile, record, |fs| fs.file_manager.clear_record(record))?;
+                repaired += 1;
+            }
+        }
+
+        // The root node keeps its VCN; nodes below it that are no longer
+        // referenced are released with the other leaked clusters
+        for (&directory, entries) in &scan.broken_directories {
+            self.transaction(OperationType::UpdateMetadata, directory, |fs| {
+                let mut index = fs.directory_index(directory)?;
+                let mut root = IndexNode::new_leaf(index.root_vcn());
+                root.update_header();
+                index.write_node(&root)?;
+
+                for entry in entries {
+                    let file_name = match &entry.file_name {
+                        Some(file_name) => file_name.clone(),
+                        None => FileName::new(directory, entry.key.clone(), false),
+                    };
+                    index.insert(&mut fs.allocator, entry.key.clone(), entry.file_record_number, file_name)?;
+                }
+                Ok(())
+            })?;
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+
+    /// Drop index entries to missing records and data runs outside the volume
+    fn repair_references(&mut self, scan: &Scan) -> FilesystemResult<usize> {
+        let total_clusters = self.super_block.legacy_super_block.total_blocks;
+        let data_start = self.super_block.index_allocation_start;
+
+        let mut repaired = 0;
+        for issue in &scan.issues {
+            match *issue {
+                CheckIssue::DanglingEntry { directory, ref name, .. } => {
+                    self.transaction(OperationType::DeleteFile, directory, |fs| {
+                        fs.directory_index(directory)?.delete(&mut fs.allocator, name).map(|_| ())
+                    })?;
+                }
+                CheckIssue::InvalidRun { record, .. } => {
+                    self.file_manager.truncate_runs(record, |run| {
+                        run.is_sparse() || run.cluster_count > 0
+                            && run.start_cluster >= data_start
+                            && run.start_cluster.checked_add(run.cluster_count).is_some_and(|end| end <= total_clusters)
+                    })?;
+                }
+                _ => continue,
+            }
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+
+    /// Mark owned clusters used and give files that share clusters their own copy
+    fn repair_allocation(&mut self, scan: &Scan) -> FilesystemResult<usize> {
+        let mut repaired = 0;
+        for issue in &scan.issues {
+            if let CheckIssue::UnmarkedClusters(run) = *issue {
+                self.transaction(OperationType::UpdateMetadata, 0, |fs| {
+                    fs.allocator.bitmap.mark_run_used(&run)?;
+                    fs.allocator.bitmap.flush_bitmap()
+                })?;
+                repaired += 1;
+            }
+        }
+
+        // Data can be copied elsewhere; the system area and index nodes cannot
+        for issue in &scan.issues {
+            let CheckIssue::DoubleAllocated { run, first, second } = *issue else {
+                continue;
+            };
+            let record = match (first, second) {
+                (_, ClusterOwner::Data { record }) | (ClusterOwner::Data { record }, _) => record,
+                _ => continue,
+            };
+
+            let Some(runs) = self.file_manager.data_runs(record)? else {
+                continue;
+            };
+            let mut vcn = 0;
+            for data_run in &runs {
+                if !data_run.is_sparse() && data_run.contains_cluster(run.start_cluster) {
+                    let first_vcn = vcn + (run.start_cluster - data_run.start_cluster);
+                    self.transaction(OperationType::WriteData, record, |fs| {
+                        let target = fs.allocator.allocate_clusters(record, run.cluster_count)?;
+                        fs.file_manager.relocate_clusters(record, first_vcn, target)
+                    })?;
+                    repaired += 1;
+                    break;
+                }
+                vcn += data_run.cluster_count;
+            }
+        }
+        Ok(repaired)
+    }
+
+    /// Move orphans into lost+found and correct link counts
+    fn repair_links(&mut self, scan: &Scan) -> FilesystemResult<usize> {
+        let mut repaired = 0;
+        let mut lost_and_found = None;
+        for issue in &scan.issues {
+            match *issue {
+                CheckIssue::Orphan { record } => {
+                    let directory = match lost_and_found {
+                        Some(directory) => directory,
+                        None => *lost_and_found.insert(self.lost_and_found()?),
+                    };
+                    let name = format!("#{}", record);
+                    let is_directory = self.is_directory(record)?;
+
+                    self.transaction(OperationType::MoveFile, record, |fs| {
+                        let file_name = FileName::new(directory, name.clone(), is_directory);
+                        fs.directory_index(directory)?.insert(&mut fs.allocator, name.clone(), record, file_name)?;
+                        fs.file_manager.relink(record, directory, &name)
+                    })?;
+                }
+                CheckIssue::LinkCountMismatch { record, links, .. } => {
+                    self.file_manager.set_link_count(record, links)?;
+                }
+                _ => continue,
+            }
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+
+    /// Free clusters the bitmap marks used that nothing owns
+    fn release_leaked(&mut self, scan: &Scan) -> FilesystemResult<usize> {
+        let mut repaired = 0;
+        for issue in &scan.issues {
+            if let CheckIssue::LeakedClusters(run) = *issue {
+                self.transaction(OperationType::UpdateMetadata, 0, |fs| {
+                    fs.allocator.bitmap.mark_run_free(&run)?;
+                    fs.allocator.bitmap.flush_bitmap()
+                })?;
+                repaired += 1;
+            }
+        }
+        Ok(repaired)
+    }
+
+    /// Record of `/lost+found`, creating it if needed
+    fn lost_and_found(&mut self) -> FilesystemResult<FileRecordNumber> {
+        if let Some(directory) = self.find_in_directory(MFT_RECORD_ROOT, LOST_AND_FOUND)? {
+            return Ok(directory);
+        }
+        let mut name = ManuallyDrop::new(String::from(LOST_AND_FOUND));
+        unsafe { self.create_directory(MFT_RECORD_ROOT, name.as_mut_ptr(), name.len(), name.capacity()) }
+    }
+}
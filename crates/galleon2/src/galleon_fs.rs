@@ -2,19 +2,28 @@
 //!
 //! Combines all components (MFT, journaling, B+trees, allocation) into a cohesive filesystem.
 
-use alloc::{vec, string::String, vec::Vec};
-use lib_kernel::{kprint, kprintln};
+use alloc::{vec, string::String, sync::Arc, vec::Vec};
+use crate::kprintln;
 use crate::{
     FilesystemResult, FilesystemError,
     super_block::SuperBlock,
-    mft::{MftManager, FileRecordNumber, MFT_RECORD_ROOT},
-    journal::JournalManager,
-    file_record::{FileRecordManager, FileName},
+    mft::{MftManager, FileRecordNumber, MFT_RECORD_ROOT, RecordFlags, Attribute, AttributeType},
+    journal::{JournalManager, JournaledDevice, SharedJournal, OperationType, with_transaction},
+    file_record::{AtimePolicy, FileRecordManager, FileName, IndexRoot, StandardInformation, TimeUpdate},
     btree::BTreeManager,
     allocation::{ClusterAllocator, ClusterBitmap, AllocationStrategy},
-    ide_write_sectors, ide_read_sectors, return_drive_size_bytes,
+    block_device::{SharedBlockDevice, SECTOR_SIZE},
+    cache::{BufferCache, DEFAULT_CACHE_BLOCKS},
+    checksum::crc32c,
+    types::pathbuf::{PathBuf, Component},
 };
 
+/// Offset of the CRC32C of the superblock sector, which covers every byte before it
+const SUPER_BLOCK_CHECKSUM_OFFSET: usize = 508;
+
+/// Sector holding a copy of the superblock, in the reserved clusters before the MFT
+pub const BACKUP_SUPER_BLOCK_SECTOR: u64 = 8;
+
 /// Enhanced SuperBlock with advanced layout
 #[derive(Debug, Clone)]
 pub struct GalleonSuperBlock {
@@ -79,6 +88,11 @@ impl GalleonSuperBlock {
         }
     }
 
+    /// Number of clusters reserved for the MFT, which ends where its mirror begins
+    pub fn mft_size_clusters(&self) -> u64 {
+        self.mft_mirror_cluster - self.mft_start_cluster
+    }
+
     pub fn serialize(&self) -> [u8; 512] {
         kprintln!("Serializing GalleonSuperBlock...");
         let mut sector = self.legacy_super_block.as_sector();
@@ -118,12 +132,21 @@ impl GalleonSuperBlock {
         sector[offset..offset+8].copy_from_slice(&self.index_allocation_start.to_le_bytes());
         kprintln!("Wrote index allocation start: {} at offset {}", self.index_allocation_start, offset);
 
+        let checksum = crc32c(&sector[..SUPER_BLOCK_CHECKSUM_OFFSET]);
+        sector[SUPER_BLOCK_CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+
         kprintln!("GalleonSuperBlock serialization completed");
         sector
     }
 
     pub fn deserialize(sector: &[u8; 512]) -> FilesystemResult<Self> {
         kprintln!("Starting GalleonSuperBlock deserialization...");
+
+        let stored = u32::from_le_bytes(sector[SUPER_BLOCK_CHECKSUM_OFFSET..].try_into().unwrap());
+        if crc32c(&sector[..SUPER_BLOCK_CHECKSUM_OFFSET]) != stored {
+            kprintln!("ERROR: Super block checksum mismatch");
+            return Err(FilesystemError::ChecksumMismatch);
+        }
         
         kprintln!("Deserializing legacy super block...");
         let legacy_super_block = SuperBlock::from_sector(sector);
@@ -313,23 +336,27 @@ impl GalleonSuperBlock {
 
 /// Complete Galleon Filesystem
 pub struct GalleonFilesystem {
-    super_block: GalleonSuperBlock,
-    mft_manager: MftManager,
-    journal_manager: JournalManager,
-    file_manager: FileRecordManager,
-    btree_manager: BTreeManager,
-    allocator: ClusterAllocator,
-    drive: u8,
+    pub(crate) super_block: GalleonSuperBlock,
+    pub(crate) mft_manager: MftManager,
+    journal_manager: SharedJournal,
+    pub(crate) file_manager: FileRecordManager,
+    pub(crate) btree_manager: BTreeManager,
+    pub(crate) allocator: ClusterAllocator,
+    pub(crate) device: SharedBlockDevice,
+    cache: Arc<BufferCache>,
 }
 
 impl GalleonFilesystem {
-    /// Create a new filesystem on the specified drive
-    pub fn format(drive: u8) -> FilesystemResult<Self> {
+    /// Create a new filesystem on the specified block device
+    pub fn format(device: SharedBlockDevice) -> FilesystemResult<Self> {
         kprintln!("!!! FORMAT WAS CALLED !!!");
         kprintln!("Format was called!");
-        kprintln!("Getting drive size for drive {}...", drive);
-        let disk_size_bytes = return_drive_size_bytes(drive)?;
-        kprintln!("Drive size: {} bytes", disk_size_bytes);
+        if device.sector_size() != SECTOR_SIZE {
+            kprintln!("ERROR: Unsupported sector size {}", device.sector_size());
+            return Err(FilesystemError::InvalidParameter);
+        }
+        let disk_size_bytes = device.capacity_bytes();
+        kprintln!("Device size: {} bytes", disk_size_bytes);
         
         let cluster_size = 4096u32;
         let total_clusters = disk_size_bytes / cluster_size as u64;
@@ -344,10 +371,31 @@ impl GalleonFilesystem {
         kprintln!("Creating super block...");
         let super_block = GalleonSuperBlock::new(total_clusters, cluster_size);
 
+        // Everything below reads and writes through the buffer cache
+        let cache = Arc::new(BufferCache::new(device, DEFAULT_CACHE_BLOCKS));
+        let device: SharedBlockDevice = cache.clone();
+
+        // Start with an empty log; metadata written by managers goes through
+        // the journaled device from here on
+        kprintln!("Initializing journal manager...");
+        let journal_start_sector = super_block.journal_start_cluster * (cluster_size / 512) as u64;
+        let journal_size_sectors = super_block.journal_size_clusters * (cluster_size / 512) as u64;
+        let mut journal_manager = JournalManager::new(
+            device.clone(),
+            journal_start_sector,
+            journal_size_sectors,
+        );
+        journal_manager.format()?;
+        let journal_manager = journal_manager.into_shared();
+        let data_device = device.clone();
+        let device: SharedBlockDevice = Arc::new(JournaledDevice::new(device, journal_manager.clone()));
+        kprintln!("Journal manager initialized");
+
         // Write super block
-        kprintln!("Writing super block to sector 1...");
+        kprintln!("Writing super block to sector 0...");
         let sector = super_block.serialize();
-        ide_write_sectors(drive, 1, 0, &sector)?;
+        device.write_sectors(0, &sector)?;
+        device.write_sectors(BACKUP_SUPER_BLOCK_SECTOR, &sector)?;
         kprintln!("Super block written successfully");
 
         // Initialize bitmap
@@ -355,15 +403,16 @@ impl GalleonFilesystem {
         let bitmap_start_sector = super_block.bitmap_start_cluster * (cluster_size / 512) as u64;
         kprintln!("Bitmap starts at sector {}", bitmap_start_sector);
         let bitmap = ClusterBitmap::new(
-            drive,
+            device.clone(),
             bitmap_start_sector,
             total_clusters,
         );
         let mut allocator = ClusterAllocator::new(bitmap, AllocationStrategy::FirstFit);
         kprintln!("Bitmap and allocator initialized");
 
-        // Mark system areas as used
-        let system_clusters = super_block.index_allocation_start + 100; // Reserve some for indexes
+        // Mark system areas and the root directory's index node as used;
+        // further index nodes are allocated from the bitmap on demand
+        let system_clusters = super_block.index_allocation_start + 1;
         kprintln!("Marking {} system clusters as used", system_clusters);
         for cluster in 0..system_clusters {
             kprintln!("Marking cluster {} as used", cluster);
@@ -375,29 +424,20 @@ impl GalleonFilesystem {
         // Initialize managers
         kprintln!("Initializing MFT manager...");
         let mft_manager = MftManager::new(
-            drive,
+            device.clone(),
             super_block.mft_start_cluster,
+            super_block.mft_size_clusters(),
             cluster_size,
         );
         kprintln!("MFT manager initialized");
 
-        kprintln!("Initializing journal manager...");
-        let journal_start_sector = super_block.journal_start_cluster * (cluster_size / 512) as u64;
-        let journal_size_sectors = super_block.journal_size_clusters * (cluster_size / 512) as u64;
-        let journal_manager = JournalManager::new(
-            drive,
-            journal_start_sector,
-            journal_size_sectors,
-        );
-        kprintln!("Journal manager initialized");
-
         kprintln!("Initializing file record manager...");
-        let file_manager = FileRecordManager::new(mft_manager.clone(), journal_manager.clone());
+        let file_manager = FileRecordManager::new(mft_manager.clone(), journal_manager.clone(), data_device);
         kprintln!("File record manager initialized");
 
         kprintln!("Initializing B-tree manager...");
         let btree_manager = BTreeManager::new(
-            drive,
+            device.clone(),
             0, // Root directory index
             super_block.index_allocation_start,
             cluster_size,
@@ -414,9 +454,12 @@ impl GalleonFilesystem {
         Self::initialize_btree_root(&btree_manager)?;
         kprintln!("B-tree root node initialized");
 
+        device.flush()?;
+
         kprintln!("Filesystem format completed successfully!");
         Ok(Self {
-            drive,
+            device,
+            cache,
             super_block,
             mft_manager,
             journal_manager,
@@ -426,20 +469,19 @@ impl GalleonFilesystem {
         })
     }
 
-    /// Mount an existing filesystem
-    pub fn mount(drive: u8) -> FilesystemResult<Self> {
+    /// Mount an existing filesystem from the specified block device
+    pub fn mount(device: SharedBlockDevice) -> FilesystemResult<Self> {
         kprintln!("!!! MOUNT WAS CALLED !!!");
         kprintln!("Mount!");
-        kprintln!("Starting filesystem mount for drive {}", drive);
+        kprintln!("Starting filesystem mount ({} sectors)", device.sector_count());
 
-        // Validate drive parameter
-        if drive > 3 {
-            kprintln!("Invalid drive number: {}", drive);
+        if device.sector_size() != SECTOR_SIZE {
+            kprintln!("Unsupported sector size: {}", device.sector_size());
             return Err(FilesystemError::InvalidParameter);
         }
 
         // Read super block with error recovery
-        kprintln!("Reading super block from sector 1...");
+        kprintln!("Reading super block from sector 0...");
         let mut sector = [0u8; 512];
 
         // Clear sector buffer first for safety
@@ -449,7 +491,7 @@ impl GalleonFilesystem {
 
         kprintln!("Sector buffer cleared, attempting to read...");
 
-        match ide_read_sectors(drive, 1, 1, &mut sector) {
+        match device.read_sectors(0, &mut sector) {
             Ok(()) => {
                 kprintln!("Super block sector read successfully");
 
@@ -468,19 +510,21 @@ impl GalleonFilesystem {
             }
             Err(e) => {
                 kprintln!("Failed to read super block: {:?}", e);
-                return Err(FilesystemError::from(e));
+                return Err(e);
             }
         }
 
         kprintln!("Validating super block...");
-        if !SuperBlock::is_valid(&sector) {
-            kprintln!("Super block validation failed - invalid boot block");
-            return Err(FilesystemError::InvalidBootBlock);
-        }
-        kprintln!("Super block validation passed");
-
-        kprintln!("Deserializing Galleon super block...");
-        let super_block = GalleonSuperBlock::deserialize(&sector)?;
+        let super_block = match Self::parse_super_block(&sector) {
+            Ok(super_block) => super_block,
+            Err(error) => {
+                // The checker rewrites a damaged primary from the mounted layout
+                kprintln!("Primary super block unusable ({:?}), trying the backup", error);
+                let mut backup = [0u8; 512];
+                device.read_sectors(BACKUP_SUPER_BLOCK_SECTOR, &mut backup)?;
+                Self::parse_super_block(&backup).map_err(|_| error)?
+            }
+        };
         kprintln!("Super block deserialized successfully");
         kprintln!("Cluster size: {}", super_block.cluster_size);
         kprintln!("Total blocks: {}", super_block.legacy_super_block.total_blocks);
@@ -491,12 +535,35 @@ impl GalleonFilesystem {
             return Err(FilesystemError::InvalidBootBlock);
         }
 
+        let cache = Arc::new(BufferCache::new(device, DEFAULT_CACHE_BLOCKS));
+        let device: SharedBlockDevice = cache.clone();
+
+        kprintln!("Initializing journal manager...");
+        let journal_start = super_block.journal_start_cluster * (super_block.cluster_size / 512) as u64;
+        let journal_size = super_block.journal_size_clusters * (super_block.cluster_size / 512) as u64;
+        kprintln!("Journal start sector: {}", journal_start);
+        kprintln!("Journal size in sectors: {}", journal_size);
+        let mut journal_manager = JournalManager::new(
+            device.clone(),
+            journal_start,
+            journal_size,
+        );
+        kprintln!("Journal manager initialized");
+
+        // Replay the log before any manager reads metadata
+        kprintln!("Starting journal recovery...");
+        journal_manager.recover()?;
+        kprintln!("Journal recovery completed");
+        let journal_manager = journal_manager.into_shared();
+        let data_device = device.clone();
+        let device: SharedBlockDevice = Arc::new(JournaledDevice::new(device, journal_manager.clone()));
+
         // Initialize bitmap
         kprintln!("Initializing cluster bitmap...");
         let bitmap_start = super_block.bitmap_start_cluster * (super_block.cluster_size / 512) as u64;
         kprintln!("Bitmap start sector: {}", bitmap_start);
         let bitmap = ClusterBitmap::new(
-            drive,
+            device.clone(),
             bitmap_start,
             super_block.legacy_super_block.total_blocks,
         );
@@ -510,37 +577,22 @@ impl GalleonFilesystem {
         kprintln!("Initializing MFT manager...");
         kprintln!("MFT start cluster: {}", super_block.mft_start_cluster);
         let mft_manager = MftManager::new(
-            drive,
+            device.clone(),
             super_block.mft_start_cluster,
+            super_block.mft_size_clusters(),
             super_block.cluster_size,
         );
+        mft_manager.load_extension()?;
         kprintln!("MFT manager initialized");
 
-        kprintln!("Initializing journal manager...");
-        let journal_start = super_block.journal_start_cluster * (super_block.cluster_size / 512) as u64;
-        let journal_size = super_block.journal_size_clusters * (super_block.cluster_size / 512) as u64;
-        kprintln!("Journal start sector: {}", journal_start);
-        kprintln!("Journal size in sectors: {}", journal_size);
-        let mut journal_manager = JournalManager::new(
-            drive,
-            journal_start,
-            journal_size,
-        );
-        kprintln!("Journal manager initialized");
-
-        // Perform recovery if needed
-        kprintln!("Starting journal recovery...");
-        journal_manager.recover()?;
-        kprintln!("Journal recovery completed");
-
         kprintln!("Initializing file record manager...");
-        let file_manager = FileRecordManager::new(mft_manager.clone(), journal_manager.clone());
+        let file_manager = FileRecordManager::new(mft_manager.clone(), journal_manager.clone(), data_device);
         kprintln!("File record manager initialized");
 
         kprintln!("Initializing B-tree manager...");
         kprintln!("Index allocation start: {}", super_block.index_allocation_start);
         let btree_manager = BTreeManager::new(
-            drive,
+            device.clone(),
             0, // Root directory index
             super_block.index_allocation_start,
             super_block.cluster_size,
@@ -558,10 +610,10 @@ impl GalleonFilesystem {
                 kprintln!("Root node flags = {} (0=leaf, 1=internal)", node.header.flags);
                 kprintln!("Root node has {} entries", node.entries.len());
 
-                // Check if it's a valid leaf node (flags should be 0 for root)
-                if !node.header.is_leaf() {
-                    kprintln!("WARNING: B-tree root has wrong flags (expected leaf, got internal)");
-                    kprintln!("Reinitializing B-tree root with correct flags...");
+                // A root that has split is internal; it just needs a child pointer
+                if !node.header.is_leaf() && node.entries.iter().any(|e| e.sub_node_vcn.is_none()) {
+                    kprintln!("WARNING: B-tree root is internal but has entries without children");
+                    kprintln!("Reinitializing B-tree root...");
                     needs_init = true;
                 }
             }
@@ -602,7 +654,8 @@ impl GalleonFilesystem {
 
         kprintln!("Filesystem mount completed successfully!");
         Ok(Self {
-            drive,
+            device,
+            cache,
             super_block,
             mft_manager,
             journal_manager,
@@ -612,6 +665,22 @@ impl GalleonFilesystem {
         })
     }
 
+    /// Validate and decode a superblock sector
+    fn parse_super_block(sector: &[u8; 512]) -> FilesystemResult<GalleonSuperBlock> {
+        if !SuperBlock::is_valid(sector) {
+            kprintln!("Super block validation failed - invalid boot block");
+            return Err(FilesystemError::InvalidBootBlock);
+        }
+        GalleonSuperBlock::deserialize(sector)
+    }
+
+    /// Write the superblock and its backup
+    pub(crate) fn write_super_block(&self) -> FilesystemResult<()> {
+        let sector = self.super_block.serialize();
+        self.device.write_sectors(0, &sector)?;
+        self.device.write_sectors(BACKUP_SUPER_BLOCK_SECTOR, &sector)
+    }
+
     fn create_system_files(mft_manager: &MftManager, _super_block: &GalleonSuperBlock) -> FilesystemResult<()> {
         kprintln!("Creating system files in MFT...");
         // Create basic system file records in MFT
@@ -622,9 +691,21 @@ impl GalleonFilesystem {
         let mft_record = crate::mft::MftRecord::new(0);
         mft_manager.write_record(&mft_record)?;
 
-        // Root directory record (record 5)
+        // Root directory record (record 5), its index root lives at vcn 0
         kprintln!("Creating MFT record {} (root directory)...", MFT_RECORD_ROOT);
-        let root_record = crate::mft::MftRecord::new(MFT_RECORD_ROOT);
+        let mut root_record = crate::mft::MftRecord::new(MFT_RECORD_ROOT);
+        let mut flags = RecordFlags::new();
+        flags.in_use = true;
+        flags.is_directory = true;
+        root_record.header.set_flags(flags);
+        root_record.add_attribute(Attribute::new_resident(
+            AttributeType::StandardInformation,
+            StandardInformation::new_directory().serialize(),
+        ));
+        root_record.add_attribute(Attribute::new_resident(
+            AttributeType::IndexRoot,
+            IndexRoot::new(0, MFT_RECORD_ROOT).serialize(),
+        ));
         mft_manager.write_record(&root_record)?;
 
         kprintln!("System files created successfully");
@@ -661,34 +742,53 @@ impl GalleonFilesystem {
     /// Create a new file
     pub fn create_file(&mut self, parent_dir: FileRecordNumber, name: String, data: Option<Vec<u8>>) -> FilesystemResult<FileRecordNumber> {
         kprintln!("Creating file '{}' in directory {}", name, parent_dir);
-        let file_record = self.file_manager.create_file(parent_dir, name.clone(), data)?;
-        kprintln!("File record {} created", file_record);
+        Self::validate_name(&name)?;
+        let parent_index = self.directory_index(parent_dir)?;
+        if parent_index.search(&name)?.is_some() {
+            return Err(FilesystemError::AlreadyExists);
+        }
 
-        // Add to directory index
-        let file_name = FileName::new(parent_dir, name, false);
-        self.btree_manager.insert(file_name.name.clone(), file_record, file_name)?;
-        kprintln!("File added to directory index");
+        self.transaction(OperationType::CreateFile, parent_dir, |fs| {
+            let file_record = fs.file_manager.create_file(&mut fs.allocator, parent_dir, name.clone(), data)?;
+            kprintln!("File record {} created", file_record);
 
-        Ok(file_record)
+            // Add to directory index
+            let file_name = FileName::new(parent_dir, name, false);
+            fs.directory_index(parent_dir)?.insert(&mut fs.allocator, file_name.name.clone(), file_record, file_name)?;
+            kprintln!("File added to directory index");
+
+            Ok(file_record)
+        })
     }
 
     /// Create a new directory
     pub unsafe fn create_directory(&mut self, parent_dir: FileRecordNumber, ptr: *const u8, len: usize, cap: usize) -> FilesystemResult<FileRecordNumber> {
-        let dir_record = self.file_manager.create_directory(parent_dir, ptr, len, cap).map_err(|e| {
-            kprintln!("Error creating directory: {:?} {:?}", ptr, e);
-            e
-        })?;
-        kprintln!("Directory record {} created", dir_record);
-
-        // Add to parent directory index
-        kprintln!("About to add directory to parent index...");
         let name = unsafe { String::from_raw_parts(ptr as *mut u8, len, cap) };
-        let file_name = FileName::new(parent_dir, name, true);
-        kprintln!("FileName created, inserting into btree...");
-        self.btree_manager.insert(file_name.name.clone(), dir_record, file_name)?;
-        kprintln!("Directory added to parent directory index");
+        Self::validate_name(&name)?;
+        let parent_index = self.directory_index(parent_dir)?;
+        if parent_index.search(&name)?.is_some() {
+            return Err(FilesystemError::AlreadyExists);
+        }
+
+        self.transaction(OperationType::CreateDirectory, parent_dir, |fs| {
+            // Every directory gets its own B+ tree in the index allocation area
+            let index_root_vcn = parent_index.create_root(&mut fs.allocator)?;
+            kprintln!("Directory index root allocated at vcn {}", index_root_vcn);
+
+            let dir_record = fs.file_manager.create_directory(&mut fs.allocator, parent_dir, index_root_vcn, name.as_ptr(), name.len(), name.capacity()).inspect_err(|e| {
+                kprintln!("Error creating directory: {:?} {:?}", ptr, e);
+            })?;
+            kprintln!("Directory record {} created", dir_record);
 
-        Ok(dir_record)
+            // Add to parent directory index
+            kprintln!("About to add directory to parent index...");
+            let file_name = FileName::new(parent_dir, name, true);
+            kprintln!("FileName created, inserting into btree...");
+            fs.directory_index(parent_dir)?.insert(&mut fs.allocator, file_name.name.clone(), dir_record, file_name)?;
+            kprintln!("Directory added to parent directory index");
+
+            Ok(dir_record)
+        })
     }
 
     /// Read file data
@@ -702,42 +802,254 @@ impl GalleonFilesystem {
     /// Write file data
     pub fn write_file(&mut self, file_record: FileRecordNumber, data: Vec<u8>) -> FilesystemResult<()> {
         kprintln!("Writing {} bytes to file record {}", data.len(), file_record);
-        self.file_manager.write_file(file_record, data)?;
+        self.transaction(OperationType::WriteData, file_record, |fs| {
+            fs.file_manager.write_file(&mut fs.allocator, file_record, data)
+        })?;
         kprintln!("File write completed");
         Ok(())
     }
 
-    /// Delete a file
+    /// Size of a file's data in bytes
+    pub fn file_size(&self, file_record: FileRecordNumber) -> FilesystemResult<u64> {
+        self.file_manager.file_size(file_record)
+    }
+
+    /// Bytes of disk space allocated to a file's data, which is less than
+    /// its size when the file has holes
+    pub fn allocated_size(&self, file_record: FileRecordNumber) -> FilesystemResult<u64> {
+        self.file_manager.allocated_size(file_record)
+    }
+
+    /// Read file data at a byte offset, returning the number of bytes read
+    pub fn read_at(&self, file_record: FileRecordNumber, offset: u64, buf: &mut [u8]) -> FilesystemResult<usize> {
+        self.file_manager.read_at(file_record, offset, buf)
+    }
+
+    /// Write file data at a byte offset, extending the file if needed
+    pub fn write_at(&mut self, file_record: FileRecordNumber, offset: u64, data: &[u8]) -> FilesystemResult<usize> {
+        self.transaction(OperationType::WriteData, file_record, |fs| {
+            fs.file_manager.write_at(&mut fs.allocator, file_record, offset, data)
+        })
+    }
+
+    /// Truncate or zero-extend a file to `len` bytes
+    pub fn set_len(&mut self, file_record: FileRecordNumber, len: u64) -> FilesystemResult<()> {
+        self.transaction(OperationType::WriteData, file_record, |fs| {
+            fs.file_manager.set_len(&mut fs.allocator, file_record, len)
+        })
+    }
+
+    /// Deallocate a byte range of a file, leaving a hole that reads as zeros
+    pub fn punch_hole(&mut self, file_record: FileRecordNumber, offset: u64, len: u64) -> FilesystemResult<()> {
+        self.transaction(OperationType::WriteData, file_record, |fs| {
+            fs.file_manager.punch_hole(&mut fs.allocator, file_record, offset, len)
+        })
+    }
+
+    /// Read the whole contents of a named data stream
+    pub fn read_stream(&self, file_record: FileRecordNumber, stream: &str) -> FilesystemResult<Vec<u8>> {
+        Self::validate_name(stream)?;
+        if !self.file_manager.list_streams(file_record)?.iter().any(|name| name == stream) {
+            return Err(FilesystemError::NotFound);
+        }
+        let mut data = vec![0u8; self.file_manager.stream_size(file_record, stream)? as usize];
+        let read = self.file_manager.read_stream_at(file_record, stream, 0, &mut data)?;
+        data.truncate(read);
+        Ok(data)
+    }
+
+    /// Replace the contents of a named data stream, creating it if needed
+    pub fn write_stream(&mut self, file_record: FileRecordNumber, stream: &str, data: Vec<u8>) -> FilesystemResult<()> {
+        Self::validate_name(stream)?;
+        self.transaction(OperationType::WriteData, file_record, |fs| {
+            fs.file_manager.write_stream(&mut fs.allocator, file_record, stream, data)
+        })
+    }
+
+    /// Names of a file's named data streams
+    pub fn list_streams(&self, file_record: FileRecordNumber) -> FilesystemResult<Vec<String>> {
+        self.file_manager.list_streams(file_record)
+    }
+
+    /// Remove a named data stream and free its clusters
+    pub fn remove_stream(&mut self, file_record: FileRecordNumber, stream: &str) -> FilesystemResult<()> {
+        Self::validate_name(stream)?;
+        self.transaction(OperationType::WriteData, file_record, |fs| {
+            fs.file_manager.delete_stream(&mut fs.allocator, file_record, stream)
+        })
+    }
+
+    /// Value of an extended attribute, or `None` if it is not set
+    pub fn get_xattr(&self, file_record: FileRecordNumber, key: &str) -> FilesystemResult<Option<Vec<u8>>> {
+        Self::validate_name(key)?;
+        self.file_manager.get_xattr(file_record, key)
+    }
+
+    /// Set an extended attribute on a file or directory
+    pub fn set_xattr(&mut self, file_record: FileRecordNumber, key: &str, value: &[u8]) -> FilesystemResult<()> {
+        Self::validate_name(key)?;
+        self.transaction(OperationType::SetAttribute, file_record, |fs| {
+            fs.file_manager.set_xattr(file_record, key, value)
+        })
+    }
+
+    /// Keys of the extended attributes set on a file or directory
+    pub fn list_xattrs(&self, file_record: FileRecordNumber) -> FilesystemResult<Vec<String>> {
+        self.file_manager.list_xattrs(file_record)
+    }
+
+    /// Remove an extended attribute
+    pub fn remove_xattr(&mut self, file_record: FileRecordNumber, key: &str) -> FilesystemResult<()> {
+        Self::validate_name(key)?;
+        self.transaction(OperationType::SetAttribute, file_record, |fs| {
+            fs.file_manager.remove_xattr(file_record, key)
+        })
+    }
+
+    /// Delete a file or an empty directory
+    ///
+    /// For a file with several links only the link named `name` goes; its
+    /// clusters are freed with the last one.
     pub fn delete_file(&mut self, file_record: FileRecordNumber, name: &str) -> FilesystemResult<()> {
+        let directory = match self.file_manager.file_names(file_record)?.into_iter().find(|file_name| file_name.name == name) {
+            Some(file_name) => file_name.parent_directory,
+            None => self.file_manager.parent_of(file_record)?,
+        };
+        self.unlink(directory, name)
+    }
+
+    /// Remove the entry `name` from `directory`, deleting the file or empty
+    /// directory it refers to once no links remain
+    pub fn unlink(&mut self, directory: FileRecordNumber, name: &str) -> FilesystemResult<()> {
+        let file_record = self.find_in_directory(directory, name)?.ok_or(FilesystemError::NotFound)?;
         kprintln!("Deleting file '{}' (record {})", name, file_record);
+        if file_record == MFT_RECORD_ROOT {
+            return Err(FilesystemError::InvalidParameter);
+        }
 
-        // Remove from directory index
-        self.btree_manager.delete(name)?;
-        kprintln!("File removed from directory index");
+        let is_directory = self.file_manager.is_directory(file_record)?;
+        if is_directory && !self.list_directory_at(file_record)?.is_empty() {
+            return Err(FilesystemError::DirectoryNotEmpty);
+        }
 
-        // Deallocate clusters
-        self.allocator.deallocate_all_clusters(file_record)?;
-        kprintln!("File clusters deallocated");
+        self.transaction(OperationType::DeleteFile, file_record, |fs| {
+            if is_directory {
+                // Release the directory's own index nodes
+                fs.directory_index(file_record)?.free_tree(&mut fs.allocator)?;
+                kprintln!("Directory index freed");
+            }
 
-        // Delete file record
-        self.file_manager.delete_file(file_record)?;
-        kprintln!("File record deleted");
+            // Remove from the parent's directory index
+            fs.directory_index(directory)?.delete(&mut fs.allocator, name)?;
+            kprintln!("File removed from directory index");
 
-        Ok(())
+            // Drop the link; the last one deletes the record and releases its data clusters
+            let links = fs.file_manager.remove_link(&mut fs.allocator, file_record, directory, name)?;
+            kprintln!("File record has {} links left", links);
+
+            Ok(())
+        })
+    }
+
+    /// Add a hard link to a file as `name` in `directory`
+    pub fn link(&mut self, file_record: FileRecordNumber, directory: FileRecordNumber, name: &str) -> FilesystemResult<()> {
+        Self::validate_name(name)?;
+        if self.file_manager.is_directory(file_record)? {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        if self.directory_index(directory)?.search(name)?.is_some() {
+            return Err(FilesystemError::AlreadyExists);
+        }
+
+        self.transaction(OperationType::CreateFile, directory, |fs| {
+            fs.file_manager.add_link(file_record, directory, name)?;
+            let file_name = FileName::new(directory, String::from(name), false);
+            fs.directory_index(directory)?.insert(&mut fs.allocator, file_name.name.clone(), file_record, file_name)?;
+            Ok(())
+        })
+    }
+
+    /// Number of directory entries that refer to a file
+    pub fn link_count(&self, file_record: FileRecordNumber) -> FilesystemResult<u16> {
+        self.file_manager.link_count(file_record)
+    }
+
+    /// Times, link count, owner and mode of a file or directory
+    pub fn stat(&self, file_record: FileRecordNumber) -> FilesystemResult<StandardInformation> {
+        self.file_manager.standard_information(file_record)
+    }
+
+    /// Set the permission bits of a file or directory
+    pub fn chmod(&mut self, file_record: FileRecordNumber, permissions: u32) -> FilesystemResult<()> {
+        self.transaction(OperationType::SetAttribute, file_record, |fs| {
+            fs.file_manager.set_mode(file_record, permissions)
+        })
+    }
+
+    /// Change the owner and group of a file or directory; `None` leaves one unchanged
+    pub fn chown(&mut self, file_record: FileRecordNumber, uid: Option<u32>, gid: Option<u32>) -> FilesystemResult<()> {
+        self.transaction(OperationType::SetAttribute, file_record, |fs| {
+            fs.file_manager.set_owner(file_record, uid, gid)
+        })
+    }
+
+    /// Set the access and modification times of a file or directory
+    pub fn utimens(&mut self, file_record: FileRecordNumber, access: TimeUpdate, modification: TimeUpdate) -> FilesystemResult<()> {
+        self.transaction(OperationType::SetAttribute, file_record, |fs| {
+            fs.file_manager.set_times(file_record, access, modification)
+        })
+    }
+
+    /// When reads update access times; `Relatime` unless changed
+    pub fn atime_policy(&self) -> AtimePolicy {
+        self.file_manager.atime_policy()
     }
 
-    /// List directory contents
+    pub fn set_atime_policy(&mut self, policy: AtimePolicy) {
+        self.file_manager.set_atime_policy(policy);
+    }
+
+    /// Run `f` as one journaled transaction
+    ///
+    /// If `f` fails, every metadata write it made is rolled back and the
+    /// cached bitmap and MFT extension are reloaded from the restored disk.
+    pub(crate) fn transaction<T>(
+        &mut self,
+        operation_type: OperationType,
+        target: FileRecordNumber,
+        f: impl FnOnce(&mut Self) -> FilesystemResult<T>,
+    ) -> FilesystemResult<T> {
+        let journal = self.journal_manager.clone();
+        let result = with_transaction(&journal, operation_type, target, || f(self));
+        if result.is_err() {
+            self.allocator.bitmap.invalidate();
+            self.mft_manager.load_extension()?;
+        }
+        result
+    }
+
+    /// List root directory contents
     pub fn list_directory(&self) -> FilesystemResult<Vec<(String, FileRecordNumber)>> {
-        kprintln!("Listing directory contents");
-        let contents = self.btree_manager.list_directory()?;
+        self.list_directory_at(MFT_RECORD_ROOT)
+    }
+
+    /// List the contents of a directory
+    pub fn list_directory_at(&self, directory: FileRecordNumber) -> FilesystemResult<Vec<(String, FileRecordNumber)>> {
+        kprintln!("Listing contents of directory {}", directory);
+        let contents = self.directory_index(directory)?.list_directory()?;
         kprintln!("Found {} directory entries", contents.len());
         Ok(contents)
     }
 
-    /// Find file by name
+    /// Find file by name in the root directory
     pub fn find_file(&self, name: &str) -> FilesystemResult<Option<FileRecordNumber>> {
-        kprintln!("Searching for file '{}'", name);
-        let result = self.btree_manager.search(name)?;
+        self.find_in_directory(MFT_RECORD_ROOT, name)
+    }
+
+    /// Find a file by name in a directory
+    pub fn find_in_directory(&self, directory: FileRecordNumber, name: &str) -> FilesystemResult<Option<FileRecordNumber>> {
+        kprintln!("Searching for file '{}' in directory {}", name, directory);
+        let result = self.directory_index(directory)?.search(name)?;
         match result {
             Some(record) => kprintln!("File '{}' found at record {}", name, record),
             None => kprintln!("File '{}' not found", name),
@@ -745,6 +1057,93 @@ impl GalleonFilesystem {
         Ok(result)
     }
 
+    /// Whether a record is a directory
+    pub fn is_directory(&self, file_record: FileRecordNumber) -> FilesystemResult<bool> {
+        if file_record == MFT_RECORD_ROOT {
+            return Ok(true);
+        }
+        self.file_manager.is_directory(file_record)
+    }
+
+    /// Parent of a directory; the root is its own parent
+    pub fn parent_directory(&self, directory: FileRecordNumber) -> FilesystemResult<FileRecordNumber> {
+        if directory == MFT_RECORD_ROOT {
+            return Ok(MFT_RECORD_ROOT);
+        }
+        Ok(self.file_manager.read_index_root(directory)?.parent_directory)
+    }
+
+    /// Resolve a path to a file record
+    ///
+    /// Absolute paths start at the root directory, relative paths at `cwd`.
+    /// `.` and `..` are honoured and repeated slashes are ignored.
+    pub fn resolve_path(&self, cwd: FileRecordNumber, path: &PathBuf) -> FilesystemResult<FileRecordNumber> {
+        let mut current = cwd;
+        for component in path.components() {
+            current = self.walk(current, component)?;
+        }
+        Ok(current)
+    }
+
+    /// Resolve all but the last component of a path
+    ///
+    /// Returns the containing directory and the final name, which must be a
+    /// plain name rather than `.`, `..` or the root.
+    pub fn resolve_parent<'a>(&self, cwd: FileRecordNumber, path: &'a PathBuf) -> FilesystemResult<(FileRecordNumber, &'a str)> {
+        let components: Vec<Component<'a>> = path.components().collect();
+        let Some((Component::Normal(name), parents)) = components.split_last() else {
+            return Err(FilesystemError::InvalidParameter);
+        };
+
+        let mut current = cwd;
+        for component in parents {
+            current = self.walk(current, *component)?;
+        }
+
+        if !self.is_directory(current)? {
+            return Err(FilesystemError::NotADirectory);
+        }
+        Ok((current, *name))
+    }
+
+    /// Follow one path component from `current`
+    fn walk(&self, current: FileRecordNumber, component: Component) -> FilesystemResult<FileRecordNumber> {
+        match component {
+            Component::RootDir => Ok(MFT_RECORD_ROOT),
+            Component::CurDir => {
+                if !self.is_directory(current)? {
+                    return Err(FilesystemError::NotADirectory);
+                }
+                Ok(current)
+            }
+            Component::ParentDir => self.parent_directory(current),
+            Component::Normal(name) => self.find_in_directory(current, name)?
+                .ok_or(FilesystemError::NotFound),
+        }
+    }
+
+    /// B+ tree index of a directory
+    pub(crate) fn directory_index(&self, directory: FileRecordNumber) -> FilesystemResult<BTreeManager> {
+        if directory == MFT_RECORD_ROOT {
+            return Ok(self.btree_manager.for_directory(self.btree_manager.root_vcn()));
+        }
+        let index_root = self.file_manager.read_index_root(directory)?;
+        Ok(self.btree_manager.for_directory(index_root.root_vcn))
+    }
+
+    fn validate_name(name: &str) -> FilesystemResult<()> {
+        // Names are stored with a one byte length in the FileName attribute
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.len() > 255 {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        Ok(())
+    }
+
+    /// On-disk layout of the mounted volume
+    pub fn super_block(&self) -> &GalleonSuperBlock {
+        &self.super_block
+    }
+
     /// Get filesystem statistics
     /// Get B-tree diagnostic information (for debugging)
     pub fn get_btree_diag(&self) -> (u64, u32, u64, u64, u8) {
@@ -763,6 +1162,7 @@ impl GalleonFilesystem {
         let total_space = self.super_block.legacy_super_block.total_blocks * self.super_block.cluster_size as u64;
         let used_space = total_space - free_space;
 
+        let cache = self.cache.stats();
         kprintln!("Stats - Total: {}, Free: {}, Used: {}", total_space, free_space, used_space);
         Ok(FilesystemStats {
             total_space,
@@ -770,34 +1170,35 @@ impl GalleonFilesystem {
             used_space,
             cluster_size: self.super_block.cluster_size,
             total_clusters: self.super_block.legacy_super_block.total_blocks,
+            cache_hits: cache.hits,
+            cache_misses: cache.misses,
         })
     }
 
-    /// Defragment the filesystem
-    pub fn defragment(&mut self) -> FilesystemResult<()> {
-        kprintln!("Starting filesystem defragmentation");
-        // Get all file records and defragment them
-        // This is a simplified implementation
-        for file_record in 10..1000 { // Skip system files
-            if let Err(_) = self.allocator.defragment_file(file_record) {
-                // Ignore errors for non-existent files
-                continue;
-            }
-        }
-        kprintln!("Filesystem defragmentation completed");
-        Ok(())
-    }
-
     /// Force sync all data to disk
+    ///
+    /// Writes back every dirty block of the buffer cache; the checkpoint does
+    /// so before it lets the log forget the transactions that wrote them.
     pub fn sync(&mut self) -> FilesystemResult<()> {
         kprintln!("Syncing filesystem to disk");
         self.allocator.bitmap.flush_bitmap()?;
-        self.journal_manager.cleanup_completed_transactions();
+        self.journal_manager.lock().checkpoint()?;
+        self.device.flush()?;
         kprintln!("Filesystem sync completed");
         Ok(())
     }
 }
 
+impl Drop for GalleonFilesystem {
+    /// Write back cached blocks when the volume goes away; if that fails the
+    /// journal still brings the volume up to date on the next mount
+    fn drop(&mut self) {
+        if self.cache.dirty_blocks() > 0 {
+            let _ = self.sync();
+        }
+    }
+}
+
 /// Filesystem statistics
 #[derive(Debug, Clone)]
 pub struct FilesystemStats {
@@ -806,14 +1207,20 @@ pub struct FilesystemStats {
     pub used_space: u64,
     pub cluster_size: u32,
     pub total_clusters: u64,
+    /// Block lookups served by the buffer cache
+    pub cache_hits: u64,
+    /// Block lookups that went to the disk
+    pub cache_misses: u64,
 }
 
 impl Clone for MftManager {
     fn clone(&self) -> Self {
         Self {
-            drive: self.drive,
+            device: self.device.clone(),
             mft_start_cluster: self.mft_start_cluster,
+            mft_size_clusters: self.mft_size_clusters,
             cluster_size: self.cluster_size,
+            extension: self.extension.clone(),
         }
     }
 }
@@ -821,12 +1228,14 @@ impl Clone for MftManager {
 impl Clone for JournalManager {
     fn clone(&self) -> Self {
         Self {
-            drive: self.drive,
+            device: self.device.clone(),
             journal_start_sector: self.journal_start_sector,
             journal_size_sectors: self.journal_size_sectors,
             current_sequence: self.current_sequence,
             active_transactions: alloc::collections::VecDeque::new(),
             next_transaction_id: self.next_transaction_id,
+            log_start: self.log_start,
+            log_tail: self.log_tail,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,83 @@
+//! Online Grow
+//!
+//! Enlarges a mounted volume after the block device under it has grown, for
+//! example when a VM disk is resized. The cluster bitmap is extended to cover
+//! the new clusters and the superblock and its backup are rewritten with the
+//! new size, all in one journaled transaction.
+//!
+//! A bitmap that no longer fits its region is moved to a free run of the
+//! volume. Its original region stays reserved with the rest of the system
+//! area; a bitmap that already lives in the data area has its old run freed.
+//! The MFT grows on its own as records run out, see
+//! [`MftManager::extend`](crate::mft::MftManager::extend).
+
+use crate::{
+    FilesystemError, FilesystemResult,
+    allocation::{AllocationStrategy, ClusterBitmap, ClusterRun},
+    galleon_fs::GalleonFilesystem,
+    journal::OperationType,
+    mft::MFT_RECORD_BITMAP,
+};
+use crate::kprintln;
+
+impl GalleonFilesystem {
+    /// Grow the volume to fill its block device, returning the new number of clusters
+    ///
+    /// Fails with `InvalidParameter` if the device is smaller than the volume.
+    pub fn grow(&mut self) -> FilesystemResult<u64> {
+        let old_total = self.super_block.legacy_super_block.total_blocks;
+        let new_total = self.device.capacity_bytes() / self.super_block.cluster_size as u64;
+        if new_total < old_total {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        if new_total == old_total {
+            return Ok(old_total);
+        }
+
+        kprintln!("Growing volume from {} to {} clusters", old_total, new_total);
+        let previous = self.super_block.clone();
+        let result = self.transaction(OperationType::UpdateMetadata, MFT_RECORD_BITMAP, |fs| fs.extend_volume(new_total));
+        if let Err(error) = result {
+            // The disk has been rolled back; the in-memory layout follows it
+            let sectors_per_cluster = (previous.cluster_size / 512) as u64;
+            self.allocator.bitmap = ClusterBitmap::new(
+                self.device.clone(),
+                previous.bitmap_start_cluster * sectors_per_cluster,
+                old_total,
+            );
+            self.super_block = previous;
+            return Err(error);
+        }
+        Ok(new_total)
+    }
+
+    /// Extend the bitmap and superblock to `total_clusters`
+    fn extend_volume(&mut self, total_clusters: u64) -> FilesystemResult<()> {
+        let cluster_size = self.super_block.cluster_size as u64;
+        let sectors_per_cluster = cluster_size / 512;
+        let old_bitmap = ClusterRun::new(self.super_block.bitmap_start_cluster, self.super_block.bitmap_size_clusters);
+        let bitmap_clusters = total_clusters.div_ceil(8 * cluster_size);
+
+        // Cover the new clusters in place first so they can be searched for a new home
+        self.allocator.bitmap.resize(old_bitmap.start_cluster * sectors_per_cluster, total_clusters)?;
+        if bitmap_clusters > old_bitmap.cluster_count {
+            let run = self.allocator.bitmap.find_free_clusters(bitmap_clusters, AllocationStrategy::FirstFit)?
+                .ok_or(FilesystemError::InsufficientSpace)?;
+            self.allocator.bitmap.mark_run_used(&run)?;
+            if old_bitmap.start_cluster >= self.super_block.index_allocation_start {
+                self.allocator.bitmap.mark_run_free(&old_bitmap)?;
+            }
+            self.allocator.bitmap.resize(run.start_cluster * sectors_per_cluster, total_clusters)?;
+            kprintln!("Bitmap moved to clusters {}..={}", run.start_cluster, run.end_cluster());
+
+            self.super_block.bitmap_start_cluster = run.start_cluster;
+            self.super_block.bitmap_size_clusters = bitmap_clusters;
+        }
+        self.allocator.bitmap.flush_bitmap()?;
+
+        let legacy = &mut self.super_block.legacy_super_block;
+        legacy.free_block_count += total_clusters - legacy.total_blocks;
+        legacy.total_blocks = total_clusters;
+        self.write_super_block()
+    }
+}
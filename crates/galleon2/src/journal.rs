@@ -2,12 +2,25 @@
 //!
 //! Provides crash recovery and transaction support with journaling.
 //! All filesystem modifications are logged before being committed.
+//!
+//! The journal is a physical write-ahead log. While a transaction is active,
+//! every metadata write that goes through a [`JournaledDevice`] is first
+//! recorded with its before image (undo) and after image (redo). Commit and
+//! abort records close a transaction; checkpoint records mark points where all
+//! earlier in-place writes are known to be durable.
+//!
+//! On-disk layout of the journal area:
+//! - sector 0: restart area pointing at the oldest record recovery needs
+//! - sectors 1..: circular log, each record starting on a sector boundary
+//!
+//! Recovery runs in three passes keyed by transaction id: analysis finds the
+//! outcome of every transaction in the log, redo re-applies committed
+//! transactions in log order, and undo rolls back transactions that never
+//! committed in reverse log order.
 
-use core::ops::{Add, AddAssign};
-
-use alloc::{borrow::ToOwned, collections::VecDeque, vec::{self, Vec}};
-use lib_kernel::kprint;
-use crate::{FilesystemResult, FilesystemError, ide_read_sectors, ide_write_sectors};
+use alloc::{borrow::ToOwned, collections::{BTreeMap, VecDeque}, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+use crate::{FilesystemResult, FilesystemError, kprintln, block_device::{BlockDevice, SharedBlockDevice, SECTOR_SIZE}};
 
 /// Transaction operation types
 #[repr(u32)]
@@ -21,8 +34,24 @@ pub enum OperationType {
     DeleteDirectory = 6,
     MoveFile = 7,
     SetAttribute = 8,
+    /// Marks a transaction as committed
+    Commit = 9,
+    /// Marks a transaction as rolled back
+    Abort = 10,
+    /// All in-place writes logged before this record are durable
+    Checkpoint = 11,
 }
 
+impl OperationType {
+    /// Whether records of this type carry undo/redo images of the device
+    pub fn carries_images(&self) -> bool {
+        !matches!(self, OperationType::Commit | OperationType::Abort | OperationType::Checkpoint)
+    }
+}
+
+/// Size of the fixed part of a serialized log record
+pub const LOG_RECORD_HEADER_SIZE: usize = 60;
+
 /// Log record entry
 #[derive(Debug, Clone)]
 pub struct LogRecord {
@@ -33,6 +62,10 @@ pub struct LogRecord {
     pub redo_data: Vec<u8>,
     pub checksum: u32,
     pub timestamp: u64, // Simple timestamp counter
+    /// Transaction this record belongs to; 0 for records outside any transaction
+    pub transaction_id: u64,
+    /// Byte offset on the device that the undo and redo images apply to
+    pub target_offset: u64,
 }
 
 impl LogRecord {
@@ -42,6 +75,19 @@ impl LogRecord {
         target_file_record: u64,
         undo_data: Vec<u8>,
         redo_data: Vec<u8>,
+    ) -> Self {
+        Self::for_transaction(sequence_number, 0, operation_type, target_file_record, 0, undo_data, redo_data)
+    }
+
+    /// Create a record that belongs to a transaction and targets `target_offset`
+    pub fn for_transaction(
+        sequence_number: u64,
+        transaction_id: u64,
+        operation_type: OperationType,
+        target_file_record: u64,
+        target_offset: u64,
+        undo_data: Vec<u8>,
+        redo_data: Vec<u8>,
     ) -> Self {
         let mut record = Self {
             sequence_number,
@@ -51,25 +97,31 @@ impl LogRecord {
             redo_data,
             checksum: 0,
             timestamp: get_timestamp(),
+            transaction_id,
+            target_offset,
         };
         record.checksum = record.calculate_checksum();
         record
     }
 
     fn calculate_checksum(&self) -> u32 {
-        let mut checksum = 0u32;
-        checksum ^= self.sequence_number as u32;
-        checksum ^= (self.sequence_number >> 32) as u32;
-        checksum ^= self.operation_type as u32;
-        checksum ^= self.target_file_record as u32;
-        checksum ^= (self.target_file_record >> 32) as u32;
+        // FNV-1a, so a record torn part-way through its data never verifies
+        let mut checksum = 0x811c_9dc5u32;
+        let mut feed = |bytes: &[u8]| {
+            for &byte in bytes {
+                checksum = (checksum ^ byte as u32).wrapping_mul(0x0100_0193);
+            }
+        };
 
-        for &byte in &self.undo_data {
-            checksum ^= byte as u32;
-        }
-        for &byte in &self.redo_data {
-            checksum ^= byte as u32;
-        }
+        feed(&self.sequence_number.to_le_bytes());
+        feed(&(self.operation_type as u32).to_le_bytes());
+        feed(&self.target_file_record.to_le_bytes());
+        feed(&self.transaction_id.to_le_bytes());
+        feed(&self.target_offset.to_le_bytes());
+        feed(&(self.undo_data.len() as u32).to_le_bytes());
+        feed(&(self.redo_data.len() as u32).to_le_bytes());
+        feed(&self.undo_data);
+        feed(&self.redo_data);
 
         checksum
     }
@@ -89,21 +141,34 @@ impl LogRecord {
         data.extend_from_slice(&(self.redo_data.len() as u32).to_le_bytes());
         data.extend_from_slice(&self.checksum.to_le_bytes());
         data.extend_from_slice(&self.timestamp.to_le_bytes());
+        data.extend_from_slice(&self.transaction_id.to_le_bytes());
+        data.extend_from_slice(&self.target_offset.to_le_bytes());
 
         // Data
         data.extend_from_slice(&self.undo_data);
         data.extend_from_slice(&self.redo_data);
 
         // Pad to 8-byte boundary
-        while data.len() % 8 != 0 {
+        while !data.len().is_multiple_of(8) {
             data.push(0);
         }
 
         data.to_owned()
     }
 
+    /// Total serialized length of a record whose header starts `data`
+    pub fn serialized_len(data: &[u8]) -> FilesystemResult<usize> {
+        if data.len() < LOG_RECORD_HEADER_SIZE || &data[0..4] != b"JRNL" {
+            return Err(FilesystemError::InvalidParameter);
+        }
+
+        let undo_data_len = u32::from_le_bytes(data[24..28].try_into().unwrap()) as usize;
+        let redo_data_len = u32::from_le_bytes(data[28..32].try_into().unwrap()) as usize;
+        Ok((LOG_RECORD_HEADER_SIZE + undo_data_len + redo_data_len).next_multiple_of(8))
+    }
+
     pub fn deserialize(data: &[u8]) -> FilesystemResult<Self> {
-        if data.len() < 48 {
+        if data.len() < LOG_RECORD_HEADER_SIZE {
             return Err(FilesystemError::InvalidParameter);
         }
 
@@ -121,6 +186,9 @@ impl LogRecord {
             6 => OperationType::DeleteDirectory,
             7 => OperationType::MoveFile,
             8 => OperationType::SetAttribute,
+            9 => OperationType::Commit,
+            10 => OperationType::Abort,
+            11 => OperationType::Checkpoint,
             _ => return Err(FilesystemError::InvalidParameter),
         };
 
@@ -129,13 +197,17 @@ impl LogRecord {
         let redo_data_len = u32::from_le_bytes(data[28..32].try_into().unwrap()) as usize;
         let checksum = u32::from_le_bytes(data[32..36].try_into().unwrap());
         let timestamp = u64::from_le_bytes(data[36..44].try_into().unwrap());
+        let transaction_id = u64::from_le_bytes(data[44..52].try_into().unwrap());
+        let target_offset = u64::from_le_bytes(data[52..60].try_into().unwrap());
 
-        if data.len() < 44 + undo_data_len + redo_data_len {
+        let undo_start = LOG_RECORD_HEADER_SIZE;
+        let redo_start = undo_start + undo_data_len;
+        if data.len() < redo_start + redo_data_len {
             return Err(FilesystemError::InvalidParameter);
         }
 
-        let undo_data = data[44..44 + undo_data_len].to_vec();
-        let redo_data = data[44 + undo_data_len..44 + undo_data_len + redo_data_len].to_vec();
+        let undo_data = data[undo_start..redo_start].to_vec();
+        let redo_data = data[redo_start..redo_start + redo_data_len].to_vec();
 
         let record = Self {
             sequence_number,
@@ -145,6 +217,8 @@ impl LogRecord {
             redo_data,
             checksum,
             timestamp,
+            transaction_id,
+            target_offset,
         };
 
         if !record.verify_checksum() {
@@ -170,15 +244,27 @@ pub struct Transaction {
     pub state: TransactionState,
     pub records: Vec<LogRecord>,
     pub start_sequence: u64,
+    /// Operation the transaction performs, stamped on each of its records
+    pub operation_type: OperationType,
+    /// File record the transaction operates on; the parent directory for creations
+    pub target_file_record: u64,
+    /// Log sector of the transaction's first record, once it has one
+    pub start_sector: Option<u64>,
+    /// How many nested `begin_transaction` calls have joined this transaction
+    pub depth: u32,
 }
 
 impl Transaction {
-    pub fn new(id: u64, start_sequence: u64) -> Self {
+    pub fn new(id: u64, operation_type: OperationType, target_file_record: u64, start_sequence: u64) -> Self {
         Self {
             id,
             state: TransactionState::Active,
             records: Vec::new(),
             start_sequence,
+            operation_type,
+            target_file_record,
+            start_sector: None,
+            depth: 1,
         }
     }
 
@@ -197,33 +283,57 @@ impl Transaction {
     }
 }
 
+/// Journal shared between a filesystem and its [`JournaledDevice`]
+pub type SharedJournal = Arc<Mutex<JournalManager>>;
+
 /// Journal Manager
 pub struct JournalManager {
-    pub drive: u8,
+    pub device: SharedBlockDevice,
     pub journal_start_sector: u64,
     pub journal_size_sectors: u64,
     pub current_sequence: u64,
     pub active_transactions: VecDeque<Transaction>,
     pub next_transaction_id: u64,
+    /// Log sector of the oldest record recovery still needs
+    pub log_start: u64,
+    /// Log sector the next record is appended at
+    pub log_tail: u64,
 }
 
 impl JournalManager {
-    pub fn new(drive: u8, journal_start_sector: u64, journal_size_sectors: u64) -> Self {
+    pub fn new(device: SharedBlockDevice, journal_start_sector: u64, journal_size_sectors: u64) -> Self {
         Self {
-            drive,
+            device,
             journal_start_sector,
             journal_size_sectors,
             current_sequence: 1,
             active_transactions: VecDeque::new(),
             next_transaction_id: 1,
+            log_start: 1,
+            log_tail: 1,
         }
     }
 
-    pub fn begin_transaction(&mut self) -> u64 {
+    /// Wrap the journal so it can be shared with a [`JournaledDevice`]
+    pub fn into_shared(self) -> SharedJournal {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Start a transaction, or join the active one
+    ///
+    /// Nested calls return the id of the transaction already in progress; it
+    /// commits once the outermost caller commits.
+    pub fn begin_transaction(&mut self, operation_type: OperationType, target_file_record: u64) -> u64 {
+        if let Some(transaction) = self.active_transactions.iter_mut()
+            .find(|t| t.state == TransactionState::Active) {
+            transaction.depth += 1;
+            return transaction.id;
+        }
+
         let transaction_id = self.next_transaction_id;
         self.next_transaction_id += 1;
 
-        let transaction = Transaction::new(transaction_id, self.current_sequence);
+        let transaction = Transaction::new(transaction_id, operation_type, target_file_record, self.current_sequence);
         self.active_transactions.push_back(transaction);
 
         transaction_id
@@ -234,66 +344,100 @@ impl JournalManager {
         transaction_id: u64,
         operation_type: OperationType,
         target_file_record: u64,
+        target_offset: u64,
         undo_data: Vec<u8>,
         redo_data: Vec<u8>,
     ) -> FilesystemResult<()> {
-        lib_kernel::kprintln!("[JOURNAL] Logging operation {:?} for transaction {}\n", operation_type, transaction_id);
-        let record = LogRecord::new(
+        if !self.active_transactions.iter().any(|t| t.id == transaction_id && t.state == TransactionState::Active) {
+            return Err(FilesystemError::InvalidParameter);
+        }
+
+        let record = LogRecord::for_transaction(
             self.current_sequence,
+            transaction_id,
             operation_type,
             target_file_record,
+            target_offset,
             undo_data,
             redo_data,
         );
-        lib_kernel::kprintln!("[JOURNAL] Created log record: {:?}", record);
 
-        // Find the transaction
+        // Write the record to journal
+        let sector = self.write_log_record(&record)?;
+
         if let Some(transaction) = self.active_transactions.iter_mut()
-            .find(|t| t.id == transaction_id && t.state == TransactionState::Active) {
-            transaction.add_record(record.clone());
-        } else {
-            return Err(FilesystemError::InvalidParameter);
+            .find(|t| t.id == transaction_id) {
+            if transaction.start_sector.is_none() {
+                transaction.start_sector = Some(sector);
+                transaction.start_sequence = record.sequence_number;
+            }
+            transaction.add_record(record);
         }
 
-        lib_kernel::kprintln!("[JOURNAL] Writing log record to journal on disk...");
+        Ok(())
+    }
 
-        // Write the record to journal
-        self.write_log_record(&record)?;
-        lib_kernel::kprintln!("[JOURNAL] Log record written successfully.");
-        self.current_sequence += 1;
+    /// Log a write of `data` at `start_sector` before it reaches the device
+    ///
+    /// Only the bytes that actually change are logged. Writes made while no
+    /// transaction is active are not journaled.
+    pub fn log_write(&mut self, start_sector: u64, data: &[u8]) -> FilesystemResult<()> {
+        let Some((transaction_id, operation_type, target_file_record)) = self.active_transactions.iter()
+            .find(|t| t.state == TransactionState::Active)
+            .map(|t| (t.id, t.operation_type, t.target_file_record)) else {
+            return Ok(());
+        };
 
+        let mut before = vec![0u8; data.len()];
+        self.device.read_sectors(start_sector, &mut before)?;
 
+        let Some(first) = before.iter().zip(data).position(|(old, new)| old != new) else {
+            return Ok(());
+        };
+        let last = before.iter().zip(data).rposition(|(old, new)| old != new).unwrap_or(first);
 
-        Ok(())
+        self.log_operation(
+            transaction_id,
+            operation_type,
+            target_file_record,
+            start_sector * SECTOR_SIZE as u64 + first as u64,
+            before[first..=last].to_vec(),
+            data[first..=last].to_vec(),
+        )
     }
 
     pub fn commit_transaction(&mut self, transaction_id: u64) -> FilesystemResult<()> {
-        if let Some(transaction) = self.active_transactions.iter_mut()
-            .find(|t| t.id == transaction_id) {
-            transaction.commit();
+        let Some(transaction) = self.active_transactions.iter_mut()
+            .find(|t| t.id == transaction_id && t.state == TransactionState::Active) else {
+            return Err(FilesystemError::InvalidParameter);
+        };
 
-            // Write commit record
-            let commit_record = LogRecord::new(
-                self.current_sequence,
-                OperationType::UpdateMetadata, // Use as commit marker
-                transaction_id,
-                Vec::new(),
-                b"COMMIT".to_vec(),
-            );
+        if transaction.depth > 1 {
+            transaction.depth -= 1;
+            return Ok(());
+        }
 
-            self.write_log_record(&commit_record)?;
-            self.current_sequence += 1;
+        // Write commit record
+        let commit_record = LogRecord::for_transaction(
+            self.current_sequence,
+            transaction_id,
+            OperationType::Commit,
+            transaction.target_file_record,
+            0,
+            Vec::new(),
+            Vec::new(),
+        );
 
-            Ok(())
-        } else {
-            Err(FilesystemError::InvalidParameter)
-        }
+        self.write_log_record(&commit_record)?;
+        self.finish_transaction(transaction_id, TransactionState::Committed);
+        Ok(())
     }
 
+    /// Roll back a transaction, including any nested callers that joined it
     pub fn abort_transaction(&mut self, transaction_id: u64) -> FilesystemResult<()> {
         // Find the transaction and get its records
         let records = if let Some(transaction) = self.active_transactions.iter()
-            .find(|t| t.id == transaction_id) {
+            .find(|t| t.id == transaction_id && t.state == TransactionState::Active) {
             transaction.records.clone()
         } else {
             return Err(FilesystemError::InvalidParameter);
@@ -303,123 +447,304 @@ impl JournalManager {
         for record in records.iter().rev() {
             self.apply_undo(record)?;
         }
+        self.device.flush()?;
 
-        // Now abort the transaction
+        // The transaction no longer pins the log, so the abort record always has room
+        self.finish_transaction(transaction_id, TransactionState::Aborted);
+
+        // Write abort record
+        let abort_record = LogRecord::for_transaction(
+            self.current_sequence,
+            transaction_id,
+            OperationType::Abort,
+            0,
+            0,
+            Vec::new(),
+            Vec::new(),
+        );
+
+        self.write_log_record(&abort_record)?;
+        Ok(())
+    }
+
+    /// Record the outcome of a transaction and drop it from the active list
+    fn finish_transaction(&mut self, transaction_id: u64, state: TransactionState) {
         if let Some(transaction) = self.active_transactions.iter_mut()
             .find(|t| t.id == transaction_id) {
-            transaction.abort();
+            match state {
+                TransactionState::Committed => transaction.commit(),
+                TransactionState::Aborted => transaction.abort(),
+                TransactionState::Active => {}
+            }
+        }
+        self.cleanup_completed_transactions();
+    }
 
-            // Write abort record
-            let abort_record = LogRecord::new(
-                self.current_sequence,
-                OperationType::UpdateMetadata, // Use as abort marker
-                transaction_id,
-                Vec::new(),
-                b"ABORT".to_vec(),
-            );
+    /// Make every finished transaction durable and record a checkpoint
+    pub fn checkpoint(&mut self) -> FilesystemResult<()> {
+        self.write_restart_area()?;
 
-            self.write_log_record(&abort_record)?;
-            self.current_sequence += 1;
+        let active: Vec<u8> = self.active_transactions.iter()
+            .flat_map(|t| t.id.to_le_bytes())
+            .collect();
+        let checkpoint_record = LogRecord::for_transaction(
+            self.current_sequence,
+            0,
+            OperationType::Checkpoint,
+            0,
+            0,
+            Vec::new(),
+            active,
+        );
+        self.write_log_record(&checkpoint_record)?;
+        Ok(())
+    }
 
-            Ok(())
-        } else {
-            Err(FilesystemError::InvalidParameter)
+    /// Initialize an empty log, discarding anything left in the journal area
+    pub fn format(&mut self) -> FilesystemResult<()> {
+        let zeroes = vec![0u8; SECTOR_SIZE * 8];
+        let mut sector = 0;
+        while sector < self.journal_size_sectors {
+            let count = (self.journal_size_sectors - sector).min(8) as usize;
+            self.device.write_sectors(self.journal_start_sector + sector, &zeroes[..count * SECTOR_SIZE])?;
+            sector += count as u64;
         }
+
+        self.active_transactions.clear();
+        self.current_sequence = 1;
+        self.next_transaction_id = 1;
+        self.log_start = 1;
+        self.log_tail = 1;
+        self.checkpoint()
     }
 
-    fn write_log_record(&self, record: &LogRecord) -> FilesystemResult<()> {
-        lib_kernel::kprintln!("[JOURNAL] Preparing to write log record: {:?}", record);
+    /// Append a record to the log and return the sector it was written at
+    fn write_log_record(&mut self, record: &LogRecord) -> FilesystemResult<u64> {
         let mut data = Vec::new();
         let serialized = record.serialize(&mut data);
-        lib_kernel::kprintln!("[JOURNAL] Serialized log record ({} bytes)", serialized.len());
-        if serialized.is_empty() {
+        if serialized.is_empty() || record.sequence_number != self.current_sequence {
             return Err(FilesystemError::InvalidParameter);
         }
 
-        let sectors_needed = serialized.len().add(511).div_ceil(512);
-
-        // Calculate journal position (circular buffer)
-        // Ensure we don't divide by zero and have a reasonable record size
-        let record_size = serialized.len().max(64) as u64; // Minimum 64 bytes
-        let max_records_in_journal = (self.journal_size_sectors * 512) / record_size;
-        let journal_offset = if max_records_in_journal > 0 {
-            (record.sequence_number % max_records_in_journal) * record_size / 512
-        } else {
-            0 // Fallback if journal is too small
+        let sectors_needed = serialized.len().div_ceil(SECTOR_SIZE) as u64;
+        let write_sector = match self.reserve(sectors_needed) {
+            Some(sector) => sector,
+            None => {
+                // Move the log start past finished transactions and retry
+                self.write_restart_area()?;
+                self.reserve(sectors_needed).ok_or(FilesystemError::InsufficientSpace)?
+            }
         };
-        let write_sector = self.journal_start_sector + journal_offset;
 
-        let thing = align_of_val(&sectors_needed);
+        // Prepare sector-aligned data
+        let mut sector_data = vec![0u8; sectors_needed as usize * SECTOR_SIZE];
+        sector_data[..serialized.len()].copy_from_slice(&serialized);
 
-        lib_kernel::kprintln!("Aligned size: {}", thing);
+        // The record must be durable before the write it describes; only the
+        // log sectors are pushed out, cached metadata stays where it is
+        self.device.write_sectors(self.journal_start_sector + write_sector, &sector_data)?;
+        self.device.flush_sectors(self.journal_start_sector + write_sector, sectors_needed)?;
 
-        // Prepare sector-aligned data
-        let mut sector_data = alloc::vec![0u8; sectors_needed * 512];
+        self.log_tail = write_sector + sectors_needed;
+        self.current_sequence += 1;
+        Ok(write_sector)
+    }
 
-        let other_thing = align_of_val(&sector_data);
-        lib_kernel::kprintln!("Aligned sector data size: {}", other_thing);
+    /// Find room for `sectors` contiguous log sectors without overwriting live records
+    fn reserve(&self, sectors: u64) -> Option<u64> {
+        let end = self.journal_size_sectors;
+        if self.log_start <= self.log_tail {
+            // Live records do not wrap: free space is after the tail and before the start
+            if self.log_tail + sectors <= end {
+                Some(self.log_tail)
+            } else if 1 + sectors < self.log_start {
+                Some(1)
+            } else {
+                None
+            }
+        } else if self.log_tail + sectors < self.log_start {
+            Some(self.log_tail)
+        } else {
+            None
+        }
+    }
 
-        sector_data[..serialized.len()].copy_from_slice(&serialized);
+    /// Point the restart area at the oldest record an active transaction needs
+    fn write_restart_area(&mut self) -> FilesystemResult<()> {
+        // Everything finished transactions wrote in place must be durable first
+        self.device.flush()?;
+
+        let (start, sequence) = self.active_transactions.iter()
+            .filter(|t| t.state == TransactionState::Active)
+            .filter_map(|t| t.start_sector.map(|sector| (sector, t.start_sequence)))
+            .min_by_key(|&(_, sequence)| sequence)
+            .unwrap_or((self.log_tail, self.current_sequence));
+
+        let restart = RestartArea {
+            log_start: start,
+            start_sequence: sequence,
+            next_transaction_id: self.next_transaction_id,
+        };
+        self.device.write_sectors(self.journal_start_sector, &restart.serialize())?;
+        self.device.flush_sectors(self.journal_start_sector, 1)?;
 
-        ide_write_sectors(self.drive, sectors_needed as u8, write_sector as u32, &sector_data)?;
+        self.log_start = start;
         Ok(())
     }
 
-    fn apply_undo(&self, _record: &LogRecord) -> FilesystemResult<()> {
+    fn apply_undo(&self, record: &LogRecord) -> FilesystemResult<()> {
         // Apply the undo data to restore previous state
-        // This would interact with the MFT manager to restore file records
-        // For now, this is a stub implementation
+        if record.operation_type.carries_images() {
+            self.apply_image(record.target_offset, &record.undo_data)?;
+        }
+        Ok(())
+    }
+
+    fn apply_redo(&self, record: &LogRecord) -> FilesystemResult<()> {
+        if record.operation_type.carries_images() {
+            self.apply_image(record.target_offset, &record.redo_data)?;
+        }
         Ok(())
     }
 
+    /// Write `image` at byte `offset` of the device, preserving the bytes around it
+    fn apply_image(&self, offset: u64, image: &[u8]) -> FilesystemResult<()> {
+        if image.is_empty() {
+            return Ok(());
+        }
+
+        let sector_size = SECTOR_SIZE as u64;
+        let first_sector = offset / sector_size;
+        let last_sector = (offset + image.len() as u64 - 1) / sector_size;
+        let mut sectors = vec![0u8; ((last_sector - first_sector + 1) * sector_size) as usize];
+        self.device.read_sectors(first_sector, &mut sectors)?;
+
+        let start = (offset - first_sector * sector_size) as usize;
+        sectors[start..start + image.len()].copy_from_slice(image);
+        self.device.write_sectors(first_sector, &sectors)
+    }
+
+    /// Replay the log after a crash
+    ///
+    /// Committed transactions are redone and transactions without a commit
+    /// or abort record are undone, after which the log is checkpointed empty.
     pub fn recover(&mut self) -> FilesystemResult<()> {
-        // Scan the journal for uncommitted transactions and apply undo operations
-        // This would be called during filesystem mount after a crash
-
-        let mut current_sector = 0u64;
-        while current_sector < self.journal_size_sectors {
-            let sector_data = self.read_journal_sector(current_sector)?;
-
-            // Parse log records from the sector
-            let mut offset = 0;
-            while offset + 48 <= sector_data.len() {
-                if &sector_data[offset..offset+4] == b"JRNL" {
-                    if let Ok(record) = LogRecord::deserialize(&sector_data[offset..]) {
-                        // Check if this is part of a committed transaction
-                        // If not, apply undo operation
-                        if !self.is_transaction_committed(record.target_file_record) {
-                            self.apply_undo(&record)?;
-                        }
-                        offset += record.serialize(&mut Vec::new()).len();
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
-                }
+        let mut restart_sector = vec![0u8; SECTOR_SIZE];
+        self.device.read_sectors(self.journal_start_sector, &mut restart_sector)?;
+        let Some(restart) = RestartArea::deserialize(&restart_sector) else {
+            kprintln!("[JOURNAL] No valid restart area, starting an empty log");
+            return self.format();
+        };
+
+        // Analysis: collect the live records and the outcome of each transaction
+        let (located, sector) = self.scan_log(&restart)?;
+        let records: Vec<LogRecord> = located.into_iter().map(|(_, record)| record).collect();
+        let sequence = restart.start_sequence + records.len() as u64;
+        let mut next_transaction_id = restart.next_transaction_id;
+
+        let mut outcomes: BTreeMap<u64, TransactionState> = BTreeMap::new();
+        for record in records.iter().filter(|r| r.transaction_id != 0) {
+            next_transaction_id = next_transaction_id.max(record.transaction_id + 1);
+            match record.operation_type {
+                OperationType::Commit => { outcomes.insert(record.transaction_id, TransactionState::Committed); }
+                OperationType::Abort => { outcomes.insert(record.transaction_id, TransactionState::Aborted); }
+                _ => { outcomes.entry(record.transaction_id).or_insert(TransactionState::Active); }
             }
+        }
 
-            current_sector += 1;
+        // Redo: repeat committed work in log order
+        let mut redone = 0;
+        for record in &records {
+            if outcomes.get(&record.transaction_id) == Some(&TransactionState::Committed) {
+                self.apply_redo(record)?;
+                redone += 1;
+            }
         }
 
-        Ok(())
+        // Undo: roll back transactions that never finished, newest first
+        let mut undone = 0;
+        for record in records.iter().rev() {
+            if outcomes.get(&record.transaction_id) == Some(&TransactionState::Active) {
+                self.apply_undo(record)?;
+                undone += 1;
+            }
+        }
+        self.device.flush()?;
+
+        kprintln!("[JOURNAL] Recovery scanned {} records, redid {}, undid {}", records.len(), redone, undone);
+
+        self.active_transactions.clear();
+        self.current_sequence = sequence;
+        self.next_transaction_id = next_transaction_id;
+        self.log_start = restart.log_start;
+        self.log_tail = sector;
+        self.checkpoint()
     }
 
-    fn read_journal_sector(&self, sector_offset: u64) -> FilesystemResult<Vec<u8>> {
-        let mut sector_data = alloc::vec![0u8; 512];
-        ide_read_sectors(
-            self.drive,
-            1,
-            (self.journal_start_sector + sector_offset) as u32,
-            &mut sector_data,
-        )?;
-        Ok(sector_data)
+    /// Live records of the log, oldest first, with the log sector each starts at
+    ///
+    /// Read-only; empty if the journal has no valid restart area.
+    pub fn log_records(&self) -> FilesystemResult<Vec<(u64, LogRecord)>> {
+        let mut restart_sector = vec![0u8; SECTOR_SIZE];
+        self.device.read_sectors(self.journal_start_sector, &mut restart_sector)?;
+        match RestartArea::deserialize(&restart_sector) {
+            Some(restart) => Ok(self.scan_log(&restart)?.0),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Follow the log from the restart area, returning the records found and
+    /// the sector after the last one
+    fn scan_log(&self, restart: &RestartArea) -> FilesystemResult<(Vec<(u64, LogRecord)>, u64)> {
+        let mut records = Vec::new();
+        let mut sector = restart.log_start;
+        let mut sequence = restart.start_sequence;
+        while let Some((found_at, record)) = self.find_record(sector, sequence)? {
+            sector = found_at + record.serialize(&mut Vec::new()).len().div_ceil(SECTOR_SIZE) as u64;
+            sequence += 1;
+            records.push((found_at, record));
+        }
+        Ok((records, sector))
+    }
+
+    /// Find the record with `sequence` at `sector`, or at the start of the log if it wrapped
+    fn find_record(&self, sector: u64, sequence: u64) -> FilesystemResult<Option<(u64, LogRecord)>> {
+        if let Some(record) = self.read_log_record(sector, sequence)? {
+            return Ok(Some((sector, record)));
+        }
+        if sector != 1 && let Some(record) = self.read_log_record(1, sequence)? {
+            return Ok(Some((1, record)));
+        }
+        Ok(None)
+    }
+
+    /// Read the record at a log sector if it is intact and has the expected sequence number
+    fn read_log_record(&self, sector: u64, sequence: u64) -> FilesystemResult<Option<LogRecord>> {
+        if sector == 0 || sector >= self.journal_size_sectors {
+            return Ok(None);
+        }
+
+        let header = self.read_journal_sector(sector)?;
+        let Ok(length) = LogRecord::serialized_len(&header) else {
+            return Ok(None);
+        };
+        let sectors = length.div_ceil(SECTOR_SIZE) as u64;
+        if sector + sectors > self.journal_size_sectors {
+            return Ok(None);
+        }
+
+        let mut data = vec![0u8; sectors as usize * SECTOR_SIZE];
+        self.device.read_sectors(self.journal_start_sector + sector, &mut data)?;
+        match LogRecord::deserialize(&data) {
+            Ok(record) if record.sequence_number == sequence => Ok(Some(record)),
+            _ => Ok(None),
+        }
     }
 
-    fn is_transaction_committed(&self, _transaction_id: u64) -> bool {
-        // Check if transaction was committed by scanning for commit record
-        // Simplified implementation
-        true
+    fn read_journal_sector(&self, sector_offset: u64) -> FilesystemResult<Vec<u8>> {
+        let mut sector_data = vec![0u8; SECTOR_SIZE];
+        self.device.read_sectors(self.journal_start_sector + sector_offset, &mut sector_data)?;
+        Ok(sector_data)
     }
 
     pub fn cleanup_completed_transactions(&mut self) {
@@ -428,6 +753,110 @@ impl JournalManager {
     }
 }
 
+/// Fixed first sector of the journal telling recovery where the log starts
+struct RestartArea {
+    log_start: u64,
+    start_sequence: u64,
+    next_transaction_id: u64,
+}
+
+impl RestartArea {
+    fn checksum(data: &[u8]) -> u32 {
+        data.iter().fold(0x811c_9dc5u32, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+    }
+
+    fn serialize(&self) -> [u8; SECTOR_SIZE] {
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[0..4].copy_from_slice(b"RSTR");
+        sector[4..12].copy_from_slice(&self.log_start.to_le_bytes());
+        sector[12..20].copy_from_slice(&self.start_sequence.to_le_bytes());
+        sector[20..28].copy_from_slice(&self.next_transaction_id.to_le_bytes());
+        let checksum = Self::checksum(&sector[0..28]);
+        sector[28..32].copy_from_slice(&checksum.to_le_bytes());
+        sector
+    }
+
+    fn deserialize(sector: &[u8]) -> Option<Self> {
+        if &sector[0..4] != b"RSTR" {
+            return None;
+        }
+        if u32::from_le_bytes(sector[28..32].try_into().unwrap()) != Self::checksum(&sector[0..28]) {
+            return None;
+        }
+
+        Some(Self {
+            log_start: u64::from_le_bytes(sector[4..12].try_into().unwrap()),
+            start_sequence: u64::from_le_bytes(sector[12..20].try_into().unwrap()),
+            next_transaction_id: u64::from_le_bytes(sector[20..28].try_into().unwrap()),
+        })
+    }
+}
+
+/// Block device view that journals every write made inside a transaction
+///
+/// All managers of a mounted filesystem write through this device, so MFT
+/// records, index nodes and the cluster bitmap are logged without each of
+/// them knowing about the journal.
+pub struct JournaledDevice {
+    inner: SharedBlockDevice,
+    journal: SharedJournal,
+}
+
+impl JournaledDevice {
+    pub fn new(inner: SharedBlockDevice, journal: SharedJournal) -> Self {
+        Self { inner, journal }
+    }
+}
+
+impl BlockDevice for JournaledDevice {
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        self.inner.read_sectors(start_sector, buffer)
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        self.journal.lock().log_write(start_sector, buffer)?;
+        self.inner.write_sectors(start_sector, buffer)
+    }
+
+    fn flush(&self) -> FilesystemResult<()> {
+        self.inner.flush()
+    }
+
+    fn flush_sectors(&self, start_sector: u64, sector_count: u64) -> FilesystemResult<()> {
+        self.inner.flush_sectors(start_sector, sector_count)
+    }
+}
+
+/// Run `f` as one transaction on `journal`
+///
+/// The transaction commits if `f` succeeds and is rolled back otherwise; the
+/// error from `f` is returned even if the rollback itself fails.
+pub fn with_transaction<T>(
+    journal: &SharedJournal,
+    operation_type: OperationType,
+    target_file_record: u64,
+    f: impl FnOnce() -> FilesystemResult<T>,
+) -> FilesystemResult<T> {
+    let transaction_id = journal.lock().begin_transaction(operation_type, target_file_record);
+    let result = f().and_then(|value| {
+        journal.lock().commit_transaction(transaction_id)?;
+        Ok(value)
+    });
+
+    if result.is_err() {
+        let _ = journal.lock().abort_transaction(transaction_id);
+    }
+    result
+}
+
 // Simple timestamp function
 fn get_timestamp() -> u64 {
     static mut COUNTER: u64 = 0;
@@ -435,4 +864,4 @@ fn get_timestamp() -> u64 {
         COUNTER += 1;
         COUNTER
     }
-}
\ No newline at end of file
+}
@@ -1,12 +1,22 @@
 #![no_std]
 
+#[cfg(feature = "kernel")]
 pub use ide::{IdeError, IdeResult, ide_read_sectors, ide_write_sectors, return_drive_size_bytes};
 
 extern crate alloc;
 
+#[cfg(feature = "kernel")]
+pub(crate) use lib_kernel::kprintln;
+
 pub mod types;
+pub mod block_device;
+pub mod cache;
+pub mod checksum;
+pub mod defrag;
 pub mod file;
+pub mod fsck;
 pub mod fs;
+pub mod grow;
 mod indexing;
 mod super_block;
 pub mod mft;
@@ -18,8 +28,24 @@ pub mod galleon_fs;
 #[cfg(test)]
 mod tests;
 
+/// Console output goes nowhere when galleon2 is built without the kernel
+#[cfg(not(feature = "kernel"))]
+macro_rules! kprintln {
+    () => {};
+    ($($arg:tt)*) => {{ let _ = core::format_args!($($arg)*); }};
+}
+
+#[cfg(not(feature = "kernel"))]
+pub(crate) use kprintln;
+
 use super_block::SuperBlock;
+pub use block_device::{BlockDevice, SharedBlockDevice, MemoryBlockDevice, PartitionBlockDevice};
+#[cfg(feature = "kernel")]
+pub use block_device::IdeBlockDevice;
+pub use cache::{BufferCache, CacheStats};
+pub use defrag::{DefragReport, FragmentationStats};
 pub use galleon_fs::{GalleonFilesystem, FilesystemStats};
+pub use fsck::{CheckIssue, CheckReport, ClusterOwner};
 
 /// The result type for all filesystem operations in this library.
 pub type FilesystemResult<T> = Result<T, FilesystemError>;
@@ -28,6 +54,7 @@ pub type FilesystemResult<T> = Result<T, FilesystemError>;
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FilesystemError {
     /// IDE operation failed with specific error
+    #[cfg(feature = "kernel")]
     Ide(IdeError),
     /// The boot block is invalid or the magic string does not match.
     InvalidBootBlock,
@@ -39,8 +66,23 @@ pub enum FilesystemError {
     InvalidParameter,
     /// Disk failed to write
     WriteError,
+    /// A sector range lies outside the block device
+    OutOfRange,
+    /// The block device reported an I/O failure
+    DeviceError,
+    /// No file or directory exists at the given name or path
+    NotFound,
+    /// A path component that must be a directory is a regular file
+    NotADirectory,
+    /// An entry with the same name already exists in the directory
+    AlreadyExists,
+    /// The directory still has entries and cannot be removed
+    DirectoryNotEmpty,
+    /// Metadata read from disk failed its checksum or update sequence check
+    ChecksumMismatch,
 }
 
+#[cfg(feature = "kernel")]
 impl From<IdeError> for FilesystemError {
     fn from(ide_error: IdeError) -> Self {
         match ide_error {
@@ -50,9 +92,9 @@ impl From<IdeError> for FilesystemError {
     }
 }
 
-/// Write a new super block to the specified drive with proper error handling
+/// Write a new super block to the specified device with proper error handling
 pub fn write_super_block(
-    drive_num: u8,
+    device: &dyn BlockDevice,
     total_blocks: u64,
     block_size: u32,
     root_dir_block: u64,
@@ -61,30 +103,21 @@ pub fn write_super_block(
     let sector = super_block.as_sector();
 
     // Write to sector 0 (boot sector)
-    ide_write_sectors(drive_num, 1, 0, &sector)?;
+    device.write_sectors(0, &sector)?;
     Ok(())
 }
 
-/// Validate the super block on the specified drive with proper error handling
-pub fn validate_super_block(drive_num: u8) -> FilesystemResult<()> {
-    let mut sector = [0u8; 512];
-
-    // Read sector 0 (boot sector)
-    ide_read_sectors(drive_num, 1, 0, &mut sector)?;
-
-    if SuperBlock::is_valid(&sector) {
-        Ok(())
-    } else {
-        Err(FilesystemError::InvalidBootBlock)
-    }
+/// Validate the super block on the specified device with proper error handling
+pub fn validate_super_block(device: &dyn BlockDevice) -> FilesystemResult<()> {
+    read_super_block(device).map(|_| ())
 }
 
-/// Read the super block from the specified drive with proper error handling
-pub fn read_super_block(drive_num: u8) -> FilesystemResult<SuperBlock> {
+/// Read the super block from the specified device with proper error handling
+pub fn read_super_block(device: &dyn BlockDevice) -> FilesystemResult<SuperBlock> {
     let mut sector = [0u8; 512];
 
     // Read sector 0 (boot sector)
-    ide_read_sectors(drive_num, 1, 0, &mut sector)?;
+    device.read_sectors(0, &mut sector)?;
 
     if SuperBlock::is_valid(&sector) {
         Ok(SuperBlock::from_sector(&sector))
@@ -94,41 +127,41 @@ pub fn read_super_block(drive_num: u8) -> FilesystemResult<SuperBlock> {
 }
 
 /// Update the free block count in the super block with proper error handling
-pub fn update_free_block_count(drive_num: u8, new_free_count: u64) -> FilesystemResult<()> {
-    let mut super_block = read_super_block(drive_num)?;
+pub fn update_free_block_count(device: &dyn BlockDevice, new_free_count: u64) -> FilesystemResult<()> {
+    let mut super_block = read_super_block(device)?;
     super_block.set_free_block_count(new_free_count);
 
     let sector = super_block.as_sector();
-    ide_write_sectors(drive_num, 1, 0, &sector)?;
+    device.write_sectors(0, &sector)?;
     Ok(())
 }
 
 /// Allocate a specified number of blocks in the filesystem with proper error handling
-pub fn allocate_blocks(drive_num: u8, block_count: u64) -> FilesystemResult<()> {
-    let mut super_block = read_super_block(drive_num)?;
+pub fn allocate_blocks(device: &dyn BlockDevice, block_count: u64) -> FilesystemResult<()> {
+    let mut super_block = read_super_block(device)?;
 
     if !super_block.allocate_blocks(block_count) {
         return Err(FilesystemError::InsufficientSpace);
     }
 
     let sector = super_block.as_sector();
-    ide_write_sectors(drive_num, 1, 0, &sector)?;
+    device.write_sectors(0, &sector)?;
     Ok(())
 }
 
 /// Deallocate a specified number of blocks in the filesystem with proper error handling
-pub fn deallocate_blocks(drive_num: u8, block_count: u64) -> FilesystemResult<()> {
-    let mut super_block = read_super_block(drive_num)?;
+pub fn deallocate_blocks(device: &dyn BlockDevice, block_count: u64) -> FilesystemResult<()> {
+    let mut super_block = read_super_block(device)?;
     super_block.deallocate_blocks(block_count);
 
     let sector = super_block.as_sector();
-    ide_write_sectors(drive_num, 1, 0, &sector)?;
+    device.write_sectors(0, &sector)?;
     Ok(())
 }
 
 /// Get filesystem information with proper error handling
-pub fn get_filesystem_info(drive_num: u8) -> FilesystemResult<(u32, u64, u64, u32)> {
-    let super_block = read_super_block(drive_num)?;
+pub fn get_filesystem_info(device: &dyn BlockDevice) -> FilesystemResult<(u32, u64, u64, u32)> {
+    let super_block = read_super_block(device)?;
     Ok((
         super_block.version,
         super_block.total_blocks,
@@ -137,9 +170,9 @@ pub fn get_filesystem_info(drive_num: u8) -> FilesystemResult<(u32, u64, u64, u3
     ))
 }
 
-/// Check if a valid filesystem is present on the specified drive with proper error handling
-pub fn has_filesystem(drive_num: u8) -> bool {
-    validate_super_block(drive_num).is_ok()
+/// Check if a valid filesystem is present on the specified device with proper error handling
+pub fn has_filesystem(device: &dyn BlockDevice) -> bool {
+    validate_super_block(device).is_ok()
 }
 
 /// Integration tests for the complete filesystem
@@ -9,9 +9,12 @@
 //! This is critical for systems with limited RAM (e.g., 96MB kernel limit).
 //!
 //! The MFT starts small (~0.78% of disk) and can grow dynamically as files are created.
+//! Clusters added beyond the initial zone are recorded as the data runs of
+//! record 0, the MFT's own record.
 
-use alloc::{vec, vec::Vec, string::String};
+use alloc::{vec::Vec, string::String, sync::Arc};
 use core::mem::MaybeUninit;
+use spin::Mutex;
 
 /// 4096-aligned buffer for disk I/O
 #[repr(align(4096))]
@@ -48,9 +51,16 @@ impl AlignedRecordBuf {
         &self.buf
     }
 }
-use crate::{FilesystemResult, FilesystemError, ide_read_sectors, ide_write_sectors};
+use crate::{
+    FilesystemResult, FilesystemError,
+    block_device::SharedBlockDevice,
+    checksum::{crc32c, apply_update_sequence, remove_update_sequence, update_sequence_size},
+};
 
 pub const MFT_RECORD_SIZE: usize = 1024; // 1KB per record
+
+/// CRC32C of the record, taken with this field and the update sequence array zeroed
+const MFT_CHECKSUM_OFFSET: usize = 44;
 pub const MFT_RECORDS_PER_SECTOR: usize = 512 / MFT_RECORD_SIZE;
 
 /// File Record Number - unique identifier for each file/directory
@@ -66,6 +76,7 @@ pub const MFT_RECORD_ROOT: FileRecordNumber = 5;     // Root directory
 pub const MFT_RECORD_BITMAP: FileRecordNumber = 6;   // Cluster bitmap
 pub const MFT_RECORD_BOOT: FileRecordNumber = 7;     // Boot sector
 pub const MFT_RECORD_BADCLUS: FileRecordNumber = 8;  // Bad cluster list
+pub const MFT_FIRST_USER_RECORD: FileRecordNumber = 16; // Records below are reserved for system files
 
 /// MFT Record flags
 #[derive(Debug, Clone, Copy)]
@@ -124,7 +135,7 @@ impl MftRecordHeader {
         Self {
             signature: *b"FILE",
             update_sequence_offset: 48,
-            update_sequence_size: 3,
+            update_sequence_size: update_sequence_size(MFT_RECORD_SIZE) as u16,
             log_file_sequence_number: 0,
             sequence_number: 1,
             hard_link_count: 1,
@@ -176,7 +187,7 @@ impl MftRecord {
         self.update_bytes_in_use();
     }
 
-    fn update_bytes_in_use(&mut self) {
+    pub fn update_bytes_in_use(&mut self) {
         let mut total = self.header.first_attribute_offset as u32;
         for attr in &self.attributes {
             total += attr.get_size();
@@ -228,14 +239,48 @@ impl MftRecord {
         // End marker
         data.buf[offset..offset+4].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
 
+        // Checksum the record, then protect its sector boundaries; the
+        // sequence number follows the checksum so it changes with the contents
+        let checksum = crc32c(&data.buf);
+        data.buf[MFT_CHECKSUM_OFFSET..MFT_CHECKSUM_OFFSET+4].copy_from_slice(&checksum.to_le_bytes());
+        let usn = (checksum as u16).max(1);
+        apply_update_sequence(&mut data.buf, self.header.update_sequence_offset as usize, usn);
+
         // Return the stack-allocated buffer (no heap allocation)
         data.buf
     }
 
+    /// Undo the update sequence fixups of a raw record and verify its checksum
+    fn verify(data: &mut [u8; MFT_RECORD_SIZE]) -> FilesystemResult<()> {
+        let array_offset = u16::from_le_bytes(data[4..6].try_into().unwrap()) as usize;
+        let array_size = u16::from_le_bytes(data[6..8].try_into().unwrap()) as usize;
+        if array_offset < MFT_CHECKSUM_OFFSET + 4 {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        remove_update_sequence(data, array_offset, array_size)?;
+
+        let stored = u32::from_le_bytes(data[MFT_CHECKSUM_OFFSET..MFT_CHECKSUM_OFFSET+4].try_into().unwrap());
+        data[MFT_CHECKSUM_OFFSET..MFT_CHECKSUM_OFFSET+4].fill(0);
+        data[array_offset..array_offset + array_size * 2].fill(0);
+        if crc32c(data) != stored {
+            return Err(FilesystemError::ChecksumMismatch);
+        }
+        Ok(())
+    }
+
     pub fn deserialize(data: &[u8], record_number: FileRecordNumber) -> FilesystemResult<Self> {
         if data.len() < MFT_RECORD_SIZE {
             return Err(FilesystemError::InvalidParameter);
         }
+        if &data[0..4] != b"FILE" {
+            return Err(FilesystemError::InvalidParameter);
+        }
+
+        // Verify a copy so the caller's buffer keeps its on-disk form
+        let mut record = AlignedRecordBuf::new();
+        record.buf.copy_from_slice(&data[..MFT_RECORD_SIZE]);
+        Self::verify(&mut record.buf)?;
+        let data = record.as_slice();
 
         let mut offset = 0;
 
@@ -318,6 +363,7 @@ pub enum AttributeType {
     IndexRoot = 0x90,
     IndexAllocation = 0xA0,
     Bitmap = 0xB0,
+    ExtendedAttribute = 0xE0,  // One resident attribute per key, named by the key
 }
 
 /// Attribute header
@@ -358,7 +404,7 @@ impl Attribute {
     pub fn new_resident(attr_type: AttributeType, data: Vec<u8>) -> Self {
         let header = AttributeHeader {
             attr_type: attr_type as u32,
-            length: 24 + data.len() as u32, // Header + data
+            length: (24 + data.len() as u32 + 7) & !7, // Header + data, padded like serialize()
             non_resident: false,
             name_length: 0,
             name_offset: 24,
@@ -374,11 +420,14 @@ impl Attribute {
     }
 
     pub fn new_non_resident(attr_type: AttributeType, runs: Vec<DataRun>, real_size: u64) -> Self {
-        let allocated_size = runs.iter().map(|r| r.cluster_count * 4096).sum(); // Assuming 4KB clusters
+        let allocated_size = runs.iter()
+            .filter(|r| !r.is_sparse())
+            .map(|r| r.cluster_count * 4096)
+            .sum(); // Assuming 4KB clusters
 
         let header = AttributeHeader {
             attr_type: attr_type as u32,
-            length: 64, // Non-resident header size
+            length: (64 + runs.len() as u32 * 16 + 1 + 7) & !7, // Header + runs + terminator, padded
             non_resident: true,
             name_length: 0,
             name_offset: 64,
@@ -398,8 +447,21 @@ impl Attribute {
         }
     }
 
+    /// Give the attribute a name, as for an alternate data stream or an extended attribute
+    pub fn with_name(mut self, name: String) -> Self {
+        self.header.name_length = name.len() as u8;
+        self.name = name;
+        self.header.length = self.get_size();
+        self
+    }
+
+    /// On-disk size of this attribute, derived from its current contents
     pub fn get_size(&self) -> u32 {
-        self.header.length
+        let unpadded = match &self.data {
+            AttributeData::Resident(content) => 24 + content.len() as u32,
+            AttributeData::NonResident { runs, .. } => 64 + runs.len() as u32 * 16 + 1,
+        } + self.name.len() as u32;
+        (unpadded + 7) & !7
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -410,13 +472,15 @@ impl Attribute {
         buf.resize(16, 0);
         buf[offset..offset+4].copy_from_slice(&self.header.attr_type.to_le_bytes());
         offset += 4;
-        buf[offset..offset+4].copy_from_slice(&self.header.length.to_le_bytes());
+        buf[offset..offset+4].copy_from_slice(&self.get_size().to_le_bytes());
         offset += 4;
-        buf[offset] = if self.header.non_resident { 1 } else { 0 };
+        buf[offset] = if matches!(self.data, AttributeData::NonResident { .. }) { 1 } else { 0 };
         offset += 1;
-        buf[offset] = self.header.name_length;
+        // The name sits between the fixed fields and the contents or runs
+        let name_offset: u16 = if matches!(self.data, AttributeData::NonResident { .. }) { 64 } else { 24 };
+        buf[offset] = self.name.len() as u8;
         offset += 1;
-        buf[offset..offset+2].copy_from_slice(&self.header.name_offset.to_le_bytes());
+        buf[offset..offset+2].copy_from_slice(&name_offset.to_le_bytes());
         offset += 2;
         buf[offset..offset+2].copy_from_slice(&self.header.flags.to_le_bytes());
         offset += 2;
@@ -425,20 +489,22 @@ impl Attribute {
 
         match &self.data {
             AttributeData::Resident(content) => {
-                buf.resize(offset + 8 + content.len(), 0);
+                buf.resize(offset + 8 + self.name.len() + content.len(), 0);
                 buf[offset..offset+4].copy_from_slice(&(content.len() as u32).to_le_bytes());
                 offset += 4;
-                buf[offset..offset+2].copy_from_slice(&24u16.to_le_bytes());
+                buf[offset..offset+2].copy_from_slice(&(24 + self.name.len() as u16).to_le_bytes());
                 offset += 2;
                 buf[offset..offset+2].copy_from_slice(&0u16.to_le_bytes());
                 offset += 2;
+                buf[offset..offset+self.name.len()].copy_from_slice(self.name.as_bytes());
+                offset += self.name.len();
                 buf[offset..offset+content.len()].copy_from_slice(content);
                 offset += content.len();
             }
             AttributeData::NonResident { runs, allocated_size, real_size, initialized_size } => {
                 let runs_size = runs.len() * 16 + 1;
                 // Header is 48 bytes (8+8+2+2+4+8+8+8), not 40
-                buf.resize(offset + 48 + runs_size, 0);
+                buf.resize(offset + 48 + self.name.len() + runs_size, 0);
                 buf[offset..offset+8].copy_from_slice(&0u64.to_le_bytes());
                 offset += 8;
                 buf[offset..offset+8].copy_from_slice(&0u64.to_le_bytes());
@@ -455,6 +521,8 @@ impl Attribute {
                 offset += 8;
                 buf[offset..offset+8].copy_from_slice(&initialized_size.to_le_bytes());
                 offset += 8;
+                buf[offset..offset+self.name.len()].copy_from_slice(self.name.as_bytes());
+                offset += self.name.len();
 
                 for run in runs {
                     buf[offset..offset+8].copy_from_slice(&run.cluster_count.to_le_bytes());
@@ -504,14 +572,38 @@ impl Attribute {
             attribute_id,
         };
 
+        let name = if name_length > 0 {
+            let name_end = name_offset as usize + name_length as usize;
+            if data.len() < name_end {
+                return Err(FilesystemError::InvalidParameter);
+            }
+            String::from(core::str::from_utf8(&data[name_offset as usize..name_end])
+                .map_err(|_| FilesystemError::InvalidParameter)?)
+        } else {
+            String::new()
+        };
+
         let attr_data = if non_resident {
-            // Parse non-resident data (simplified)
-            let allocated_size = u64::from_le_bytes(data[32..40].try_into().unwrap());
-            let real_size = u64::from_le_bytes(data[40..48].try_into().unwrap());
-            let initialized_size = u64::from_le_bytes(data[48..56].try_into().unwrap());
+            if data.len() < length as usize || (length as usize) < 65 + name.len() {
+                return Err(FilesystemError::InvalidParameter);
+            }
+
+            let allocated_size = u64::from_le_bytes(data[40..48].try_into().unwrap());
+            let real_size = u64::from_le_bytes(data[48..56].try_into().unwrap());
+            let initialized_size = u64::from_le_bytes(data[56..64].try_into().unwrap());
+
+            // Runs are (cluster_count, start_cluster) pairs after the name, followed by a terminator byte
+            let run_count = (length as usize - 65 - name.len()) / 16;
+            let mut runs = Vec::with_capacity(run_count);
+            for i in 0..run_count {
+                let offset = 64 + name.len() + i * 16;
+                let cluster_count = u64::from_le_bytes(data[offset..offset+8].try_into().unwrap());
+                let start_cluster = u64::from_le_bytes(data[offset+8..offset+16].try_into().unwrap());
+                runs.push(DataRun::new(start_cluster, cluster_count));
+            }
 
             AttributeData::NonResident {
-                runs: Vec::new(), // Simplified - would parse run list here
+                runs,
                 allocated_size,
                 real_size,
                 initialized_size,
@@ -528,7 +620,7 @@ impl Attribute {
 
         Ok(Self {
             header,
-            name: String::new(),
+            name,
             data: attr_data,
         })
     }
@@ -536,18 +628,92 @@ impl Attribute {
 
 /// MFT Manager
 pub struct MftManager {
-    pub drive: u8,
+    pub device: SharedBlockDevice,
     pub mft_start_cluster: u64,
+    pub mft_size_clusters: u64,
     pub cluster_size: u32,
+    /// Runs the MFT has grown into beyond its initial zone, shared by every clone
+    pub(crate) extension: Arc<Mutex<Vec<DataRun>>>,
 }
 
 impl MftManager {
-    pub fn new(drive: u8, mft_start_cluster: u64, cluster_size: u32) -> Self {
+    pub fn new(device: SharedBlockDevice, mft_start_cluster: u64, mft_size_clusters: u64, cluster_size: u32) -> Self {
         Self {
-            drive,
+            device,
             mft_start_cluster,
+            mft_size_clusters,
             cluster_size,
+            extension: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Pick up the runs recorded in the MFT's own record, replacing the ones in memory
+    pub fn load_extension(&self) -> FilesystemResult<()> {
+        let runs = match self.read_slot(MFT_RECORD_MFT)? {
+            Some(record) => record.attributes.into_iter()
+                .find(|attr| attr.header.attr_type == AttributeType::Data as u32 && attr.name.is_empty())
+                .and_then(|attr| match attr.data {
+                    AttributeData::NonResident { runs, .. } => Some(runs),
+                    AttributeData::Resident(_) => None,
+                })
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        *self.extension.lock() = runs;
+        Ok(())
+    }
+
+    /// Runs the MFT occupies beyond its initial zone
+    pub fn extension_runs(&self) -> Vec<DataRun> {
+        self.extension.lock().clone()
+    }
+
+    /// Grow the MFT into `run`, whose clusters must already be zeroed
+    ///
+    /// The run is recorded in the MFT's own record; fails with
+    /// `InsufficientSpace` if that record cannot hold another run.
+    pub fn extend(&self, run: DataRun) -> FilesystemResult<()> {
+        let mut runs = self.extension_runs();
+        match runs.last_mut() {
+            Some(last) if last.end_cluster() + 1 == run.start_cluster => last.cluster_count += run.cluster_count,
+            _ => runs.push(run),
         }
+
+        let mut record = MftRecord::new(MFT_RECORD_MFT);
+        let mut flags = RecordFlags::new();
+        flags.in_use = true;
+        record.header.set_flags(flags);
+        let size = runs.iter().map(|run| run.cluster_count).sum::<u64>() * self.cluster_size as u64;
+        record.add_attribute(Attribute::new_non_resident(AttributeType::Data, runs.clone(), size));
+        if record.header.bytes_in_use as usize > MFT_RECORD_SIZE {
+            return Err(FilesystemError::InsufficientSpace);
+        }
+
+        self.write_record(&record)?;
+        *self.extension.lock() = runs;
+        Ok(())
+    }
+
+    /// Total number of clusters holding MFT records
+    fn size_clusters(&self) -> u64 {
+        self.mft_size_clusters + self.extension.lock().iter().map(|run| run.cluster_count).sum::<u64>()
+    }
+
+    /// First sector of the `cluster_offset`th cluster of the MFT
+    fn cluster_sector(&self, cluster_offset: u64) -> FilesystemResult<u64> {
+        let sectors_per_cluster = (self.cluster_size / 512).max(1) as u64; // Ensure at least 1 sector per cluster
+        if cluster_offset < self.mft_size_clusters {
+            return Ok((self.mft_start_cluster + cluster_offset) * sectors_per_cluster);
+        }
+
+        let mut skip = cluster_offset - self.mft_size_clusters;
+        for run in self.extension.lock().iter() {
+            if skip < run.cluster_count {
+                return Ok((run.start_cluster + skip) * sectors_per_cluster);
+            }
+            skip -= run.cluster_count;
+        }
+        Err(FilesystemError::OutOfRange)
     }
 
     pub fn read_record(&self, record_number: FileRecordNumber) -> FilesystemResult<MftRecord> {
@@ -558,12 +724,11 @@ impl MftManager {
         let cluster_offset = record_number / records_per_cluster as u64;
         let record_offset = (record_number % records_per_cluster as u64) * MFT_RECORD_SIZE as u64;
 
-        let sectors_per_cluster = (self.cluster_size / 512).max(1) as u64; // Ensure at least 1 sector per cluster
-        let cluster_sector = (self.mft_start_cluster + cluster_offset) * sectors_per_cluster;
+        let cluster_sector = self.cluster_sector(cluster_offset)?;
         let mut cluster_data = AlignedBuf::new();
 
         // Read the cluster containing the record
-        ide_read_sectors(self.drive, sectors_per_cluster as u8, cluster_sector as u32, &mut cluster_data.buf[..self.cluster_size as usize])?;
+        self.device.read_sectors(cluster_sector, &mut cluster_data.buf[..self.cluster_size as usize])?;
 
         let record_data = &cluster_data.buf[record_offset as usize..(record_offset as usize + MFT_RECORD_SIZE)];
         MftRecord::deserialize(record_data, record_number)
@@ -577,12 +742,11 @@ impl MftManager {
         let cluster_offset = record.record_number / records_per_cluster as u64;
         let record_offset = (record.record_number % records_per_cluster as u64) * MFT_RECORD_SIZE as u64;
 
-        let sectors_per_cluster = (self.cluster_size / 512).max(1) as u64; // Ensure at least 1 sector per cluster
-        let cluster_sector = (self.mft_start_cluster + cluster_offset) * sectors_per_cluster;
+        let cluster_sector = self.cluster_sector(cluster_offset)?;
         let mut cluster_data = AlignedBuf::new();
 
         // Read existing cluster data
-        ide_read_sectors(self.drive, sectors_per_cluster as u8, cluster_sector as u32, &mut cluster_data.buf[..self.cluster_size as usize])?;
+        self.device.read_sectors(cluster_sector, &mut cluster_data.buf[..self.cluster_size as usize])?;
 
         // Update the specific record
         let record_data = record.serialize();
@@ -590,20 +754,68 @@ impl MftManager {
             .copy_from_slice(&record_data[..MFT_RECORD_SIZE]);
 
         // Write back the cluster
-        ide_write_sectors(self.drive, sectors_per_cluster as u8, cluster_sector as u32, &cluster_data.buf[..self.cluster_size as usize])?;
+        self.device.write_sectors(cluster_sector, &cluster_data.buf[..self.cluster_size as usize])?;
         Ok(())
     }
 
+    /// Number of record slots the MFT has room for
+    pub fn record_capacity(&self) -> u64 {
+        self.size_clusters() * (self.cluster_size as usize / MFT_RECORD_SIZE) as u64
+    }
+
+    /// Read a record slot, or `None` if it has never been written
+    ///
+    /// Unlike [`read_record`](Self::read_record) this tells a blank slot apart
+    /// from one that holds a damaged record.
+    pub fn read_slot(&self, record_number: FileRecordNumber) -> FilesystemResult<Option<MftRecord>> {
+        let records_per_cluster = self.cluster_size as usize / MFT_RECORD_SIZE;
+        if records_per_cluster == 0 {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        let cluster_offset = record_number / records_per_cluster as u64;
+        let record_offset = (record_number % records_per_cluster as u64) as usize * MFT_RECORD_SIZE;
+
+        let cluster_sector = self.cluster_sector(cluster_offset)?;
+        let mut cluster_data = AlignedBuf::new();
+        self.device.read_sectors(cluster_sector, &mut cluster_data.buf[..self.cluster_size as usize])?;
+
+        let record_data = &cluster_data.buf[record_offset..record_offset + MFT_RECORD_SIZE];
+        if record_data.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        MftRecord::deserialize(record_data, record_number).map(Some)
+    }
+
     pub fn allocate_record(&self) -> FilesystemResult<FileRecordNumber> {
-        // Simplified allocation - would typically use MFT bitmap
-        // For now, we'll use a simple counter approach
-        // In a real implementation, this would scan the MFT bitmap
-        static mut NEXT_RECORD: FileRecordNumber = 16; // Start after system records
-
-        unsafe {
-            let record_number = NEXT_RECORD;
-            NEXT_RECORD += 1;
-            Ok(record_number)
+        // Scan the MFT for the first user record that is not in use; records
+        // freed by a delete are reused
+        let records_per_cluster = (self.cluster_size as usize / MFT_RECORD_SIZE) as u64;
+        if records_per_cluster == 0 {
+            return Err(FilesystemError::InvalidParameter);
         }
+        let mut cluster_data = AlignedBuf::new();
+
+        let first_cluster = MFT_FIRST_USER_RECORD / records_per_cluster;
+        for cluster_offset in first_cluster..self.size_clusters() {
+            let cluster_sector = self.cluster_sector(cluster_offset)?;
+            self.device.read_sectors(cluster_sector, &mut cluster_data.buf[..self.cluster_size as usize])?;
+
+            for slot in 0..records_per_cluster {
+                let record_number = cluster_offset * records_per_cluster + slot;
+                if record_number < MFT_FIRST_USER_RECORD {
+                    continue;
+                }
+
+                let offset = slot as usize * MFT_RECORD_SIZE;
+                let in_use = MftRecord::deserialize(&cluster_data.buf[offset..offset + MFT_RECORD_SIZE], record_number)
+                    .map(|record| record.header.get_flags().in_use)
+                    .unwrap_or(false);
+                if !in_use {
+                    return Ok(record_number);
+                }
+            }
+        }
+
+        Err(FilesystemError::InsufficientSpace)
     }
 }
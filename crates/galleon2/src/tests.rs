@@ -7,14 +7,24 @@
 mod tests {
     use alloc::{string::{String, ToString}, vec::Vec, vec};
     use crate::{
-        galleon_fs::GalleonFilesystem,
+        galleon_fs::{GalleonFilesystem, GalleonSuperBlock, BACKUP_SUPER_BLOCK_SECTOR},
         mft::{MftRecord, MftManager, AttributeType, Attribute, AttributeData},
-        journal::{JournalManager, OperationType},
-        file_record::{FileRecordManager, StandardInformation, FileName},
+        journal::{JournalManager, JournaledDevice, OperationType, with_transaction},
+        file_record::{self, AtimePolicy, FileRecordManager, StandardInformation, FileName, TimeUpdate, mode},
         btree::{BTreeManager, IndexNode, IndexEntry},
         allocation::{ClusterAllocator, ClusterBitmap, ClusterRun, RunList, AllocationStrategy},
-        FilesystemResult,
+        block_device::{BlockDevice, MemoryBlockDevice, PartitionBlockDevice, SharedBlockDevice},
+        cache::BufferCache,
+        checksum::crc32c,
+        file::{FileManager, SeekFrom},
+        defrag::FragmentationStats,
+        fsck::{CheckIssue, ClusterOwner},
+        mft::{FileRecordNumber, MFT_RECORD_ROOT},
+        types::pathbuf::{PathBuf, Component},
+        FilesystemResult, FilesystemError,
     };
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
 
     #[test]
     fn test_cluster_run_operations() {
@@ -47,7 +57,7 @@ mod tests {
 
         // Test virtual to physical cluster mapping
         assert_eq!(run_list.find_cluster(0), Some(10)); // VCN 0 -> LCN 10
-        assert_eq!(run_list.find_cluster(6), Some(15)); // VCN 6 -> LCN 15
+        assert_eq!(run_list.find_cluster(6), Some(16)); // VCN 6 -> LCN 16
         assert_eq!(run_list.find_cluster(7), Some(20)); // VCN 7 -> LCN 20
     }
 
@@ -142,6 +152,62 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cluster_run_bounds() {
+        let run = ClusterRun::new(10, 5);
+        assert!(run.contains_cluster(10) && run.contains_cluster(14));
+        assert!(!run.contains_cluster(9) && !run.contains_cluster(15));
+
+        // An empty run has no last cluster to compare against
+        let empty = ClusterRun::new(10, 0);
+        assert!(!empty.contains_cluster(9) && !empty.contains_cluster(10));
+    }
+
+    #[test]
+    fn test_run_list_maps_merged_runs() {
+        let mut run_list = RunList::new();
+        run_list.add_run(ClusterRun::new(10, 5));
+        run_list.add_run(ClusterRun::new(20, 3));
+        run_list.add_run(ClusterRun::new(15, 2));
+
+        // 10..15 and 15..17 are one run of 7 clusters, so VCN 6 is its last
+        assert_eq!(run_list.runs[0], ClusterRun::new(10, 7));
+        assert_eq!(run_list.find_cluster(5), Some(15));
+        assert_eq!(run_list.find_cluster(6), Some(16));
+        assert_eq!(run_list.find_cluster(7), Some(20));
+        assert_eq!(run_list.find_cluster(10), None);
+    }
+
+    #[test]
+    fn test_non_resident_run_list_round_trip() {
+        let runs = vec![ClusterRun::new(100, 10), ClusterRun::new(300, 2), ClusterRun::new(7, 1)];
+        let attr = Attribute::new_non_resident(AttributeType::Data, runs.clone(), 13 * 4096 - 5);
+        let serialized = attr.serialize();
+        assert_eq!(attr.header.length, attr.get_size());
+        assert_eq!(serialized.len() as u32, attr.get_size());
+
+        match Attribute::deserialize(&serialized).unwrap().data {
+            AttributeData::NonResident { runs: parsed, allocated_size, real_size, initialized_size } => {
+                assert_eq!(parsed, runs);
+                assert_eq!(allocated_size, 13 * 4096);
+                assert_eq!(real_size, 13 * 4096 - 5);
+                assert_eq!(initialized_size, 13 * 4096 - 5);
+            }
+            _ => panic!("Expected non-resident data"),
+        }
+
+        // A length reaching past the buffer is rejected rather than read out of bounds
+        assert_eq!(Attribute::deserialize(&serialized[..serialized.len() - 8]).err(), Some(FilesystemError::InvalidParameter));
+    }
+
+    #[test]
+    fn test_resident_attribute_length_is_padded() {
+        let attr = Attribute::new_resident(AttributeType::Data, b"Hello, World!".to_vec());
+        assert_eq!(attr.header.length, 40);
+        assert_eq!(attr.get_size(), 40);
+        assert_eq!(attr.serialize().len(), 40);
+    }
+
     #[test]
     fn test_file_attributes() {
         use crate::file_record::FileAttributes;
@@ -171,6 +237,12 @@ mod tests {
         let serialized = std_info.serialize();
         let deserialized = StandardInformation::deserialize(&serialized).unwrap();
         assert_eq!(std_info.times.creation_time, deserialized.times.creation_time);
+        assert_eq!(deserialized.mode, mode::DEFAULT_FILE);
+
+        // Records from before POSIX metadata was kept get root-owned defaults
+        let directory = StandardInformation::new_directory().serialize();
+        let legacy = StandardInformation::deserialize(&directory[..80]).unwrap();
+        assert_eq!((legacy.uid, legacy.gid, legacy.mode), (0, 0, mode::DEFAULT_DIRECTORY));
     }
 
     #[test]
@@ -187,14 +259,214 @@ mod tests {
     }
 
     // Integration test helper functions
+    fn create_mock_device() -> SharedBlockDevice {
+        Arc::new(MemoryBlockDevice::new(16 * 1024 * 1024)) // 16MB RAM disk
+    }
+
     fn create_mock_filesystem() -> GalleonFilesystem {
-        // This would require proper mock IDE interface for real testing
-        // For now, we'll test individual components
-        todo!("Implement mock filesystem for integration tests")
+        GalleonFilesystem::format(create_mock_device()).unwrap()
+    }
+
+    fn make_directory(fs: &mut GalleonFilesystem, parent: FileRecordNumber, name: &str) -> FileRecordNumber {
+        let mut name = core::mem::ManuallyDrop::new(String::from(name));
+        unsafe { fs.create_directory(parent, name.as_mut_ptr(), name.len(), name.capacity()) }.unwrap()
+    }
+
+    #[test]
+    fn test_memory_block_device() {
+        let device = MemoryBlockDevice::new(4096);
+        assert_eq!(device.sector_size(), 512);
+        assert_eq!(device.sector_count(), 8);
+        assert_eq!(device.capacity_bytes(), 4096);
+
+        let data = [0xA5u8; 1024];
+        device.write_sectors(2, &data).unwrap();
+        let mut read_back = [0u8; 1024];
+        device.read_sectors(2, &mut read_back).unwrap();
+        assert_eq!(read_back, data);
+
+        // Partial sectors and out-of-range transfers are rejected
+        assert_eq!(device.write_sectors(0, &[0u8; 100]), Err(FilesystemError::InvalidParameter));
+        assert_eq!(device.read_sectors(7, &mut [0u8; 1024]), Err(FilesystemError::OutOfRange));
+    }
+
+    #[test]
+    fn test_partition_block_device() {
+        let disk: SharedBlockDevice = Arc::new(MemoryBlockDevice::new(8 * 512));
+        let partition = PartitionBlockDevice::new(disk.clone(), 4, 4).unwrap();
+        assert_eq!(partition.sector_count(), 4);
+
+        partition.write_sectors(1, &[0x5Au8; 512]).unwrap();
+        let mut sector = [0u8; 512];
+        disk.read_sectors(5, &mut sector).unwrap();
+        assert_eq!(sector, [0x5Au8; 512]);
+
+        assert!(partition.write_sectors(4, &[0u8; 512]).is_err());
+        assert!(PartitionBlockDevice::new(disk, 6, 4).is_err());
+    }
+
+    #[test]
+    fn test_buffer_cache_write_back() {
+        let disk = Arc::new(MemoryBlockDevice::new(64 * 512));
+        let cache = BufferCache::new(disk.clone(), 2);
+
+        // Writes stay in the cache until they are flushed
+        cache.write_sectors(1, &[0x11u8; 512]).unwrap();
+        cache.write_sectors(9, &[0x22u8; 1024]).unwrap();
+        let mut sector = [0u8; 512];
+        disk.read_sectors(1, &mut sector).unwrap();
+        assert_eq!(sector, [0u8; 512]);
+        cache.read_sectors(1, &mut sector).unwrap();
+        assert_eq!(sector, [0x11u8; 512]);
+        assert_eq!(cache.dirty_blocks(), 2);
+
+        // Flushing one range leaves the other block dirty
+        cache.flush_sectors(8, 8).unwrap();
+        disk.read_sectors(10, &mut sector).unwrap();
+        assert_eq!(sector, [0x22u8; 512]);
+        assert_eq!(cache.dirty_blocks(), 1);
+
+        // Touching a third block evicts the least recently used one, writing it back
+        cache.read_sectors(10, &mut sector).unwrap();
+        cache.read_sectors(20, &mut sector).unwrap();
+        disk.read_sectors(1, &mut sector).unwrap();
+        assert_eq!(sector, [0x11u8; 512]);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.writebacks, 2);
+
+        cache.write_sectors(40, &[0x33u8; 512]).unwrap();
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty_blocks(), 0);
+        disk.read_sectors(40, &mut sector).unwrap();
+        assert_eq!(sector, [0x33u8; 512]);
+    }
+
+    #[test]
+    fn test_cache_stats_in_filesystem_stats() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let file = fs.create_file(MFT_RECORD_ROOT, "cached.txt".to_string(), Some(b"data".to_vec())).unwrap();
+        let before = fs.get_stats().unwrap();
+
+        // Reading the same record again is served from memory
+        fs.read_file(file).unwrap();
+        fs.read_file(file).unwrap();
+        let after = fs.get_stats().unwrap();
+        assert!(after.cache_hits > before.cache_hits);
+        assert_eq!(after.cache_misses, before.cache_misses);
+
+        // Unsynced metadata is recovered from the journal if the cache is lost
+        fs.sync().unwrap();
+        fs.create_file(MFT_RECORD_ROOT, "unsynced.txt".to_string(), None).unwrap();
+        core::mem::forget(fs);
+        let fs = GalleonFilesystem::mount(device).unwrap();
+        assert!(fs.find_file("unsynced.txt").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_format_and_mount_memory_device() {
+        let device = create_mock_device();
+        let fs = GalleonFilesystem::format(device.clone()).unwrap();
+        drop(fs);
+
+        let mut mounted = GalleonFilesystem::mount(device).unwrap();
+        let stats = mounted.get_stats().unwrap();
+        assert_eq!(stats.total_clusters, 4096);
+        assert!(stats.free_space < stats.total_space);
+        assert!(mounted.list_directory().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_super_block_lives_in_sector_zero() {
+        let device = create_mock_device();
+        let fs = GalleonFilesystem::format(device.clone()).unwrap();
+        drop(fs);
+
+        // Format writes the superblock to sector 0, which is where mount reads it
+        let mut sector = [0u8; 512];
+        device.read_sectors(0, &mut sector).unwrap();
+        assert_eq!(GalleonSuperBlock::deserialize(&sector).unwrap().cluster_size, 4096);
+        device.read_sectors(1, &mut sector).unwrap();
+        assert!(GalleonSuperBlock::deserialize(&sector).is_err());
+        assert!(GalleonFilesystem::mount(device).is_ok());
+    }
+
+    #[test]
+    fn test_mount_rejects_blank_device() {
+        assert!(matches!(
+            GalleonFilesystem::mount(create_mock_device()),
+            Err(FilesystemError::InvalidBootBlock)
+        ));
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn test_metadata_checksums() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let torn = fs.create_file(MFT_RECORD_ROOT, "torn.txt".to_string(), Some(b"first".to_vec())).unwrap();
+        let rotten = fs.create_file(MFT_RECORD_ROOT, "rotten.txt".to_string(), Some(b"data".to_vec())).unwrap();
+
+        // A record write that only reached its first sector leaves the old second one behind
+        let sector = fs.mft_manager.mft_start_cluster * 8 + torn * 2;
+        let mut old_tail = [0u8; 512];
+        fs.device.read_sectors(sector + 1, &mut old_tail).unwrap();
+        fs.write_file(torn, b"second version".to_vec()).unwrap();
+        fs.device.write_sectors(sector + 1, &old_tail).unwrap();
+        assert_eq!(fs.read_file(torn), Err(FilesystemError::ChecksumMismatch));
+
+        // A flipped bit inside the record is caught by its checksum
+        let sector = fs.mft_manager.mft_start_cluster * 8 + rotten * 2;
+        let mut data = [0u8; 512];
+        fs.device.read_sectors(sector, &mut data).unwrap();
+        data[100] ^= 0x04;
+        fs.device.write_sectors(sector, &data).unwrap();
+        assert_eq!(fs.read_file(rotten), Err(FilesystemError::ChecksumMismatch));
+
+        let report = fs.check(false).unwrap();
+        assert!(report.issues.contains(&CheckIssue::CorruptRecord { record: torn, error: FilesystemError::ChecksumMismatch }));
+        assert!(report.issues.contains(&CheckIssue::CorruptRecord { record: rotten, error: FilesystemError::ChecksumMismatch }));
+
+        // Index nodes carry their own checksum
+        let root_node = fs.super_block().index_allocation_start * 8;
+        fs.device.read_sectors(root_node + 3, &mut data).unwrap();
+        data[7] ^= 0x80;
+        fs.device.write_sectors(root_node + 3, &data).unwrap();
+        assert_eq!(fs.find_file("torn.txt"), Err(FilesystemError::ChecksumMismatch));
+
+        // So does the superblock; mount falls back to the backup and repair rewrites the primary
+        drop(fs);
+        device.read_sectors(0, &mut data).unwrap();
+        data[260] ^= 0x01;
+        device.write_sectors(0, &data).unwrap();
+        let mut fs = GalleonFilesystem::mount(device.clone()).unwrap();
+        let report = fs.check(false).unwrap();
+        assert!(report.issues.contains(&CheckIssue::BadSuperBlock("checksum mismatch")));
+        assert!(report.issues.contains(&CheckIssue::BadSuperBlock("backup differs from the primary")));
+        fs.check(true).unwrap();
+        assert!(!fs.check(false).unwrap().issues.iter().any(|issue| matches!(issue, CheckIssue::BadSuperBlock(_))));
+        fs.sync().unwrap();
+        drop(fs);
+
+        // Losing both copies leaves nothing to mount
+        for sector in [0, BACKUP_SUPER_BLOCK_SECTOR] {
+            device.read_sectors(sector, &mut data).unwrap();
+            data[260] ^= 0x01;
+            device.write_sectors(sector, &data).unwrap();
+        }
+        assert!(matches!(GalleonFilesystem::mount(device), Err(FilesystemError::ChecksumMismatch)));
     }
 
     #[test]
-    #[ignore] // Ignore until we have proper mock IDE interface
     fn test_file_creation_flow() {
         let mut fs = create_mock_filesystem();
 
@@ -221,22 +493,437 @@ mod tests {
     }
 
     #[test]
-    #[ignore] // Ignore until we have proper mock IDE interface
     fn test_filesystem_recovery() {
         // Test that the filesystem can recover from crashes using the journal
-        let mut fs = create_mock_filesystem();
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
 
         // Start some operations
         let _file_record = fs.create_file(5, "test.txt".to_string(), Some(b"Hello".to_vec())).unwrap();
 
         // Simulate crash and recovery
         drop(fs);
-        let recovered_fs = GalleonFilesystem::mount(0).unwrap(); // Mount drive 0
+        let recovered_fs = GalleonFilesystem::mount(device).unwrap();
 
         // Verify file exists after recovery
         assert!(recovered_fs.find_file("test.txt").unwrap().is_some());
     }
 
+    /// Memory device that loses power part-way through a chosen write
+    ///
+    /// Once armed with `n`, the n-th following write persists only the first
+    /// half of its sectors and fails; every write and flush after that fails too.
+    struct CrashingDevice {
+        inner: Arc<MemoryBlockDevice>,
+        writes_until_crash: spin::Mutex<Option<usize>>,
+        crashed: AtomicBool,
+    }
+
+    impl CrashingDevice {
+        fn new(inner: Arc<MemoryBlockDevice>) -> Self {
+            Self {
+                inner,
+                writes_until_crash: spin::Mutex::new(None),
+                crashed: AtomicBool::new(false),
+            }
+        }
+
+        fn arm(&self, writes: usize) {
+            *self.writes_until_crash.lock() = Some(writes);
+        }
+
+        fn has_crashed(&self) -> bool {
+            self.crashed.load(Ordering::SeqCst)
+        }
+    }
+
+    impl BlockDevice for CrashingDevice {
+        fn sector_size(&self) -> usize {
+            self.inner.sector_size()
+        }
+
+        fn sector_count(&self) -> u64 {
+            self.inner.sector_count()
+        }
+
+        fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+            self.inner.read_sectors(start_sector, buffer)
+        }
+
+        fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+            if self.has_crashed() {
+                return Err(FilesystemError::DeviceError);
+            }
+
+            let mut writes_until_crash = self.writes_until_crash.lock();
+            match writes_until_crash.as_mut() {
+                Some(0) => {
+                    self.crashed.store(true, Ordering::SeqCst);
+                    let torn = buffer.len() / 512 / 2 * 512;
+                    if torn > 0 {
+                        self.inner.write_sectors(start_sector, &buffer[..torn])?;
+                    }
+                    Err(FilesystemError::DeviceError)
+                }
+                Some(remaining) => {
+                    *remaining -= 1;
+                    self.inner.write_sectors(start_sector, buffer)
+                }
+                None => self.inner.write_sectors(start_sector, buffer),
+            }
+        }
+
+        fn flush(&self) -> FilesystemResult<()> {
+            if self.has_crashed() {
+                return Err(FilesystemError::DeviceError);
+            }
+            self.inner.flush()
+        }
+    }
+
+    /// Crash `operation` at each of its writes in turn and check that mounting
+    /// afterwards leaves the volume exactly as it was before or after the operation
+    fn assert_crash_atomic(
+        setup: impl Fn(&mut GalleonFilesystem),
+        operation: impl Fn(&mut GalleonFilesystem) -> FilesystemResult<()>,
+    ) {
+        let device = Arc::new(MemoryBlockDevice::new(16 * 1024 * 1024));
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        setup(&mut fs);
+        let super_block = fs.super_block();
+        let cluster_size = super_block.cluster_size as usize;
+        let journal_start = super_block.journal_start_cluster as usize * cluster_size;
+        let journal_end = journal_start + super_block.journal_size_clusters as usize * cluster_size;
+        drop(fs);
+        let before = device.snapshot();
+
+        // The journal itself differs from run to run; everything else must match
+        let same_volume = |a: &[u8], b: &[u8]| {
+            a[..journal_start] == b[..journal_start] && a[journal_end..] == b[journal_end..]
+        };
+
+        let reference = Arc::new(MemoryBlockDevice::from_image(before.clone()));
+        let mut fs = GalleonFilesystem::mount(reference.clone()).unwrap();
+        operation(&mut fs).unwrap();
+        drop(fs);
+        let after = reference.snapshot();
+        assert!(!same_volume(&before, &after));
+
+        for crash_at in 0.. {
+            let disk = Arc::new(MemoryBlockDevice::from_image(before.clone()));
+            let crashing = Arc::new(CrashingDevice::new(disk.clone()));
+            let mut fs = GalleonFilesystem::mount(crashing.clone()).unwrap();
+            crashing.arm(crash_at);
+            // Syncing pushes the cached writes out, so the crash can land in write-back too
+            let result = operation(&mut fs).and_then(|()| fs.sync());
+            drop(fs);
+
+            if !crashing.has_crashed() {
+                // Every write of the operation has had a crash injected
+                result.unwrap();
+                assert!(crash_at > 0);
+                break;
+            }
+            assert!(result.is_err());
+
+            let recovered = GalleonFilesystem::mount(disk.clone()).unwrap();
+            recovered.list_directory().unwrap();
+            drop(recovered);
+
+            let image = disk.snapshot();
+            assert!(
+                same_volume(&image, &before) || same_volume(&image, &after),
+                "crash at write {} left the volume half-updated", crash_at
+            );
+        }
+    }
+
+    #[test]
+    fn test_crash_during_create_file() {
+        assert_crash_atomic(|_| {}, |fs| {
+            fs.create_file(MFT_RECORD_ROOT, "journal.txt".to_string(), Some(b"Hello".to_vec()))?;
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_crash_during_create_file_with_root_split() {
+        // The 31st entry no longer fits in the root leaf
+        let setup = |fs: &mut GalleonFilesystem| {
+            for i in 0..30 {
+                fs.create_file(MFT_RECORD_ROOT, alloc::format!("file_{:03}.txt", i), None).unwrap();
+            }
+        };
+
+        let device = Arc::new(MemoryBlockDevice::new(16 * 1024 * 1024));
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let (index_start, cluster_size, _, _, _) = fs.get_btree_diag();
+        let root_index = BTreeManager::new(device.clone(), 0, index_start, cluster_size);
+        setup(&mut fs);
+        assert!(root_index.read_node(0).unwrap().header.is_leaf());
+        fs.create_file(MFT_RECORD_ROOT, "file_030.txt".to_string(), None).unwrap();
+        fs.sync().unwrap();
+        assert!(!root_index.read_node(0).unwrap().header.is_leaf());
+
+        assert_crash_atomic(setup, |fs| {
+            fs.create_file(MFT_RECORD_ROOT, "file_030.txt".to_string(), None)?;
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_crash_during_write_file() {
+        assert_crash_atomic(
+            |fs| { fs.create_file(MFT_RECORD_ROOT, "data.bin".to_string(), Some(b"old contents".to_vec())).unwrap(); },
+            |fs| {
+                let record = fs.find_file("data.bin")?.ok_or(FilesystemError::NotFound)?;
+                fs.write_file(record, b"new and somewhat longer contents".to_vec())
+            },
+        );
+    }
+
+    #[test]
+    fn test_crash_during_delete_file() {
+        assert_crash_atomic(
+            |fs| {
+                for name in ["a.txt", "b.txt", "c.txt"] {
+                    fs.create_file(MFT_RECORD_ROOT, name.to_string(), Some(name.as_bytes().to_vec())).unwrap();
+                }
+            },
+            |fs| {
+                let record = fs.find_file("b.txt")?.ok_or(FilesystemError::NotFound)?;
+                fs.delete_file(record, "b.txt")
+            },
+        );
+    }
+
+    #[test]
+    fn test_crash_during_delete_directory() {
+        assert_crash_atomic(
+            |fs| { make_directory(fs, MFT_RECORD_ROOT, "scratch"); },
+            |fs| {
+                let record = fs.find_file("scratch")?.ok_or(FilesystemError::NotFound)?;
+                fs.delete_file(record, "scratch")
+            },
+        );
+    }
+
+    #[test]
+    fn test_journal_redo_and_undo() {
+        let device = Arc::new(MemoryBlockDevice::new(16 * 1024 * 1024));
+        let fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let super_block = fs.super_block();
+        let sectors_per_cluster = (super_block.cluster_size / 512) as u64;
+        let journal_start = super_block.journal_start_cluster * sectors_per_cluster;
+        let journal_size = super_block.journal_size_clusters * sectors_per_cluster;
+        drop(fs);
+
+        let open_journal = || {
+            let mut journal = JournalManager::new(device.clone(), journal_start, journal_size);
+            journal.recover().unwrap();
+            journal.into_shared()
+        };
+        let read_target = |target: u64| {
+            let mut sector = [0u8; 512];
+            device.read_sectors(target, &mut sector).unwrap();
+            sector
+        };
+        let target = device.sector_count() - 1;
+
+        // A committed write whose in-place copy never reached the disk is redone
+        let journal = open_journal();
+        let journaled = JournaledDevice::new(device.clone(), journal.clone());
+        with_transaction(&journal, OperationType::WriteData, 0, || journaled.write_sectors(target, &[0x5A; 512])).unwrap();
+        device.write_sectors(target, &[0u8; 512]).unwrap();
+        drop(journaled);
+        drop(journal);
+
+        let records = JournalManager::new(device.clone(), journal_start, journal_size).log_records().unwrap();
+        let operations: Vec<_> = records.iter().map(|(_, record)| record.operation_type).collect();
+        assert!(operations.ends_with(&[OperationType::WriteData, OperationType::Commit]));
+
+        open_journal();
+        assert_eq!(read_target(target), [0x5A; 512]);
+
+        // A write whose transaction never committed is undone
+        let journal = open_journal();
+        let journaled = JournaledDevice::new(device.clone(), journal.clone());
+        journal.lock().begin_transaction(OperationType::WriteData, 0);
+        journaled.write_sectors(target, &[0xC3; 512]).unwrap();
+        assert_eq!(read_target(target), [0xC3; 512]);
+        drop(journaled);
+        drop(journal);
+
+        open_journal();
+        assert_eq!(read_target(target), [0x5A; 512]);
+    }
+
+    #[test]
+    fn test_path_components() {
+        let path = PathBuf::from("//etc/./config//../app.toml/");
+        assert!(path.is_absolute());
+        let components: Vec<_> = path.components().collect();
+        assert_eq!(components, vec![
+            Component::RootDir,
+            Component::Normal("etc"),
+            Component::CurDir,
+            Component::Normal("config"),
+            Component::ParentDir,
+            Component::Normal("app.toml"),
+        ]);
+
+        let relative = PathBuf::from("docs/readme");
+        assert!(!relative.is_absolute());
+        assert_eq!(relative.components().count(), 2);
+    }
+
+    #[test]
+    fn test_nested_path_resolution() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+
+        let etc = make_directory(&mut fs, MFT_RECORD_ROOT, "etc");
+        let config = make_directory(&mut fs, etc, "config");
+        let app = fs.create_file(config, "app.toml".to_string(), Some(b"debug = true".to_vec())).unwrap();
+
+        // Each directory only indexes its own children
+        let root_entries = fs.list_directory().unwrap();
+        assert_eq!(root_entries.len(), 1);
+        assert_eq!(root_entries[0].0, "etc");
+        assert_eq!(fs.list_directory_at(etc).unwrap().len(), 1);
+        assert!(fs.find_file("app.toml").unwrap().is_none());
+
+        for path in ["/etc/config/app.toml", "//etc///config/./app.toml", "/etc/config/../config/app.toml", "/../etc/config/app.toml"] {
+            assert_eq!(fs.resolve_path(MFT_RECORD_ROOT, &PathBuf::from(path)).unwrap(), app);
+        }
+        assert_eq!(fs.resolve_path(etc, &PathBuf::from("config/app.toml")).unwrap(), app);
+        assert_eq!(fs.resolve_path(config, &PathBuf::from("..")).unwrap(), etc);
+        assert_eq!(fs.resolve_path(config, &PathBuf::from("../..")).unwrap(), MFT_RECORD_ROOT);
+        assert_eq!(fs.resolve_path(config, &PathBuf::from("/")).unwrap(), MFT_RECORD_ROOT);
+
+        assert_eq!(fs.resolve_path(MFT_RECORD_ROOT, &PathBuf::from("/etc/missing")), Err(FilesystemError::NotFound));
+        assert_eq!(fs.resolve_path(MFT_RECORD_ROOT, &PathBuf::from("/etc/config/app.toml/x")), Err(FilesystemError::NotADirectory));
+        assert_eq!(fs.resolve_parent(MFT_RECORD_ROOT, &PathBuf::from("/etc/config/new.txt")).unwrap(), (config, "new.txt"));
+        assert!(fs.resolve_parent(MFT_RECORD_ROOT, &PathBuf::from("/etc/..")).is_err());
+
+        // Duplicate names and non-empty directories are rejected
+        assert_eq!(fs.create_file(config, "app.toml".to_string(), None), Err(FilesystemError::AlreadyExists));
+        assert_eq!(fs.delete_file(config, "config"), Err(FilesystemError::DirectoryNotEmpty));
+
+        // The tree survives a remount
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        assert_eq!(fs.resolve_path(MFT_RECORD_ROOT, &PathBuf::from("/etc/config/app.toml")).unwrap(), app);
+        assert_eq!(fs.read_file(app).unwrap(), b"debug = true");
+
+        fs.delete_file(app, "app.toml").unwrap();
+        assert!(fs.list_directory_at(config).unwrap().is_empty());
+        fs.delete_file(config, "config").unwrap();
+        assert!(fs.list_directory_at(etc).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_manager_paths() {
+        let mut manager = FileManager::format_device(create_mock_device()).unwrap();
+        let mut name = core::mem::ManuallyDrop::new(String::from("docs"));
+        let docs = manager.create_directory(name.as_mut_ptr(), name.len(), name.capacity()).unwrap();
+
+        manager.create_file_at_path(PathBuf::from("/docs"), "a.txt".to_string(), Some("A".to_string())).unwrap();
+        manager.create_file_at_path(PathBuf::from("docs/"), "b.txt".to_string(), None).unwrap();
+        let names: Vec<String> = manager.list_files_at_path(PathBuf::from("/docs")).unwrap()
+            .into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["a.txt".to_string(), "b.txt".to_string()]);
+
+        manager.change_directory("/docs").unwrap();
+        assert_eq!(manager.get_current_directory(), docs);
+        assert!(manager.file_exists("a.txt").unwrap());
+        assert!(manager.file_exists("../docs/b.txt").unwrap());
+        assert_eq!(manager.change_directory("a.txt"), Err(FilesystemError::NotADirectory));
+
+        manager.delete_file_at_path(PathBuf::from("."), "b.txt").unwrap();
+        manager.delete_file("/docs/a.txt").unwrap();
+        assert!(manager.list_files().unwrap().is_empty());
+
+        manager.move_to_parent().unwrap();
+        assert_eq!(manager.get_current_directory(), MFT_RECORD_ROOT);
+        assert_eq!(manager.list_files().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_large_directory_splits() {
+        // 32MB gives an MFT of 256 records
+        let device: SharedBlockDevice = Arc::new(MemoryBlockDevice::new(32 * 1024 * 1024));
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let dir = make_directory(&mut fs, MFT_RECORD_ROOT, "many");
+
+        // Enough entries to split the root and then several of its leaves
+        let mut records = Vec::new();
+        for i in 0..150 {
+            let name = alloc::format!("entry_with_a_long_name_{:04}.dat", (i * 7919) % 150);
+            records.push((fs.create_file(dir, name.clone(), None).unwrap(), name));
+        }
+
+        drop(fs);
+        let fs = GalleonFilesystem::mount(device).unwrap();
+        for (record, name) in &records {
+            assert_eq!(fs.find_in_directory(dir, name).unwrap(), Some(*record));
+        }
+
+        let listing = fs.list_directory_at(dir).unwrap();
+        assert_eq!(listing.len(), 150);
+        assert!(listing.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(fs.resolve_path(MFT_RECORD_ROOT, &PathBuf::from("/many/entry_with_a_long_name_0123.dat")).unwrap(),
+            records.iter().find(|(_, name)| name.ends_with("0123.dat")).unwrap().0);
+    }
+
+    #[test]
+    fn test_btree_delete_rebalances() {
+        let device: SharedBlockDevice = Arc::new(MemoryBlockDevice::new(32 * 1024 * 1024));
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let baseline_free = fs.get_stats().unwrap().free_space;
+        let (index_start, cluster_size, _, _, _) = fs.get_btree_diag();
+        let root_index = BTreeManager::new(device.clone(), 0, index_start, cluster_size);
+
+        let mut records = Vec::new();
+        for i in 0..150 {
+            let name = alloc::format!("entry_with_a_long_name_{:04}.dat", i);
+            records.push((fs.create_file(MFT_RECORD_ROOT, name.clone(), None).unwrap(), name));
+        }
+        assert!(!root_index.read_node(0).unwrap().header.is_leaf());
+
+        // The split root survives a remount
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device.clone()).unwrap();
+        assert_eq!(fs.list_directory().unwrap().len(), 150);
+
+        // Delete in a scrambled order, keeping every tenth entry
+        for i in 0..150 {
+            let (record, name) = &records[(i * 7919) % 150];
+            if (i * 7919) % 150 % 10 == 0 {
+                continue;
+            }
+            fs.delete_file(*record, name).unwrap();
+            assert!(fs.find_file(name).unwrap().is_none());
+        }
+
+        let listing = fs.list_directory().unwrap();
+        assert_eq!(listing.len(), 15);
+        assert!(listing.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (record, name) in records.iter().step_by(10) {
+            assert_eq!(fs.find_file(name).unwrap(), Some(*record));
+        }
+
+        // The tree collapsed back into a single leaf and released its nodes
+        assert!(root_index.read_node(0).unwrap().header.is_leaf());
+        assert_eq!(fs.get_stats().unwrap().free_space, baseline_free);
+
+        // Removing a directory frees its index root as well
+        let dir = make_directory(&mut fs, MFT_RECORD_ROOT, "scratch");
+        assert!(fs.get_stats().unwrap().free_space < baseline_free);
+        fs.delete_file(dir, "scratch").unwrap();
+        assert_eq!(fs.get_stats().unwrap().free_space, baseline_free);
+    }
+
     #[test]
     fn test_extent_allocation_strategy() {
         // Test different allocation strategies
@@ -254,17 +941,566 @@ mod tests {
 
     #[test]
     fn test_large_file_handling() {
-        // Test handling of files larger than cluster size
-        let large_data = vec![0u8; 8192]; // 8KB file (2 clusters)
+        // Files larger than a cluster are stored non-resident across several clusters
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let free_before = fs.get_stats().unwrap().free_space;
+
+        let large_data: Vec<u8> = (0..3 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        let file = fs.create_file(MFT_RECORD_ROOT, "big.bin".to_string(), Some(large_data.clone())).unwrap();
+        assert_eq!(fs.file_size(file).unwrap(), large_data.len() as u64);
+        assert_eq!(fs.read_file(file).unwrap(), large_data);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before - 4 * 4096);
+
+        // Contents survive a remount
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        assert_eq!(fs.read_file(file).unwrap(), large_data);
+
+        // Shrinking back below the resident limit releases every cluster
+        fs.write_file(file, b"small".to_vec()).unwrap();
+        assert_eq!(fs.read_file(file).unwrap(), b"small");
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before);
+
+        fs.write_file(file, large_data.clone()).unwrap();
+        fs.delete_file(file, "big.bin").unwrap();
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before);
+    }
 
-        // This would test:
-        // 1. Conversion from resident to non-resident data
-        // 2. Multiple cluster allocation
-        // 3. Run list management
-        // 4. Extent-based storage
+    #[test]
+    fn test_positional_file_io() {
+        let mut fs = create_mock_filesystem();
+        let file = fs.create_file(MFT_RECORD_ROOT, "log.txt".to_string(), Some(b"hello".to_vec())).unwrap();
+        let free_before = fs.get_stats().unwrap().free_space;
+
+        // Overwrite in the middle of a resident file
+        fs.write_at(file, 1, b"ELL").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(fs.read_at(file, 0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hELLo");
+        assert_eq!(fs.read_at(file, 5, &mut buf).unwrap(), 0);
+
+        // Writing past the end leaves a hole and moves the data out of the record
+        fs.write_at(file, 10_000, b"tail").unwrap();
+        assert_eq!(fs.file_size(file).unwrap(), 10_004);
+        let mut gap = vec![0xFFu8; 10_000 - 5];
+        assert_eq!(fs.read_at(file, 5, &mut gap).unwrap(), gap.len());
+        assert!(gap.iter().all(|&b| b == 0));
+        assert_eq!(fs.read_at(file, 9_998, &mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"\0\0tail");
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before - 2 * 4096);
+
+        // A write spanning a cluster boundary only changes the bytes it covers
+        fs.write_at(file, 4090, &[7u8; 12]).unwrap();
+        let data = fs.read_file(file).unwrap();
+        assert_eq!(&data[..5], b"hELLo");
+        assert_eq!(&data[4088..4104], &[0, 0, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 0, 0]);
+        assert_eq!(&data[10_000..], b"tail");
+
+        // Truncating frees the clusters past the new end and zeroes the rest of the last one
+        fs.set_len(file, 4095).unwrap();
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before - 4096);
+        fs.set_len(file, 9000).unwrap();
+        let data = fs.read_file(file).unwrap();
+        assert_eq!(data.len(), 9000);
+        assert_eq!(&data[4088..4096], &[0, 0, 7, 7, 7, 7, 7, 0]);
+        assert!(data[4096..].iter().all(|&b| b == 0));
+
+        fs.set_len(file, 0).unwrap();
+        assert_eq!(fs.file_size(file).unwrap(), 0);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before);
+    }
+
+    #[test]
+    fn test_sparse_files_and_punch_hole() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let free_before = fs.get_stats().unwrap().free_space;
+
+        // Extending a file only records a hole
+        let file = fs.create_file(MFT_RECORD_ROOT, "disk.img".to_string(), None).unwrap();
+        fs.set_len(file, 1 << 20).unwrap();
+        assert_eq!(fs.file_size(file).unwrap(), 1 << 20);
+        assert_eq!(fs.allocated_size(file).unwrap(), 0);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before);
+        let mut buf = vec![0xFFu8; 8192];
+        assert_eq!(fs.read_at(file, 500_000, &mut buf).unwrap(), 8192);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Writing into the hole allocates only the clusters written
+        fs.write_at(file, 512 * 1024 + 10, b"middle").unwrap();
+        assert_eq!(fs.allocated_size(file).unwrap(), 4096);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before - 4096);
+
+        let data: Vec<u8> = (0..5 * 4096).map(|i| (i % 199 + 1) as u8).collect();
+        fs.write_at(file, 0, &data).unwrap();
+        assert_eq!(fs.allocated_size(file).unwrap(), 6 * 4096);
+
+        // Whole clusters inside the range are released, partial ones zeroed
+        fs.punch_hole(file, 4000, 2 * 4096 + 200).unwrap();
+        assert_eq!(fs.allocated_size(file).unwrap(), 4 * 4096);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before - 4 * 4096);
+        let mut contents = vec![0u8; 5 * 4096];
+        fs.read_at(file, 0, &mut contents).unwrap();
+        assert_eq!(&contents[..4000], &data[..4000]);
+        assert!(contents[4000..4000 + 2 * 4096 + 200].iter().all(|&b| b == 0));
+        assert_eq!(&contents[4000 + 2 * 4096 + 200..], &data[4000 + 2 * 4096 + 200..]);
+        assert_eq!(fs.file_size(file).unwrap(), 1 << 20);
+
+        // Writing over a hole again only fills the clusters written
+        fs.write_at(file, 4096, &[5u8; 10]).unwrap();
+        assert_eq!(fs.allocated_size(file).unwrap(), 5 * 4096);
+        assert!(fs.check(false).unwrap().is_clean());
+
+        // Punching to end of file releases everything past the offset
+        fs.punch_hole(file, 4096, u64::MAX).unwrap();
+        assert_eq!(fs.allocated_size(file).unwrap(), 4096);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before - 4096);
+
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        assert_eq!(fs.file_size(file).unwrap(), 1 << 20);
+        let mut head = vec![0u8; 4096];
+        fs.read_at(file, 0, &mut head).unwrap();
+        assert_eq!(&head[..4000], &data[..4000]);
+        assert!(head[4000..].iter().all(|&b| b == 0));
+        assert!(fs.check(false).unwrap().is_clean());
+
+        fs.delete_file(file, "disk.img").unwrap();
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before);
+    }
+
+    #[test]
+    fn test_named_streams() {
+        let device = create_mock_device();
+        let mut manager = FileManager::format_device(device.clone()).unwrap();
+        let file = manager.create_file("photo.jpg".to_string(), Some("pixels".to_string())).unwrap();
+        let free_before = manager.get_stats().unwrap().free_space;
+
+        // A small stream lives in the record next to the file's data
+        manager.write_stream(file, "thumbnail", b"tiny".to_vec()).unwrap();
+        assert_eq!(manager.read_stream(file, "thumbnail").unwrap(), b"tiny");
+        assert_eq!(manager.read_file_text(file).unwrap(), "pixels");
+        assert_eq!(manager.get_file_size(file).unwrap(), 6);
+
+        // A large one moves out to clusters without touching the main data
+        let large: Vec<u8> = (0..3 * 4096 + 17).map(|i| (i % 251) as u8).collect();
+        manager.write_stream(file, "preview", large.clone()).unwrap();
+        assert_eq!(manager.get_stats().unwrap().free_space, free_before - 4 * 4096);
+        assert_eq!(manager.list_streams(file).unwrap(), vec!["thumbnail".to_string(), "preview".to_string()]);
+        assert_eq!(manager.read_file_text(file).unwrap(), "pixels");
+
+        assert_eq!(manager.read_stream(file, "missing"), Err(FilesystemError::NotFound));
+        assert_eq!(manager.write_stream(file, "", Vec::new()), Err(FilesystemError::InvalidParameter));
+
+        drop(manager);
+        let mut manager = FileManager::mount_device(device).unwrap();
+        assert_eq!(manager.read_stream(file, "preview").unwrap(), large);
+        assert_eq!(manager.read_stream(file, "thumbnail").unwrap(), b"tiny");
+
+        manager.remove_stream(file, "thumbnail").unwrap();
+        assert_eq!(manager.list_streams(file).unwrap(), vec!["preview".to_string()]);
+        assert_eq!(manager.remove_stream(file, "thumbnail"), Err(FilesystemError::NotFound));
+
+        // Deleting the file frees the clusters of its streams
+        manager.delete_file("photo.jpg").unwrap();
+        assert_eq!(manager.get_stats().unwrap().free_space, free_before);
+    }
+
+    #[test]
+    fn test_hard_links() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let bin = make_directory(&mut fs, MFT_RECORD_ROOT, "bin");
+        let free_before = fs.get_stats().unwrap().free_space;
+
+        let data: Vec<u8> = (0..2 * 4096).map(|i| (i % 241) as u8).collect();
+        let file = fs.create_file(MFT_RECORD_ROOT, "tool".to_string(), Some(data.clone())).unwrap();
+        fs.link(file, bin, "tool").unwrap();
+        fs.link(file, bin, "tool-alias").unwrap();
+        assert_eq!(fs.link_count(file).unwrap(), 3);
+        assert_eq!(fs.find_in_directory(bin, "tool").unwrap(), Some(file));
+        assert_eq!(fs.file_manager.file_names(file).unwrap().len(), 3);
+
+        assert_eq!(fs.link(file, bin, "tool"), Err(FilesystemError::AlreadyExists));
+        assert_eq!(fs.link(bin, MFT_RECORD_ROOT, "bin2"), Err(FilesystemError::InvalidParameter));
+
+        // Every name refers to the same data
+        fs.write_at(file, 0, b"#!").unwrap();
+        let mut head = [0u8; 2];
+        fs.read_at(fs.find_in_directory(bin, "tool-alias").unwrap().unwrap(), 0, &mut head).unwrap();
+        assert_eq!(&head, b"#!");
+
+        // Removing a name keeps the data while others remain
+        fs.delete_file(file, "tool").unwrap();
+        assert_eq!(fs.find_file("tool").unwrap(), None);
+        assert_eq!(fs.link_count(file).unwrap(), 2);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before - 2 * 4096);
+        assert!(fs.check(false).unwrap().is_clean());
+
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        assert_eq!(fs.link_count(file).unwrap(), 2);
+        assert_eq!(fs.read_file(file).unwrap()[2..], data[2..]);
+
+        fs.unlink(bin, "tool").unwrap();
+        assert_eq!(fs.link_count(file).unwrap(), 1);
+        assert_eq!(fs.file_manager.parent_of(file).unwrap(), bin);
+        fs.unlink(bin, "tool-alias").unwrap();
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before);
+        assert!(fs.check(false).unwrap().is_clean());
+    }
 
-        assert_eq!(large_data.len(), 8192);
-        // Further testing would require proper filesystem instance
+    #[test]
+    fn test_extended_attributes() {
+        let device = create_mock_device();
+        let mut manager = FileManager::format_device(device.clone()).unwrap();
+        let file = manager.create_file("notes.txt".to_string(), Some("hello".to_string())).unwrap();
+
+        assert_eq!(manager.get_xattr(file, "user.author").unwrap(), None);
+        manager.set_xattr(file, "user.author", b"ada").unwrap();
+        manager.set_xattr(file, "user.tags", b"draft,todo").unwrap();
+        manager.set_xattr(file, "user.author", b"grace").unwrap();
+        assert_eq!(manager.get_xattr(file, "user.author").unwrap(), Some(b"grace".to_vec()));
+        assert_eq!(manager.list_xattrs(file).unwrap(), vec!["user.author".to_string(), "user.tags".to_string()]);
+
+        // Directories take extended attributes too
+        manager.set_xattr(MFT_RECORD_ROOT, "user.label", b"root").unwrap();
+
+        // Values share the file record, so one that cannot fit is refused
+        assert_eq!(manager.set_xattr(file, "user.big", &[0u8; 2048]), Err(FilesystemError::InsufficientSpace));
+        assert_eq!(manager.get_xattr(file, "user.big").unwrap(), None);
+
+        drop(manager);
+        let mut manager = FileManager::mount_device(device.clone()).unwrap();
+        assert_eq!(manager.get_xattr(file, "user.tags").unwrap(), Some(b"draft,todo".to_vec()));
+        assert_eq!(manager.get_xattr(MFT_RECORD_ROOT, "user.label").unwrap(), Some(b"root".to_vec()));
+        assert_eq!(manager.read_file_text(file).unwrap(), "hello");
+
+        manager.remove_xattr(file, "user.tags").unwrap();
+        assert_eq!(manager.list_xattrs(file).unwrap(), vec!["user.author".to_string()]);
+        assert_eq!(manager.remove_xattr(file, "user.tags"), Err(FilesystemError::NotFound));
+
+        drop(manager);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        assert!(fs.check(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_posix_metadata() {
+        use core::sync::atomic::AtomicU64;
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        file_record::set_clock(|| NOW.load(Ordering::SeqCst));
+        let set_now = |ns: u64| NOW.store(ns, Ordering::SeqCst);
+
+        set_now(1_000);
+        let device = create_mock_device();
+        let mut manager = FileManager::format_device(device.clone()).unwrap();
+        let file = manager.create_file("run.sh".to_string(), Some("echo hi".to_string())).unwrap();
+        let mut name = core::mem::ManuallyDrop::new(String::from("bin"));
+        let directory = manager.create_directory(name.as_mut_ptr(), name.len(), name.capacity()).unwrap();
+
+        let meta = manager.get_metadata(file).unwrap();
+        assert_eq!((meta.uid, meta.gid, meta.mode), (0, 0, mode::S_IFREG | 0o644));
+        assert_eq!(meta.times.creation_time, 1_000);
+        assert_eq!(manager.get_metadata(directory).unwrap().mode, mode::S_IFDIR | 0o755);
+
+        // chmod keeps the file type; chown drops setuid and setgid from regular files
+        set_now(2_000);
+        manager.chmod(file, mode::S_IFDIR | 0o6755).unwrap();
+        let meta = manager.get_metadata(file).unwrap();
+        assert_eq!(meta.mode, mode::S_IFREG | 0o6755);
+        assert_eq!(meta.times.mft_change_time, 2_000);
+        manager.chown(file, Some(1000), None).unwrap();
+        let meta = manager.get_metadata(file).unwrap();
+        assert_eq!((meta.uid, meta.gid, meta.mode), (1000, 0, mode::S_IFREG | 0o755));
+        manager.chmod(directory, 0o2775).unwrap();
+        manager.chown(directory, None, Some(100)).unwrap();
+        assert_eq!(manager.get_metadata(directory).unwrap().mode, mode::S_IFDIR | 0o2775);
+
+        set_now(2_500);
+        manager.utimens(file, TimeUpdate::At(5), TimeUpdate::Omit).unwrap();
+        let meta = manager.get_metadata(file).unwrap();
+        assert_eq!(meta.times.last_access_time, 5);
+        assert_eq!(meta.times.last_write_time, 1_000);
+        assert_eq!(meta.times.mft_change_time, 2_500);
+
+        // Relatime: the first read after a change, then at most once a day
+        let access_time = |manager: &FileManager| manager.get_metadata(file).unwrap().times.last_access_time;
+        set_now(3_000);
+        manager.read_file_binary(file).unwrap();
+        assert_eq!(access_time(&manager), 3_000);
+        set_now(4_000);
+        manager.read_file_binary(file).unwrap();
+        assert_eq!(access_time(&manager), 3_000);
+        let day = 24 * 60 * 60 * 1_000_000_000;
+        set_now(3_000 + day);
+        manager.read_file_binary(file).unwrap();
+        assert_eq!(access_time(&manager), 3_000 + day);
+
+        manager.set_atime_policy(AtimePolicy::Strict);
+        set_now(3_001 + day);
+        manager.read_file_binary(file).unwrap();
+        assert_eq!(access_time(&manager), 3_001 + day);
+        manager.set_atime_policy(AtimePolicy::Noatime);
+        manager.utimens(file, TimeUpdate::Omit, TimeUpdate::Now).unwrap();
+        set_now(5_000 + day);
+        manager.read_file_binary(file).unwrap();
+        assert_eq!(access_time(&manager), 3_001 + day);
+        assert_eq!(manager.get_metadata(file).unwrap().times.last_write_time, 3_001 + day);
+
+        drop(manager);
+        let manager = FileManager::mount_device(device).unwrap();
+        let meta = manager.get_metadata(directory).unwrap();
+        assert_eq!((meta.uid, meta.gid, meta.mode), (0, 100, mode::S_IFDIR | 0o2775));
+        assert_eq!(manager.get_metadata(file).unwrap().uid, 1000);
+    }
+
+    #[test]
+    fn test_file_handle_cursor() {
+        let device = create_mock_device();
+        let mut manager = FileManager::format_device(device.clone()).unwrap();
+        let file = manager.create_file("app.log".to_string(), Some("start\n".to_string())).unwrap();
+
+        // Appends go to the end without rewriting the existing contents
+        for i in 0..1000 {
+            manager.append_file_text(file, alloc::format!("line {:04}\n", i)).unwrap();
+        }
+        assert_eq!(manager.get_file_size(file).unwrap(), 6 + 1000 * 10);
+
+        let mut handle = manager.open("/app.log").unwrap();
+        assert_eq!(handle.file_record(), file);
+        let mut buf = [0u8; 10];
+        assert_eq!(handle.seek(&manager, SeekFrom::Start(6 + 500 * 10)).unwrap(), 5006);
+        assert_eq!(handle.read(&manager, &mut buf).unwrap(), 10);
+        assert_eq!(&buf, b"line 0500\n");
+        assert_eq!(handle.position(), 5016);
+
+        handle.seek(&manager, SeekFrom::End(-10)).unwrap();
+        assert_eq!(handle.read(&manager, &mut buf).unwrap(), 10);
+        assert_eq!(&buf, b"line 0999\n");
+        assert_eq!(handle.read(&manager, &mut buf).unwrap(), 0);
+        assert!(handle.seek(&manager, SeekFrom::Current(-20_000)).is_err());
+
+        handle.seek(&manager, SeekFrom::Current(-10)).unwrap();
+        handle.write(&mut manager, b"last line\n").unwrap();
+        manager.truncate_file(file, 6).unwrap();
+        assert_eq!(handle.len(&manager).unwrap(), 6);
+        assert!(manager.open("/").is_err());
+
+        manager.sync().unwrap();
+        drop(manager);
+        let mut manager = FileManager::mount_device(device).unwrap();
+        assert_eq!(manager.read_file_text(file).unwrap(), "start\n");
+    }
+
+    /// Overwrite the signature of a record slot as a torn or stray write would
+    fn corrupt_record(fs: &GalleonFilesystem, record: FileRecordNumber) {
+        let sector = fs.mft_manager.mft_start_cluster * 8 + record * 2;
+        let mut data = [0u8; 512];
+        fs.device.read_sectors(sector, &mut data).unwrap();
+        data[..4].copy_from_slice(b"JUNK");
+        fs.device.write_sectors(sector, &data).unwrap();
+    }
+
+    #[test]
+    fn test_check_clean_volume() {
+        let mut fs = create_mock_filesystem();
+        let home = make_directory(&mut fs, MFT_RECORD_ROOT, "home");
+        fs.create_file(home, "small.txt".to_string(), Some(b"hi".to_vec())).unwrap();
+        fs.create_file(home, "big.bin".to_string(), Some(vec![9u8; 3 * 4096])).unwrap();
+        for i in 0..60 {
+            fs.create_file(MFT_RECORD_ROOT, alloc::format!("file{:02}", i), None).unwrap();
+        }
+
+        let report = fs.check(false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(report.directories_checked, 2);
+        assert_eq!(report.records_checked, 2 + 2 + 60);
+        let used = fs.get_stats().unwrap().used_space / 4096;
+        assert_eq!(report.clusters_in_use, used);
+    }
+
+    #[test]
+    fn test_check_repairs_orphans_and_dangling_entries() {
+        let mut fs = create_mock_filesystem();
+        let data: Vec<u8> = (0..2 * 4096).map(|i| (i % 253) as u8).collect();
+        let orphan = fs.create_file(MFT_RECORD_ROOT, "orphan.bin".to_string(), Some(data.clone())).unwrap();
+        let cleared = fs.create_file(MFT_RECORD_ROOT, "cleared.bin".to_string(), Some(vec![1u8; 4096])).unwrap();
+        let corrupt = fs.create_file(MFT_RECORD_ROOT, "corrupt.txt".to_string(), Some(b"x".to_vec())).unwrap();
+
+        // Lose the directory entry of one file and the records of two others
+        fs.directory_index(MFT_RECORD_ROOT).unwrap().delete(&mut fs.allocator, "orphan.bin").unwrap();
+        fs.file_manager.clear_record(cleared).unwrap();
+        corrupt_record(&fs, corrupt);
+
+        let report = fs.check(false).unwrap();
+        assert!(report.issues.contains(&CheckIssue::Orphan { record: orphan }));
+        assert!(report.issues.iter().any(|issue| matches!(issue,
+            CheckIssue::DanglingEntry { record, .. } if *record == cleared)));
+        assert!(report.issues.iter().any(|issue| matches!(issue,
+            CheckIssue::CorruptRecord { record, .. } if *record == corrupt)));
+        assert!(report.issues.iter().any(|issue| matches!(issue, CheckIssue::LeakedClusters(_))));
+        assert_eq!(report.remaining, report.issues);
+
+        let report = fs.check(true).unwrap();
+        assert!(report.is_clean(), "{:?}", report.remaining);
+        assert!(report.repaired >= 4);
+        assert!(fs.check(false).unwrap().is_clean());
+
+        // The orphan keeps its data under lost+found; the other two are gone
+        let lost = fs.find_file("lost+found").unwrap().unwrap();
+        let name = alloc::format!("#{}", orphan);
+        assert_eq!(fs.find_in_directory(lost, &name).unwrap(), Some(orphan));
+        assert_eq!(fs.parent_directory(lost).unwrap(), MFT_RECORD_ROOT);
+        assert_eq!(fs.read_file(orphan).unwrap(), data);
+        assert_eq!(fs.find_file("cleared.bin").unwrap(), None);
+        assert_eq!(fs.find_file("corrupt.txt").unwrap(), None);
+        fs.delete_file(orphan, &name).unwrap();
+        assert!(fs.check(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_check_repairs_cluster_allocation() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let first = fs.create_file(MFT_RECORD_ROOT, "first.bin".to_string(), Some(vec![1u8; 2 * 4096])).unwrap();
+        let second = fs.create_file(MFT_RECORD_ROOT, "second.bin".to_string(), Some(vec![2u8; 2 * 4096])).unwrap();
+
+        // Point the second file at the clusters of the first and clear one bitmap bit
+        let shared = fs.file_manager.data_runs(first).unwrap().unwrap();
+        let mut record = fs.mft_manager.read_record(second).unwrap();
+        for attr in &mut record.attributes {
+            if let AttributeData::NonResident { ref mut runs, .. } = attr.data {
+                *runs = shared.clone();
+            }
+        }
+        fs.mft_manager.write_record(&record).unwrap();
+        fs.allocator.bitmap.set_cluster_free(shared[0].start_cluster).unwrap();
+        fs.allocator.bitmap.flush_bitmap().unwrap();
+
+        let report = fs.check(false).unwrap();
+        assert!(report.issues.contains(&CheckIssue::DoubleAllocated {
+            run: shared[0],
+            first: ClusterOwner::Data { record: first },
+            second: ClusterOwner::Data { record: second },
+        }));
+        assert!(report.issues.contains(&CheckIssue::UnmarkedClusters(ClusterRun::new(shared[0].start_cluster, 1))));
+        assert!(report.issues.iter().any(|issue| matches!(issue, CheckIssue::LeakedClusters(_))));
+
+        let report = fs.check(true).unwrap();
+        assert!(report.is_clean(), "{:?}", report.remaining);
+
+        // Each file now has its own copy of the shared contents
+        fs.write_file(second, vec![3u8; 2 * 4096]).unwrap();
+        assert_eq!(fs.read_file(first).unwrap(), vec![1u8; 2 * 4096]);
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        assert_eq!(fs.read_file(second).unwrap(), vec![3u8; 2 * 4096]);
+        assert!(fs.check(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_defragment_files() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+
+        // Interleave appends to three files so each ends up in one run per append
+        let names = ["a.bin", "b.bin", "c.bin"];
+        let files: Vec<FileRecordNumber> = names.iter()
+            .map(|name| fs.create_file(MFT_RECORD_ROOT, name.to_string(), Some(vec![0u8; 4096])).unwrap())
+            .collect();
+        for round in 1..4u8 {
+            for (i, &file) in files.iter().enumerate() {
+                fs.write_at(file, round as u64 * 4096, &vec![round * 10 + i as u8; 4096]).unwrap();
+            }
+        }
+        let contents: Vec<Vec<u8>> = files.iter().map(|&file| fs.read_file(file).unwrap()).collect();
+        let free_before = fs.get_stats().unwrap().free_space;
+
+        let report = fs.defragment().unwrap();
+        assert_eq!(report.before, FragmentationStats { files: 3, fragmented_files: 3, extents: 12 });
+        assert_eq!(report.after, FragmentationStats { files: 3, fragmented_files: 0, extents: 3 });
+        assert_eq!(report.files_moved, 3);
+        assert_eq!(report.clusters_moved, 12);
+        assert_eq!(fs.get_stats().unwrap().free_space, free_before);
+        assert!(fs.check(false).unwrap().is_clean());
+
+        // A second pass has nothing to do and the moved data survives a remount
+        assert_eq!(fs.defragment().unwrap().files_moved, 0);
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        for (&file, data) in files.iter().zip(&contents) {
+            assert_eq!(fs.file_manager.data_runs(file).unwrap().unwrap().len(), 1);
+            assert_eq!(&fs.read_file(file).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_online_grow() {
+        // Format only the start of the disk, then let the volume grow into the rest
+        let disk: SharedBlockDevice = Arc::new(MemoryBlockDevice::new(136 * 1024 * 1024));
+        let partition = Arc::new(PartitionBlockDevice::new(disk.clone(), 0, 16 * 1024 * 1024 / 512).unwrap());
+        let mut fs = GalleonFilesystem::format(partition).unwrap();
+        let kept = fs.create_file(MFT_RECORD_ROOT, "kept.bin".to_string(), Some(vec![7u8; 3 * 4096])).unwrap();
+        fs.sync().unwrap();
+        drop(fs);
+
+        let mut fs = GalleonFilesystem::mount(disk.clone()).unwrap();
+        let old_bitmap = fs.super_block().bitmap_start_cluster;
+        let free_before = fs.get_stats().unwrap().free_space;
+        assert_eq!(fs.grow().unwrap(), 136 * 256);
+        assert_eq!(fs.grow().unwrap(), 136 * 256);
+
+        // One bitmap cluster covers 32768 clusters, so the bitmap had to move
+        let stats = fs.get_stats().unwrap();
+        assert_eq!(stats.total_clusters, 136 * 256);
+        assert_eq!(stats.free_space, free_before + 120 * 1024 * 1024 - 2 * 4096);
+        assert_ne!(fs.super_block().bitmap_start_cluster, old_bitmap);
+        assert_eq!(fs.super_block().bitmap_size_clusters, 2);
+        assert!(fs.check(false).unwrap().is_clean());
+
+        // The new space is usable and everything survives a remount
+        let big: Vec<u8> = (0..40 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let file = fs.create_file(MFT_RECORD_ROOT, "big.bin".to_string(), Some(big.clone())).unwrap();
+        fs.sync().unwrap();
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(disk).unwrap();
+        assert_eq!(fs.get_stats().unwrap().total_clusters, 136 * 256);
+        assert_eq!(fs.read_file(kept).unwrap(), vec![7u8; 3 * 4096]);
+        assert_eq!(fs.read_file(file).unwrap(), big);
+        assert!(fs.check(false).unwrap().is_clean());
+
+        // A device smaller than the volume cannot be grown into
+        let disk: SharedBlockDevice = Arc::new(MemoryBlockDevice::new(16 * 1024 * 1024));
+        drop(GalleonFilesystem::format(disk.clone()).unwrap());
+        let mut fs = GalleonFilesystem::mount(Arc::new(PartitionBlockDevice::new(disk, 0, 8 * 1024 * 1024 / 512).unwrap())).unwrap();
+        assert_eq!(fs.grow(), Err(FilesystemError::InvalidParameter));
+    }
+
+    #[test]
+    fn test_mft_grows_past_initial_zone() {
+        let device = create_mock_device();
+        let mut fs = GalleonFilesystem::format(device.clone()).unwrap();
+        let initial_capacity = fs.mft_manager.record_capacity();
+        let count = initial_capacity as usize + 20;
+        let files: Vec<FileRecordNumber> = (0..count)
+            .map(|i| fs.create_file(MFT_RECORD_ROOT, alloc::format!("f{:03}", i), Some(alloc::format!("{}", i).into_bytes())).unwrap())
+            .collect();
+
+        assert!(fs.mft_manager.record_capacity() > initial_capacity);
+        assert!(!fs.mft_manager.extension_runs().is_empty());
+        assert!(files.iter().any(|&file| file >= initial_capacity));
+        assert!(fs.check(false).unwrap().is_clean());
+
+        fs.sync().unwrap();
+        drop(fs);
+        let mut fs = GalleonFilesystem::mount(device).unwrap();
+        assert!(fs.mft_manager.record_capacity() > initial_capacity);
+        for (i, &file) in files.iter().enumerate() {
+            assert_eq!(fs.read_file(file).unwrap(), alloc::format!("{}", i).into_bytes());
+        }
+        assert!(fs.check(false).unwrap().is_clean());
     }
 
     #[test]
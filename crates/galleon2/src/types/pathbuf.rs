@@ -24,4 +24,46 @@ impl PathBuf {
     pub fn as_str(&self) -> &str {
         &self.path
     }
+
+    /// Returns true if the path starts at the root directory.
+    pub fn is_absolute(&self) -> bool {
+        self.path.starts_with('/')
+    }
+
+    /// Iterates over the components of the path.
+    ///
+    /// Repeated and trailing slashes are ignored; `.` and `..` are reported
+    /// as-is so the caller can resolve them against the directory tree.
+    pub fn components(&self) -> impl Iterator<Item = Component<'_>> {
+        let root = self.is_absolute().then_some(Component::RootDir);
+        root.into_iter().chain(
+            self.path
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| match segment {
+                    "." => Component::CurDir,
+                    ".." => Component::ParentDir,
+                    name => Component::Normal(name),
+                }),
+        )
+    }
+}
+
+impl From<&str> for PathBuf {
+    fn from(path: &str) -> Self {
+        PathBuf::new(String::from(path))
+    }
+}
+
+/// A single component of a `PathBuf`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component<'a> {
+    /// The leading `/` of an absolute path.
+    RootDir,
+    /// A `.` component.
+    CurDir,
+    /// A `..` component.
+    ParentDir,
+    /// A file or directory name.
+    Normal(&'a str),
 }
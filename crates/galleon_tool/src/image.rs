@@ -0,0 +1,80 @@
+//! Disk images on the host
+//!
+//! A [`BlockDevice`] over a regular file, so the same galleon2 code the kernel
+//! runs can format and mount the images it boots from.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+use galleon2::{BlockDevice, FilesystemError, FilesystemResult, block_device::SECTOR_SIZE};
+
+/// Block device backed by an image file
+pub struct ImageFile {
+    file: Mutex<File>,
+    sector_count: u64,
+}
+
+impl ImageFile {
+    /// Open an existing image for reading and writing
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::from_file(file)
+    }
+
+    /// Create an image of `size_bytes`, or resize an existing one to it
+    pub fn create(path: &Path, size_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        file.set_len(size_bytes)?;
+        Self::from_file(file)
+    }
+
+    fn from_file(file: File) -> io::Result<Self> {
+        let sector_count = file.metadata()?.len() / SECTOR_SIZE as u64;
+        Ok(Self { file: Mutex::new(file), sector_count })
+    }
+
+    /// Offset of `start_sector`, if a `len`-byte transfer from it stays inside the image
+    fn transfer_offset(&self, start_sector: u64, len: usize) -> FilesystemResult<u64> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(FilesystemError::InvalidParameter);
+        }
+        match start_sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sector_count => Ok(start_sector * SECTOR_SIZE as u64),
+            _ => Err(FilesystemError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for ImageFile {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        let offset = self.transfer_offset(start_sector, buffer.len())?;
+        let mut file = self.file.lock().map_err(|_| FilesystemError::DeviceError)?;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(buffer))
+            .map_err(|_| FilesystemError::DeviceError)
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        let offset = self.transfer_offset(start_sector, buffer.len())?;
+        let mut file = self.file.lock().map_err(|_| FilesystemError::DeviceError)?;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(buffer))
+            .map_err(|_| FilesystemError::WriteError)
+    }
+
+    fn flush(&self) -> FilesystemResult<()> {
+        let file = self.file.lock().map_err(|_| FilesystemError::DeviceError)?;
+        file.sync_data().map_err(|_| FilesystemError::DeviceError)
+    }
+}
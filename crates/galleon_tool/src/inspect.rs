@@ -0,0 +1,150 @@
+//! Dumps of on-disk structures
+//!
+//! Everything here reads the image directly instead of mounting it, so a
+//! volume that no longer mounts can still be looked at. Nothing is written,
+//! not even journal recovery.
+
+use std::{fmt::Write, sync::Arc};
+use galleon2::{
+    FilesystemResult, SharedBlockDevice,
+    btree::BTreeManager,
+    file_record::{FileName, IndexRoot, StandardInformation},
+    galleon_fs::GalleonSuperBlock,
+    journal::JournalManager,
+    mft::{Attribute, AttributeData, AttributeType, FileRecordNumber, MftManager, RecordFlags},
+};
+
+/// Read the superblock from sector 0
+pub fn read_super_block(device: &SharedBlockDevice) -> FilesystemResult<GalleonSuperBlock> {
+    let mut sector = [0u8; 512];
+    device.read_sectors(0, &mut sector)?;
+    GalleonSuperBlock::deserialize(&sector)
+}
+
+/// Layout recorded in the superblock
+pub fn super_block(device: &SharedBlockDevice) -> FilesystemResult<String> {
+    let sb = read_super_block(device)?;
+    let legacy = &sb.legacy_super_block;
+    let mut out = String::new();
+    let _ = writeln!(out, "version              {}", legacy.version);
+    let _ = writeln!(out, "cluster size         {}", sb.cluster_size);
+    let _ = writeln!(out, "total clusters       {}", legacy.total_blocks);
+    let _ = writeln!(out, "mft                  clusters {}..{}", sb.mft_start_cluster, sb.mft_start_cluster + sb.mft_size_clusters());
+    let _ = writeln!(out, "mft mirror           cluster {}", sb.mft_mirror_cluster);
+    let _ = writeln!(out, "journal              clusters {}..{}", sb.journal_start_cluster, sb.journal_start_cluster + sb.journal_size_clusters);
+    let _ = writeln!(out, "bitmap               clusters {}..{}", sb.bitmap_start_cluster, sb.bitmap_start_cluster + sb.bitmap_size_clusters);
+    let _ = writeln!(out, "index allocation     cluster {}", sb.index_allocation_start);
+    Ok(out)
+}
+
+/// Header and attributes of one MFT record slot
+pub fn record(device: &SharedBlockDevice, record_number: FileRecordNumber) -> FilesystemResult<String> {
+    let sb = read_super_block(device)?;
+    let mft = MftManager::new(Arc::clone(device), sb.mft_start_cluster, sb.mft_size_clusters(), sb.cluster_size);
+    let Some(record) = mft.read_slot(record_number)? else {
+        return Ok(format!("record {record_number}: never written\n"));
+    };
+
+    let header = &record.header;
+    let flags = RecordFlags::from_u16(header.flags);
+    let mut out = String::new();
+    let _ = writeln!(out, "record {record_number}");
+    let _ = writeln!(out, "  flags              {}{}", if flags.in_use { "in-use" } else { "free" }, if flags.is_directory { " directory" } else { "" });
+    let _ = writeln!(out, "  sequence           {}", header.sequence_number);
+    let _ = writeln!(out, "  hard links         {}", header.hard_link_count);
+    let _ = writeln!(out, "  log sequence       {}", header.log_file_sequence_number);
+    let _ = writeln!(out, "  bytes in use       {} of {}", header.bytes_in_use, header.bytes_allocated);
+    for attribute in &record.attributes {
+        describe_attribute(&mut out, attribute);
+    }
+    Ok(out)
+}
+
+fn describe_attribute(out: &mut String, attribute: &Attribute) {
+    let attr_type = attribute.header.attr_type;
+    let type_name = match attr_type {
+        t if t == AttributeType::StandardInformation as u32 => "standard information",
+        t if t == AttributeType::FileName as u32 => "file name",
+        t if t == AttributeType::Data as u32 => "data",
+        t if t == AttributeType::IndexRoot as u32 => "index root",
+        t if t == AttributeType::IndexAllocation as u32 => "index allocation",
+        t if t == AttributeType::Bitmap as u32 => "bitmap",
+        t if t == AttributeType::ExtendedAttribute as u32 => "extended attribute",
+        _ => "unknown",
+    };
+    let name = if attribute.name.is_empty() { String::new() } else { format!(" \"{}\"", attribute.name) };
+    let _ = write!(out, "  {attr_type:#04x} {type_name}{name}");
+
+    match &attribute.data {
+        AttributeData::Resident(data) => {
+            let _ = writeln!(out, ", resident, {} bytes", data.len());
+            if attr_type == AttributeType::StandardInformation as u32 && let Ok(info) = StandardInformation::deserialize(data) {
+                let _ = writeln!(out, "      links {}, created {}, written {}, changed {}",
+                    info.link_count, info.times.creation_time, info.times.last_write_time, info.times.mft_change_time);
+                let _ = writeln!(out, "      mode {:06o}, uid {}, gid {}", info.mode, info.uid, info.gid);
+            } else if attr_type == AttributeType::FileName as u32 && let Ok(file_name) = FileName::deserialize(data) {
+                let _ = writeln!(out, "      \"{}\" in directory {}, {} bytes", file_name.name, file_name.parent_directory, file_name.file_size);
+            } else if attr_type == AttributeType::IndexRoot as u32 && let Ok(root) = IndexRoot::deserialize(data) {
+                let _ = writeln!(out, "      root node vcn {}, parent directory {}", root.root_vcn, root.parent_directory);
+            }
+        }
+        AttributeData::NonResident { runs, allocated_size, real_size, .. } => {
+            let _ = writeln!(out, ", non-resident, {real_size} bytes, {allocated_size} allocated");
+            for run in runs {
+                if run.is_sparse() {
+                    let _ = writeln!(out, "      hole of {} clusters", run.cluster_count);
+                } else {
+                    let _ = writeln!(out, "      clusters {}..{}", run.start_cluster, run.start_cluster + run.cluster_count);
+                }
+            }
+        }
+    }
+}
+
+/// Header and entries of the index node at `vcn`
+pub fn node(device: &SharedBlockDevice, vcn: u64) -> FilesystemResult<String> {
+    let sb = read_super_block(device)?;
+    let btree = BTreeManager::new(Arc::clone(device), vcn, sb.index_allocation_start, sb.cluster_size);
+    let node = btree.read_node(vcn)?;
+
+    let mut out = String::new();
+    let kind = if node.header.is_leaf() { "leaf" } else { "internal" };
+    let _ = writeln!(out, "node vcn {vcn} ({kind}), cluster {}, {} bytes used",
+        sb.index_allocation_start + vcn, node.header.index_length);
+    for entry in &node.entries {
+        let child = entry.sub_node_vcn.map(|vcn| format!(" -> vcn {vcn}")).unwrap_or_default();
+        if entry.flags.is_last_entry {
+            let _ = writeln!(out, "  (end){child}");
+        } else if node.header.is_leaf() {
+            let _ = writeln!(out, "  \"{}\" record {}", entry.key, entry.file_record_number);
+        } else {
+            let _ = writeln!(out, "  \"{}\"{child}", entry.key);
+        }
+    }
+    Ok(out)
+}
+
+/// Records still live in the journal, oldest first
+pub fn journal(device: &SharedBlockDevice) -> FilesystemResult<String> {
+    let sb = read_super_block(device)?;
+    let sectors_per_cluster = (sb.cluster_size / 512) as u64;
+    let journal = JournalManager::new(
+        Arc::clone(device),
+        sb.journal_start_cluster * sectors_per_cluster,
+        sb.journal_size_clusters * sectors_per_cluster,
+    );
+
+    let records = journal.log_records()?;
+    let mut out = String::new();
+    let _ = writeln!(out, "{} live log records", records.len());
+    for (sector, record) in records {
+        let _ = write!(out, "  #{} at sector {sector}: {:?}, transaction {}, record {}",
+            record.sequence_number, record.operation_type, record.transaction_id, record.target_file_record);
+        if record.operation_type.carries_images() {
+            let _ = write!(out, ", offset {}, undo {} bytes, redo {} bytes",
+                record.target_offset, record.undo_data.len(), record.redo_data.len());
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
@@ -0,0 +1,265 @@
+//! galleon - build and examine galleon2 disk images on the host
+//!
+//! Formats image files, copies files in and out of them, dumps the on-disk
+//! structures and runs the consistency checker, using the same galleon2 code
+//! the kernel mounts volumes with.
+
+mod image;
+mod inspect;
+
+use std::{
+    env, fmt, fs, io::{self, Write},
+    mem::ManuallyDrop,
+    path::Path,
+    process::ExitCode,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use galleon2::{
+    FilesystemError, GalleonFilesystem, SharedBlockDevice, file_record,
+    mft::{FileRecordNumber, MFT_RECORD_ROOT},
+    types::pathbuf::PathBuf,
+};
+use image::ImageFile;
+
+const USAGE: &str = "\
+usage: galleon <command> <image> [arguments]
+
+commands:
+  mkfs <image> [size]             format an image, creating it with size bytes (K/M/G suffixes, default 64M)
+  grow <image> <size>             enlarge an image to size bytes and grow its volume to fill it
+  ls <image> [path]               list a directory
+  mkdir <image> <path>            create a directory and any missing parents
+  put <image> <host-file> <path>  copy a host file into the image, replacing an existing file
+  get <image> <path> [host-file]  copy a file out of the image, to stdout without a host file
+  inspect <image> superblock      dump the superblock
+  inspect <image> record <n>      dump an MFT record
+  inspect <image> node <vcn>      dump a directory index node
+  inspect <image> journal         dump the live journal records
+  fsck <image> [--repair]         check the volume, repairing what can be fixed";
+
+const DEFAULT_IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+enum ToolError {
+    Usage(String),
+    Io(io::Error),
+    Filesystem(FilesystemError),
+}
+
+impl fmt::Display for ToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToolError::Usage(message) => write!(f, "{message}\n\n{USAGE}"),
+            ToolError::Io(error) => write!(f, "{error}"),
+            ToolError::Filesystem(error) => write!(f, "filesystem error: {error:?}"),
+        }
+    }
+}
+
+impl From<io::Error> for ToolError {
+    fn from(error: io::Error) -> Self {
+        ToolError::Io(error)
+    }
+}
+
+impl From<FilesystemError> for ToolError {
+    fn from(error: FilesystemError) -> Self {
+        ToolError::Filesystem(error)
+    }
+}
+
+type ToolResult<T> = Result<T, ToolError>;
+
+/// Nanoseconds since the Unix epoch, so files put on an image carry the host's time
+fn host_clock() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+}
+
+fn main() -> ExitCode {
+    file_record::set_clock(host_clock);
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("galleon: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> ToolResult<ExitCode> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["mkfs", image, rest @ ..] => mkfs(Path::new(image), rest),
+        ["grow", image, size] => grow(Path::new(image), size),
+        ["ls", image, rest @ ..] if rest.len() <= 1 => ls(Path::new(image), rest.first().copied().unwrap_or("/")),
+        ["mkdir", image, path] => mkdir(Path::new(image), path),
+        ["put", image, host_file, path] => put(Path::new(image), Path::new(host_file), path),
+        ["get", image, path, rest @ ..] if rest.len() <= 1 => get(Path::new(image), path, rest.first().map(Path::new)),
+        ["inspect", image, what @ ..] => inspect(Path::new(image), what),
+        ["fsck", image] => fsck(Path::new(image), false),
+        ["fsck", image, "--repair"] => fsck(Path::new(image), true),
+        ["help" | "--help" | "-h"] => {
+            println!("{USAGE}");
+            Ok(ExitCode::SUCCESS)
+        }
+        _ => Err(ToolError::Usage(String::from("unrecognised arguments"))),
+    }
+}
+
+fn open_device(image: &Path) -> ToolResult<SharedBlockDevice> {
+    Ok(Arc::new(ImageFile::open(image)?))
+}
+
+fn mount(image: &Path) -> ToolResult<GalleonFilesystem> {
+    Ok(GalleonFilesystem::mount(open_device(image)?)?)
+}
+
+/// Parse a byte count with an optional K, M or G suffix
+fn parse_size(text: &str) -> ToolResult<u64> {
+    let (digits, multiplier) = match text.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&text[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&text[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    digits.parse::<u64>().ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| ToolError::Usage(format!("invalid size '{text}'")))
+}
+
+fn mkfs(image: &Path, args: &[&str]) -> ToolResult<ExitCode> {
+    let device = match args {
+        [] if image.exists() => ImageFile::open(image)?,
+        [] => ImageFile::create(image, DEFAULT_IMAGE_SIZE)?,
+        [size] => ImageFile::create(image, parse_size(size)?)?,
+        _ => return Err(ToolError::Usage(String::from("mkfs takes at most one size"))),
+    };
+
+    let mut fs = GalleonFilesystem::format(Arc::new(device))?;
+    fs.sync()?;
+    let stats = fs.get_stats()?;
+    println!("formatted {}: {} clusters of {} bytes, {} bytes free",
+        image.display(), stats.total_clusters, stats.cluster_size, stats.free_space);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn grow(image: &Path, size: &str) -> ToolResult<ExitCode> {
+    let size = parse_size(size)?;
+    if size < fs::metadata(image)?.len() {
+        return Err(ToolError::Usage(String::from("images can only grow")));
+    }
+    let mut fs = GalleonFilesystem::mount(Arc::new(ImageFile::create(image, size)?))?;
+    let total_clusters = fs.grow()?;
+    fs.sync()?;
+    println!("grew {} to {} clusters", image.display(), total_clusters);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn ls(image: &Path, path: &str) -> ToolResult<ExitCode> {
+    let fs = mount(image)?;
+    let directory = fs.resolve_path(MFT_RECORD_ROOT, &PathBuf::from(path))?;
+    for (name, record) in fs.list_directory_at(directory)? {
+        if fs.is_directory(record)? {
+            println!("d {:>12} {:>8} {name}/", "-", record);
+        } else {
+            println!("- {:>12} {:>8} {name}", fs.file_size(record)?, record);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Split a path inside the image into its directory components and final name
+fn split_path(path: &str) -> ToolResult<(Vec<&str>, &str)> {
+    let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    match components.pop() {
+        Some(name) if name != "." && name != ".." && !components.iter().any(|c| *c == "." || *c == "..") => Ok((components, name)),
+        _ => Err(ToolError::Usage(format!("'{path}' does not name a file"))),
+    }
+}
+
+/// Walk `components` from the root, creating directories that do not exist yet
+fn make_directories(fs: &mut GalleonFilesystem, components: &[&str]) -> ToolResult<FileRecordNumber> {
+    let mut directory = MFT_RECORD_ROOT;
+    for component in components {
+        directory = match fs.find_in_directory(directory, component)? {
+            Some(record) if fs.is_directory(record)? => record,
+            Some(_) => return Err(FilesystemError::NotADirectory.into()),
+            None => {
+                let mut name = ManuallyDrop::new(String::from(*component));
+                unsafe { fs.create_directory(directory, name.as_mut_ptr(), name.len(), name.capacity())? }
+            }
+        };
+    }
+    Ok(directory)
+}
+
+fn mkdir(image: &Path, path: &str) -> ToolResult<ExitCode> {
+    let (parents, name) = split_path(path)?;
+    let mut fs = mount(image)?;
+    let mut components = parents;
+    components.push(name);
+    make_directories(&mut fs, &components)?;
+    fs.sync()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn put(image: &Path, host_file: &Path, path: &str) -> ToolResult<ExitCode> {
+    let data = fs::read(host_file)?;
+    let (parents, name) = split_path(path)?;
+    let mut fs = mount(image)?;
+    let directory = make_directories(&mut fs, &parents)?;
+    match fs.find_in_directory(directory, name)? {
+        Some(record) if fs.is_directory(record)? => return Err(FilesystemError::AlreadyExists.into()),
+        Some(record) => fs.write_file(record, data)?,
+        None => {
+            fs.create_file(directory, String::from(name), Some(data))?;
+        }
+    }
+    fs.sync()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn get(image: &Path, path: &str, host_file: Option<&Path>) -> ToolResult<ExitCode> {
+    let mut fs = mount(image)?;
+    let record = fs.resolve_path(MFT_RECORD_ROOT, &PathBuf::from(path))?;
+    if fs.is_directory(record)? {
+        return Err(FilesystemError::InvalidParameter.into());
+    }
+    let data = fs.read_file(record)?;
+    match host_file {
+        Some(host_file) => fs::write(host_file, data)?,
+        None => io::stdout().write_all(&data)?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn inspect(image: &Path, what: &[&str]) -> ToolResult<ExitCode> {
+    let device = open_device(image)?;
+    let number = |text: &str| text.parse::<u64>().map_err(|_| ToolError::Usage(format!("invalid number '{text}'")));
+    let dump = match what {
+        ["superblock"] => inspect::super_block(&device)?,
+        ["record", record] => inspect::record(&device, number(record)?)?,
+        ["node", vcn] => inspect::node(&device, number(vcn)?)?,
+        ["journal"] => inspect::journal(&device)?,
+        _ => return Err(ToolError::Usage(String::from("inspect expects superblock, record <n>, node <vcn> or journal"))),
+    };
+    print!("{dump}");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn fsck(image: &Path, repair: bool) -> ToolResult<ExitCode> {
+    let mut fs = mount(image)?;
+    let report = fs.check(repair)?;
+    for issue in &report.issues {
+        println!("{issue:?}");
+    }
+    println!("{} records, {} directories, {} clusters in use: {} issues, {} repaired, {} remaining",
+        report.records_checked, report.directories_checked, report.clusters_in_use,
+        report.issues.len(), report.repaired, report.remaining.len());
+    if repair {
+        fs.sync()?;
+    }
+    Ok(if report.is_clean() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
@@ -8,7 +8,7 @@
 //! - Deduplication
 //! - Quotas and limits
 
-use alloc::{boxed::Box, collections::BTreeMap, string::{String, ToString}, vec::Vec, vec};
+use alloc::{collections::{BTreeMap, BTreeSet}, string::{String, ToString}, vec::Vec, vec};
 use core::{future::Future, pin::Pin};
 use luminal::Runtime;
 use core::cell::UnsafeCell;
@@ -38,7 +38,13 @@ impl GalleonRuntime {
 
 /// The global runtime instance
 pub static GALLEON_RUNTIME: GalleonRuntime = GalleonRuntime::new();
-use super::{Result, ObjectId, Inode, Timestamp, Transaction, CompressionAlgorithm, EncryptionAlgorithm, GalleonError};
+use super::{
+    Result, ObjectId, Inode, InodeType, Permissions, Timestamp, Transaction, CompressionAlgorithm, EncryptionAlgorithm, GalleonError,
+    TransactionOperation, StorageBackend, ChunkedStorage, StorageFilesystem, Directory, DirectoryEntry, codec, crypto,
+    PlatformRng, encoding::{Encoder, Decoder},
+};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::time::Duration;
 
 /// Advanced features trait
@@ -87,10 +93,71 @@ pub struct SnapshotInfo {
     pub metadata: BTreeMap<String, String>,
 }
 
+/// Stored bytes a snapshot takes up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotUsage {
+    /// Everything the snapshot refers to, including blocks it shares
+    pub referenced: u64,
+    /// What only the snapshot refers to, which deleting it frees
+    pub exclusive: u64,
+}
+
 /// Snapshot manager implementation
+///
+/// A snapshot is a copy of a file or directory tree kept in the attached
+/// [`ChunkedStorage`] under the snapshot's id, which no directory links to.
+/// Directories and other small objects are copied; regular files are cloned
+/// with [`ChunkedStorage::clone_file`], so they share their blocks with the
+/// source until either side writes them. A snapshot of
+/// [`ObjectId::root()`] covers the whole filesystem.
+///
+/// The registry of snapshots is kept in the [`ObjectId::snapshot_table`]
+/// object, rewritten in the transaction that creates or deletes a snapshot
+/// and loaded when storage is attached.
 pub struct SnapshotManager {
     snapshots: spin::Mutex<BTreeMap<ObjectId, SnapshotInfo>>,
     snapshot_hierarchy: spin::Mutex<BTreeMap<ObjectId, Vec<ObjectId>>>, // parent -> children
+    storage: spin::Mutex<Option<Arc<ChunkedStorage>>>,
+}
+
+const SNAPSHOT_TABLE_ENTRY: u8 = 1;
+
+fn encode_snapshot(e: &mut Encoder, snapshot: &SnapshotInfo) {
+    e.put_varint(snapshot.id.as_u64());
+    e.put_str(&snapshot.name);
+    e.put_bool(snapshot.parent_id.is_some());
+    if let Some(parent_id) = snapshot.parent_id {
+        e.put_varint(parent_id.as_u64());
+    }
+    e.put_timestamp(snapshot.created_at);
+    e.put_varint(snapshot.size);
+    e.put_varint(snapshot.reference_count as u64);
+    e.put_varint(snapshot.metadata.len() as u64);
+    for (key, value) in &snapshot.metadata {
+        e.put_str(key);
+        e.put_str(value);
+    }
+}
+
+fn decode_snapshot(d: &mut Decoder) -> Result<SnapshotInfo> {
+    let id = ObjectId(d.get_varint()?);
+    let name = d.get_string()?;
+    let parent_id = if d.get_bool()? { Some(ObjectId(d.get_varint()?)) } else { None };
+    let created_at = d.get_timestamp()?;
+    let size = d.get_varint()?;
+    let reference_count = d.get_varint_u32()?;
+    let mut metadata = BTreeMap::new();
+    for _ in 0..d.get_varint()? {
+        let key = d.get_string()?;
+        metadata.insert(key, d.get_string()?);
+    }
+    Ok(SnapshotInfo { id, name, parent_id, created_at, size, reference_count, metadata })
+}
+
+impl Default for SnapshotManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SnapshotManager {
@@ -98,70 +165,303 @@ impl SnapshotManager {
         Self {
             snapshots: spin::Mutex::new(BTreeMap::new()),
             snapshot_hierarchy: spin::Mutex::new(BTreeMap::new()),
+            storage: spin::Mutex::new(None),
         }
     }
 
+    /// Keep snapshots in `storage`, which must hold the objects snapshotted,
+    /// and load the registry saved in it
+    ///
+    /// Entries whose snapshot tree is missing are skipped.
+    pub async fn attach_storage(&self, storage: Arc<ChunkedStorage>) -> Result<()> {
+        let table = stored_data(storage.as_ref(), ObjectId::snapshot_table()).await?;
+        let mut decoder = Decoder::new(&table);
+        let mut saved = Vec::new();
+        while let Some((tag, mut d)) = decoder.next_field()? {
+            if tag == SNAPSHOT_TABLE_ENTRY {
+                saved.push(decode_snapshot(&mut d)?);
+            }
+        }
+        for snapshot_info in saved {
+            if storage.exists(snapshot_info.id).await? {
+                self.record(snapshot_info);
+            }
+        }
+        *self.storage.lock() = Some(storage);
+        Ok(())
+    }
+
+    fn record(&self, snapshot_info: SnapshotInfo) {
+        if let Some(parent_id) = snapshot_info.parent_id {
+            self.snapshot_hierarchy.lock().entry(parent_id).or_default().push(snapshot_info.id);
+        }
+        self.snapshots.lock().insert(snapshot_info.id, snapshot_info);
+    }
+
+    fn forget(&self, snapshot_id: ObjectId) -> Option<SnapshotInfo> {
+        let snapshot_info = self.snapshots.lock().remove(&snapshot_id)?;
+        if let Some(parent_id) = snapshot_info.parent_id {
+            let mut hierarchy = self.snapshot_hierarchy.lock();
+            if let Some(children) = hierarchy.get_mut(&parent_id) {
+                children.retain(|&id| id != snapshot_id);
+                if children.is_empty() {
+                    hierarchy.remove(&parent_id);
+                }
+            }
+        }
+        Some(snapshot_info)
+    }
+
+    /// Rewrite the saved registry with the snapshots recorded now, in `transaction`
+    async fn save(&self, storage: &ChunkedStorage, transaction: &Transaction) -> Result<()> {
+        let mut encoder = Encoder::new();
+        for snapshot_info in self.snapshots.lock().values() {
+            encoder.put_field(SNAPSHOT_TABLE_ENTRY, |e| encode_snapshot(e, snapshot_info));
+        }
+        let table = encoder.into_bytes();
+        let id = ObjectId::snapshot_table();
+        if current_inode(storage, id, transaction).await?.is_none() {
+            let inode = Inode::new(id, InodeType::RegularFile, Permissions::new(0o600, 0, 0), 0);
+            storage.write_inode(&inode, transaction).await?;
+        }
+        storage.write_data(id, 0, &table, transaction).await?;
+        storage.truncate(id, table.len() as u64, transaction).await
+    }
+
+    fn storage(&self) -> Result<Arc<ChunkedStorage>> {
+        self.storage.lock().clone().ok_or(GalleonError::InvalidState("No storage attached for snapshots"))
+    }
+
+    fn check_exists(&self, snapshot_id: ObjectId) -> Result<()> {
+        if !self.snapshots.lock().contains_key(&snapshot_id) {
+            return Err(GalleonError::NotFound);
+        }
+        Ok(())
+    }
+
     pub async fn create_snapshot(&self, source_id: ObjectId, name: String) -> Result<ObjectId> {
+        let storage = self.storage()?;
         let snapshot_id = ObjectId::new();
+        let transaction = Transaction::new();
+        copy_tree(&storage, source_id, snapshot_id, &transaction).await?;
+
         let snapshot_info = SnapshotInfo {
             id: snapshot_id,
             name,
             parent_id: Some(source_id),
             created_at: Timestamp::now(),
-            size: 0, // Will be calculated
+            size: 0, // Calculated once recorded
             reference_count: 1,
             metadata: BTreeMap::new(),
         };
-
-        // Add to snapshots
-        {
-            let mut snapshots = self.snapshots.lock();
-            snapshots.insert(snapshot_id, snapshot_info);
+        self.record(snapshot_info);
+        let saved = match self.save(&storage, &transaction).await {
+            Ok(()) => transaction.commit(storage.as_ref()).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = saved {
+            self.forget(snapshot_id);
+            return Err(error);
         }
 
-        // Update hierarchy
-        {
-            let mut hierarchy = self.snapshot_hierarchy.lock();
-            hierarchy.entry(source_id).or_insert_with(Vec::new).push(snapshot_id);
+        let size = self.usage(snapshot_id).await?.exclusive;
+        if let Some(snapshot_info) = self.snapshots.lock().get_mut(&snapshot_id) {
+            snapshot_info.size = size;
         }
-
         Ok(snapshot_id)
     }
 
     pub async fn delete_snapshot(&self, snapshot_id: ObjectId) -> Result<()> {
-        // Remove from snapshots
-        let snapshot_info = {
-            let mut snapshots = self.snapshots.lock();
-            snapshots.remove(&snapshot_id)
-                .ok_or(GalleonError::NotFound)?
+        self.check_exists(snapshot_id)?;
+        let storage = self.storage()?;
+        let transaction = Transaction::new();
+        release_links(&storage, vec![snapshot_id], &transaction).await?;
+
+        let snapshot_info = self.forget(snapshot_id).ok_or(GalleonError::NotFound)?;
+        let saved = match self.save(&storage, &transaction).await {
+            Ok(()) => transaction.commit(storage.as_ref()).await,
+            Err(error) => Err(error),
         };
+        if saved.is_err() {
+            self.record(snapshot_info);
+        }
+        saved
+    }
 
-        // Update hierarchy
-        if let Some(parent_id) = snapshot_info.parent_id {
-            let mut hierarchy = self.snapshot_hierarchy.lock();
-            if let Some(children) = hierarchy.get_mut(&parent_id) {
-                children.retain(|&id| id != snapshot_id);
-                if children.is_empty() {
-                    hierarchy.remove(&parent_id);
+    /// Snapshots taken of `object_id`, with their sizes brought up to date
+    pub async fn list_snapshots(&self, object_id: ObjectId) -> Result<Vec<SnapshotInfo>> {
+        let children = self.snapshot_hierarchy.lock().get(&object_id).cloned().unwrap_or_default();
+        let mut listed = Vec::with_capacity(children.len());
+        for id in children {
+            let Some(mut snapshot_info) = self.snapshots.lock().get(&id).cloned() else {
+                continue;
+            };
+            snapshot_info.size = self.usage(id).await?.exclusive;
+            if let Some(stored) = self.snapshots.lock().get_mut(&id) {
+                stored.size = snapshot_info.size;
+            }
+            listed.push(snapshot_info);
+        }
+        Ok(listed)
+    }
+
+    /// Roll `target_id` back to a snapshot
+    ///
+    /// The target must be of the snapshot's type. A directory loses everything
+    /// under it and gets copies of what is under the snapshot; the snapshot
+    /// itself is kept.
+    pub async fn restore_from_snapshot(&self, snapshot_id: ObjectId, target_id: ObjectId) -> Result<()> {
+        self.check_exists(snapshot_id)?;
+        let storage = self.storage()?;
+        let snapshot = storage.read_inode(snapshot_id).await?;
+        let target = storage.read_inode(target_id).await?;
+        if snapshot.inode_type() != target.inode_type() {
+            return Err(GalleonError::InvalidArgument("Snapshot and target are of different types"));
+        }
+
+        let transaction = Transaction::new();
+        if target.is_directory() {
+            let directory = Directory::deserialize(&stored_data(storage.as_ref(), target_id).await?)?;
+            release_links(&storage, directory.entries().map(|entry| entry.object_id).collect(), &transaction).await?;
+        }
+        copy_tree(&storage, snapshot_id, target_id, &transaction).await?;
+        transaction.commit(storage.as_ref()).await
+    }
+
+    /// Space taken up by a snapshot, counting each block it shares once
+    pub async fn usage(&self, snapshot_id: ObjectId) -> Result<SnapshotUsage> {
+        self.check_exists(snapshot_id)?;
+        let storage = self.storage()?;
+        let mut usage = SnapshotUsage::default();
+        let mut visited = BTreeSet::new();
+        // Block -> (stored size, references from the snapshot, references in all)
+        let mut blocks: BTreeMap<ObjectId, (u64, u32, u32)> = BTreeMap::new();
+        let mut pending = vec![snapshot_id];
+
+        while let Some(id) = pending.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let inode = storage.read_inode(id).await?;
+            // Chunked files keep their data in blocks, which may be shared
+            if inode.chunked_size().is_some() {
+                for block in storage.block_inodes(&inode).await? {
+                    blocks.entry(block.id()).or_insert((block.size(), 0, block.link_count())).1 += 1;
                 }
+                continue;
+            }
+            let data = stored_data(storage.as_ref(), id).await?;
+            usage.referenced += data.len() as u64;
+            usage.exclusive += data.len() as u64;
+            if inode.is_directory() {
+                pending.extend(Directory::deserialize(&data)?.entries().map(|entry| entry.object_id));
             }
         }
 
-        Ok(())
+        for (size, references, links) in blocks.into_values() {
+            usage.referenced += size;
+            if references >= links {
+                usage.exclusive += size;
+            }
+        }
+        Ok(usage)
     }
 
-    pub async fn list_snapshots(&self, object_id: ObjectId) -> Result<Vec<SnapshotInfo>> {
-        let hierarchy = self.snapshot_hierarchy.lock();
-        let snapshots = self.snapshots.lock();
+    /// Read-only filesystem showing a snapshot as its root, to mount in the VFS
+    pub fn filesystem(&self, snapshot_id: ObjectId) -> Result<StorageFilesystem> {
+        self.check_exists(snapshot_id)?;
+        Ok(StorageFilesystem::read_only(self.storage()?, snapshot_id))
+    }
+}
 
-        if let Some(children) = hierarchy.get(&object_id) {
-            Ok(children.iter()
-                .filter_map(|&id| snapshots.get(&id).cloned())
-                .collect())
-        } else {
-            Ok(Vec::new())
+/// All data stored in object `id`, empty if there is none
+async fn stored_data(storage: &dyn StorageBackend, id: ObjectId) -> Result<Vec<u8>> {
+    match storage.read_data(id, 0, u64::MAX).await {
+        Err(GalleonError::NotFound) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Inode of `id` as `transaction` leaves it so far, or `None` if there is none
+async fn current_inode(storage: &ChunkedStorage, id: ObjectId, transaction: &Transaction) -> Result<Option<Inode>> {
+    if let Some(pending) = transaction.pending_inode(id) {
+        return Ok(pending);
+    }
+    match storage.read_inode(id).await {
+        Ok(inode) => Ok(Some(inode)),
+        Err(GalleonError::NotFound) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Copy the tree under `source` to `target`, giving the objects under it new ids
+///
+/// Objects linked more than once in the tree are copied once, and their
+/// copies count only the links within it. An existing target is overwritten
+/// but keeps its link count; a new one has a single link.
+async fn copy_tree(storage: &ChunkedStorage, source: ObjectId, target: ObjectId, transaction: &Transaction) -> Result<()> {
+    let root_links = current_inode(storage, target, transaction).await?.map_or(1, |inode| inode.link_count());
+    let mut copies = BTreeMap::from([(source, target)]);
+    let mut links = BTreeMap::from([(target, root_links)]);
+    let mut pending = vec![(source, target)];
+
+    while let Some((source, target)) = pending.pop() {
+        let inode = storage.read_inode(source).await?;
+        if inode.inode_type() == InodeType::RegularFile {
+            storage.clone_file(source, target, transaction).await?;
+            continue;
+        }
+
+        let mut data = stored_data(storage, source).await?;
+        if inode.is_directory() {
+            let mut directory = Directory::new();
+            for entry in Directory::deserialize(&data)?.entries() {
+                let copy = *copies.entry(entry.object_id).or_insert_with(|| {
+                    let copy = ObjectId::new();
+                    pending.push((entry.object_id, copy));
+                    copy
+                });
+                *links.entry(copy).or_insert(0) += 1;
+                directory.add_entry(DirectoryEntry::new(entry.name.clone(), copy, entry.inode_type))?;
+            }
+            data = directory.serialize()?;
+        }
+        storage.write_inode(&inode.with_id(target), transaction).await?;
+        storage.write_data(target, 0, &data, transaction).await?;
+        storage.truncate(target, data.len() as u64, transaction).await?;
+    }
+
+    for (id, count) in links {
+        if let Some(Some(mut inode)) = transaction.pending_inode(id) {
+            if inode.link_count() != count {
+                inode.set_link_count(count);
+                storage.write_inode(&inode, transaction).await?;
+            }
         }
     }
+    Ok(())
+}
+
+/// Drop a link to each of `ids`, deleting the objects left without one
+/// along with what is under them
+async fn release_links(storage: &ChunkedStorage, ids: Vec<ObjectId>, transaction: &Transaction) -> Result<()> {
+    let mut pending = ids;
+    while let Some(id) = pending.pop() {
+        let Some(mut inode) = current_inode(storage, id, transaction).await? else {
+            continue;
+        };
+        inode.decrement_link_count();
+        if inode.link_count() > 0 {
+            storage.write_inode(&inode, transaction).await?;
+            continue;
+        }
+        if inode.is_directory() {
+            let directory = Directory::deserialize(&stored_data(storage, id).await?)?;
+            pending.extend(directory.entries().map(|entry| entry.object_id));
+        }
+        storage.delete_inode(id, transaction).await?;
+    }
+    Ok(())
 }
 
 /// Compression policy configuration
@@ -218,14 +518,12 @@ impl CompressionManager {
         }
     }
 
-    async fn compress_lz4(&self, _data: &[u8]) -> Result<Vec<u8>> {
-        // TODO: Implement LZ4 compression
-        Err(GalleonError::NotSupported)
+    async fn compress_lz4(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(codec::lz4_compress(data))
     }
 
-    async fn decompress_lz4(&self, _data: &[u8]) -> Result<Vec<u8>> {
-        // TODO: Implement LZ4 decompression
-        Err(GalleonError::NotSupported)
+    async fn decompress_lz4(&self, data: &[u8]) -> Result<Vec<u8>> {
+        codec::lz4_decompress(data, usize::MAX)
     }
 
     async fn compress_zstd(&self, _data: &[u8]) -> Result<Vec<u8>> {
@@ -243,9 +541,8 @@ impl CompressionManager {
         Err(GalleonError::NotSupported)
     }
 
-    async fn decompress_gzip(&self, _data: &[u8]) -> Result<Vec<u8>> {
-        // TODO: Implement Gzip decompression
-        Err(GalleonError::NotSupported)
+    async fn decompress_gzip(&self, data: &[u8]) -> Result<Vec<u8>> {
+        codec::gunzip(data, usize::MAX)
     }
 
     async fn compress_brotli(&self, _data: &[u8]) -> Result<Vec<u8>> {
@@ -258,7 +555,14 @@ impl CompressionManager {
         Err(GalleonError::NotSupported)
     }
 
+    /// Set the policy [`ChunkedStorage`](crate::ChunkedStorage) applies to writes of `object_id`
+    ///
+    /// Only algorithms with a compressor can be used; chunks are stored with
+    /// LZ4 or left uncompressed.
     pub async fn set_compression_policy(&self, object_id: ObjectId, policy: CompressionPolicy) -> Result<()> {
+        if !matches!(policy.algorithm, CompressionAlgorithm::None | CompressionAlgorithm::Lz4) {
+            return Err(GalleonError::NotSupported);
+        }
         let mut policies = self.policies.lock();
         policies.insert(object_id, policy);
         Ok(())
@@ -280,113 +584,449 @@ pub struct EncryptionPolicy {
 }
 
 /// Encryption manager
+///
+/// Data keys are stored wrapped, each sealed under the master key, and are
+/// only unwrapped for the operation that uses them. ChaCha20-Poly1305 is the
+/// only cipher implemented.
+///
+/// The master key and the entropy source new data keys are drawn from are
+/// the caller's; a manager without them can't encrypt anything. No nonce is
+/// random: keys are wrapped under a nonce made from their id, and callers of
+/// [`encrypt_block`](Self::encrypt_block) derive theirs from object ids.
+///
+/// The wrapped keys and the policies are saved with [`export_keys`](Self::export_keys)
+/// and loaded back with [`import_keys`](Self::import_keys);
+/// [`ChunkedStorage`](crate::ChunkedStorage) keeps them on the volume it writes to.
 pub struct EncryptionManager {
+    master_key: spin::Mutex<Option<[u8; crypto::KEY_SIZE]>>,
+    rng: spin::Mutex<Option<Box<dyn PlatformRng + Send>>>,
     keys: spin::Mutex<BTreeMap<u64, EncryptionKey>>,
     policies: spin::Mutex<BTreeMap<ObjectId, EncryptionPolicy>>,
+    /// Bumped on every change to the keys or policies
+    generation: AtomicU64,
+    /// Whether keys saved earlier have been imported, so new ids can't clash with theirs
+    keys_loaded: AtomicBool,
 }
 
+const KEY_TABLE_KEY: u8 = 1;
+const KEY_TABLE_POLICY: u8 = 2;
+
 #[derive(Debug, Clone)]
 pub struct EncryptionKey {
     pub id: u64,
     pub algorithm: EncryptionAlgorithm,
+    /// The key, sealed under the master key
     pub key_data: Vec<u8>,
+    /// Nonce `key_data` was sealed with
     pub iv: Vec<u8>,
     pub created_at: Timestamp,
     pub expires_at: Option<Timestamp>,
 }
 
+fn check_cipher(algorithm: EncryptionAlgorithm) -> Result<()> {
+    match algorithm {
+        EncryptionAlgorithm::None | EncryptionAlgorithm::ChaCha20Poly1305 => Ok(()),
+        EncryptionAlgorithm::Aes256Gcm | EncryptionAlgorithm::Aes256Ctr => Err(GalleonError::NotSupported),
+    }
+}
+
+/// Nonce holding `id`, an object id that is never handed out twice on a volume
+fn id_nonce(id: u64) -> [u8; crypto::NONCE_SIZE] {
+    let mut nonce = [0u8; crypto::NONCE_SIZE];
+    nonce[4..].copy_from_slice(&id.to_le_bytes());
+    nonce
+}
+
 impl EncryptionManager {
+    /// Manager without a master key, which can't create or unwrap data keys
     pub fn new() -> Self {
         Self {
+            master_key: spin::Mutex::new(None),
+            rng: spin::Mutex::new(None),
             keys: spin::Mutex::new(BTreeMap::new()),
             policies: spin::Mutex::new(BTreeMap::new()),
+            generation: AtomicU64::new(0),
+            keys_loaded: AtomicBool::new(true),
         }
     }
 
+    /// Manager whose data keys are wrapped under `master_key` and drawn from `rng`
+    ///
+    /// Keys are only as strong as `rng`, which should be the platform's
+    /// entropy source rather than a seeded generator.
+    pub fn with_master_key(master_key: [u8; crypto::KEY_SIZE], rng: Box<dyn PlatformRng + Send>) -> Self {
+        let manager = Self::new();
+        *manager.master_key.lock() = Some(master_key);
+        *manager.rng.lock() = Some(rng);
+        manager
+    }
+
     pub async fn generate_key(&self, algorithm: EncryptionAlgorithm) -> Result<u64> {
+        check_cipher(algorithm)?;
+        if !self.keys_loaded.load(Ordering::Acquire) {
+            return Err(GalleonError::InvalidState("the saved key table hasn't been loaded"));
+        }
+        let mut key = [0u8; crypto::KEY_SIZE];
+        self.rng.lock().as_mut()
+            .ok_or(GalleonError::InvalidState("no entropy source for new keys"))?
+            .fill_bytes(&mut key);
         let key_id = ObjectId::new().as_u64();
-        let key_size = match algorithm {
-            EncryptionAlgorithm::None => 0,
-            EncryptionAlgorithm::Aes256Gcm => 32,
-            EncryptionAlgorithm::ChaCha20Poly1305 => 32,
-            EncryptionAlgorithm::Aes256Ctr => 32,
-        };
+        let wrapped = self.wrap_key(key_id, algorithm, &key, Timestamp::now())?;
+        self.keys.lock().insert(key_id, wrapped);
+        self.changed();
+        Ok(key_id)
+    }
 
-        let mut key_data = vec![0u8; key_size];
-        // TODO: Fill with cryptographically secure random data
-        
-        let mut iv = vec![0u8; 16]; // Standard IV size
-        // TODO: Fill with cryptographically secure random data
-
-        let key = EncryptionKey {
-            id: key_id,
-            algorithm,
-            key_data,
-            iv,
-            created_at: Timestamp::now(),
-            expires_at: None,
-        };
+    fn master_key(&self) -> Result<[u8; crypto::KEY_SIZE]> {
+        self.master_key.lock().ok_or(GalleonError::InvalidState("no master key configured"))
+    }
 
-        let mut keys = self.keys.lock();
-        keys.insert(key_id, key);
+    /// Key ids are unique, and a key is wrapped once per master key, so the
+    /// id makes a nonce that is never reused
+    fn wrap_key(&self, id: u64, algorithm: EncryptionAlgorithm, key: &[u8; crypto::KEY_SIZE], created_at: Timestamp) -> Result<EncryptionKey> {
+        let nonce = id_nonce(id);
+        let key_data = crypto::seal(&self.master_key()?, &nonce, &id.to_le_bytes(), key);
+        Ok(EncryptionKey { id, algorithm, key_data, iv: nonce.to_vec(), created_at, expires_at: None })
+    }
 
-        Ok(key_id)
+    fn unwrap_key(&self, key_id: u64) -> Result<(EncryptionAlgorithm, [u8; crypto::KEY_SIZE])> {
+        let key = self.keys.lock().get(&key_id).cloned()
+            .ok_or(GalleonError::CryptoError("Key not found"))?;
+        let malformed = GalleonError::CryptoError("malformed wrapped key");
+        let nonce: [u8; crypto::NONCE_SIZE] = key.iv.as_slice().try_into().map_err(|_| malformed.clone())?;
+        let raw = crypto::open(&self.master_key()?, &nonce, &key_id.to_le_bytes(), &key.key_data)?;
+        Ok((key.algorithm, raw.try_into().map_err(|_| malformed)?))
     }
 
-    pub async fn encrypt_data(&self, data: &[u8], key_id: u64) -> Result<Vec<u8>> {
-        let keys = self.keys.lock();
-        let key = keys.get(&key_id)
-            .ok_or(GalleonError::CryptoError("Key not found".into()))?;
+    fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Changes whenever the keys or policies do, so a saved table can tell it is stale
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Refuse to create keys until a saved key table has been imported, or
+    /// allow it again once there is none to import
+    pub fn set_keys_loaded(&self, loaded: bool) {
+        self.keys_loaded.store(loaded, Ordering::Release);
+    }
 
-        match key.algorithm {
-            EncryptionAlgorithm::None => Ok(data.to_vec()),
-            EncryptionAlgorithm::Aes256Gcm => self.encrypt_aes256_gcm(data, key).await,
-            EncryptionAlgorithm::ChaCha20Poly1305 => self.encrypt_chacha20_poly1305(data, key).await,
-            EncryptionAlgorithm::Aes256Ctr => self.encrypt_aes256_ctr(data, key).await,
+    /// The wrapped keys and the policies, for storing next to the data they protect
+    ///
+    /// Keys stay sealed under the master key, which isn't part of the table.
+    pub fn export_keys(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        for key in self.keys.lock().values() {
+            encoder.put_field(KEY_TABLE_KEY, |e| {
+                e.put_varint(key.id);
+                e.put_u8(key.algorithm as u8);
+                e.put_bytes(&key.key_data);
+                e.put_bytes(&key.iv);
+                e.put_timestamp(key.created_at);
+                e.put_bool(key.expires_at.is_some());
+                if let Some(expires_at) = key.expires_at {
+                    e.put_timestamp(expires_at);
+                }
+            });
         }
+        for (object_id, policy) in self.policies.lock().iter() {
+            encoder.put_field(KEY_TABLE_POLICY, |e| {
+                e.put_varint(object_id.as_u64());
+                e.put_u8(policy.algorithm as u8);
+                e.put_varint(policy.key_id);
+                e.put_bool(policy.auto_encrypt);
+                e.put_bool(policy.require_authentication);
+            });
+        }
+        encoder.into_bytes()
+    }
+
+    /// Add the keys and policies of a table from [`export_keys`](Self::export_keys)
+    ///
+    /// Keys and policies this manager already has are kept. Nothing is added
+    /// if the table is corrupt.
+    pub fn import_keys(&self, table: &[u8]) -> Result<()> {
+        let mut keys = Vec::new();
+        let mut policies = Vec::new();
+        let mut decoder = Decoder::new(table);
+        while let Some((tag, mut d)) = decoder.next_field()? {
+            match tag {
+                KEY_TABLE_KEY => {
+                    let id = d.get_varint()?;
+                    let algorithm = EncryptionAlgorithm::from_raw(d.get_u8()?)?;
+                    let key_data = d.get_bytes()?.to_vec();
+                    let iv = d.get_bytes()?.to_vec();
+                    let created_at = d.get_timestamp()?;
+                    let expires_at = if d.get_bool()? { Some(d.get_timestamp()?) } else { None };
+                    keys.push(EncryptionKey { id, algorithm, key_data, iv, created_at, expires_at });
+                }
+                KEY_TABLE_POLICY => {
+                    let object_id = ObjectId(d.get_varint()?);
+                    let algorithm = EncryptionAlgorithm::from_raw(d.get_u8()?)?;
+                    let key_id = d.get_varint()?;
+                    let auto_encrypt = d.get_bool()?;
+                    let require_authentication = d.get_bool()?;
+                    policies.push((object_id, EncryptionPolicy { algorithm, key_id, auto_encrypt, require_authentication }));
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(last) = keys.iter().map(|key| key.id).max() {
+            ObjectId::reserve_through(ObjectId(last));
+        }
+        let mut known = self.keys.lock();
+        for key in keys {
+            known.entry(key.id).or_insert(key);
+        }
+        drop(known);
+        let mut known = self.policies.lock();
+        for (object_id, policy) in policies {
+            known.entry(object_id).or_insert(policy);
+        }
+        Ok(())
+    }
+
+    /// Algorithm of key `key_id`
+    pub fn key_algorithm(&self, key_id: u64) -> Result<EncryptionAlgorithm> {
+        self.keys.lock().get(&key_id).map(|key| key.algorithm).ok_or(GalleonError::CryptoError("Key not found"))
+    }
+
+    /// Encrypt `data` under `key_id`, putting the nonce in front
+    ///
+    /// The nonce hashes the key, a fresh object id and `data`. Nothing records
+    /// the id, so it may come round again after a remount, but then only with
+    /// the same data, which encrypts to the same bytes.
+    pub async fn encrypt_data(&self, data: &[u8], key_id: u64) -> Result<Vec<u8>> {
+        let (algorithm, key) = self.unwrap_key(key_id)?;
+        if algorithm == EncryptionAlgorithm::None {
+            return Ok(data.to_vec());
+        }
+        let mut hashed = key.to_vec();
+        hashed.extend_from_slice(&ObjectId::new().as_u64().to_le_bytes());
+        hashed.extend_from_slice(data);
+        let mut nonce = [0u8; crypto::NONCE_SIZE];
+        nonce.copy_from_slice(&crypto::sha256(&hashed)[..crypto::NONCE_SIZE]);
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&self.encrypt_block(key_id, &nonce, &[], data)?);
+        Ok(encrypted)
     }
 
     pub async fn decrypt_data(&self, data: &[u8], key_id: u64) -> Result<Vec<u8>> {
-        let keys = self.keys.lock();
-        let key = keys.get(&key_id)
-            .ok_or(GalleonError::CryptoError("Key not found".into()))?;
+        if self.key_algorithm(key_id)? == EncryptionAlgorithm::None {
+            return Ok(data.to_vec());
+        }
+        if data.len() < crypto::NONCE_SIZE {
+            return Err(GalleonError::CryptoError("encrypted data too short"));
+        }
+        let (nonce, sealed) = data.split_at(crypto::NONCE_SIZE);
+        self.decrypt_block(key_id, nonce.try_into().unwrap(), &[], sealed)
+    }
 
-        match key.algorithm {
-            EncryptionAlgorithm::None => Ok(data.to_vec()),
-            EncryptionAlgorithm::Aes256Gcm => self.decrypt_aes256_gcm(data, key).await,
-            EncryptionAlgorithm::ChaCha20Poly1305 => self.decrypt_chacha20_poly1305(data, key).await,
-            EncryptionAlgorithm::Aes256Ctr => self.decrypt_aes256_ctr(data, key).await,
+    /// Encrypt and authenticate `data` together with `aad`, which isn't stored
+    ///
+    /// The caller picks the nonce and must never reuse one under the same key.
+    pub fn encrypt_block(&self, key_id: u64, nonce: &[u8; crypto::NONCE_SIZE], aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        match self.unwrap_key(key_id)? {
+            (EncryptionAlgorithm::None, _) => Ok(data.to_vec()),
+            (EncryptionAlgorithm::ChaCha20Poly1305, key) => Ok(crypto::seal(&key, nonce, aad, data)),
+            _ => Err(GalleonError::NotSupported),
         }
     }
 
-    async fn encrypt_aes256_gcm(&self, _data: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>> {
-        // TODO: Implement AES-256-GCM encryption
-        Err(GalleonError::NotSupported)
+    /// Decrypt the output of [`encrypt_block`](Self::encrypt_block), refusing
+    /// anything that was altered
+    pub fn decrypt_block(&self, key_id: u64, nonce: &[u8; crypto::NONCE_SIZE], aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        match self.unwrap_key(key_id)? {
+            (EncryptionAlgorithm::None, _) => Ok(data.to_vec()),
+            (EncryptionAlgorithm::ChaCha20Poly1305, key) => crypto::open(&key, nonce, aad, data),
+            _ => Err(GalleonError::NotSupported),
+        }
     }
 
-    async fn decrypt_aes256_gcm(&self, _data: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>> {
-        // TODO: Implement AES-256-GCM decryption
-        Err(GalleonError::NotSupported)
+    /// Replace `key_id` with a new key for everything written from now on
+    ///
+    /// Policies using the old key switch to the new one. The old key is kept,
+    /// as data written under it stays readable until it is rewritten.
+    pub async fn rotate_key(&self, key_id: u64) -> Result<u64> {
+        let new_key = self.generate_key(self.key_algorithm(key_id)?).await?;
+        for policy in self.policies.lock().values_mut() {
+            if policy.key_id == key_id {
+                policy.key_id = new_key;
+            }
+        }
+        self.changed();
+        Ok(new_key)
     }
 
-    async fn encrypt_chacha20_poly1305(&self, _data: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>> {
-        // TODO: Implement ChaCha20-Poly1305 encryption
-        Err(GalleonError::NotSupported)
+    /// Wrap every data key under `master_key` instead; no data is re-encrypted
+    pub async fn rotate_master_key(&self, master_key: [u8; crypto::KEY_SIZE]) -> Result<()> {
+        let ids: Vec<u64> = self.keys.lock().keys().copied().collect();
+        let mut unwrapped = Vec::with_capacity(ids.len());
+        for id in ids {
+            unwrapped.push((id, self.unwrap_key(id)?));
+        }
+        *self.master_key.lock() = Some(master_key);
+        for (id, (algorithm, key)) in unwrapped {
+            let created_at = self.keys.lock()[&id].created_at;
+            let wrapped = self.wrap_key(id, algorithm, &key, created_at)?;
+            self.keys.lock().insert(id, wrapped);
+        }
+        self.changed();
+        Ok(())
     }
 
-    async fn decrypt_chacha20_poly1305(&self, _data: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>> {
-        // TODO: Implement ChaCha20-Poly1305 decryption
-        Err(GalleonError::NotSupported)
+    /// Set the policy [`ChunkedStorage`](crate::ChunkedStorage) enforces on writes of `object_id`
+    pub async fn set_encryption_policy(&self, object_id: ObjectId, policy: EncryptionPolicy) -> Result<()> {
+        check_cipher(policy.algorithm)?;
+        if policy.algorithm != EncryptionAlgorithm::None && self.key_algorithm(policy.key_id)? != policy.algorithm {
+            return Err(GalleonError::InvalidArgument("policy algorithm differs from its key's"));
+        }
+        self.policies.lock().insert(object_id, policy);
+        self.changed();
+        Ok(())
     }
 
-    async fn encrypt_aes256_ctr(&self, _data: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>> {
-        // TODO: Implement AES-256-CTR encryption
-        Err(GalleonError::NotSupported)
+    pub async fn get_encryption_policy(&self, object_id: ObjectId) -> Result<Option<EncryptionPolicy>> {
+        Ok(self.policies.lock().get(&object_id).cloned())
     }
+}
 
-    async fn decrypt_aes256_ctr(&self, _data: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>> {
-        // TODO: Implement AES-256-CTR decryption
-        Err(GalleonError::NotSupported)
+/// A block in the deduplication index
+#[derive(Debug, Clone)]
+struct IndexedBlock {
+    hash: [u8; 32],
+    size: u64,
+    references: u32,
+    /// Files whose chunks are stored in the block
+    files: BTreeSet<ObjectId>,
+}
+
+#[derive(Default)]
+struct DeduplicationIndex {
+    by_hash: BTreeMap<[u8; 32], u64>,
+    blocks: BTreeMap<u64, IndexedBlock>,
+}
+
+/// Deduplication manager
+///
+/// Keeps which files are deduplicated and an index of the content hashes of
+/// their committed blocks, so a [`ChunkedStorage`](crate::ChunkedStorage) can
+/// store a chunk it has seen before as another reference to the same block.
+/// The index is built from committed transactions and lives in memory.
+pub struct DeduplicationManager {
+    enabled: spin::Mutex<BTreeSet<ObjectId>>,
+    index: spin::Mutex<DeduplicationIndex>,
+}
+
+impl Default for DeduplicationManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeduplicationManager {
+    pub fn new() -> Self {
+        Self {
+            enabled: spin::Mutex::new(BTreeSet::new()),
+            index: spin::Mutex::new(DeduplicationIndex::default()),
+        }
+    }
+
+    /// SHA-256 of a chunk's contents, which identifies its block
+    pub fn calculate_hash(data: &[u8]) -> [u8; 32] {
+        crypto::sha256(data)
+    }
+
+    pub async fn enable_deduplication(&self, object_id: ObjectId) -> Result<()> {
+        self.enabled.lock().insert(object_id);
+        Ok(())
+    }
+
+    pub async fn disable_deduplication(&self, object_id: ObjectId) -> Result<()> {
+        self.enabled.lock().remove(&object_id);
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, object_id: ObjectId) -> bool {
+        self.enabled.lock().contains(&object_id)
+    }
+
+    /// Committed block holding data with `hash`, if any
+    pub fn lookup(&self, hash: &[u8; 32]) -> Option<u64> {
+        self.index.lock().by_hash.get(hash).copied()
+    }
+
+    /// Files with a chunk whose contents hash to `hash`
+    pub async fn find_duplicates(&self, hash: &[u8; 32]) -> Result<Vec<ObjectId>> {
+        let index = self.index.lock();
+        Ok(index.by_hash.get(hash)
+            .and_then(|block| index.blocks.get(block))
+            .map(|block| block.files.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    /// Number of blocks with more than one reference, and the bytes saved by sharing them
+    pub fn saved_space(&self) -> (u64, u64) {
+        let index = self.index.lock();
+        index.blocks.values()
+            .filter(|block| block.references > 1)
+            .fold((0, 0), |(shared, saved), block| (shared + 1, saved + (block.references as u64 - 1) * block.size))
+    }
+
+    /// Bring the index up to date with a transaction that has just committed
+    ///
+    /// Blocks are the inodes with a content hash and no chunk layout of their
+    /// own; chunked files are the ones with one.
+    pub fn record_committed(&self, operations: &[TransactionOperation]) {
+        let mut index = self.index.lock();
+        for operation in operations {
+            match operation {
+                TransactionOperation::CreateInode { inode, .. } | TransactionOperation::UpdateInode { new_inode: inode, .. } => {
+                    if let (Some(hash), None) = (inode.dedup_hash(), inode.chunked_size()) {
+                        let block = index.blocks.entry(inode.id().as_u64()).or_insert_with(|| IndexedBlock {
+                            hash: *hash,
+                            size: 0,
+                            references: 0,
+                            files: BTreeSet::new(),
+                        });
+                        block.size = inode.size();
+                        block.references = inode.link_count();
+                        index.by_hash.entry(*hash).or_insert(inode.id().as_u64());
+                    }
+                }
+                TransactionOperation::DeleteInode { id, .. } => {
+                    if let Some(block) = index.blocks.remove(&id.as_u64()) {
+                        if index.by_hash.get(&block.hash) == Some(&id.as_u64()) {
+                            index.by_hash.remove(&block.hash);
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            // Which files use which blocks
+            let (id, old_blocks, new_blocks): (ObjectId, &[u64], &[u64]) = match operation {
+                TransactionOperation::CreateInode { id, inode } if inode.chunked_size().is_some() => (*id, &[], inode.blocks()),
+                TransactionOperation::UpdateInode { id, old_inode, new_inode } if new_inode.chunked_size().is_some() || old_inode.chunked_size().is_some() => {
+                    (*id, old_inode.blocks(), new_inode.blocks())
+                }
+                TransactionOperation::DeleteInode { id, inode } if inode.chunked_size().is_some() => (*id, inode.blocks(), &[]),
+                _ => continue,
+            };
+            for block in old_blocks.iter().filter(|block| !new_blocks.contains(block)) {
+                if let Some(block) = index.blocks.get_mut(block) {
+                    block.files.remove(&id);
+                }
+            }
+            for block in new_blocks {
+                if let Some(block) = index.blocks.get_mut(block) {
+                    block.files.insert(id);
+                }
+            }
+        }
     }
 }
 
@@ -557,6 +1197,7 @@ pub struct GalleonAdvancedFeatures {
     snapshot_manager: Arc<SnapshotManager>,
     compression_manager: Arc<CompressionManager>,
     encryption_manager: Arc<EncryptionManager>,
+    deduplication_manager: Arc<DeduplicationManager>,
     quota_manager: Arc<QuotaManager>,
     journal_manager: Arc<JournalManager>,
 }
@@ -567,10 +1208,35 @@ impl GalleonAdvancedFeatures {
             snapshot_manager: Arc::new(SnapshotManager::new()),
             compression_manager: Arc::new(CompressionManager::new()),
             encryption_manager: Arc::new(EncryptionManager::new()),
+            deduplication_manager: Arc::new(DeduplicationManager::new()),
             quota_manager: Arc::new(QuotaManager::new()),
             journal_manager: Arc::new(JournalManager::new()),
         }
     }
+
+    /// Manager that keeps snapshots in the storage attached to it
+    pub fn snapshot_manager(&self) -> Arc<SnapshotManager> {
+        self.snapshot_manager.clone()
+    }
+
+    /// Manager whose policies a [`ChunkedStorage`](crate::ChunkedStorage) should follow
+    pub fn compression_manager(&self) -> Arc<CompressionManager> {
+        self.compression_manager.clone()
+    }
+
+    pub fn encryption_manager(&self) -> Arc<EncryptionManager> {
+        self.encryption_manager.clone()
+    }
+
+    /// Use `encryption_manager`, which holds the master key, instead of one that can't encrypt
+    pub fn with_encryption_manager(mut self, encryption_manager: EncryptionManager) -> Self {
+        self.encryption_manager = Arc::new(encryption_manager);
+        self
+    }
+
+    pub fn deduplication_manager(&self) -> Arc<DeduplicationManager> {
+        self.deduplication_manager.clone()
+    }
 }
 
 impl AdvancedFeatures for GalleonAdvancedFeatures {
@@ -596,10 +1262,10 @@ impl AdvancedFeatures for GalleonAdvancedFeatures {
         })
     }
 
-    fn restore_from_snapshot(&self, _snapshot_id: ObjectId, _target_id: ObjectId) -> luminal::JoinHandle<Result<()>> {
+    fn restore_from_snapshot(&self, snapshot_id: ObjectId, target_id: ObjectId) -> luminal::JoinHandle<Result<()>> {
+        let mgr = self.snapshot_manager.clone();
         GALLEON_RUNTIME.get().spawn(async move {
-            // TODO: Implement snapshot restoration
-            Err(GalleonError::NotSupported)
+            mgr.restore_from_snapshot(snapshot_id, target_id).await
         })
     }
 
@@ -642,38 +1308,32 @@ impl AdvancedFeatures for GalleonAdvancedFeatures {
         })
     }
 
-    fn set_encryption_policy(&self, _object_id: ObjectId, _policy: EncryptionPolicy) -> luminal::JoinHandle<Result<()>> {
+    fn set_encryption_policy(&self, object_id: ObjectId, policy: EncryptionPolicy) -> luminal::JoinHandle<Result<()>> {
+        let mgr = self.encryption_manager.clone();
         GALLEON_RUNTIME.get().spawn(async move {
-            // TODO: Implement encryption policy setting
-            Err(GalleonError::NotSupported)
+            mgr.set_encryption_policy(object_id, policy).await
         })
     }
 
     fn calculate_hash(&self, data: &[u8]) -> luminal::JoinHandle<Result<[u8; 32]>> {
         let data = data.to_vec();
         GALLEON_RUNTIME.get().spawn(async move {
-            // TODO: Implement SHA-256 hash calculation
-            // For now, return a simple hash
-            let mut hash = [0u8; 32];
-            for (i, &byte) in data.iter().take(32).enumerate() {
-                hash[i] = byte;
-            }
-            Ok(hash)
+            Ok(DeduplicationManager::calculate_hash(&data))
         })
     }
 
     fn find_duplicates(&self, hash: &[u8; 32]) -> luminal::JoinHandle<Result<Vec<ObjectId>>> {
         let hash = *hash;
+        let mgr = self.deduplication_manager.clone();
         GALLEON_RUNTIME.get().spawn(async move {
-            // TODO: Implement duplicate finding
-            Ok(Vec::new())
+            mgr.find_duplicates(&hash).await
         })
     }
 
-    fn enable_deduplication(&self, _object_id: ObjectId) -> luminal::JoinHandle<Result<()>> {
+    fn enable_deduplication(&self, object_id: ObjectId) -> luminal::JoinHandle<Result<()>> {
+        let mgr = self.deduplication_manager.clone();
         GALLEON_RUNTIME.get().spawn(async move {
-            // TODO: Implement deduplication enabling
-            Err(GalleonError::NotSupported)
+            mgr.enable_deduplication(object_id).await
         })
     }
 
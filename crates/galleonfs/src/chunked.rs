@@ -0,0 +1,687 @@
+//! Chunked object storage for GalleonFS
+//!
+//! Files that are compressed, encrypted or deduplicated keep their data in
+//! fixed-size chunks, each encoded on its own and stored as a separate block
+//! object of the backend underneath. The file's inode lists its blocks in
+//! order, so a write only re-encodes the chunks it touches and a read only
+//! decodes the ones it needs. Blocks are reference counted by their link
+//! count and never modified: a write stores new blocks and releases the old
+//! ones, so a block can be shared between files.
+
+use alloc::{vec::Vec, sync::Arc, boxed::Box, string::String};
+use core::{future::Future, pin::Pin, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+use super::{
+    Result, ObjectId, Inode, InodeType, Permissions, Transaction, TransactionOperation, StorageBackend,
+    StorageCapabilities, FilesystemStats, CompressionInfo, CompressionAlgorithm, CompressionManager,
+    CompressionPolicy, EncryptionInfo, EncryptionAlgorithm, EncryptionManager, DeduplicationManager,
+    GalleonAdvancedFeatures, GalleonError, codec, crypto,
+};
+
+/// Bytes of file data per chunk
+pub const CHUNK_SIZE: u64 = 16 * 1024;
+
+/// Block list entry for a chunk that was never written, which reads as zeros
+const HOLE: u64 = 0;
+
+/// Flags in the first byte of every block, saying how the rest encodes the chunk
+const FRAME_LZ4: u8 = 1;
+/// Encrypted; the id of the key comes next, then the sealed chunk
+const FRAME_SEALED: u8 = 2;
+
+/// How new chunks of a file are encoded
+struct Encoding {
+    compression: Option<CompressionPolicy>,
+    key_id: Option<u64>,
+    /// Share blocks with identical contents
+    deduplicate: bool,
+}
+
+/// Nonce for sealing `block`: the file's nonce prefix and the block's id
+///
+/// Block ids come from the object id counter, which the volume persists, so
+/// no id and hence no nonce is used twice under a key.
+fn block_nonce(encryption: &EncryptionInfo, block: u64) -> [u8; crypto::NONCE_SIZE] {
+    let mut nonce = [0u8; crypto::NONCE_SIZE];
+    nonce[..4].copy_from_slice(&encryption.iv[..4]);
+    nonce[4..].copy_from_slice(&block.to_le_bytes());
+    nonce
+}
+
+/// Data a sealed chunk is bound to, so it can't be moved to another file or position
+fn chunk_aad(encryption: &EncryptionInfo, index: usize) -> Vec<u8> {
+    let mut aad = encryption.iv.clone();
+    aad.extend_from_slice(&(index as u64).to_le_bytes());
+    aad
+}
+
+/// `data` as it will be once `transaction`'s recorded writes to `id` are applied
+fn with_pending_writes(mut data: Vec<u8>, transaction: &Transaction, id: ObjectId) -> Vec<u8> {
+    for operation in transaction.operations() {
+        match operation {
+            TransactionOperation::WriteData { id: target, offset, data: written } if target == id => {
+                let end = offset as usize + written.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset as usize..end].copy_from_slice(&written);
+            }
+            TransactionOperation::TruncateData { id: target, new_size, .. } if target == id => {
+                data.resize(new_size as usize, 0);
+            }
+            TransactionOperation::DeleteInode { id: target, .. } if target == id => data.clear(),
+            _ => {}
+        }
+    }
+    data
+}
+
+/// Whether `inode`'s data lives in chunks rather than in the object itself
+fn is_chunked(inode: &Inode) -> bool {
+    inode.chunked_size().is_some()
+}
+
+/// `inode` with the chunk layout of `current`, the stored inode it replaces
+///
+/// The layout is only ever changed here, so an inode read before a write and
+/// written back afterwards doesn't lose the blocks the write added.
+fn keep_layout(inode: &Inode, current: Option<&Inode>) -> Inode {
+    let mut inode = inode.clone();
+    inode.set_blocks(current.map(|current| current.blocks().clone()).unwrap_or_default());
+    match current.and_then(|current| current.chunked_size()) {
+        Some(size) => inode.set_chunked_size(size),
+        None => inode.clear_chunked_size(),
+    }
+    match current.and_then(|current| current.compression()) {
+        Some(compression) => inode.set_compression(compression.clone()),
+        None => inode.clear_compression(),
+    }
+    match current.and_then(|current| current.encryption()) {
+        Some(encryption) => inode.set_encryption(encryption.clone()),
+        None => inode.clear_encryption(),
+    }
+    // Content hashes mark blocks, which files must not be taken for
+    match current.and_then(|current| current.dedup_hash()) {
+        Some(hash) => inode.set_dedup_hash(*hash),
+        None => inode.clear_dedup_hash(),
+    }
+    inode
+}
+
+/// Storage backend that stores compressed, encrypted and deduplicated files in chunks
+///
+/// Policies come from the managers of a [`GalleonAdvancedFeatures`] and apply
+/// to regular files. A file that was stored whole is converted on its first
+/// write under a policy; compression waits until the file reaches the
+/// policy's `min_file_size`.
+///
+/// Each chunk is compressed with the policy's algorithm and kept as is if
+/// that doesn't make it smaller, and the file's [`CompressionInfo`] records
+/// the totals. The stored size of a file's chunks is kept in its inode
+/// whether or not they are compressed. Encrypted files get an [`EncryptionInfo`] made from their id
+/// and key; every chunk is then sealed under the policy's key with a nonce
+/// from its block id and bound to its file and position, and a chunk that
+/// fails to authenticate can't be read. A file stays encrypted once it is,
+/// under the key it was last written with if its policy is removed.
+///
+/// The [`EncryptionManager`]'s wrapped keys and policies are kept in the
+/// [`ObjectId::key_table`] object, loaded before the first read or write
+/// and rewritten by every commit that follows a change to them.
+///
+/// Chunks of deduplicated files are looked up by their SHA-256 in the
+/// [`DeduplicationManager`]'s index and in the transaction being written,
+/// and stored as another reference to a block with the same contents if
+/// there is one. Encrypted files are never deduplicated.
+///
+/// Exclude patterns are matched against names, which this layer doesn't see.
+pub struct ChunkedStorage {
+    inner: Arc<dyn StorageBackend>,
+    compression: Arc<CompressionManager>,
+    encryption: Arc<EncryptionManager>,
+    deduplication: Arc<DeduplicationManager>,
+    keys_loaded: AtomicBool,
+    /// Generation of the encryption manager's keys that the key table holds
+    keys_saved: AtomicU64,
+}
+
+impl ChunkedStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, features: &GalleonAdvancedFeatures) -> Self {
+        let encryption = features.encryption_manager();
+        encryption.set_keys_loaded(false);
+        Self {
+            inner,
+            compression: features.compression_manager(),
+            keys_saved: AtomicU64::new(encryption.generation()),
+            encryption,
+            deduplication: features.deduplication_manager(),
+            keys_loaded: AtomicBool::new(false),
+        }
+    }
+
+    /// The backend holding the blocks
+    pub fn inner(&self) -> &dyn StorageBackend {
+        self.inner.as_ref()
+    }
+
+    /// Inode of `id` as `transaction` leaves it so far, or `None` if there is none
+    async fn current_inode(&self, id: ObjectId, transaction: &Transaction) -> Result<Option<Inode>> {
+        if let Some(pending) = transaction.pending_inode(id) {
+            return Ok(pending);
+        }
+        match self.inner.read_inode(id).await {
+            Ok(inode) => Ok(Some(inode)),
+            Err(GalleonError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Data of a stored object, including what `transaction` has written to it
+    async fn stored_data(&self, id: ObjectId, transaction: Option<&Transaction>) -> Result<Vec<u8>> {
+        let committed = match self.inner.read_data(id, 0, u64::MAX).await {
+            Err(GalleonError::NotFound) => Vec::new(),
+            result => result?,
+        };
+        Ok(match transaction {
+            Some(transaction) => with_pending_writes(committed, transaction, id),
+            None => committed,
+        })
+    }
+
+    /// Import the key table saved on the volume, unless that has been done
+    ///
+    /// The encryption manager refuses to create keys until then, as their
+    /// ids could clash with saved ones. Reads and writes load the table
+    /// first; calling this on mount reports a damaged table straight away.
+    pub async fn load_keys(&self) -> Result<()> {
+        if self.keys_loaded.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        match self.inner.read_data(ObjectId::key_table(), 0, u64::MAX).await {
+            Ok(table) => self.encryption.import_keys(&table)?,
+            Err(GalleonError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+        self.encryption.set_keys_loaded(true);
+        self.keys_loaded.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Operations replacing the saved key table with the encryption manager's
+    async fn key_table_operations(&self) -> Result<Vec<TransactionOperation>> {
+        let id = ObjectId::key_table();
+        let transaction = Transaction::new();
+        if self.inner.exists(id).await? {
+            self.inner.truncate(id, 0, &transaction).await?;
+        } else {
+            let inode = Inode::new(id, InodeType::RegularFile, Permissions::new(0o600, 0, 0), 0);
+            self.inner.write_inode(&inode, &transaction).await?;
+        }
+        self.inner.write_data(id, 0, &self.encryption.export_keys(), &transaction).await?;
+        Ok(transaction.operations())
+    }
+
+    /// How to encode new chunks of `inode`
+    async fn encoding(&self, inode: &Inode) -> Result<Encoding> {
+        self.load_keys().await?;
+        if inode.inode_type() != InodeType::RegularFile {
+            return Ok(Encoding { compression: None, key_id: None, deduplicate: false });
+        }
+        let compression = self.compression.get_compression_policy(inode.id()).await?
+            .filter(|policy| policy.auto_compress);
+        let policy_key = self.encryption.get_encryption_policy(inode.id()).await?
+            .filter(|policy| policy.auto_encrypt && policy.algorithm != EncryptionAlgorithm::None)
+            .map(|policy| policy.key_id);
+        let key_id = policy_key.or_else(|| inode.encryption().map(|encryption| encryption.key_id));
+        let deduplicate = key_id.is_none() && self.deduplication.is_enabled(inode.id());
+        Ok(Encoding { compression, key_id, deduplicate })
+    }
+
+    fn encode(&self, inode: &Inode, index: usize, block: u64, chunk: &[u8], encoding: &Encoding) -> Result<Vec<u8>> {
+        let mut flags = 0;
+        let mut payload = chunk.to_vec();
+        if encoding.compression.as_ref().is_some_and(|policy| policy.algorithm == CompressionAlgorithm::Lz4) {
+            let compressed = codec::lz4_compress(chunk);
+            // Incompressible chunks are kept as they are
+            if compressed.len() < chunk.len() {
+                flags |= FRAME_LZ4;
+                payload = compressed;
+            }
+        }
+
+        let mut frame = Vec::with_capacity(payload.len() + 9 + crypto::TAG_SIZE);
+        if let (Some(key_id), Some(encryption)) = (encoding.key_id, inode.encryption()) {
+            flags |= FRAME_SEALED;
+            payload = self.encryption.encrypt_block(key_id, &block_nonce(encryption, block), &chunk_aad(encryption, index), &payload)?;
+            frame.push(flags);
+            frame.extend_from_slice(&key_id.to_le_bytes());
+        } else {
+            frame.push(flags);
+        }
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    fn decode(&self, inode: &Inode, index: usize, block: u64, frame: &[u8]) -> Result<Vec<u8>> {
+        let (&flags, mut payload) = frame.split_first().ok_or(GalleonError::Corruption("empty chunk"))?;
+        if flags & !(FRAME_LZ4 | FRAME_SEALED) != 0 {
+            return Err(GalleonError::Corruption("unknown chunk encoding"));
+        }
+        let opened;
+        match (flags & FRAME_SEALED != 0, inode.encryption()) {
+            (true, Some(encryption)) if payload.len() >= 8 && encryption.iv.len() >= 4 => {
+                let (key_id, sealed) = payload.split_at(8);
+                let key_id = u64::from_le_bytes(key_id.try_into().unwrap());
+                opened = self.encryption.decrypt_block(key_id, &block_nonce(encryption, block), &chunk_aad(encryption, index), sealed)?;
+                payload = &opened;
+            }
+            (false, None) => {}
+            (false, Some(_)) => return Err(GalleonError::CryptoError("unsealed chunk in an encrypted file")),
+            (true, _) => return Err(GalleonError::Corruption("malformed sealed chunk")),
+        }
+
+        if flags & FRAME_LZ4 != 0 {
+            codec::lz4_decompress(payload, CHUNK_SIZE as usize)
+        } else if payload.len() as u64 <= CHUNK_SIZE {
+            Ok(payload.to_vec())
+        } else {
+            Err(GalleonError::Corruption("chunk larger than chunk size"))
+        }
+    }
+
+    /// Decoded contents of chunk `index` of `inode`, empty for a hole
+    async fn read_chunk(&self, inode: &Inode, index: usize, transaction: Option<&Transaction>) -> Result<Vec<u8>> {
+        match inode.blocks().get(index) {
+            None | Some(&HOLE) => Ok(Vec::new()),
+            Some(&block) => self.decode(inode, index, block, &self.stored_data(ObjectId(block), transaction).await?),
+        }
+    }
+
+    /// Store `chunk` as chunk `index` of `inode` in a new block, returning
+    /// the block's id and size
+    async fn store_chunk(&self, inode: &Inode, index: usize, chunk: &[u8], encoding: &Encoding, transaction: &Transaction) -> Result<(u64, u64)> {
+        let hash = encoding.deduplicate.then(|| DeduplicationManager::calculate_hash(chunk));
+        if let Some(hash) = &hash {
+            if let Some(mut block) = self.find_block(hash, transaction).await? {
+                block.increment_link_count();
+                self.inner.write_inode(&block, transaction).await?;
+                return Ok((block.id().as_u64(), block.size()));
+            }
+        }
+
+        let id = ObjectId::new();
+        let frame = self.encode(inode, index, id.as_u64(), chunk, encoding)?;
+        let mut block = Inode::new(id, InodeType::RegularFile, Permissions::new(0o600, 0, 0), frame.len() as u64);
+        if let Some(hash) = hash {
+            block.set_dedup_hash(hash);
+        }
+        self.inner.write_inode(&block, transaction).await?;
+        self.inner.write_data(id, 0, &frame, transaction).await?;
+        Ok((id.as_u64(), frame.len() as u64))
+    }
+
+    /// Block holding a chunk with `hash`, either written earlier in `transaction` or committed
+    async fn find_block(&self, hash: &[u8; 32], transaction: &Transaction) -> Result<Option<Inode>> {
+        let pending = transaction.operations().into_iter().rev().find_map(|operation| match operation {
+            TransactionOperation::CreateInode { inode, .. } if inode.dedup_hash() == Some(hash) => Some(inode.id()),
+            _ => None,
+        });
+        let Some(id) = pending.or_else(|| self.deduplication.lookup(hash).map(ObjectId)) else {
+            return Ok(None);
+        };
+        // The block may have been released since
+        let block = self.current_inode(id, transaction).await?;
+        Ok(block.filter(|block| block.dedup_hash() == Some(hash)))
+    }
+
+    /// Drop a reference to `block`, returning the size of its frame
+    async fn release_block(&self, block: u64, transaction: &Transaction) -> Result<u64> {
+        if block == HOLE {
+            return Ok(0);
+        }
+        let id = ObjectId(block);
+        let Some(mut inode) = self.current_inode(id, transaction).await? else {
+            return Ok(0);
+        };
+        inode.decrement_link_count();
+        if inode.link_count() == 0 {
+            self.inner.delete_inode(id, transaction).await?;
+        } else {
+            self.inner.write_inode(&inode, transaction).await?;
+        }
+        Ok(inode.size())
+    }
+
+    /// Record the stored size of `inode`'s chunks, `stored` bytes in all, and
+    /// the compression totals if they are compressed
+    fn set_stored_size(inode: &mut Inode, stored: u64, encoding: &Encoding) {
+        inode.set_chunked_size(stored);
+        let algorithm = encoding.compression.as_ref().map(|policy| policy.algorithm)
+            .or_else(|| inode.compression().map(|compression| compression.algorithm));
+        let Some(algorithm) = algorithm else {
+            return;
+        };
+        let uncompressed_size = inode.size();
+        inode.set_compression(CompressionInfo {
+            algorithm,
+            compressed_size: stored,
+            uncompressed_size,
+            compression_ratio: if stored == 0 { 1.0 } else { uncompressed_size as f32 / stored as f32 },
+        });
+    }
+
+    /// Write `data` at `offset` into the chunks of `inode`, returning the updated inode
+    async fn write_chunks(&self, mut inode: Inode, offset: u64, data: &[u8], encoding: &Encoding, transaction: &Transaction) -> Result<Inode> {
+        if data.is_empty() {
+            return Ok(inode);
+        }
+        let end = offset + data.len() as u64;
+        let old_size = inode.size();
+        let mut blocks = inode.blocks().clone();
+        let mut stored = inode.chunked_size().unwrap_or(0);
+
+        for index in (offset / CHUNK_SIZE)..=((end - 1) / CHUNK_SIZE) {
+            let start = index * CHUNK_SIZE;
+            let index = index as usize;
+            let mut chunk = self.read_chunk(&inode, index, Some(transaction)).await?;
+            let existing = old_size.saturating_sub(start).min(CHUNK_SIZE) as usize;
+            let from = (offset.max(start) - start) as usize;
+            let to = (end.min(start + CHUNK_SIZE) - start) as usize;
+            chunk.resize(existing.max(to), 0);
+            chunk[from..to].copy_from_slice(&data[(start + from as u64 - offset) as usize..(start + to as u64 - offset) as usize]);
+
+            let (block, frame_size) = self.store_chunk(&inode, index, &chunk, encoding, transaction).await?;
+            stored += frame_size;
+            if index < blocks.len() {
+                stored = stored.saturating_sub(self.release_block(blocks[index], transaction).await?);
+                blocks[index] = block;
+            } else {
+                blocks.resize(index, HOLE);
+                blocks.push(block);
+            }
+        }
+
+        inode.set_blocks(blocks);
+        inode.set_size(old_size.max(end));
+        Self::set_stored_size(&mut inode, stored, encoding);
+        Ok(inode)
+    }
+
+    async fn truncate_chunks(&self, mut inode: Inode, size: u64, transaction: &Transaction) -> Result<Inode> {
+        let encoding = self.encoding(&inode).await?;
+        let mut blocks = inode.blocks().clone();
+        let mut stored = inode.chunked_size().unwrap_or(0);
+        let kept = size.div_ceil(CHUNK_SIZE) as usize;
+
+        for block in blocks.split_off(kept.min(blocks.len())) {
+            stored = stored.saturating_sub(self.release_block(block, transaction).await?);
+        }
+        // The chunk the file now ends in keeps only what is still part of it
+        let tail = (size % CHUNK_SIZE) as usize;
+        if tail != 0 && kept == blocks.len() && blocks[kept - 1] != HOLE {
+            let mut chunk = self.read_chunk(&inode, kept - 1, Some(transaction)).await?;
+            if chunk.len() > tail {
+                chunk.truncate(tail);
+                let (block, frame_size) = self.store_chunk(&inode, kept - 1, &chunk, &encoding, transaction).await?;
+                stored += frame_size;
+                stored = stored.saturating_sub(self.release_block(blocks[kept - 1], transaction).await?);
+                blocks[kept - 1] = block;
+            }
+        }
+
+        inode.set_blocks(blocks);
+        inode.set_size(size);
+        Self::set_stored_size(&mut inode, stored, &encoding);
+        Ok(inode)
+    }
+
+    /// Re-encode everything `inode` stores, whole or in plaintext chunks,
+    /// into new chunks, sealing them if `encoding` has a key the file wasn't
+    /// encrypted with
+    async fn rechunk(&self, mut inode: Inode, encoding: &Encoding, transaction: &Transaction) -> Result<Inode> {
+        let existing = if is_chunked(&inode) {
+            let existing = self.read_chunks(&inode, 0, u64::MAX, Some(transaction)).await?;
+            for &block in inode.blocks() {
+                self.release_block(block, transaction).await?;
+            }
+            inode.set_blocks(Vec::new());
+            existing
+        } else {
+            let existing = self.stored_data(inode.id(), Some(transaction)).await?;
+            if !existing.is_empty() {
+                self.inner.truncate(inode.id(), 0, transaction).await?;
+            }
+            existing
+        };
+        if let Some(key_id) = encoding.key_id {
+            inode.set_encryption(EncryptionInfo {
+                algorithm: self.encryption.key_algorithm(key_id)?,
+                key_id,
+                iv: [inode.id().as_u64().to_le_bytes(), key_id.to_le_bytes()].concat(),
+                authenticated: true,
+            });
+        }
+        inode.set_size(inode.size().max(existing.len() as u64));
+        Self::set_stored_size(&mut inode, 0, encoding);
+        self.write_chunks(inode, 0, &existing, encoding, transaction).await
+    }
+
+    /// Make `target` a copy-on-write copy of the regular file `source`,
+    /// returning the inode written for it
+    ///
+    /// The copy takes the source's metadata and shares its blocks, so it
+    /// costs one reference per chunk; a file stored whole is moved into
+    /// chunks first. An existing target keeps its link count and releases
+    /// the data it had.
+    pub async fn clone_file(&self, source: ObjectId, target: ObjectId, transaction: &Transaction) -> Result<Inode> {
+        let mut source = self.current_inode(source, transaction).await?.ok_or(GalleonError::NotFound)?;
+        if source.inode_type() != InodeType::RegularFile {
+            return Err(GalleonError::InvalidArgument("only regular files can be cloned"));
+        }
+        if !is_chunked(&source) {
+            let mut encoding = self.encoding(&source).await?;
+            let compress = encoding.compression.as_ref().is_some_and(|policy| source.size() >= policy.min_file_size);
+            if !compress {
+                encoding.compression = None;
+            }
+            source = self.rechunk(source, &encoding, transaction).await?;
+            self.inner.write_inode(&source, transaction).await?;
+        }
+
+        let mut clone = source.with_id(target);
+        clone.clear_dedup_hash();
+        if let Some(existing) = self.current_inode(target, transaction).await? {
+            if is_chunked(&existing) {
+                for &block in existing.blocks() {
+                    self.release_block(block, transaction).await?;
+                }
+            } else if !self.stored_data(target, Some(transaction)).await?.is_empty() {
+                self.inner.truncate(target, 0, transaction).await?;
+            }
+            clone.set_link_count(existing.link_count());
+        }
+        for &block in source.blocks().iter().filter(|&&block| block != HOLE) {
+            if let Some(mut block) = self.current_inode(ObjectId(block), transaction).await? {
+                block.increment_link_count();
+                self.inner.write_inode(&block, transaction).await?;
+            }
+        }
+        self.inner.write_inode(&clone, transaction).await?;
+        Ok(clone)
+    }
+
+    /// Blocks holding the chunks of `inode`, whose link counts say how many
+    /// references to each there are
+    pub async fn block_inodes(&self, inode: &Inode) -> Result<Vec<Inode>> {
+        let mut blocks = Vec::new();
+        for &block in inode.blocks().iter().filter(|&&block| block != HOLE) {
+            blocks.push(self.inner.read_inode(ObjectId(block)).await?);
+        }
+        Ok(blocks)
+    }
+
+    async fn read_chunks(&self, inode: &Inode, offset: u64, length: u64, transaction: Option<&Transaction>) -> Result<Vec<u8>> {
+        self.load_keys().await?;
+        let size = inode.size();
+        let end = offset.saturating_add(length).min(size);
+        let mut data = Vec::new();
+        if offset >= end {
+            return Ok(data);
+        }
+
+        for index in (offset / CHUNK_SIZE)..=((end - 1) / CHUNK_SIZE) {
+            let start = index * CHUNK_SIZE;
+            let mut chunk = self.read_chunk(inode, index as usize, transaction).await?;
+            chunk.resize((size - start).min(CHUNK_SIZE) as usize, 0);
+            let from = (offset.max(start) - start) as usize;
+            let to = (end.min(start + CHUNK_SIZE) - start) as usize;
+            data.extend_from_slice(&chunk[from..to]);
+        }
+        Ok(data)
+    }
+}
+
+impl StorageBackend for ChunkedStorage {
+    fn exists(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<bool>> + Send + '_>> {
+        self.inner.exists(id)
+    }
+
+    fn read_inode(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>> {
+        self.inner.read_inode(id)
+    }
+
+    fn write_inode(&self, inode: &Inode, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let inode = inode.clone();
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            let current = self.current_inode(inode.id(), &transaction).await?;
+            self.inner.write_inode(&keep_layout(&inode, current.as_ref()), &transaction).await
+        })
+    }
+
+    fn delete_inode(&self, id: ObjectId, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            if let Some(inode) = self.current_inode(id, &transaction).await? {
+                for &block in inode.blocks() {
+                    self.release_block(block, &transaction).await?;
+                }
+            }
+            self.inner.delete_inode(id, &transaction).await
+        })
+    }
+
+    fn read_data(&self, id: ObjectId, offset: u64, length: u64) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + '_>> {
+        Box::pin(async move {
+            let inode = self.inner.read_inode(id).await?;
+            if is_chunked(&inode) {
+                self.read_chunks(&inode, offset, length, None).await
+            } else {
+                self.inner.read_data(id, offset, length).await
+            }
+        })
+    }
+
+    fn write_data(&self, id: ObjectId, offset: u64, data: &[u8], transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+        let data = data.to_vec();
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            let Some(mut inode) = self.current_inode(id, &transaction).await? else {
+                return self.inner.write_data(id, offset, &data, &transaction).await;
+            };
+            let mut encoding = self.encoding(&inode).await?;
+            let end = offset + data.len() as u64;
+            let unsealed = encoding.key_id.is_some() && inode.encryption().is_none();
+
+            if !is_chunked(&inode) || unsealed {
+                let compress = encoding.compression.as_ref().is_some_and(|policy| inode.size().max(end) >= policy.min_file_size);
+                if !unsealed && !compress && !encoding.deduplicate {
+                    return self.inner.write_data(id, offset, &data, &transaction).await;
+                }
+                if !compress {
+                    encoding.compression = None;
+                }
+                inode = self.rechunk(inode, &encoding, &transaction).await?;
+            }
+
+            // After a key rotation, the file records the key it is now written with
+            if let (Some(key_id), Some(encryption)) = (encoding.key_id, inode.encryption()) {
+                if encryption.key_id != key_id {
+                    let mut encryption = encryption.clone();
+                    encryption.key_id = key_id;
+                    inode.set_encryption(encryption);
+                }
+            }
+            let inode = self.write_chunks(inode, offset, &data, &encoding, &transaction).await?;
+            self.inner.write_inode(&inode, &transaction).await?;
+            Ok(data.len() as u64)
+        })
+    }
+
+    fn truncate(&self, id: ObjectId, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            match self.current_inode(id, &transaction).await? {
+                Some(inode) if is_chunked(&inode) => {
+                    let inode = self.truncate_chunks(inode, size, &transaction).await?;
+                    self.inner.write_inode(&inode, &transaction).await
+                }
+                _ => self.inner.truncate(id, size, &transaction).await,
+            }
+        })
+    }
+
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<FilesystemStats>> + Send + '_>> {
+        Box::pin(async move {
+            let mut stats = self.inner.stats().await?;
+            let (shared_blocks, saved) = self.deduplication.saved_space();
+            stats.shared_blocks += shared_blocks;
+            stats.dedup_saved_space += saved;
+            Ok(stats)
+        })
+    }
+
+    fn sync(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            if self.encryption.generation() != self.keys_saved.load(Ordering::Acquire) {
+                self.apply_transaction(&[]).await?;
+            }
+            self.inner.sync().await
+        })
+    }
+
+    fn check_integrity(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send + '_>> {
+        self.inner.check_integrity()
+    }
+
+    fn allocate(&self, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+        self.inner.allocate(size, transaction)
+    }
+
+    fn deallocate(&self, offset: u64, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.inner.deallocate(offset, size, transaction)
+    }
+
+    fn apply_transaction<'a>(&'a self, operations: &'a [TransactionOperation]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.load_keys().await?;
+            let generation = self.encryption.generation();
+            if generation == self.keys_saved.load(Ordering::Acquire) {
+                self.inner.apply_transaction(operations).await?;
+            } else {
+                let mut with_keys = operations.to_vec();
+                with_keys.extend(self.key_table_operations().await?);
+                self.inner.apply_transaction(&with_keys).await?;
+                self.keys_saved.fetch_max(generation, Ordering::AcqRel);
+            }
+            self.deduplication.record_committed(operations);
+            Ok(())
+        })
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let mut capabilities = self.inner.capabilities();
+        capabilities.supports_compression = true;
+        capabilities.supports_encryption = true;
+        capabilities.supports_deduplication = true;
+        capabilities
+    }
+}
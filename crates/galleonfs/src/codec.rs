@@ -0,0 +1,433 @@
+//! Compression codecs for GalleonFS
+//!
+//! Pure Rust and `no_std`:
+//! - LZ4 block format, both directions
+//! - DEFLATE (RFC 1951) and gzip (RFC 1952) decoding, for images produced elsewhere
+
+use alloc::vec::Vec;
+use super::{Result, GalleonError};
+
+/// Shortest match LZ4 encodes
+const MIN_MATCH: usize = 4;
+/// The last match must start at least this far from the end of the block
+const MF_LIMIT: usize = 12;
+/// The block always ends with at least this many literals
+const LAST_LITERALS: usize = 5;
+const HASH_LOG: u32 = 12;
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+fn read_u32(input: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([input[at], input[at + 1], input[at + 2], input[at + 3]])
+}
+
+fn lz4_hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Write an LZ4 length continuation: runs of 255 ended by a smaller byte
+fn put_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+fn put_sequence(output: &mut Vec<u8>, literals: &[u8], offset: usize, match_length: usize) {
+    let literal_code = literals.len().min(15);
+    let match_code = (match_length - MIN_MATCH).min(15);
+    output.push(((literal_code as u8) << 4) | match_code as u8);
+    if literal_code == 15 {
+        put_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+    output.extend_from_slice(&(offset as u16).to_le_bytes());
+    if match_code == 15 {
+        put_length(output, match_length - MIN_MATCH - 15);
+    }
+}
+
+fn put_last_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    let literal_code = literals.len().min(15);
+    output.push((literal_code as u8) << 4);
+    if literal_code == 15 {
+        put_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+}
+
+/// Compress `input` as a single LZ4 block
+///
+/// Greedy matching over a hash of the next four bytes, as the reference
+/// encoder's fast mode does. The block doesn't record its decompressed size.
+pub fn lz4_compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    let mut anchor = 0;
+
+    if input.len() > MF_LIMIT {
+        let mut table = alloc::vec![usize::MAX; 1 << HASH_LOG];
+        let match_limit = input.len() - LAST_LITERALS;
+        let mut position = 0;
+
+        while position + MF_LIMIT < input.len() {
+            let sequence = read_u32(input, position);
+            let slot = &mut table[lz4_hash(sequence)];
+            let candidate = core::mem::replace(slot, position);
+
+            if candidate == usize::MAX || position - candidate > MAX_OFFSET || read_u32(input, candidate) != sequence {
+                position += 1;
+                continue;
+            }
+
+            let (mut start, mut source) = (position, candidate);
+            while start > anchor && source > 0 && input[start - 1] == input[source - 1] {
+                start -= 1;
+                source -= 1;
+            }
+            let mut end = position + MIN_MATCH;
+            while end < match_limit && input[end] == input[source + end - start] {
+                end += 1;
+            }
+
+            put_sequence(&mut output, &input[anchor..start], start - source, end - start);
+            position = end;
+            anchor = end;
+        }
+    }
+
+    put_last_literals(&mut output, &input[anchor..]);
+    output
+}
+
+/// Read an LZ4 length continuation
+fn get_length(input: &[u8], at: &mut usize) -> Result<usize> {
+    let mut length = 0usize;
+    loop {
+        let byte = *input.get(*at).ok_or(GalleonError::CompressionError("truncated LZ4 length"))?;
+        *at += 1;
+        length = length.checked_add(byte as usize).ok_or(GalleonError::CompressionError("LZ4 length overflow"))?;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+/// Decompress a single LZ4 block
+///
+/// Fails rather than produce more than `limit` bytes.
+pub fn lz4_decompress(input: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut at = 0;
+
+    loop {
+        let token = *input.get(at).ok_or(GalleonError::CompressionError("truncated LZ4 block"))?;
+        at += 1;
+
+        let mut literal_length = (token >> 4) as usize;
+        if literal_length == 15 {
+            literal_length += get_length(input, &mut at)?;
+        }
+        let literals = input.get(at..at + literal_length).ok_or(GalleonError::CompressionError("truncated LZ4 literals"))?;
+        if output.len() + literal_length > limit {
+            return Err(GalleonError::CompressionError("LZ4 block larger than expected"));
+        }
+        output.extend_from_slice(literals);
+        at += literal_length;
+
+        // The last sequence has literals only
+        if at == input.len() {
+            return Ok(output);
+        }
+
+        let offset = input.get(at..at + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or(GalleonError::CompressionError("truncated LZ4 offset"))?;
+        at += 2;
+        if offset == 0 || offset > output.len() {
+            return Err(GalleonError::CompressionError("LZ4 offset out of range"));
+        }
+
+        let mut match_length = (token & 0x0f) as usize + MIN_MATCH;
+        if match_length == 15 + MIN_MATCH {
+            match_length += get_length(input, &mut at)?;
+        }
+        if output.len() + match_length > limit {
+            return Err(GalleonError::CompressionError("LZ4 block larger than expected"));
+        }
+        // Copied a byte at a time, as the match may overlap what it produces
+        let start = output.len() - offset;
+        for index in 0..match_length {
+            let byte = output[start + index];
+            output.push(byte);
+        }
+    }
+}
+
+/// Reads a DEFLATE stream least significant bit first
+struct BitReader<'a> {
+    input: &'a [u8],
+    at: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self { input, at: 0, bit: 0 }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for index in 0..count {
+            let byte = *self.input.get(self.at).ok_or(GalleonError::CompressionError("truncated DEFLATE stream"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << index;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.at += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.at += 1;
+        }
+    }
+
+    /// Offset of the next whole byte
+    fn position(&self) -> usize {
+        self.at + (self.bit != 0) as usize
+    }
+}
+
+/// Canonical Huffman code, decoded one bit at a time
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; 16],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = alloc::vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(GalleonError::CompressionError("invalid DEFLATE code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order the code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn fixed_codes() -> Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(reader: &mut BitReader<'_>) -> Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_count = reader.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(GalleonError::CompressionError("too many DEFLATE codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_count] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = alloc::vec![0u8; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_length_code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *index.checked_sub(1).and_then(|i| lengths.get(i))
+                    .ok_or(GalleonError::CompressionError("DEFLATE repeat with nothing before it"))?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err(GalleonError::CompressionError("DEFLATE code lengths overrun"));
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(GalleonError::CompressionError("DEFLATE block has no end code"));
+    }
+
+    Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+fn inflate_block(reader: &mut BitReader<'_>, output: &mut Vec<u8>, codes: &(Huffman, Huffman), limit: usize) -> Result<()> {
+    let (literals, distances) = codes;
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err(GalleonError::CompressionError("invalid DEFLATE length"));
+                }
+                let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = distances.decode(reader)? as usize;
+                if index >= DISTANCE_BASE.len() {
+                    return Err(GalleonError::CompressionError("invalid DEFLATE distance"));
+                }
+                let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+                if distance > output.len() {
+                    return Err(GalleonError::CompressionError("DEFLATE distance out of range"));
+                }
+                let start = output.len() - distance;
+                for offset in 0..length {
+                    let byte = output[start + offset];
+                    output.push(byte);
+                }
+            }
+        }
+        if output.len() > limit {
+            return Err(GalleonError::CompressionError("DEFLATE stream larger than expected"));
+        }
+    }
+}
+
+/// Decode a raw DEFLATE stream, returning the data and the bytes of `input` used
+///
+/// Fails rather than produce more than `limit` bytes.
+pub fn inflate(input: &[u8], limit: usize) -> Result<(Vec<u8>, usize)> {
+    let mut reader = BitReader::new(input);
+    let mut output = Vec::new();
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = input.get(reader.at..reader.at + 4).ok_or(GalleonError::CompressionError("truncated stored block"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(GalleonError::CompressionError("corrupt stored block length"));
+                }
+                let start = reader.at + 4;
+                let stored = input.get(start..start + length as usize).ok_or(GalleonError::CompressionError("truncated stored block"))?;
+                if output.len() + stored.len() > limit {
+                    return Err(GalleonError::CompressionError("DEFLATE stream larger than expected"));
+                }
+                output.extend_from_slice(stored);
+                reader.at = start + length as usize;
+            }
+            1 => inflate_block(&mut reader, &mut output, &fixed_codes()?, limit)?,
+            2 => {
+                let codes = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &codes, limit)?;
+            }
+            _ => return Err(GalleonError::CompressionError("invalid DEFLATE block type")),
+        }
+        if last {
+            return Ok((output, reader.position()));
+        }
+    }
+}
+
+/// CRC-32 as used by gzip (IEEE 802.3, reflected)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+const GZIP_TEXT: u8 = 0x01;
+const GZIP_HEADER_CRC: u8 = 0x02;
+const GZIP_EXTRA: u8 = 0x04;
+const GZIP_NAME: u8 = 0x08;
+const GZIP_COMMENT: u8 = 0x10;
+
+/// Decode a single-member gzip stream, checking its CRC and length
+pub fn gunzip(input: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let truncated = GalleonError::CompressionError("truncated gzip header");
+    let header = input.get(..10).ok_or(truncated.clone())?;
+    if header[0] != 0x1f || header[1] != 0x8b || header[2] != 8 {
+        return Err(GalleonError::CompressionError("not a gzip stream"));
+    }
+    let flags = header[3];
+    if flags & !(GZIP_TEXT | GZIP_HEADER_CRC | GZIP_EXTRA | GZIP_NAME | GZIP_COMMENT) != 0 {
+        return Err(GalleonError::CompressionError("unknown gzip flags"));
+    }
+
+    let mut at = 10;
+    if flags & GZIP_EXTRA != 0 {
+        let length = input.get(at..at + 2).ok_or(truncated.clone())?;
+        at += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+    }
+    for flag in [GZIP_NAME, GZIP_COMMENT] {
+        if flag & flags != 0 {
+            let end = input.get(at..).and_then(|rest| rest.iter().position(|&byte| byte == 0)).ok_or(truncated.clone())?;
+            at += end + 1;
+        }
+    }
+    if flags & GZIP_HEADER_CRC != 0 {
+        at += 2;
+    }
+
+    let (output, used) = inflate(input.get(at..).ok_or(truncated)?, limit)?;
+    let trailer = input.get(at + used..at + used + 8).ok_or(GalleonError::CompressionError("truncated gzip trailer"))?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&output) || size != output.len() as u32 {
+        return Err(GalleonError::CompressionError("gzip checksum mismatch"));
+    }
+    Ok(output)
+}
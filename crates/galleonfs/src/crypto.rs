@@ -0,0 +1,291 @@
+//! Cryptographic primitives for GalleonFS (no_std compatible)
+//!
+//! ChaCha20-Poly1305 authenticated encryption as specified in RFC 8439 and
+//! SHA-256 for content hashes, written out in plain Rust so they run wherever
+//! the filesystem does.
+
+use alloc::vec::Vec;
+use super::{Result, GalleonError};
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; NONCE_SIZE]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        state[4 + i] = le32(&key[4 * i..]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = le32(&nonce[4 * i..]);
+    }
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; 64];
+    for (i, word) in working.iter().enumerate() {
+        block[4 * i..4 * i + 4].copy_from_slice(&word.wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+/// XOR `data` with the ChaCha20 key stream starting at block `counter`
+pub fn chacha20(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let stream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, key_byte) in chunk.iter_mut().zip(stream) {
+            *byte ^= key_byte;
+        }
+    }
+}
+
+/// Poly1305 over 26-bit limbs
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+}
+
+impl Poly1305 {
+    const MASK: u32 = 0x3ff_ffff;
+
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            r: [
+                le32(&key[0..]) & 0x3ff_ffff,
+                (le32(&key[3..]) >> 2) & 0x3ff_ff03,
+                (le32(&key[6..]) >> 4) & 0x3ff_c0ff,
+                (le32(&key[9..]) >> 6) & 0x3f0_3fff,
+                (le32(&key[12..]) >> 8) & 0x00f_ffff,
+            ],
+            h: [0; 5],
+            pad: [le32(&key[16..]), le32(&key[20..]), le32(&key[24..]), le32(&key[28..])],
+        }
+    }
+
+    /// Absorb one block; `high` is the bit above the block, unset for a
+    /// final partial block that already carries its own
+    fn block(&mut self, block: &[u8; 16], high: u32) {
+        let [r0, r1, r2, r3, r4] = self.r.map(u64::from);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+        h[0] += le32(&block[0..]) & Self::MASK;
+        h[1] += (le32(&block[3..]) >> 2) & Self::MASK;
+        h[2] += (le32(&block[6..]) >> 4) & Self::MASK;
+        h[3] += (le32(&block[9..]) >> 6) & Self::MASK;
+        h[4] += (le32(&block[12..]) >> 8) | high;
+
+        let [h0, h1, h2, h3, h4] = h.map(u64::from);
+        let d = [
+            h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1,
+            h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2,
+            h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3,
+            h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4,
+            h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0,
+        ];
+
+        let mut carry = 0u64;
+        for i in 0..5 {
+            let value = d[i] + carry;
+            h[i] = value as u32 & Self::MASK;
+            carry = value >> 26;
+        }
+        h[0] += carry as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= Self::MASK;
+    }
+
+    fn update(&mut self, message: &[u8]) {
+        let (blocks, rest) = message.as_chunks::<16>();
+        for block in blocks {
+            self.block(block, 1 << 24);
+        }
+        if !rest.is_empty() {
+            let mut last = [0u8; 16];
+            last[..rest.len()].copy_from_slice(rest);
+            last[rest.len()] = 1;
+            self.block(&last, 0);
+        }
+    }
+
+    fn finish(mut self) -> [u8; TAG_SIZE] {
+        let h = &mut self.h;
+        for i in 1..5 {
+            h[i] += h[i - 1] >> 26;
+            h[i - 1] &= Self::MASK;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= Self::MASK;
+        h[1] += h[0] >> 26;
+        h[0] &= Self::MASK;
+
+        // h - p, kept if it doesn't go negative
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for i in 0..5 {
+            let value = h[i].wrapping_add(carry);
+            g[i] = value & Self::MASK;
+            carry = value >> 26;
+        }
+        g[4] = g[4].wrapping_add(carry << 26).wrapping_sub(1 << 26);
+        let keep_g = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !keep_g) | (g[i] & keep_g);
+        }
+
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0u8; TAG_SIZE];
+        let mut carry = 0u64;
+        for i in 0..4 {
+            let value = u64::from(words[i]) + u64::from(self.pad[i]) + carry;
+            tag[4 * i..4 * i + 4].copy_from_slice(&(value as u32).to_le_bytes());
+            carry = value >> 32;
+        }
+        tag
+    }
+}
+
+/// One-time Poly1305 authenticator of `message` under `key`
+pub fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; TAG_SIZE] {
+    let mut mac = Poly1305::new(key);
+    mac.update(message);
+    mac.finish()
+}
+
+fn aead_tag(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+    let block = chacha20_block(key, 0, nonce);
+    let padded = |len: usize| len.next_multiple_of(16);
+    let mut message = Vec::with_capacity(padded(aad.len()) + padded(ciphertext.len()) + 16);
+    message.extend_from_slice(aad);
+    message.resize(padded(aad.len()), 0);
+    message.extend_from_slice(ciphertext);
+    message.resize(message.len() + padded(ciphertext.len()) - ciphertext.len(), 0);
+    message.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    message.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly1305(block[..32].try_into().unwrap(), &message)
+}
+
+/// Encrypt `plaintext` and authenticate it along with `aad`
+///
+/// Returns the ciphertext followed by the tag. A nonce must never be used
+/// twice with the same key.
+pub fn seal(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut sealed = plaintext.to_vec();
+    chacha20(key, 1, nonce, &mut sealed);
+    let tag = aead_tag(key, nonce, aad, &sealed);
+    sealed.extend_from_slice(&tag);
+    sealed
+}
+
+/// Check and decrypt the output of [`seal`]
+///
+/// Fails without returning anything if the ciphertext, the tag or `aad`
+/// differ from what was sealed.
+pub fn open(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let split = sealed.len().checked_sub(TAG_SIZE).ok_or(GalleonError::CryptoError("sealed data too short"))?;
+    let (ciphertext, tag) = sealed.split_at(split);
+    let expected = aead_tag(key, nonce, aad, ciphertext);
+    // Compare without an early exit
+    if tag.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+        return Err(GalleonError::CryptoError("authentication failed"));
+    }
+    let mut plaintext = ciphertext.to_vec();
+    chacha20(key, 1, nonce, &mut plaintext);
+    Ok(plaintext)
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256_block(state: &mut [u32; 8], block: &[u8; 64]) {
+    let mut w = [0u32; 64];
+    for i in 0..16 {
+        w[i] = u32::from_be_bytes([block[4 * i], block[4 * i + 1], block[4 * i + 2], block[4 * i + 3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let choice = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let majority = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(majority);
+        (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+/// SHA-256 digest of `data`
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let (blocks, rest) = data.as_chunks::<64>();
+    for block in blocks {
+        sha256_block(&mut state, block);
+    }
+
+    // The rest, a one bit, zeros and the length in bits fill one or two more blocks
+    let mut tail = [0u8; 128];
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let tail_len = if rest.len() < 56 { 64 } else { 128 };
+    tail[tail_len - 8..tail_len].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in tail[..tail_len].as_chunks::<64>().0 {
+        sha256_block(&mut state, block);
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, word) in state.iter().enumerate() {
+        digest[4 * i..4 * i + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
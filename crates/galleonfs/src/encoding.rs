@@ -0,0 +1,244 @@
+//! Binary encoding primitives for GalleonFS on-disk structures (no_std compatible)
+//!
+//! Integers are little-endian. Lengths and counts use LEB128 varints, so
+//! small values take one byte. Signed values are zigzag encoded before
+//! being written as varints.
+//!
+//! Structures are encoded as a sequence of tagged fields. Each field is a
+//! tag byte, a varint payload length and the payload. A reader skips tags it
+//! does not recognise, and ignores bytes left over at the end of a payload it
+//! does recognise. A newer writer can therefore add fields, or append to an
+//! existing field, without breaking older readers.
+
+// #![no_std] // Only at crate root
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use super::{GalleonError, Result, platform::Timestamp};
+
+/// Append-only writer for encoded structures
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_f32(&mut self, value: f32) {
+        self.put_u32(value.to_bits());
+    }
+
+    /// Write an unsigned LEB128 varint
+    pub fn put_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    /// Write a zigzag-encoded signed varint
+    pub fn put_signed_varint(&mut self, value: i64) {
+        self.put_varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    pub fn put_raw(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Write a length-prefixed byte string
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn put_str(&mut self, value: &str) {
+        self.put_bytes(value.as_bytes());
+    }
+
+    pub fn put_timestamp(&mut self, value: Timestamp) {
+        self.put_varint(value.seconds);
+        self.put_varint(value.nanoseconds as u64);
+    }
+
+    /// Write a field whose payload is produced by `write`
+    pub fn put_field(&mut self, tag: u8, write: impl FnOnce(&mut Encoder)) {
+        let mut payload = Encoder::new();
+        write(&mut payload);
+        self.put_u8(tag);
+        self.put_bytes(&payload.buf);
+    }
+}
+
+/// Cursor over an encoded structure
+///
+/// Every read is bounds checked; running off the end is reported as corruption.
+#[derive(Debug, Clone)]
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn get_raw(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(GalleonError::Corruption("encoded structure is truncated"));
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn get_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.get_raw(N)?);
+        Ok(array)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.get_raw(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(GalleonError::Corruption("invalid boolean")),
+        }
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_bits(self.get_u32()?))
+    }
+
+    /// Read an unsigned LEB128 varint, rejecting encodings longer than 64 bits
+    pub fn get_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.get_u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(GalleonError::Corruption("varint overflows 64 bits"));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn get_varint_u32(&mut self) -> Result<u32> {
+        u32::try_from(self.get_varint()?)
+            .map_err(|_| GalleonError::Corruption("varint overflows 32 bits"))
+    }
+
+    pub fn get_signed_varint(&mut self) -> Result<i64> {
+        let value = self.get_varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Read a varint length, checking that that many bytes can follow
+    pub fn get_length(&mut self) -> Result<usize> {
+        let len = self.get_varint()?;
+        if len > self.remaining() as u64 {
+            return Err(GalleonError::Corruption("encoded length exceeds the structure"));
+        }
+        Ok(len as usize)
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.get_length()?;
+        self.get_raw(len)
+    }
+
+    pub fn get_string(&mut self) -> Result<String> {
+        let bytes = self.get_bytes()?;
+        core::str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|_| GalleonError::Corruption("string is not valid UTF-8"))
+    }
+
+    pub fn get_timestamp(&mut self) -> Result<Timestamp> {
+        let seconds = self.get_varint()?;
+        let nanoseconds = self.get_varint_u32()?;
+        if nanoseconds >= 1_000_000_000 {
+            return Err(GalleonError::Corruption("timestamp nanoseconds out of range"));
+        }
+        Ok(Timestamp { seconds, nanoseconds })
+    }
+
+    /// Read the next field as its tag and a decoder over its payload
+    pub fn next_field(&mut self) -> Result<Option<(u8, Decoder<'a>)>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let tag = self.get_u8()?;
+        let payload = self.get_bytes()?;
+        Ok(Some((tag, Decoder::new(payload))))
+    }
+}
@@ -13,7 +13,8 @@ extern crate alloc;
 
 use alloc::{vec::Vec, collections::BTreeMap, string::String};
 use core::{fmt, time::Duration};
-use super::{ObjectId, Permissions, Result, platform::Timestamp};
+use super::{ObjectId, Permissions, Result, GalleonError, platform::Timestamp};
+use super::encoding::{Encoder, Decoder};
 
 /// Inode type enumeration
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -47,7 +48,7 @@ impl fmt::Display for InodeType {
 }
 
 /// Extended attribute value
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ExtendedAttributeValue {
     String(String),
     Binary(Vec<u8>),
@@ -59,14 +60,14 @@ pub enum ExtendedAttributeValue {
 pub type ExtendedAttributes = BTreeMap<String, ExtendedAttributeValue>;
 
 /// Access Control List entry
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AclEntry {
     pub entry_type: AclEntryType,
     pub principal: u32, // uid or gid
     pub permissions: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AclEntryType {
     User,
     Group,
@@ -78,7 +79,7 @@ pub enum AclEntryType {
 pub type AccessControlList = Vec<AclEntry>;
 
 /// Version information for versioned files
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct VersionInfo {
     pub version_number: u64,
     pub parent_version: Option<u64>,
@@ -89,7 +90,7 @@ pub struct VersionInfo {
 }
 
 /// Inode structure with extensible metadata
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Inode {
     /// Unique identifier
     id: ObjectId,
@@ -133,6 +134,10 @@ pub struct Inode {
     
     /// Deduplication information
     dedup_hash: Option<[u8; 32]>,
+
+    /// Stored size of the chunk blocks holding the data, for files whose
+    /// data doesn't live in the object itself
+    chunked_size: Option<u64>,
     
     /// Replication metadata
     replication_meta: Option<ReplicationMetadata>,
@@ -141,7 +146,7 @@ pub struct Inode {
     custom_metadata: BTreeMap<String, Vec<u8>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CompressionInfo {
     pub algorithm: CompressionAlgorithm,
     pub compressed_size: u64,
@@ -149,7 +154,7 @@ pub struct CompressionInfo {
     pub compression_ratio: f32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CompressionAlgorithm {
     None,
     Lz4,
@@ -158,7 +163,7 @@ pub enum CompressionAlgorithm {
     Brotli,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct EncryptionInfo {
     pub algorithm: EncryptionAlgorithm,
     pub key_id: u64,
@@ -166,7 +171,7 @@ pub struct EncryptionInfo {
     pub authenticated: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EncryptionAlgorithm {
     None,
     Aes256Gcm,
@@ -174,7 +179,7 @@ pub enum EncryptionAlgorithm {
     Aes256Ctr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ReplicationMetadata {
     pub replica_count: u32,
     pub replicas: Vec<String>, // Node identifiers
@@ -183,6 +188,138 @@ pub struct ReplicationMetadata {
     pub conflict_version: Option<u64>,
 }
 
+/// Leading bytes of every encoded inode
+pub const INODE_MAGIC: [u8; 4] = *b"GINO";
+
+/// Version of the inode encoding
+///
+/// Only bumped for changes older readers cannot skip over; new optional
+/// metadata gets a new field tag instead.
+pub const INODE_FORMAT_VERSION: u16 = 1;
+
+/// Field tags of the inode encoding. Tags are never reused.
+mod field {
+    pub const ID: u8 = 1;
+    pub const TYPE: u8 = 2;
+    pub const PERMISSIONS: u8 = 3;
+    pub const SIZE: u8 = 4;
+    pub const LINK_COUNT: u8 = 5;
+    pub const TIMES: u8 = 6;
+    pub const BLOCKS: u8 = 7;
+    pub const INDIRECT_BLOCKS: u8 = 8;
+    pub const EXTENDED_ATTRIBUTE: u8 = 9;
+    pub const ACL: u8 = 10;
+    pub const VERSION_INFO: u8 = 11;
+    pub const COMPRESSION: u8 = 12;
+    pub const ENCRYPTION: u8 = 13;
+    pub const DEDUP_HASH: u8 = 14;
+    pub const REPLICATION: u8 = 15;
+    pub const CUSTOM_METADATA: u8 = 16;
+    pub const CHUNKED_SIZE: u8 = 17;
+}
+
+impl InodeType {
+    fn to_raw(self) -> u8 {
+        match self {
+            InodeType::RegularFile => 0,
+            InodeType::Directory => 1,
+            InodeType::SymbolicLink => 2,
+            InodeType::BlockDevice => 3,
+            InodeType::CharacterDevice => 4,
+            InodeType::Fifo => 5,
+            InodeType::Socket => 6,
+            InodeType::Snapshot => 7,
+            InodeType::HardLink => 8,
+        }
+    }
+
+    fn from_raw(raw: u8) -> Result<Self> {
+        Ok(match raw {
+            0 => InodeType::RegularFile,
+            1 => InodeType::Directory,
+            2 => InodeType::SymbolicLink,
+            3 => InodeType::BlockDevice,
+            4 => InodeType::CharacterDevice,
+            5 => InodeType::Fifo,
+            6 => InodeType::Socket,
+            7 => InodeType::Snapshot,
+            8 => InodeType::HardLink,
+            _ => return Err(GalleonError::Corruption("unknown inode type")),
+        })
+    }
+}
+
+impl AclEntryType {
+    fn from_raw(raw: u8) -> Result<Self> {
+        Ok(match raw {
+            0 => AclEntryType::User,
+            1 => AclEntryType::Group,
+            2 => AclEntryType::Other,
+            3 => AclEntryType::Mask,
+            _ => return Err(GalleonError::Corruption("unknown ACL entry type")),
+        })
+    }
+}
+
+impl CompressionAlgorithm {
+    fn from_raw(raw: u8) -> Result<Self> {
+        Ok(match raw {
+            0 => CompressionAlgorithm::None,
+            1 => CompressionAlgorithm::Lz4,
+            2 => CompressionAlgorithm::Zstd,
+            3 => CompressionAlgorithm::Gzip,
+            4 => CompressionAlgorithm::Brotli,
+            _ => return Err(GalleonError::Corruption("unknown compression algorithm")),
+        })
+    }
+}
+
+impl EncryptionAlgorithm {
+    pub fn from_raw(raw: u8) -> Result<Self> {
+        Ok(match raw {
+            0 => EncryptionAlgorithm::None,
+            1 => EncryptionAlgorithm::Aes256Gcm,
+            2 => EncryptionAlgorithm::ChaCha20Poly1305,
+            3 => EncryptionAlgorithm::Aes256Ctr,
+            _ => return Err(GalleonError::Corruption("unknown encryption algorithm")),
+        })
+    }
+}
+
+fn put_block_list(encoder: &mut Encoder, blocks: &[u64]) {
+    encoder.put_varint(blocks.len() as u64);
+    for &block in blocks {
+        encoder.put_varint(block);
+    }
+}
+
+fn get_block_list(decoder: &mut Decoder<'_>) -> Result<Vec<u64>> {
+    let count = decoder.get_varint()?;
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        blocks.push(decoder.get_varint()?);
+    }
+    Ok(blocks)
+}
+
+fn put_optional_varint(encoder: &mut Encoder, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            encoder.put_bool(true);
+            encoder.put_varint(value);
+        }
+        None => encoder.put_bool(false),
+    }
+}
+
+fn get_optional_varint(decoder: &mut Decoder<'_>) -> Result<Option<u64>> {
+    if decoder.get_bool()? {
+        Ok(Some(decoder.get_varint()?))
+    } else {
+        Ok(None)
+    }
+}
+
 impl Inode {
     /// Create a new inode
     pub fn new(id: ObjectId, inode_type: InodeType, permissions: Permissions, size: u64) -> Self {
@@ -206,6 +343,7 @@ impl Inode {
             compression: None,
             encryption: None,
             dedup_hash: None,
+            chunked_size: None,
             replication_meta: None,
             custom_metadata: BTreeMap::new(),
         }
@@ -228,8 +366,14 @@ impl Inode {
     pub fn compression(&self) -> Option<&CompressionInfo> { self.compression.as_ref() }
     pub fn encryption(&self) -> Option<&EncryptionInfo> { self.encryption.as_ref() }
     pub fn dedup_hash(&self) -> Option<&[u8; 32]> { self.dedup_hash.as_ref() }
+    pub fn chunked_size(&self) -> Option<u64> { self.chunked_size }
     pub fn replication_meta(&self) -> Option<&ReplicationMetadata> { self.replication_meta.as_ref() }
 
+    /// Copy of this inode under another identifier, metadata unchanged
+    pub fn with_id(&self, id: ObjectId) -> Self {
+        Self { id, ..self.clone() }
+    }
+
     // Setters
     pub fn set_size(&mut self, size: u64) {
         self.size = size;
@@ -254,6 +398,11 @@ impl Inode {
         self.changed_at = Timestamp::now();
     }
 
+    pub fn set_link_count(&mut self, link_count: u32) {
+        self.link_count = link_count;
+        self.changed_at = Timestamp::now();
+    }
+
     pub fn touch_accessed(&mut self) {
         self.accessed_at = Timestamp::now();
     }
@@ -273,6 +422,11 @@ impl Inode {
         self.changed_at = Timestamp::now();
     }
 
+    pub fn set_blocks(&mut self, blocks: Vec<u64>) {
+        self.blocks = blocks;
+        self.changed_at = Timestamp::now();
+    }
+
     // Extended attributes
     pub fn set_extended_attribute(&mut self, name: String, value: ExtendedAttributeValue) {
         self.extended_attributes.insert(name, value);
@@ -364,6 +518,17 @@ impl Inode {
         self.changed_at = Timestamp::now();
     }
 
+    // Chunk layout
+    pub fn set_chunked_size(&mut self, size: u64) {
+        self.chunked_size = Some(size);
+        self.changed_at = Timestamp::now();
+    }
+
+    pub fn clear_chunked_size(&mut self) {
+        self.chunked_size = None;
+        self.changed_at = Timestamp::now();
+    }
+
     // Replication
     pub fn set_replication_meta(&mut self, meta: ReplicationMetadata) {
         self.replication_meta = Some(meta);
@@ -394,16 +559,260 @@ impl Inode {
     }
 
     // Serialization (for storage)
+
+    /// Encode the inode in the versioned on-disk format
+    ///
+    /// The encoding starts with [`INODE_MAGIC`], [`INODE_FORMAT_VERSION`] and the
+    /// length of the field list that follows. Optional metadata is only written
+    /// when present, so a plain file costs a few dozen bytes.
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        // TODO: Implement proper serialization (could use bincode, protobuf, etc.)
-        // For now, return a placeholder
-        Err(super::GalleonError::NotSupported)
-    }
+        let mut fields = Encoder::new();
+        fields.put_field(field::ID, |e| e.put_u64(self.id.as_u64()));
+        fields.put_field(field::TYPE, |e| e.put_u8(self.inode_type.to_raw()));
+        fields.put_field(field::PERMISSIONS, |e| {
+            e.put_varint(self.permissions.mode as u64);
+            e.put_varint(self.permissions.uid as u64);
+            e.put_varint(self.permissions.gid as u64);
+        });
+        fields.put_field(field::SIZE, |e| e.put_varint(self.size));
+        fields.put_field(field::LINK_COUNT, |e| e.put_varint(self.link_count as u64));
+        fields.put_field(field::TIMES, |e| {
+            e.put_timestamp(self.created_at);
+            e.put_timestamp(self.modified_at);
+            e.put_timestamp(self.accessed_at);
+            e.put_timestamp(self.changed_at);
+        });
+        if !self.blocks.is_empty() {
+            fields.put_field(field::BLOCKS, |e| put_block_list(e, &self.blocks));
+        }
+        if !self.indirect_blocks.is_empty() {
+            fields.put_field(field::INDIRECT_BLOCKS, |e| put_block_list(e, &self.indirect_blocks));
+        }
+        for (name, value) in &self.extended_attributes {
+            fields.put_field(field::EXTENDED_ATTRIBUTE, |e| {
+                e.put_str(name);
+                match value {
+                    ExtendedAttributeValue::String(s) => { e.put_u8(0); e.put_str(s); }
+                    ExtendedAttributeValue::Binary(b) => { e.put_u8(1); e.put_bytes(b); }
+                    ExtendedAttributeValue::Integer(i) => { e.put_u8(2); e.put_signed_varint(*i); }
+                    ExtendedAttributeValue::Boolean(b) => { e.put_u8(3); e.put_bool(*b); }
+                }
+            });
+        }
+        if let Some(acl) = &self.acl {
+            fields.put_field(field::ACL, |e| {
+                e.put_varint(acl.len() as u64);
+                for entry in acl {
+                    e.put_u8(entry.entry_type as u8);
+                    e.put_varint(entry.principal as u64);
+                    e.put_varint(entry.permissions as u64);
+                }
+            });
+        }
+        if let Some(version) = &self.version_info {
+            fields.put_field(field::VERSION_INFO, |e| {
+                e.put_varint(version.version_number);
+                put_optional_varint(e, version.parent_version);
+                e.put_timestamp(version.created_at);
+                e.put_varint(version.created_by as u64);
+                e.put_str(&version.description);
+                match &version.checksum {
+                    Some(checksum) => { e.put_bool(true); e.put_raw(checksum); }
+                    None => e.put_bool(false),
+                }
+            });
+        }
+        if let Some(compression) = &self.compression {
+            fields.put_field(field::COMPRESSION, |e| {
+                e.put_u8(compression.algorithm as u8);
+                e.put_varint(compression.compressed_size);
+                e.put_varint(compression.uncompressed_size);
+                e.put_f32(compression.compression_ratio);
+            });
+        }
+        if let Some(encryption) = &self.encryption {
+            fields.put_field(field::ENCRYPTION, |e| {
+                e.put_u8(encryption.algorithm as u8);
+                e.put_u64(encryption.key_id);
+                e.put_bytes(&encryption.iv);
+                e.put_bool(encryption.authenticated);
+            });
+        }
+        if let Some(hash) = &self.dedup_hash {
+            fields.put_field(field::DEDUP_HASH, |e| e.put_raw(hash));
+        }
+        if let Some(size) = self.chunked_size {
+            fields.put_field(field::CHUNKED_SIZE, |e| e.put_varint(size));
+        }
+        if let Some(meta) = &self.replication_meta {
+            fields.put_field(field::REPLICATION, |e| {
+                e.put_varint(meta.replica_count as u64);
+                e.put_varint(meta.replicas.len() as u64);
+                for replica in &meta.replicas {
+                    e.put_str(replica);
+                }
+                e.put_str(&meta.consistency_level);
+                e.put_timestamp(meta.last_synchronized);
+                put_optional_varint(e, meta.conflict_version);
+            });
+        }
+        for (key, value) in &self.custom_metadata {
+            fields.put_field(field::CUSTOM_METADATA, |e| {
+                e.put_str(key);
+                e.put_bytes(value);
+            });
+        }
+
+        let body_len = u32::try_from(fields.len())
+            .map_err(|_| GalleonError::InvalidArgument("inode metadata too large to encode"))?;
+        let mut encoder = Encoder::new();
+        encoder.put_raw(&INODE_MAGIC);
+        encoder.put_u16(INODE_FORMAT_VERSION);
+        encoder.put_u32(body_len);
+        encoder.put_raw(&fields.into_bytes());
+        Ok(encoder.into_bytes())
+    }
+
+    /// Decode an inode written by [`Inode::serialize`]
+    ///
+    /// Bytes after the encoded length are ignored, so an inode can be read
+    /// straight out of a padded slot. Fields with unknown tags are skipped.
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut header = Decoder::new(data);
+        if header.get_array::<4>()? != INODE_MAGIC {
+            return Err(GalleonError::Corruption("bad inode magic"));
+        }
+        let version = header.get_u16()?;
+        if version != INODE_FORMAT_VERSION {
+            return Err(GalleonError::CorruptionDynamic(
+                alloc::format!("unsupported inode format version {}", version)));
+        }
+        let body_len = header.get_u32()? as usize;
+        let mut fields = Decoder::new(header.get_raw(body_len)?);
+
+        let mut id = None;
+        let mut inode_type = None;
+        let mut inode = Self {
+            id: ObjectId(0),
+            inode_type: InodeType::RegularFile,
+            permissions: Permissions::new(0, 0, 0),
+            size: 0,
+            link_count: 1,
+            created_at: Timestamp::zero(),
+            modified_at: Timestamp::zero(),
+            accessed_at: Timestamp::zero(),
+            changed_at: Timestamp::zero(),
+            blocks: Vec::new(),
+            indirect_blocks: Vec::new(),
+            extended_attributes: BTreeMap::new(),
+            acl: None,
+            version_info: None,
+            compression: None,
+            encryption: None,
+            dedup_hash: None,
+            chunked_size: None,
+            replication_meta: None,
+            custom_metadata: BTreeMap::new(),
+        };
+
+        while let Some((tag, mut e)) = fields.next_field()? {
+            match tag {
+                field::ID => id = Some(ObjectId(e.get_u64()?)),
+                field::TYPE => inode_type = Some(InodeType::from_raw(e.get_u8()?)?),
+                field::PERMISSIONS => {
+                    let mode = e.get_varint_u32()?;
+                    let uid = e.get_varint_u32()?;
+                    let gid = e.get_varint_u32()?;
+                    inode.permissions = Permissions::new(mode, uid, gid);
+                }
+                field::SIZE => inode.size = e.get_varint()?,
+                field::LINK_COUNT => inode.link_count = e.get_varint_u32()?,
+                field::TIMES => {
+                    inode.created_at = e.get_timestamp()?;
+                    inode.modified_at = e.get_timestamp()?;
+                    inode.accessed_at = e.get_timestamp()?;
+                    inode.changed_at = e.get_timestamp()?;
+                }
+                field::BLOCKS => inode.blocks = get_block_list(&mut e)?,
+                field::INDIRECT_BLOCKS => inode.indirect_blocks = get_block_list(&mut e)?,
+                field::EXTENDED_ATTRIBUTE => {
+                    let name = e.get_string()?;
+                    let value = match e.get_u8()? {
+                        0 => ExtendedAttributeValue::String(e.get_string()?),
+                        1 => ExtendedAttributeValue::Binary(e.get_bytes()?.to_vec()),
+                        2 => ExtendedAttributeValue::Integer(e.get_signed_varint()?),
+                        3 => ExtendedAttributeValue::Boolean(e.get_bool()?),
+                        _ => return Err(GalleonError::Corruption("unknown extended attribute kind")),
+                    };
+                    inode.extended_attributes.insert(name, value);
+                }
+                field::ACL => {
+                    let count = e.get_varint()?;
+                    let mut acl = Vec::new();
+                    for _ in 0..count {
+                        acl.push(AclEntry {
+                            entry_type: AclEntryType::from_raw(e.get_u8()?)?,
+                            principal: e.get_varint_u32()?,
+                            permissions: e.get_varint_u32()?,
+                        });
+                    }
+                    inode.acl = Some(acl);
+                }
+                field::VERSION_INFO => {
+                    inode.version_info = Some(VersionInfo {
+                        version_number: e.get_varint()?,
+                        parent_version: get_optional_varint(&mut e)?,
+                        created_at: e.get_timestamp()?,
+                        created_by: e.get_varint_u32()?,
+                        description: e.get_string()?,
+                        checksum: if e.get_bool()? { Some(e.get_array()?) } else { None },
+                    });
+                }
+                field::COMPRESSION => {
+                    inode.compression = Some(CompressionInfo {
+                        algorithm: CompressionAlgorithm::from_raw(e.get_u8()?)?,
+                        compressed_size: e.get_varint()?,
+                        uncompressed_size: e.get_varint()?,
+                        compression_ratio: e.get_f32()?,
+                    });
+                }
+                field::ENCRYPTION => {
+                    inode.encryption = Some(EncryptionInfo {
+                        algorithm: EncryptionAlgorithm::from_raw(e.get_u8()?)?,
+                        key_id: e.get_u64()?,
+                        iv: e.get_bytes()?.to_vec(),
+                        authenticated: e.get_bool()?,
+                    });
+                }
+                field::DEDUP_HASH => inode.dedup_hash = Some(e.get_array()?),
+                field::CHUNKED_SIZE => inode.chunked_size = Some(e.get_varint()?),
+                field::REPLICATION => {
+                    let replica_count = e.get_varint_u32()?;
+                    let replica_len = e.get_varint()?;
+                    let mut replicas = Vec::new();
+                    for _ in 0..replica_len {
+                        replicas.push(e.get_string()?);
+                    }
+                    inode.replication_meta = Some(ReplicationMetadata {
+                        replica_count,
+                        replicas,
+                        consistency_level: e.get_string()?,
+                        last_synchronized: e.get_timestamp()?,
+                        conflict_version: get_optional_varint(&mut e)?,
+                    });
+                }
+                field::CUSTOM_METADATA => {
+                    let key = e.get_string()?;
+                    inode.custom_metadata.insert(key, e.get_bytes()?.to_vec());
+                }
+                // Written by a newer version; safe to ignore
+                _ => {}
+            }
+        }
 
-    pub fn deserialize(_data: &[u8]) -> Result<Self> {
-        // TODO: Implement proper deserialization
-        // For now, return an error
-        Err(super::GalleonError::NotSupported)
+        inode.id = id.ok_or(GalleonError::Corruption("inode has no id"))?;
+        inode.inode_type = inode_type.ok_or(GalleonError::Corruption("inode has no type"))?;
+        Ok(inode)
     }
 
     // Check if inode is a specific type
@@ -0,0 +1,588 @@
+//! On-disk layout used by PlatformStorage (no_std compatible)
+//!
+//! A volume is divided into blocks of the device block size:
+//!
+//! ```text
+//! | superblock | inode table | allocation bitmap | journal | data blocks ... |
+//! ```
+//!
+//! The inode table is an array of fixed-size slots, each holding one encoded
+//! inode together with the extents of its data. The bitmap has one bit per
+//! data block. All block numbers stored on disk are relative to the start of
+//! the data area.
+//!
+//! The journal makes transactions atomic across crashes. Its first block
+//! holds a [`JournalHeader`]; [`UndoRecord`]s follow it, each saving
+//! the previous contents of a byte range before the range is overwritten.
+//! Entries tagged with the header's sequence number belong to a transaction
+//! that never finished and are written back when the volume is opened.
+
+// #![no_std] // Only at crate root
+
+extern crate alloc;
+
+use alloc::{vec::Vec, collections::BTreeSet, format};
+use super::{ObjectId, Result, GalleonError};
+use super::encoding::{Encoder, Decoder};
+use super::codec::crc32;
+
+/// Leading bytes of block 0 on a formatted device
+pub const PLATFORM_MAGIC: [u8; 8] = *b"GALLEONP";
+
+/// Version of the layout described in this module
+pub const LAYOUT_VERSION: u16 = 2;
+
+/// Size of an inode table slot in bytes
+pub const DEFAULT_INODE_SLOT_SIZE: u32 = 1024;
+
+/// Device capacity set aside per inode slot when formatting
+pub const BYTES_PER_INODE: u64 = 16 * 1024;
+
+/// Fewest inode slots a volume is formatted with
+pub const MIN_INODE_SLOTS: u64 = 16;
+
+/// Leading bytes of the journal header block
+pub const JOURNAL_MAGIC: [u8; 8] = *b"GALLEONJ";
+
+/// Fewest blocks given to the journal, which otherwise takes 1/32 of the volume
+pub const MIN_JOURNAL_BLOCKS: u64 = 16;
+
+/// Most blocks given to the journal
+pub const MAX_JOURNAL_BLOCKS: u64 = 1024;
+
+/// Volume geometry, stored in block 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuperBlock {
+    pub block_size: u32,
+    pub total_blocks: u64,
+    pub inode_slot_size: u32,
+    pub inode_slots: u64,
+    pub inode_table_start: u64,
+    pub inode_table_blocks: u64,
+    pub bitmap_start: u64,
+    pub bitmap_blocks: u64,
+    pub journal_start: u64,
+    pub journal_blocks: u64,
+    pub data_start: u64,
+    pub data_blocks: u64,
+    /// Object ids below this one may be on the volume; see [`ObjectId::reserve_through`]
+    pub next_object_id: u64,
+}
+
+impl SuperBlock {
+    /// Lay out a volume of `capacity` bytes
+    pub fn for_device(capacity: u64, block_size: u32) -> Result<Self> {
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(GalleonError::InvalidArgument("block size must be a power of two of at least 512"));
+        }
+        let bs = block_size as u64;
+        let total_blocks = capacity / bs;
+        let inode_slot_size = DEFAULT_INODE_SLOT_SIZE;
+        let inode_slots = (capacity / BYTES_PER_INODE).max(MIN_INODE_SLOTS);
+        let inode_table_blocks = (inode_slots * inode_slot_size as u64).div_ceil(bs);
+        let journal_blocks = (total_blocks / 32).clamp(MIN_JOURNAL_BLOCKS, MAX_JOURNAL_BLOCKS);
+
+        // Each bitmap block covers bs * 8 data blocks and takes one block itself
+        let remaining = total_blocks.saturating_sub(1 + inode_table_blocks + journal_blocks);
+        let bitmap_blocks = remaining.div_ceil(bs * 8 + 1);
+        let data_blocks = remaining - bitmap_blocks;
+        if data_blocks == 0 {
+            return Err(GalleonError::NoSpace);
+        }
+
+        Ok(Self {
+            block_size,
+            total_blocks,
+            inode_slot_size,
+            inode_slots,
+            inode_table_start: 1,
+            inode_table_blocks,
+            bitmap_start: 1 + inode_table_blocks,
+            bitmap_blocks,
+            journal_start: 1 + inode_table_blocks + bitmap_blocks,
+            journal_blocks,
+            data_start: 1 + inode_table_blocks + bitmap_blocks + journal_blocks,
+            data_blocks,
+            next_object_id: ObjectId::FIRST_DYNAMIC,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.put_raw(&PLATFORM_MAGIC);
+        encoder.put_u16(LAYOUT_VERSION);
+        encoder.put_u32(self.block_size);
+        encoder.put_u64(self.total_blocks);
+        encoder.put_u32(self.inode_slot_size);
+        encoder.put_u64(self.inode_slots);
+        encoder.put_u64(self.inode_table_start);
+        encoder.put_u64(self.inode_table_blocks);
+        encoder.put_u64(self.bitmap_start);
+        encoder.put_u64(self.bitmap_blocks);
+        encoder.put_u64(self.journal_start);
+        encoder.put_u64(self.journal_blocks);
+        encoder.put_u64(self.data_start);
+        encoder.put_u64(self.data_blocks);
+        encoder.put_u64(self.next_object_id);
+        encoder.into_bytes()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(data);
+        if decoder.get_array::<8>()? != PLATFORM_MAGIC {
+            return Err(GalleonError::Corruption("device is not formatted for GalleonFS"));
+        }
+        if decoder.get_u16()? != LAYOUT_VERSION {
+            return Err(GalleonError::Corruption("unsupported layout version"));
+        }
+        let super_block = Self {
+            block_size: decoder.get_u32()?,
+            total_blocks: decoder.get_u64()?,
+            inode_slot_size: decoder.get_u32()?,
+            inode_slots: decoder.get_u64()?,
+            inode_table_start: decoder.get_u64()?,
+            inode_table_blocks: decoder.get_u64()?,
+            bitmap_start: decoder.get_u64()?,
+            bitmap_blocks: decoder.get_u64()?,
+            journal_start: decoder.get_u64()?,
+            journal_blocks: decoder.get_u64()?,
+            data_start: decoder.get_u64()?,
+            data_blocks: decoder.get_u64()?,
+            next_object_id: decoder.get_u64()?,
+        };
+        super_block.validate()?;
+        Ok(super_block)
+    }
+
+    /// Check that the regions are in order, non-overlapping and on the volume
+    fn validate(&self) -> Result<()> {
+        let bs = self.block_size as u64;
+        let ok = self.block_size >= 512
+            && self.block_size.is_power_of_two()
+            && self.inode_slot_size >= 64
+            && self.inode_table_start == 1
+            && self.inode_table_blocks * bs >= self.inode_slots * self.inode_slot_size as u64
+            && self.bitmap_start == self.inode_table_start + self.inode_table_blocks
+            && self.bitmap_blocks * bs * 8 >= self.data_blocks
+            && self.journal_start == self.bitmap_start + self.bitmap_blocks
+            && self.journal_blocks >= 2
+            && self.data_start == self.journal_start + self.journal_blocks
+            && self.data_start.checked_add(self.data_blocks).is_some_and(|end| end <= self.total_blocks);
+        if ok { Ok(()) } else { Err(GalleonError::Corruption("superblock geometry is inconsistent")) }
+    }
+
+    /// Device byte offset of an inode slot
+    pub fn slot_offset(&self, slot: u64) -> u64 {
+        self.inode_table_start * self.block_size as u64 + slot * self.inode_slot_size as u64
+    }
+
+    /// Device byte offset of a data block
+    pub fn data_offset(&self, block: u64) -> u64 {
+        (self.data_start + block) * self.block_size as u64
+    }
+
+    /// Device byte range holding journal entries, after the header block
+    pub fn journal_entries(&self) -> (u64, u64) {
+        let bs = self.block_size as u64;
+        ((self.journal_start + 1) * bs, (self.journal_start + self.journal_blocks) * bs)
+    }
+}
+
+/// First block of the journal
+///
+/// Moving to the next sequence number is what commits a transaction: it
+/// disowns every entry written under the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalHeader {
+    pub sequence: u64,
+}
+
+impl JournalHeader {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.put_raw(&JOURNAL_MAGIC);
+        encoder.put_u64(self.sequence);
+        encoder.into_bytes()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(data);
+        if decoder.get_array::<8>()? != JOURNAL_MAGIC {
+            return Err(GalleonError::Corruption("journal header is missing"));
+        }
+        Ok(Self { sequence: decoder.get_u64()? })
+    }
+}
+
+/// Previous contents of a device byte range, saved before overwriting it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    pub sequence: u64,
+    pub offset: u64,
+    pub image: Vec<u8>,
+}
+
+impl UndoRecord {
+    /// Bytes taken by the sequence, offset, length and checksum
+    pub const HEADER_SIZE: usize = 24;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.put_u64(self.sequence);
+        encoder.put_u64(self.offset);
+        encoder.put_u32(self.image.len() as u32);
+        encoder.put_u32(self.checksum());
+        encoder.put_raw(&self.image);
+        encoder.into_bytes()
+    }
+
+    /// Decode the entry at the start of `data`, returning it with its encoded length
+    ///
+    /// `None` marks the end of the entries for `sequence`: an entry from an
+    /// earlier transaction, or one torn by a crash while it was written.
+    pub fn deserialize(data: &[u8], sequence: u64) -> Option<(Self, usize)> {
+        let mut decoder = Decoder::new(data);
+        let entry_sequence = decoder.get_u64().ok()?;
+        let offset = decoder.get_u64().ok()?;
+        let length = decoder.get_u32().ok()? as usize;
+        let checksum = decoder.get_u32().ok()?;
+        if entry_sequence != sequence {
+            return None;
+        }
+        let image = decoder.get_raw(length).ok()?.to_vec();
+        let entry = Self { sequence, offset, image };
+        (entry.checksum() == checksum).then_some((entry, Self::HEADER_SIZE + length))
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut covered = Encoder::new();
+        covered.put_u64(self.sequence);
+        covered.put_u64(self.offset);
+        covered.put_raw(&self.image);
+        crc32(&covered.into_bytes())
+    }
+}
+
+/// A run of contiguous data blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub start: u64,
+    pub count: u64,
+}
+
+impl Extent {
+    pub fn new(start: u64, count: u64) -> Self {
+        Self { start, count }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.start + self.count
+    }
+}
+
+/// Contents of an occupied inode slot
+#[derive(Debug, Clone, PartialEq)]
+pub struct InodeRecord {
+    pub id: ObjectId,
+    /// Bytes of data stored for the object, independent of the inode size
+    pub data_length: u64,
+    /// Data extents in file order
+    pub extents: Vec<Extent>,
+    /// The inode in the encoding of [`Inode::serialize`](crate::Inode::serialize)
+    pub inode: Vec<u8>,
+}
+
+impl InodeRecord {
+    const SLOT_USED: u8 = 1;
+
+    /// Fail with a message naming the object if the record needs more than `slot_size` bytes
+    ///
+    /// There is no overflow block for the extent list, so a record that
+    /// does not fit is refused rather than truncated.
+    pub fn check_fits(&self, slot_size: u32) -> Result<()> {
+        let length = self.encode().len();
+        if length > slot_size as usize {
+            return Err(GalleonError::InvalidArgumentDynamic(format!(
+                "object {:#x} needs {} bytes for its inode and {} extents, more than the {}-byte inode slot",
+                self.id.as_u64(), length, self.extents.len(), slot_size)));
+        }
+        Ok(())
+    }
+
+    /// Encode into a slot image of `slot_size` bytes
+    pub fn serialize(&self, slot_size: u32) -> Result<Vec<u8>> {
+        self.check_fits(slot_size)?;
+        let mut slot = self.encode().into_bytes();
+        slot.resize(slot_size as usize, 0);
+        Ok(slot)
+    }
+
+    fn encode(&self) -> Encoder {
+        let mut encoder = Encoder::new();
+        encoder.put_u8(Self::SLOT_USED);
+        encoder.put_u64(self.id.as_u64());
+        encoder.put_varint(self.data_length);
+        encoder.put_varint(self.extents.len() as u64);
+        for extent in &self.extents {
+            encoder.put_varint(extent.start);
+            encoder.put_varint(extent.count);
+        }
+        encoder.put_bytes(&self.inode);
+        encoder
+    }
+
+    /// Decode a slot image, returning `None` for a free slot
+    pub fn deserialize(slot: &[u8]) -> Result<Option<Self>> {
+        let mut decoder = Decoder::new(slot);
+        match decoder.get_u8()? {
+            0 => return Ok(None),
+            Self::SLOT_USED => {}
+            _ => return Err(GalleonError::Corruption("invalid inode slot state")),
+        }
+        let id = ObjectId(decoder.get_u64()?);
+        let data_length = decoder.get_varint()?;
+        let extent_count = decoder.get_varint()?;
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            extents.push(Extent::new(decoder.get_varint()?, decoder.get_varint()?));
+        }
+        let inode = decoder.get_bytes()?.to_vec();
+        Ok(Some(Self { id, data_length, extents, inode }))
+    }
+
+    pub fn allocated_blocks(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.count).sum()
+    }
+}
+
+/// Bitmap allocator for data blocks
+///
+/// Kept in memory and written back block by block; `take_dirty` names the
+/// bitmap blocks changed since the last call.
+#[derive(Debug, Clone)]
+pub struct ExtentAllocator {
+    bits: Vec<u8>,
+    data_blocks: u64,
+    free_blocks: u64,
+    bytes_per_block: usize,
+    dirty: BTreeSet<u64>,
+    /// No block below this one is free, so searches start here
+    hint: u64,
+}
+
+impl ExtentAllocator {
+    /// An allocator with every data block free
+    pub fn new(super_block: &SuperBlock) -> Self {
+        let bytes_per_block = super_block.block_size as usize;
+        Self {
+            bits: alloc::vec![0u8; super_block.bitmap_blocks as usize * bytes_per_block],
+            data_blocks: super_block.data_blocks,
+            free_blocks: super_block.data_blocks,
+            bytes_per_block,
+            dirty: (0..super_block.bitmap_blocks).collect(),
+            hint: 0,
+        }
+    }
+
+    /// Load the allocator from the on-disk bitmap
+    pub fn from_bitmap(super_block: &SuperBlock, bits: Vec<u8>) -> Self {
+        let mut allocator = Self {
+            bits,
+            data_blocks: super_block.data_blocks,
+            free_blocks: 0,
+            bytes_per_block: super_block.block_size as usize,
+            dirty: BTreeSet::new(),
+            hint: 0,
+        };
+        allocator.free_blocks = (0..allocator.data_blocks).filter(|&b| !allocator.is_used(b)).count() as u64;
+        allocator
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        self.free_blocks
+    }
+
+    pub fn is_used(&self, block: u64) -> bool {
+        self.bits[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    fn set(&mut self, block: u64, used: bool) {
+        let byte = (block / 8) as usize;
+        if used {
+            self.bits[byte] |= 1 << (block % 8);
+        } else {
+            self.bits[byte] &= !(1 << (block % 8));
+        }
+        self.dirty.insert((byte / self.bytes_per_block) as u64);
+    }
+
+    pub fn mark_used(&mut self, extent: Extent) {
+        for block in extent.start..extent.end() {
+            if !self.is_used(block) {
+                self.set(block, true);
+                self.free_blocks -= 1;
+            }
+        }
+    }
+
+    pub fn free(&mut self, extent: Extent) {
+        for block in extent.start..extent.end() {
+            if self.is_used(block) {
+                self.set(block, false);
+                self.free_blocks += 1;
+            }
+        }
+        self.hint = self.hint.min(extent.start);
+    }
+
+    /// Length of the free run starting at `start`, up to `limit` blocks
+    fn free_run(&self, start: u64, limit: u64) -> u64 {
+        let end = (start + limit).min(self.data_blocks);
+        (start..end).take_while(|&b| !self.is_used(b)).count() as u64
+    }
+
+    /// First free block at or after `block`, or `data_blocks` if there is none
+    fn next_free(&self, mut block: u64) -> u64 {
+        while block < self.data_blocks {
+            // Skip fully allocated bytes quickly
+            if block.is_multiple_of(8) && self.bits[(block / 8) as usize] == 0xff {
+                block += 8;
+                continue;
+            }
+            if !self.is_used(block) {
+                return block;
+            }
+            block += 1;
+        }
+        self.data_blocks
+    }
+
+    /// Free runs from the search hint on, in block order
+    fn runs(&self) -> impl Iterator<Item = Extent> + '_ {
+        let mut block = self.hint;
+        core::iter::from_fn(move || {
+            let start = self.next_free(block);
+            if start >= self.data_blocks {
+                return None;
+            }
+            let count = self.free_run(start, self.data_blocks - start);
+            block = start + count;
+            Some(Extent::new(start, count))
+        })
+    }
+
+    /// Every free run on the volume, in block order
+    pub fn free_runs(&self) -> Vec<Extent> {
+        self.runs().collect()
+    }
+
+    /// Allocate `count` blocks, preferring to continue directly after `after`
+    ///
+    /// Returns the new extents in order, or `NoSpace` leaving the bitmap untouched.
+    pub fn allocate(&mut self, count: u64, after: Option<Extent>) -> Result<Vec<Extent>> {
+        if count > self.free_blocks {
+            return Err(GalleonError::NoSpace);
+        }
+        let mut extents = Vec::new();
+        let mut needed = count;
+
+        if let Some(previous) = after {
+            let run = self.free_run(previous.end(), needed);
+            if run > 0 {
+                extents.push(Extent::new(previous.end(), run));
+                self.mark_used(Extent::new(previous.end(), run));
+                needed -= run;
+            }
+        }
+
+        // First fit for the whole remainder, otherwise gather runs in order.
+        // Enough blocks are free, so gathering always succeeds.
+        let mut fragments = Vec::new();
+        if needed > 0 {
+            self.hint = self.next_free(self.hint);
+            match self.runs().find(|run| run.count >= needed) {
+                Some(run) => fragments.push(Extent::new(run.start, needed)),
+                None => {
+                    for run in self.runs() {
+                        let take = run.count.min(needed);
+                        fragments.push(Extent::new(run.start, take));
+                        needed -= take;
+                        if needed == 0 {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        for &extent in &fragments {
+            self.mark_used(extent);
+        }
+        append_extents(&mut extents, &fragments);
+        Ok(extents)
+    }
+
+    /// Bitmap blocks changed since the last call, with their contents
+    pub fn take_dirty(&mut self) -> Vec<(u64, &[u8])> {
+        let dirty = core::mem::take(&mut self.dirty);
+        dirty.into_iter()
+            .map(|index| {
+                let start = index as usize * self.bytes_per_block;
+                (index, &self.bits[start..start + self.bytes_per_block])
+            })
+            .collect()
+    }
+
+    /// Have the next `take_dirty` return the bitmap blocks covering `extent`
+    pub fn mark_dirty(&mut self, extent: Extent) {
+        let bits_per_block = self.bytes_per_block as u64 * 8;
+        if extent.count > 0 {
+            self.dirty.extend(extent.start / bits_per_block..=(extent.end() - 1) / bits_per_block);
+        }
+    }
+
+    /// Have the next `take_dirty` return every bitmap block
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty = (0..(self.bits.len() / self.bytes_per_block) as u64).collect();
+    }
+}
+
+/// Append `new` to an extent list, merging with the last extent where contiguous
+pub fn append_extents(extents: &mut Vec<Extent>, new: &[Extent]) {
+    for &extent in new {
+        match extents.last_mut() {
+            Some(last) if last.end() == extent.start => last.count += extent.count,
+            _ => extents.push(extent),
+        }
+    }
+}
+
+/// Cut an extent list down to its first `blocks` blocks, returning the removed tail
+pub fn split_extents(extents: &mut Vec<Extent>, blocks: u64) -> Vec<Extent> {
+    let mut kept = 0;
+    let mut retained = Vec::new();
+    let mut removed = Vec::new();
+    for extent in extents.drain(..) {
+        let keep = extent.count.min(blocks - kept);
+        if keep > 0 {
+            retained.push(Extent::new(extent.start, keep));
+            kept += keep;
+        }
+        if keep < extent.count {
+            removed.push(Extent::new(extent.start + keep, extent.count - keep));
+        }
+    }
+    *extents = retained;
+    removed
+}
+
+/// Map a block index within a file to a data block through its extents
+pub fn map_block(extents: &[Extent], file_block: u64) -> Option<u64> {
+    let mut base = 0;
+    for extent in extents {
+        if file_block < base + extent.count {
+            return Some(extent.start + file_block - base);
+        }
+        base += extent.count;
+    }
+    None
+}
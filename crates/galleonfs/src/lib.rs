@@ -13,7 +13,7 @@
 extern crate alloc;
 
 use alloc::{boxed::Box, vec::Vec, sync::Arc};
-use core::{future::Future, pin::Pin};
+use core::{future::Future, pin::Pin, sync::atomic::{AtomicU64, Ordering}};
 
 pub mod storage;
 pub mod inode;
@@ -24,6 +24,13 @@ pub mod vfs;
 pub mod error;
 pub mod transaction;
 pub mod platform;
+pub mod encoding;
+pub mod layout;
+pub mod codec;
+pub mod crypto;
+pub mod chunked;
+#[cfg(test)]
+mod tests;
 
 pub use error::*;
 pub use storage::*;
@@ -35,29 +42,54 @@ pub use vfs::*;
 pub use transaction::*;
 pub use platform::*;
 pub use platform::*;
+pub use encoding::*;
+pub use layout::*;
+pub use chunked::*;
 
 /// Unique identifier for filesystem objects
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ObjectId(pub u64);
 
+/// Next id handed out by [`ObjectId::new`]
+///
+/// Storage that persists objects raises this past every id it holds when
+/// mounted, see [`ObjectId::reserve_through`].
+static NEXT_OBJECT_ID: AtomicU64 = AtomicU64::new(ObjectId::FIRST_DYNAMIC);
+
 impl ObjectId {
+    /// Lowest id handed out by [`ObjectId::new`]; those below are for fixed system objects
+    pub const FIRST_DYNAMIC: u64 = 16;
+
     pub fn new() -> Self {
-        // Use platform-specific entropy source
-        let rng = platform::get_rng();
-        ObjectId(rng.next_u64())
+        ObjectId(NEXT_OBJECT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Make sure [`ObjectId::new`] never returns `id` or anything below it
+    pub fn reserve_through(id: ObjectId) {
+        NEXT_OBJECT_ID.fetch_max(id.0.saturating_add(1), Ordering::Relaxed);
     }
 
     pub fn root() -> Self {
         ObjectId(0)
     }
 
+    /// Object holding the wrapped encryption keys and policies of a [`ChunkedStorage`]
+    pub fn key_table() -> Self {
+        ObjectId(1)
+    }
+
+    /// Object holding the registry of a [`SnapshotManager`]
+    pub fn snapshot_table() -> Self {
+        ObjectId(2)
+    }
+
     pub fn as_u64(&self) -> u64 {
         self.0
     }
 }
 
 /// Filesystem permissions and access control
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Permissions {
     pub mode: u32,
     pub uid: u32,
@@ -110,6 +142,10 @@ pub struct FilesystemStats {
     pub block_size: u32,
     pub fragment_size: u32,
     pub max_filename_length: u32,
+    /// Blocks referenced from more than one place
+    pub shared_blocks: u64,
+    /// Bytes saved by sharing blocks instead of storing each reference
+    pub dedup_saved_space: u64,
 }
 
 /// Main filesystem trait - extensible design for different filesystem types
@@ -193,6 +229,7 @@ pub struct GalleonFS {
     replication: Option<Box<dyn ReplicationManager>>,
     advanced: Option<Arc<dyn AdvancedFeatures>>,
     root_inode: ObjectId,
+    vfs: VfsManager,
 }
 
 impl GalleonFS {
@@ -204,6 +241,7 @@ impl GalleonFS {
             replication: None,
             advanced: None,
             root_inode: ObjectId::root(),
+            vfs: VfsManager::new(Box::new(StorageFilesystem::new(storage.clone()))),
         };
 
         // Initialize root directory if it doesn't exist
@@ -224,6 +262,21 @@ impl GalleonFS {
         self
     }
 
+    /// Compress, encrypt and deduplicate files according to the policies in `features`
+    ///
+    /// Wraps the storage in a [`ChunkedStorage`], which also keeps the
+    /// snapshots of `features`, so call this before mounting anything in the
+    /// VFS. The encryption keys, policies and snapshots saved on the storage
+    /// are loaded into the managers of `features`.
+    pub async fn with_chunked_storage(mut self, features: &GalleonAdvancedFeatures) -> Result<Self> {
+        let storage = Arc::new(ChunkedStorage::new(self.storage.clone(), features));
+        storage.load_keys().await?;
+        features.snapshot_manager().attach_storage(storage.clone()).await?;
+        self.storage = storage;
+        self.vfs = VfsManager::new(Box::new(StorageFilesystem::new(self.storage.clone())));
+        Ok(self)
+    }
+
     /// Initialize the root directory
     async fn init_root_directory(&mut self) -> Result<()> {
         // Check if root directory already exists
@@ -249,23 +302,28 @@ impl GalleonFS {
         let dir_data = empty_dir.serialize()?;
         self.storage.write_data(self.root_inode, 0, &dir_data, &transaction).await?;
 
-        transaction.commit().await?;
+        transaction.commit(self.storage.as_ref()).await?;
 
         Ok(())
     }
 
+    /// Mount namespace rooted at this filesystem
+    pub fn vfs(&self) -> &VfsManager {
+        &self.vfs
+    }
+
     /// Mount additional filesystems
     pub async fn mount(&self, 
-                      _path: &str,
-                      _filesystem: Box<dyn Filesystem>,
-                      _options: MountOptions) -> Result<()> {
-        // VFS mounting implementation would go here
-        Err(GalleonError::NotSupported)
+                      path: &str,
+                      filesystem: Box<dyn Filesystem>,
+                      options: MountOptions) -> Result<()> {
+        self.vfs.mount(path, filesystem, "galleonfs", "none", options).await?;
+        Ok(())
     }
 
-    /// Unmount filesystem
-    pub async fn unmount(&self, _path: &str) -> Result<()> {
-        Err(GalleonError::NotSupported)
+    /// Unmount filesystem, failing while files are open on it
+    pub async fn unmount(&self, path: &str) -> Result<()> {
+        self.vfs.unmount(path, false).await
     }
 }
 
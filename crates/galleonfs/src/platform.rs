@@ -334,6 +334,33 @@ pub trait StorageDevice {
     fn is_read_only(&self) -> bool;
 }
 
+/// A shared device, e.g. one handed to a storage backend and kept for remounting
+impl<D: StorageDevice + ?Sized> StorageDevice for alloc::sync::Arc<D> {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        (**self).read(offset, buffer)
+    }
+
+    fn write(&self, offset: u64, buffer: &[u8]) -> Result<usize, &'static str> {
+        (**self).write(offset, buffer)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        (**self).flush()
+    }
+
+    fn capacity(&self) -> u64 {
+        (**self).capacity()
+    }
+
+    fn block_size(&self) -> u32 {
+        (**self).block_size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        (**self).is_read_only()
+    }
+}
+
 /// Mock storage device for testing
 pub struct MockStorageDevice {
     data: spin::Mutex<alloc::vec::Vec<u8>>,
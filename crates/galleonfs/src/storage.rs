@@ -10,9 +10,11 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec, collections::BTreeMap, string::String};
+use alloc::{boxed::Box, vec::Vec, vec, collections::{BTreeMap, BTreeSet}, string::String, format};
 use core::{future::Future, pin::Pin};
-use super::{Result, ObjectId, Inode, FilesystemStats, Transaction, GalleonError};
+use super::{Result, ObjectId, Inode, FilesystemStats, Transaction, TransactionOperation, GalleonError};
+use super::platform::StorageDevice;
+use super::layout::{SuperBlock, InodeRecord, Extent, ExtentAllocator, JournalHeader, UndoRecord, append_extents, split_extents, map_block};
 
 /// Storage backend trait - allows different storage implementations
 pub trait StorageBackend: Send + Sync {
@@ -22,19 +24,19 @@ pub trait StorageBackend: Send + Sync {
     /// Read an inode from storage
     fn read_inode(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>>;
 
-    /// Write an inode to storage
+    /// Record writing an inode in `transaction`
     fn write_inode(&self, inode: &Inode, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 
-    /// Delete an inode from storage
+    /// Record deleting an inode and its data in `transaction`
     fn delete_inode(&self, id: ObjectId, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 
     /// Read data from storage
     fn read_data(&self, id: ObjectId, offset: u64, length: u64) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + '_>>;
 
-    /// Write data to storage
+    /// Record a data write in `transaction`, returning the bytes it will write
     fn write_data(&self, id: ObjectId, offset: u64, data: &[u8], transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>>;
 
-    /// Truncate data
+    /// Record truncating or extending data in `transaction`
     fn truncate(&self, id: ObjectId, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 
     /// Get storage statistics
@@ -46,16 +48,44 @@ pub trait StorageBackend: Send + Sync {
     /// Check storage integrity
     fn check_integrity(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send + '_>>;
 
-    /// Allocate space for data
+    /// Reserve space for data
+    ///
+    /// Takes effect at once; a reservation is not undone if `transaction` aborts.
     fn allocate(&self, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>>;
 
-    /// Deallocate space
+    /// Record releasing space in `transaction`
     fn deallocate(&self, offset: u64, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 
+    /// Apply the operations of a committing transaction, in order
+    ///
+    /// Either every operation takes effect or, if one fails, the ones before
+    /// it are undone and the error returned.
+    fn apply_transaction<'a>(&'a self, operations: &'a [TransactionOperation]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
     /// Get backend capabilities
     fn capabilities(&self) -> StorageCapabilities;
 }
 
+/// Record writing `inode` in `transaction`, given its committed state
+///
+/// Shared by the backends, which only differ in how they look up `committed`.
+fn record_inode_write(transaction: &Transaction, inode: Inode, committed: Option<Inode>) -> Result<()> {
+    let id = inode.id();
+    let operation = match transaction.pending_inode(id).unwrap_or(committed) {
+        Some(old_inode) => TransactionOperation::UpdateInode { id, old_inode, new_inode: inode },
+        None => TransactionOperation::CreateInode { id, inode },
+    };
+    transaction.add_operation(operation)
+}
+
+/// Record deleting `id` in `transaction`; deleting a missing inode does nothing
+fn record_inode_delete(transaction: &Transaction, id: ObjectId, committed: Option<Inode>) -> Result<()> {
+    match transaction.pending_inode(id).unwrap_or(committed) {
+        Some(inode) => transaction.add_operation(TransactionOperation::DeleteInode { id, inode }),
+        None => Ok(()),
+    }
+}
+
 /// Storage backend capabilities
 #[derive(Debug, Clone)]
 pub struct StorageCapabilities {
@@ -108,6 +138,65 @@ impl MemoryStorage {
         let data = self.data.lock();
         data.values().map(|v| v.len() as u64).sum()
     }
+
+    fn apply_operation(
+        inodes: &mut BTreeMap<ObjectId, Inode>,
+        data: &mut BTreeMap<ObjectId, Vec<u8>>,
+        used: &mut u64,
+        total_space: u64,
+        operation: &TransactionOperation,
+    ) -> Result<()> {
+        match operation {
+            TransactionOperation::CreateInode { id, inode } => {
+                if inodes.contains_key(id) {
+                    return Err(GalleonError::AlreadyExists);
+                }
+                inodes.insert(*id, inode.clone());
+            }
+            TransactionOperation::UpdateInode { id, new_inode, .. } => {
+                let current = inodes.get_mut(id).ok_or(GalleonError::NotFound)?;
+                *current = new_inode.clone();
+            }
+            TransactionOperation::DeleteInode { id, .. } => {
+                inodes.remove(id).ok_or(GalleonError::NotFound)?;
+                if let Some(file_data) = data.remove(id) {
+                    *used -= file_data.len() as u64;
+                }
+            }
+            TransactionOperation::WriteData { id, offset, data: new_data } => {
+                // Check space constraints
+                let current_size = data.get(id).map(|v| v.len()).unwrap_or(0);
+                let end = *offset as usize + new_data.len();
+                let size_increase = end.saturating_sub(current_size) as u64;
+                if *used + size_increase > total_space {
+                    return Err(GalleonError::NoSpace);
+                }
+                *used += size_increase;
+
+                let file_data = data.entry(*id).or_default();
+                if end > file_data.len() {
+                    file_data.resize(end, 0);
+                }
+                file_data[*offset as usize..end].copy_from_slice(new_data);
+            }
+            TransactionOperation::TruncateData { id, new_size, .. } => {
+                let file_data = data.get_mut(id).ok_or(GalleonError::NotFound)?;
+                let new_size = *new_size as usize;
+                if new_size > file_data.len() {
+                    let size_increase = (new_size - file_data.len()) as u64;
+                    if *used + size_increase > total_space {
+                        return Err(GalleonError::NoSpace);
+                    }
+                    *used += size_increase;
+                } else {
+                    *used -= (file_data.len() - new_size) as u64;
+                }
+                file_data.resize(new_size, 0);
+            }
+            TransactionOperation::AllocateSpace { .. } | TransactionOperation::DeallocateSpace { .. } => {}
+        }
+        Ok(())
+    }
 }
 
 impl StorageBackend for MemoryStorage {
@@ -127,24 +216,20 @@ impl StorageBackend for MemoryStorage {
         })
     }
 
-    fn write_inode(&self, inode: &Inode, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    fn write_inode(&self, inode: &Inode, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
         let inode = inode.clone();
+        let transaction = transaction.clone();
         Box::pin(async move {
-            let mut inodes = self.inodes.lock();
-            inodes.insert(inode.id(), inode);
-            Ok(())
+            let committed = self.inodes.lock().get(&inode.id()).cloned();
+            record_inode_write(&transaction, inode, committed)
         })
     }
 
-    fn delete_inode(&self, id: ObjectId, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    fn delete_inode(&self, id: ObjectId, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
         Box::pin(async move {
-            let mut inodes = self.inodes.lock();
-            let mut data = self.data.lock();
-            
-            inodes.remove(&id);
-            data.remove(&id);
-            
-            Ok(())
+            let committed = self.inodes.lock().get(&id).cloned();
+            record_inode_delete(&transaction, id, committed)
         })
     }
 
@@ -166,44 +251,21 @@ impl StorageBackend for MemoryStorage {
         })
     }
 
-    fn write_data(&self, id: ObjectId, offset: u64, new_data: &[u8], _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+    fn write_data(&self, id: ObjectId, offset: u64, new_data: &[u8], transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
         let new_data = new_data.to_vec();
+        let transaction = transaction.clone();
         Box::pin(async move {
-            let mut data = self.data.lock();
-            
-            // Check space constraints
-            let current_size = data.get(&id).map(|v| v.len()).unwrap_or(0);
-            let new_size = (offset as usize + new_data.len()).max(current_size);
-            let size_increase = new_size.saturating_sub(current_size) as u64;
-            
-            if self.used_space() + size_increase > self.total_space {
-                return Err(GalleonError::NoSpace);
-            }
-
-            let file_data = data.entry(id).or_insert_with(Vec::new);
-            
-            // Extend file if necessary
-            if offset as usize + new_data.len() > file_data.len() {
-                file_data.resize(offset as usize + new_data.len(), 0);
-            }
-            
-            // Write data
-            file_data[offset as usize..offset as usize + new_data.len()]
-                .copy_from_slice(&new_data);
-            
-            Ok(new_data.len() as u64)
+            let written = new_data.len() as u64;
+            transaction.add_operation(TransactionOperation::WriteData { id, offset, data: new_data })?;
+            Ok(written)
         })
     }
 
-    fn truncate(&self, id: ObjectId, size: u64, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    fn truncate(&self, id: ObjectId, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
         Box::pin(async move {
-            let mut data = self.data.lock();
-            if let Some(file_data) = data.get_mut(&id) {
-                file_data.resize(size as usize, 0);
-                Ok(())
-            } else {
-                Err(GalleonError::NotFound)
-            }
+            let old_size = self.data.lock().get(&id).map(|v| v.len() as u64).unwrap_or(0);
+            transaction.add_operation(TransactionOperation::TruncateData { id, old_size, new_size: size })
         })
     }
 
@@ -221,6 +283,8 @@ impl StorageBackend for MemoryStorage {
                 block_size: 4096,
                 fragment_size: 4096,
                 max_filename_length: 255,
+                shared_blocks: 0,
+                dedup_saved_space: 0,
             })
         })
     }
@@ -257,32 +321,216 @@ impl StorageBackend for MemoryStorage {
         })
     }
 
+    fn apply_transaction<'a>(&'a self, operations: &'a [TransactionOperation]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut inodes = self.inodes.lock();
+            let mut data = self.data.lock();
+            // State of each touched object before the transaction, to undo on failure
+            let mut undo: BTreeMap<ObjectId, (Option<Inode>, Option<Vec<u8>>)> = BTreeMap::new();
+            let mut used: u64 = data.values().map(|v| v.len() as u64).sum();
+
+            for operation in operations {
+                let id = match operation {
+                    TransactionOperation::CreateInode { id, .. }
+                    | TransactionOperation::UpdateInode { id, .. }
+                    | TransactionOperation::DeleteInode { id, .. }
+                    | TransactionOperation::WriteData { id, .. }
+                    | TransactionOperation::TruncateData { id, .. } => *id,
+                    TransactionOperation::AllocateSpace { .. }
+                    | TransactionOperation::DeallocateSpace { .. } => continue,
+                };
+                undo.entry(id).or_insert_with(|| (inodes.get(&id).cloned(), data.get(&id).cloned()));
+
+                if let Err(error) = Self::apply_operation(&mut inodes, &mut data, &mut used, self.total_space, operation) {
+                    for (id, (inode, file_data)) in undo {
+                        match inode {
+                            Some(inode) => { inodes.insert(id, inode); }
+                            None => { inodes.remove(&id); }
+                        }
+                        match file_data {
+                            Some(file_data) => { data.insert(id, file_data); }
+                            None => { data.remove(&id); }
+                        }
+                    }
+                    return Err(error);
+                }
+            }
+            Ok(())
+        })
+    }
+
     fn capabilities(&self) -> StorageCapabilities {
         self.capabilities.clone()
     }
 }
 
+/// Object ids reserved in the superblock each time a new object passes the reservation
+const OBJECT_ID_BATCH: u64 = 1024;
+
 /// Platform storage backend for embedded systems
+///
+/// Keeps inodes and data on a [`StorageDevice`] in the layout described in
+/// [`layout`](super::layout). Metadata is written through on every change;
+/// the block cache only saves re-reading blocks. Every range a transaction
+/// overwrites is saved to the journal first, so a transaction cut short by a
+/// crash is undone when the volume is next opened.
 pub struct PlatformStorage {
-    device: Box<dyn super::platform::StorageDevice + Send + Sync>,
+    device: Box<dyn StorageDevice + Send + Sync>,
     capabilities: StorageCapabilities,
     block_cache: spin::Mutex<BTreeMap<u64, Vec<u8>>>,
     cache_size: usize,
+    volume: spin::Mutex<Volume>,
+}
+
+/// What a committing transaction has changed so far on a PlatformStorage
+struct UndoLog {
+    /// Previous contents of every byte range overwritten, oldest first
+    writes: Vec<(u64, Vec<u8>)>,
+    /// Released only once the whole transaction has applied
+    freed_extents: Vec<Extent>,
+    freed_slots: Vec<u64>,
+    /// Device byte ranges of blocks allocated by this transaction; nothing
+    /// refers to them until it commits, so their old contents are not saved
+    fresh: Vec<(u64, u64)>,
+    /// Sequence number the journal entries are tagged with
+    sequence: u64,
+    /// Device byte offset of the next journal entry, and where the journal ends
+    journal_next: u64,
+    journal_end: u64,
+}
+
+impl UndoLog {
+    fn new(volume: &Volume) -> Self {
+        let (journal_next, journal_end) = volume.super_block.journal_entries();
+        Self {
+            writes: Vec::new(),
+            freed_extents: Vec::new(),
+            freed_slots: Vec::new(),
+            fresh: Vec::new(),
+            sequence: volume.journal_sequence,
+            journal_next,
+            journal_end,
+        }
+    }
+
+    fn is_fresh(&self, offset: u64, len: usize) -> bool {
+        self.fresh.iter().any(|&(start, end)| start <= offset && offset + len as u64 <= end)
+    }
+}
+
+/// In-memory state of a mounted volume
+struct Volume {
+    super_block: SuperBlock,
+    allocator: ExtentAllocator,
+    /// Inode table slot of every object
+    objects: BTreeMap<ObjectId, u64>,
+    free_slots: BTreeSet<u64>,
+    /// Sequence number of the next transaction's journal entries
+    journal_sequence: u64,
+    /// Runs handed out by [`StorageBackend::allocate`] whose transaction has
+    /// not committed; used in memory but still free in the on-disk bitmap
+    reserved: Vec<Extent>,
 }
 
 impl PlatformStorage {
-    pub fn new(device: Box<dyn super::platform::StorageDevice + Send + Sync>, cache_size: usize) -> Self {
-unsafe impl Send for PlatformStorage {}
-unsafe impl Sync for PlatformStorage {}
-        let mut capabilities = StorageCapabilities::default();
-        capabilities.block_size = device.block_size();
-        capabilities.max_file_size = device.capacity();
-        
+    /// Write an empty filesystem to `device`, destroying its contents
+    pub fn format(device: Box<dyn StorageDevice + Send + Sync>, cache_size: usize) -> Result<Self> {
+        if device.is_read_only() {
+            return Err(GalleonError::ReadOnlyFilesystem);
+        }
+        let super_block = SuperBlock::for_device(device.capacity(), device.block_size())?;
+        let volume = Volume {
+            allocator: ExtentAllocator::new(&super_block),
+            objects: BTreeMap::new(),
+            free_slots: (0..super_block.inode_slots).collect(),
+            journal_sequence: 1,
+            reserved: Vec::new(),
+            super_block,
+        };
+        let storage = Self::mount(device, cache_size, volume);
+
+        {
+            let mut volume = storage.volume.lock();
+            let zeros = vec![0u8; volume.super_block.block_size as usize];
+            let table = volume.super_block.inode_table_start;
+            for block in table..table + volume.super_block.inode_table_blocks {
+                storage.write_block(block, &zeros)?;
+            }
+            storage.flush_bitmap(&mut volume)?;
+            storage.write_journal_header(&volume)?;
+            // The superblock goes last so a torn format is not mountable
+            let mut image = volume.super_block.serialize();
+            image.resize(volume.super_block.block_size as usize, 0);
+            storage.write_block(0, &image)?;
+        }
+        storage.device.flush().map_err(GalleonError::IoError)?;
+        Ok(storage)
+    }
+
+    /// Mount a device previously written by [`PlatformStorage::format`]
+    pub fn open(device: Box<dyn StorageDevice + Send + Sync>, cache_size: usize) -> Result<Self> {
+        let block_size = device.block_size();
+        let mut header = vec![0u8; block_size as usize];
+        device.read(0, &mut header).map_err(GalleonError::IoError)?;
+        let super_block = SuperBlock::deserialize(&header)?;
+        if super_block.block_size != block_size
+            || super_block.total_blocks * block_size as u64 > device.capacity() {
+            return Err(GalleonError::Corruption("volume does not match the device geometry"));
+        }
+
+        // The allocator is loaded once the journal has been recovered
+        let volume = Volume {
+            allocator: ExtentAllocator::new(&super_block),
+            objects: BTreeMap::new(),
+            free_slots: BTreeSet::new(),
+            journal_sequence: 0,
+            reserved: Vec::new(),
+            super_block,
+        };
+        let storage = Self::mount(device, cache_size, volume);
+
+        {
+            let mut volume = storage.volume.lock();
+            if storage.recover_journal(&mut volume)? {
+                // The superblock may have been among the ranges put back
+                volume.super_block = SuperBlock::deserialize(&storage.read_block(0)?)?;
+            }
+            let mut bits = vec![0u8; (volume.super_block.bitmap_blocks * block_size as u64) as usize];
+            storage.read_bytes(volume.super_block.bitmap_start * block_size as u64, &mut bits)?;
+            volume.allocator = ExtentAllocator::from_bitmap(&volume.super_block, bits);
+            for slot in 0..volume.super_block.inode_slots {
+                match storage.read_record(&volume.super_block, slot)? {
+                    Some(record) => {
+                        if volume.objects.insert(record.id, slot).is_some() {
+                            return Err(GalleonError::CorruptionDynamic(
+                                format!("object {:#x} appears in more than one inode slot", record.id.as_u64())));
+                        }
+                    }
+                    None => {
+                        volume.free_slots.insert(slot);
+                    }
+                }
+            }
+            // New objects must not reuse an id already on the volume
+            let highest = volume.objects.keys().next_back().map_or(0, |id| id.as_u64());
+            ObjectId::reserve_through(ObjectId(highest.max(volume.super_block.next_object_id.saturating_sub(1))));
+        }
+        Ok(storage)
+    }
+
+    fn mount(device: Box<dyn StorageDevice + Send + Sync>, cache_size: usize, volume: Volume) -> Self {
+        let capabilities = StorageCapabilities {
+            block_size: device.block_size(),
+            max_file_size: volume.super_block.data_blocks * device.block_size() as u64,
+            ..StorageCapabilities::default()
+        };
+
         Self {
             device,
             capabilities,
             block_cache: spin::Mutex::new(BTreeMap::new()),
             cache_size,
+            volume: spin::Mutex::new(volume),
         }
     }
 
@@ -335,69 +583,444 @@ unsafe impl Sync for PlatformStorage {}
         
         cache.insert(block_number, data);
     }
+
+    /// Read `buffer.len()` bytes at a device byte offset
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let block_size = self.device.block_size() as u64;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let len = (block_size as usize - within).min(buffer.len() - done);
+            let block = self.read_block(position / block_size)?;
+            buffer[done..done + len].copy_from_slice(&block[within..within + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at a device byte offset, merging partial blocks
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let block_size = self.device.block_size() as u64;
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let len = (block_size as usize - within).min(data.len() - done);
+            if len == block_size as usize {
+                self.write_block(position / block_size, &data[done..done + len])?;
+            } else {
+                let mut block = self.read_block(position / block_size)?;
+                block[within..within + len].copy_from_slice(&data[done..done + len]);
+                self.write_block(position / block_size, &block)?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn read_record(&self, super_block: &SuperBlock, slot: u64) -> Result<Option<InodeRecord>> {
+        let mut image = vec![0u8; super_block.inode_slot_size as usize];
+        self.read_bytes(super_block.slot_offset(slot), &mut image)?;
+        InodeRecord::deserialize(&image)
+    }
+
+    /// Read the record of an object that must exist
+    fn object_record(&self, volume: &Volume, id: ObjectId) -> Result<(u64, InodeRecord)> {
+        let slot = *volume.objects.get(&id).ok_or(GalleonError::NotFound)?;
+        let record = self.read_record(&volume.super_block, slot)?
+            .ok_or(GalleonError::Corruption("inode slot of a live object is empty"))?;
+        Ok((slot, record))
+    }
+
+    /// Bitmap blocks changed since the last flush, as device offsets and images
+    ///
+    /// Reserved runs are left out, as their transactions have not committed.
+    fn dirty_bitmap(volume: &mut Volume) -> Vec<(u64, Vec<u8>)> {
+        let block_size = volume.super_block.block_size as u64;
+        let bits_per_block = block_size * 8;
+        let bitmap_start = volume.super_block.bitmap_start;
+        let reserved = &volume.reserved;
+        volume.allocator.take_dirty().into_iter()
+            .map(|(index, bits)| {
+                let mut image = bits.to_vec();
+                let first = index * bits_per_block;
+                for extent in reserved {
+                    for block in extent.start.max(first)..extent.end().min(first + bits_per_block) {
+                        let bit = block - first;
+                        image[(bit / 8) as usize] &= !(1 << (bit % 8));
+                    }
+                }
+                ((bitmap_start + index) * block_size, image)
+            })
+            .collect()
+    }
+
+    /// Write the bitmap blocks changed since the last flush
+    fn flush_bitmap(&self, volume: &mut Volume) -> Result<()> {
+        for (offset, image) in Self::dirty_bitmap(volume) {
+            self.write_bytes(offset, &image)?;
+        }
+        Ok(())
+    }
+
+    fn write_journal_header(&self, volume: &Volume) -> Result<()> {
+        let mut image = JournalHeader { sequence: volume.journal_sequence }.serialize();
+        image.resize(volume.super_block.block_size as usize, 0);
+        self.write_block(volume.super_block.journal_start, &image)?;
+        self.device.flush().map_err(GalleonError::IoError)
+    }
+
+    /// Retire the journal entries of a transaction that has finished either way
+    fn close_journal(&self, volume: &mut Volume, undo: &UndoLog) -> Result<()> {
+        if undo.journal_next == volume.super_block.journal_entries().0 {
+            // Nothing was logged under this sequence number, so it can be reused
+            return Ok(());
+        }
+        self.device.flush().map_err(GalleonError::IoError)?;
+        volume.journal_sequence += 1;
+        self.write_journal_header(volume)
+    }
+
+    /// Put back what an unfinished transaction overwrote, returning whether there was one
+    fn recover_journal(&self, volume: &mut Volume) -> Result<bool> {
+        let header = self.read_block(volume.super_block.journal_start)?;
+        volume.journal_sequence = JournalHeader::deserialize(&header)?.sequence;
+        let (start, end) = volume.super_block.journal_entries();
+        let mut journal = vec![0u8; (end - start) as usize];
+        self.read_bytes(start, &mut journal)?;
+
+        let mut entries = Vec::new();
+        let mut position = 0;
+        while let Some((entry, length)) = UndoRecord::deserialize(&journal[position..], volume.journal_sequence) {
+            entries.push(entry);
+            position += length;
+        }
+        if entries.is_empty() {
+            return Ok(false);
+        }
+        if self.device.is_read_only() {
+            return Err(GalleonError::InvalidState("volume has an unfinished transaction to undo but the device is read-only"));
+        }
+        // Newest first, so the oldest image of each range wins
+        for entry in entries.iter().rev() {
+            self.write_bytes(entry.offset, &entry.image)?;
+        }
+        self.device.flush().map_err(GalleonError::IoError)?;
+        volume.journal_sequence += 1;
+        self.write_journal_header(volume)?;
+        Ok(true)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.device.is_read_only() {
+            Err(GalleonError::ReadOnlyFilesystem)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run a metadata update, undoing its in-memory allocations if it fails
+    fn update<T>(&self, operation: impl FnOnce(&mut Volume) -> Result<T>) -> Result<T> {
+        self.check_writable()?;
+        let mut volume = self.volume.lock();
+        let saved = volume.allocator.clone();
+        match operation(&mut volume) {
+            Ok(value) => {
+                self.flush_bitmap(&mut volume)?;
+                Ok(value)
+            }
+            Err(error) => {
+                // The bitmap may have been flushed part way; put all of it back
+                volume.allocator = saved;
+                volume.allocator.mark_all_dirty();
+                self.flush_bitmap(&mut volume)?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Refuse an inode that cannot be stored in a slot together with its current extents
+    fn check_inode_fits(&self, inode: &Inode) -> Result<()> {
+        let volume = self.volume.lock();
+        let (data_length, extents) = match volume.objects.contains_key(&inode.id()) {
+            true => {
+                let (_, record) = self.object_record(&volume, inode.id())?;
+                (record.data_length, record.extents)
+            }
+            false => (0, Vec::new()),
+        };
+        let record = InodeRecord { id: inode.id(), data_length, extents, inode: inode.serialize()? };
+        record.check_fits(volume.super_block.inode_slot_size)
+    }
+
+    /// Inode of `id` as committed to the device
+    fn committed_inode(&self, id: ObjectId) -> Result<Option<Inode>> {
+        let volume = self.volume.lock();
+        if !volume.objects.contains_key(&id) {
+            return Ok(None);
+        }
+        let (_, record) = self.object_record(&volume, id)?;
+        Inode::deserialize(&record.inode).map(Some)
+    }
+
+    /// Write at a device byte offset, saving what was there to the journal first
+    fn write_logged(&self, undo: &mut UndoLog, offset: u64, data: &[u8]) -> Result<()> {
+        if !undo.is_fresh(offset, data.len()) {
+            let mut image = vec![0u8; data.len()];
+            self.read_bytes(offset, &mut image)?;
+            let entry = UndoRecord { sequence: undo.sequence, offset, image };
+            let encoded = entry.serialize();
+            if undo.journal_next + encoded.len() as u64 > undo.journal_end {
+                return Err(GalleonError::TransactionError("transaction overwrites more than the journal holds"));
+            }
+            // The saved image must be on the device before the range changes
+            self.write_bytes(undo.journal_next, &encoded)?;
+            self.device.flush().map_err(GalleonError::IoError)?;
+            undo.journal_next += encoded.len() as u64;
+            undo.writes.push((offset, entry.image));
+        }
+        self.write_bytes(offset, data)
+    }
+
+    /// Data blocks covered by a device byte range from [`StorageBackend::allocate`]
+    fn data_extent(super_block: &SuperBlock, offset: u64, size: u64) -> Result<Extent> {
+        let block_size = super_block.block_size as u64;
+        let data_offset = super_block.data_offset(0);
+        if offset < data_offset || !offset.is_multiple_of(block_size) {
+            return Err(GalleonError::InvalidArgument("offset is not a data block"));
+        }
+        let extent = Extent::new((offset - data_offset) / block_size, size.div_ceil(block_size).max(1));
+        if extent.end() > super_block.data_blocks {
+            return Err(GalleonError::InvalidArgument("range runs past the data area"));
+        }
+        Ok(extent)
+    }
+
+    fn apply_operation(&self, volume: &mut Volume, operation: &TransactionOperation, undo: &mut UndoLog) -> Result<()> {
+        let slot_size = volume.super_block.inode_slot_size;
+        match operation {
+            TransactionOperation::CreateInode { id, inode } => {
+                if volume.objects.contains_key(id) {
+                    return Err(GalleonError::AlreadyExists);
+                }
+                let slot = *volume.free_slots.first().ok_or(GalleonError::NoInodes)?;
+                let record = InodeRecord { id: *id, data_length: 0, extents: Vec::new(), inode: inode.serialize()? };
+                self.write_logged(undo, volume.super_block.slot_offset(slot), &record.serialize(slot_size)?)?;
+                volume.free_slots.remove(&slot);
+                volume.objects.insert(*id, slot);
+                if id.as_u64() >= volume.super_block.next_object_id {
+                    // Reserve a batch so the superblock is not rewritten for every new object
+                    volume.super_block.next_object_id = id.as_u64().saturating_add(1 + OBJECT_ID_BATCH);
+                    self.write_logged(undo, 0, &volume.super_block.serialize())?;
+                }
+            }
+            TransactionOperation::UpdateInode { id, new_inode, .. } => {
+                let (slot, mut record) = self.object_record(volume, *id)?;
+                record.inode = new_inode.serialize()?;
+                self.write_logged(undo, volume.super_block.slot_offset(slot), &record.serialize(slot_size)?)?;
+            }
+            TransactionOperation::DeleteInode { id, .. } => {
+                let (slot, record) = self.object_record(volume, *id)?;
+                self.write_logged(undo, volume.super_block.slot_offset(slot), &vec![0u8; slot_size as usize])?;
+                volume.objects.remove(id);
+                undo.freed_extents.extend(record.extents);
+                undo.freed_slots.push(slot);
+            }
+            TransactionOperation::WriteData { id, offset, data } => {
+                let (slot, mut record) = self.object_record(volume, *id)?;
+                let end = offset + data.len() as u64;
+                let length = record.data_length.max(end);
+                self.grow_extents(volume, undo, &mut record, length, (*offset, end))?;
+                record.data_length = length;
+                // Checked before any data is overwritten
+                let image = record.serialize(slot_size)?;
+                self.file_io(&volume.super_block, &record.extents, *offset, data.len(), |device_offset, at, len| {
+                    self.write_logged(undo, device_offset, &data[at..at + len])
+                })?;
+                self.write_logged(undo, volume.super_block.slot_offset(slot), &image)?;
+            }
+            TransactionOperation::TruncateData { id, new_size, .. } => {
+                let size = *new_size;
+                let (slot, mut record) = self.object_record(volume, *id)?;
+                let block_size = volume.super_block.block_size as u64;
+                if size > record.data_length {
+                    // Bytes past the old length are already zero
+                    self.grow_extents(volume, undo, &mut record, size, (0, 0))?;
+                } else {
+                    let removed = split_extents(&mut record.extents, size.div_ceil(block_size));
+                    let tail = size % block_size;
+                    if tail != 0 && size < record.data_length {
+                        let block = map_block(&record.extents, size / block_size)
+                            .ok_or(GalleonError::Corruption("file data runs past its extents"))?;
+                        let stale = (block_size - tail).min(record.data_length - size) as usize;
+                        self.write_logged(undo, volume.super_block.data_offset(block) + tail, &vec![0u8; stale])?;
+                    }
+                    undo.freed_extents.extend(removed);
+                }
+                record.data_length = size;
+                let image = record.serialize(slot_size)?;
+                self.write_logged(undo, volume.super_block.slot_offset(slot), &image)?;
+            }
+            TransactionOperation::AllocateSpace { offset, size } => {
+                let extent = Self::data_extent(&volume.super_block, *offset, *size)?;
+                match volume.reserved.iter().position(|reserved| *reserved == extent) {
+                    Some(index) => {
+                        // Already used in memory; the bitmap block still has to be written
+                        volume.reserved.remove(index);
+                        volume.allocator.mark_dirty(extent);
+                    }
+                    None => volume.allocator.mark_used(extent),
+                }
+            }
+            TransactionOperation::DeallocateSpace { offset, size } => {
+                undo.freed_extents.push(Self::data_extent(&volume.super_block, *offset, *size)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Give `record` enough blocks for `length` bytes, zeroing the ones `keep` will not overwrite
+    ///
+    /// The bitmap reaches the device with the rest of the transaction.
+    fn grow_extents(&self, volume: &mut Volume, undo: &mut UndoLog, record: &mut InodeRecord, length: u64, keep: (u64, u64)) -> Result<()> {
+        let block_size = volume.super_block.block_size as u64;
+        let have = record.allocated_blocks();
+        let need = length.div_ceil(block_size);
+        if need <= have {
+            return Ok(());
+        }
+        let new = volume.allocator.allocate(need - have, record.extents.last().copied())?;
+        append_extents(&mut record.extents, &new);
+        // Refuse a record that would outgrow its slot before touching any block
+        record.check_fits(volume.super_block.inode_slot_size)?;
+        undo.fresh.extend(new.iter().map(|extent| {
+            (volume.super_block.data_offset(extent.start), volume.super_block.data_offset(extent.end()))
+        }));
+        let zeros = vec![0u8; block_size as usize];
+        let mut file_block = have;
+        for extent in &new {
+            for block in extent.start..extent.end() {
+                let start = file_block * block_size;
+                if start < keep.0 || start + block_size > keep.1 {
+                    self.write_block(volume.super_block.data_start + block, &zeros)?;
+                }
+                file_block += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy between a buffer and the data of a file, block by block
+    fn file_io(&self, super_block: &SuperBlock, extents: &[Extent], offset: u64, len: usize,
+               mut io: impl FnMut(u64, usize, usize) -> Result<()>) -> Result<()> {
+        let block_size = super_block.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let chunk = ((block_size - within) as usize).min(len - done);
+            let block = map_block(extents, position / block_size)
+                .ok_or(GalleonError::Corruption("file data runs past its extents"))?;
+            io(super_block.data_offset(block) + within, done, chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
 }
 
 impl StorageBackend for PlatformStorage {
-    fn exists(&self, _id: ObjectId) -> Pin<Box<dyn Future<Output = Result<bool>> + Send + '_>> {
+    fn exists(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<bool>> + Send + '_>> {
         Box::pin(async move {
-            // TODO: Implement existence check based on platform storage layout
-            Err(GalleonError::NotSupported)
+            Ok(self.volume.lock().objects.contains_key(&id))
         })
     }
 
-    fn read_inode(&self, _id: ObjectId) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>> {
+    fn read_inode(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>> {
         Box::pin(async move {
-            // TODO: Implement inode reading from platform storage
-            Err(GalleonError::NotSupported)
+            let volume = self.volume.lock();
+            let (_, record) = self.object_record(&volume, id)?;
+            Inode::deserialize(&record.inode)
         })
     }
 
-    fn write_inode(&self, _inode: &Inode, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    fn write_inode(&self, inode: &Inode, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let inode = inode.clone();
+        let transaction = transaction.clone();
         Box::pin(async move {
-            // TODO: Implement inode writing to platform storage
-            Err(GalleonError::NotSupported)
+            self.check_inode_fits(&inode)?;
+            let committed = self.committed_inode(inode.id())?;
+            record_inode_write(&transaction, inode, committed)
         })
     }
 
-    fn delete_inode(&self, _id: ObjectId, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    fn delete_inode(&self, id: ObjectId, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
         Box::pin(async move {
-            // TODO: Implement inode deletion from platform storage
-            Err(GalleonError::NotSupported)
+            let committed = self.committed_inode(id)?;
+            record_inode_delete(&transaction, id, committed)
         })
     }
 
-    fn read_data(&self, _id: ObjectId, _offset: u64, _length: u64) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + '_>> {
+    fn read_data(&self, id: ObjectId, offset: u64, length: u64) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + '_>> {
         Box::pin(async move {
-            // TODO: Implement data reading from platform storage
-            Err(GalleonError::NotSupported)
+            let volume = self.volume.lock();
+            let (_, record) = self.object_record(&volume, id)?;
+            if offset >= record.data_length {
+                return Ok(Vec::new());
+            }
+            let end = offset.saturating_add(length).min(record.data_length);
+            let mut buffer = vec![0u8; (end - offset) as usize];
+            self.file_io(&volume.super_block, &record.extents, offset, buffer.len(), |device_offset, at, len| {
+                self.read_bytes(device_offset, &mut buffer[at..at + len])
+            })?;
+            Ok(buffer)
         })
     }
 
-    fn write_data(&self, _id: ObjectId, _offset: u64, _data: &[u8], _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+    fn write_data(&self, id: ObjectId, offset: u64, data: &[u8], transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+        let data = data.to_vec();
+        let transaction = transaction.clone();
         Box::pin(async move {
-            // TODO: Implement data writing to platform storage
-            Err(GalleonError::NotSupported)
+            self.check_writable()?;
+            let written = data.len() as u64;
+            transaction.add_operation(TransactionOperation::WriteData { id, offset, data })?;
+            Ok(written)
         })
     }
 
-    fn truncate(&self, _id: ObjectId, _size: u64, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    fn truncate(&self, id: ObjectId, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
         Box::pin(async move {
-            // TODO: Implement truncate for platform storage
-            Err(GalleonError::NotSupported)
+            self.check_writable()?;
+            let old_size = {
+                let volume = self.volume.lock();
+                match volume.objects.contains_key(&id) {
+                    true => self.object_record(&volume, id)?.1.data_length,
+                    false => 0,
+                }
+            };
+            transaction.add_operation(TransactionOperation::TruncateData { id, old_size, new_size: size })
         })
     }
 
     fn stats(&self) -> Pin<Box<dyn Future<Output = Result<FilesystemStats>> + Send + '_>> {
         Box::pin(async move {
+            let volume = self.volume.lock();
+            let block_size = volume.super_block.block_size as u64;
+            let total_space = volume.super_block.data_blocks * block_size;
+            let free_space = volume.allocator.free_blocks() * block_size;
             Ok(FilesystemStats {
-                total_space: self.device.capacity(),
-                free_space: self.device.capacity(), // TODO: Calculate actual free space
-                used_space: 0, // TODO: Calculate actual used space
-                total_inodes: 65536, // Platform dependent
-                free_inodes: 65536, // TODO: Calculate actual free inodes
-                block_size: self.device.block_size(),
-                fragment_size: self.device.block_size(),
+                total_space,
+                free_space,
+                used_space: total_space - free_space,
+                total_inodes: volume.super_block.inode_slots,
+                free_inodes: volume.free_slots.len() as u64,
+                block_size: volume.super_block.block_size,
+                fragment_size: volume.super_block.block_size,
                 max_filename_length: 255,
+                shared_blocks: 0,
+                dedup_saved_space: 0,
             })
         })
     }
@@ -411,24 +1034,134 @@ impl StorageBackend for PlatformStorage {
         })
     }
 
+    /// Check every inode slot against the allocation bitmap
+    ///
+    /// Blocks handed out by [`StorageBackend::allocate`] belong to no inode,
+    /// so blocks that are marked used but unowned are not reported.
     fn check_integrity(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send + '_>> {
         Box::pin(async move {
-            // TODO: Implement platform storage integrity check
-            Ok(Vec::new())
+            let volume = self.volume.lock();
+            let super_block = &volume.super_block;
+            let mut issues = Vec::new();
+            let mut claimed = vec![0u8; super_block.data_blocks.div_ceil(8) as usize];
+
+            for (&id, &slot) in &volume.objects {
+                let record = match self.read_record(super_block, slot) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => {
+                        issues.push(format!("object {:#x}: inode slot {} is empty", id.as_u64(), slot));
+                        continue;
+                    }
+                    Err(error) => {
+                        issues.push(format!("object {:#x}: inode slot {} is unreadable: {}", id.as_u64(), slot, error));
+                        continue;
+                    }
+                };
+                if record.id != id {
+                    issues.push(format!("object {:#x}: inode slot {} holds object {:#x}", id.as_u64(), slot, record.id.as_u64()));
+                }
+                if let Err(error) = Inode::deserialize(&record.inode) {
+                    issues.push(format!("object {:#x}: inode does not decode: {}", id.as_u64(), error));
+                }
+                if record.allocated_blocks() < record.data_length.div_ceil(super_block.block_size as u64) {
+                    issues.push(format!("object {:#x}: extents are shorter than its {} bytes of data", id.as_u64(), record.data_length));
+                }
+                for extent in &record.extents {
+                    if extent.end() > super_block.data_blocks {
+                        issues.push(format!("object {:#x}: extent {}+{} is outside the data area", id.as_u64(), extent.start, extent.count));
+                        continue;
+                    }
+                    if (extent.start..extent.end()).any(|block| !volume.allocator.is_used(block)) {
+                        issues.push(format!("object {:#x}: extent {}+{} is marked free", id.as_u64(), extent.start, extent.count));
+                    }
+                    let mut shared = false;
+                    for block in extent.start..extent.end() {
+                        let (byte, bit) = ((block / 8) as usize, 1 << (block % 8));
+                        shared |= claimed[byte] & bit != 0;
+                        claimed[byte] |= bit;
+                    }
+                    if shared {
+                        issues.push(format!("object {:#x}: extent {}+{} is claimed by another object", id.as_u64(), extent.start, extent.count));
+                    }
+                }
+            }
+            Ok(issues)
         })
     }
 
-    fn allocate(&self, _size: u64, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+    /// Reserve a contiguous run of blocks, returning its device byte offset
+    ///
+    /// The run is recorded in `transaction` and only reaches the on-disk
+    /// bitmap when it commits. Until then it is held in memory, so a run whose
+    /// transaction never commits is free again once the volume is reopened.
+    fn allocate(&self, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+        let transaction = transaction.clone();
         Box::pin(async move {
-            // TODO: Implement space allocation for platform storage
-            Err(GalleonError::NotSupported)
+            self.check_writable()?;
+            let mut volume = self.volume.lock();
+            let blocks = size.div_ceil(volume.super_block.block_size as u64).max(1);
+            let run = volume.allocator.free_runs().into_iter()
+                .find(|run| run.count >= blocks)
+                .ok_or(GalleonError::NoSpace)?;
+            let offset = volume.super_block.data_offset(run.start);
+            transaction.add_operation(TransactionOperation::AllocateSpace { offset, size })?;
+            volume.allocator.mark_used(Extent::new(run.start, blocks));
+            volume.reserved.push(Extent::new(run.start, blocks));
+            Ok(offset)
         })
     }
 
-    fn deallocate(&self, _offset: u64, _size: u64, _transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    fn deallocate(&self, offset: u64, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
         Box::pin(async move {
-            // TODO: Implement space deallocation for platform storage
-            Err(GalleonError::NotSupported)
+            self.check_writable()?;
+            transaction.add_operation(TransactionOperation::DeallocateSpace { offset, size })
+        })
+    }
+
+    /// Apply the operations, journaling what each overwrites so a failure can put it back
+    ///
+    /// Blocks and inode slots freed by the transaction are only released once
+    /// every operation has succeeded, so nothing later in the transaction can
+    /// overwrite them before they might be needed again. The bitmap is written
+    /// last, and the transaction commits when the journal moves to the next
+    /// sequence number; a crash before that is undone by [`PlatformStorage::open`].
+    fn apply_transaction<'a>(&'a self, operations: &'a [TransactionOperation]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.update(|volume| {
+                let saved_objects = volume.objects.clone();
+                let saved_free_slots = volume.free_slots.clone();
+                let saved_reserved = volume.reserved.clone();
+                let saved_next_id = volume.super_block.next_object_id;
+                let mut undo = UndoLog::new(volume);
+
+                let result = operations.iter()
+                    .try_for_each(|operation| self.apply_operation(volume, operation, &mut undo))
+                    .and_then(|()| {
+                        for extent in core::mem::take(&mut undo.freed_extents) {
+                            volume.allocator.free(extent);
+                            volume.reserved.retain(|reserved| reserved.end() <= extent.start || reserved.start >= extent.end());
+                        }
+                        Self::dirty_bitmap(volume).into_iter()
+                            .try_for_each(|(offset, image)| self.write_logged(&mut undo, offset, &image))
+                    });
+                if let Err(error) = result {
+                    // Newest first, so the oldest image of each range wins
+                    for (offset, image) in undo.writes.iter().rev() {
+                        self.write_bytes(*offset, image)?;
+                    }
+                    volume.objects = saved_objects;
+                    volume.free_slots = saved_free_slots;
+                    volume.reserved = saved_reserved;
+                    volume.super_block.next_object_id = saved_next_id;
+                    self.close_journal(volume, &undo)?;
+                    return Err(error);
+                }
+
+                self.close_journal(volume, &undo)?;
+                volume.free_slots.extend(undo.freed_slots);
+                Ok(())
+            })
         })
     }
 
@@ -561,6 +1294,13 @@ impl StorageBackend for NetworkStorage {
         })
     }
 
+    fn apply_transaction<'a>(&'a self, _operations: &'a [TransactionOperation]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            // TODO: Implement network transaction commit
+            Err(GalleonError::NotSupported)
+        })
+    }
+
     fn capabilities(&self) -> StorageCapabilities {
         self.capabilities.clone()
     }
@@ -0,0 +1,1944 @@
+//! Tests for GalleonFS
+//!
+//! Covers the on-disk encodings, the codecs and ciphers, the storage backends
+//! and the VFS.
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::{String, ToString}, vec::Vec, vec, format, boxed::Box, sync::Arc};
+    use alloc::task::Wake;
+    use core::{future::Future, pin::{pin, Pin}, task::{Context, Poll, Waker}, time::Duration};
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use crate::{
+        ObjectId, Permissions, GalleonError, Timestamp, GalleonFS, Transaction, Result,
+        TransactionState, TransactionManager, TransactionOperation, TransactionId,
+        LockType, LockManager, LockConfig, OperationContext, Filesystem,
+        vfs::{VfsManager, VfsOperations, StorageFilesystem, MountOptions, UnmountMode, UnionFilesystem, OverlayFilesystem},
+        codec, crypto, chunked::{ChunkedStorage, CHUNK_SIZE}, CompressionManager, CompressionPolicy,
+        EncryptionManager, EncryptionPolicy, GalleonAdvancedFeatures, DeduplicationManager,
+        directory::file_flags,
+        storage::{StorageBackend, MemoryStorage, PlatformStorage},
+        directory::Directory,
+        layout::{ExtentAllocator, Extent, SuperBlock, split_extents},
+        inode::{
+            Inode, InodeType, ExtendedAttributeValue, AclEntry, AclEntryType, VersionInfo,
+            CompressionInfo, CompressionAlgorithm, EncryptionInfo, EncryptionAlgorithm,
+            ReplicationMetadata, INODE_MAGIC,
+        },
+        encoding::{Encoder, Decoder},
+        platform::{PlatformRng, SimpleRng, MockStorageDevice, StorageDevice},
+    };
+
+    /// Drive a future that never waits on anything, as backend futures don't
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Waker that remembers being woken
+    #[derive(Default)]
+    struct WakeFlag(AtomicBool);
+
+    impl Wake for WakeFlag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl WakeFlag {
+        fn take(&self) -> bool {
+            self.0.swap(false, Ordering::SeqCst)
+        }
+    }
+
+    /// Poll once with a waker that records wake-ups in `flag`
+    fn poll_once<F: Future + ?Sized>(future: Pin<&mut F>, flag: &Arc<WakeFlag>) -> Poll<F::Output> {
+        let waker = Waker::from(flag.clone());
+        future.poll(&mut Context::from_waker(&waker))
+    }
+
+    /// Record operations in a fresh transaction and commit it
+    fn commit<S: StorageBackend>(storage: &S, record: impl FnOnce(&Transaction) -> Result<()>) -> Result<()> {
+        let transaction = Transaction::new();
+        record(&transaction)?;
+        block_on(transaction.commit(storage))
+    }
+
+    fn file_inode(id: u64) -> Inode {
+        Inode::new(ObjectId(id), InodeType::RegularFile, Permissions::default_file(), 0)
+    }
+
+    /// Inode and data of each of `ids`, or `None` where the inode is missing
+    fn snapshot<S: StorageBackend>(storage: &S, ids: &[u64]) -> Vec<Option<(Inode, Vec<u8>)>> {
+        ids.iter()
+            .map(|&id| {
+                let inode = block_on(storage.read_inode(ObjectId(id))).ok()?;
+                let data = block_on(storage.read_data(ObjectId(id), 0, u64::MAX)).unwrap_or_default();
+                Some((inode, data))
+            })
+            .collect()
+    }
+
+    /// Commit a multi-step transaction whose last step cannot fit, and check
+    /// that none of the earlier steps took effect
+    fn check_failed_transaction_undone<S: StorageBackend>(storage: &S) -> Vec<Option<(Inode, Vec<u8>)>> {
+        let ids = [1, 2, 3, 10, 11];
+        commit(storage, |t| {
+            for (id, len) in [(1, 3000), (2, 5000), (3, 2000)] {
+                block_on(storage.write_inode(&file_inode(id), t))?;
+                block_on(storage.write_data(ObjectId(id), 0, &vec![id as u8; len], t))?;
+            }
+            Ok(())
+        }).unwrap();
+        let before = snapshot(storage, &ids);
+        let stats = block_on(storage.stats()).unwrap();
+
+        let mut changed = file_inode(1);
+        changed.set_size(1);
+        let transaction = Transaction::new();
+        block_on(storage.write_inode(&file_inode(10), &transaction)).unwrap();
+        block_on(storage.write_data(ObjectId(10), 0, &[0xaa; 4000], &transaction)).unwrap();
+        block_on(storage.write_data(ObjectId(1), 100, &[0xbb; 1500], &transaction)).unwrap();
+        block_on(storage.write_inode(&changed, &transaction)).unwrap();
+        block_on(storage.truncate(ObjectId(2), 100, &transaction)).unwrap();
+        block_on(storage.delete_inode(ObjectId(3), &transaction)).unwrap();
+        block_on(storage.write_inode(&file_inode(11), &transaction)).unwrap();
+        let huge = vec![0xcc; stats.total_space as usize + 4096];
+        block_on(storage.write_data(ObjectId(11), 0, &huge, &transaction)).unwrap();
+        let handle = transaction.clone();
+        assert!(matches!(block_on(transaction.commit(storage)), Err(GalleonError::NoSpace)));
+        assert_eq!(handle.state(), TransactionState::Aborted);
+
+        assert_eq!(snapshot(storage, &ids), before);
+        let after = block_on(storage.stats()).unwrap();
+        assert_eq!((after.free_space, after.free_inodes), (stats.free_space, stats.free_inodes));
+        assert!(block_on(storage.check_integrity()).unwrap().is_empty());
+        before
+    }
+
+    /// Fresh memory storage holding just an empty root directory
+    fn memory_storage() -> Arc<MemoryStorage> {
+        let storage = MemoryStorage::new(1024 * 1024);
+        commit(&storage, |t| {
+            let root = Inode::new(ObjectId::root(), InodeType::Directory, Permissions::default_dir(), 0);
+            block_on(storage.write_inode(&root, t))?;
+            block_on(storage.write_data(ObjectId::root(), 0, &Directory::new().serialize()?, t))?;
+            Ok(())
+        }).unwrap();
+        Arc::new(storage)
+    }
+
+    fn memory_filesystem() -> Box<dyn Filesystem> {
+        Box::new(StorageFilesystem::new(memory_storage()))
+    }
+
+    /// Memory storage with `dirs` and then `files` created in it
+    fn populated_storage(dirs: &[&str], files: &[(&str, &[u8])]) -> Arc<MemoryStorage> {
+        let storage = memory_storage();
+        let vfs = VfsManager::new(Box::new(StorageFilesystem::new(storage.clone())));
+        for dir in dirs {
+            block_on(vfs.mkdir(dir, Permissions::default_dir(), &OperationContext::kernel())).unwrap();
+        }
+        for (path, data) in files {
+            write_file(&vfs, path, data);
+        }
+        storage
+    }
+
+    fn write_file(vfs: &VfsManager, path: &str, data: &[u8]) {
+        let flags = file_flags::O_CREAT | file_flags::O_WRONLY | file_flags::O_TRUNC;
+        let handle = block_on(vfs.open(path, flags, &OperationContext::kernel())).unwrap();
+        block_on(vfs.write(&handle, 0, data)).unwrap();
+        block_on(vfs.close(handle)).unwrap();
+    }
+
+    fn read_file(vfs: &VfsManager, path: &str) -> Result<Vec<u8>> {
+        let handle = block_on(vfs.open(path, file_flags::O_RDONLY, &OperationContext::kernel()))?;
+        let data = block_on(vfs.read(&handle, 0, u64::MAX));
+        block_on(vfs.close(handle))?;
+        data
+    }
+
+    /// Namespace over a memory filesystem with `dirs` created in it
+    fn vfs_with_dirs(dirs: &[&str]) -> VfsManager {
+        let vfs = VfsManager::new(memory_filesystem());
+        for dir in dirs {
+            block_on(vfs.mkdir(dir, Permissions::default_dir(), &OperationContext::kernel())).unwrap();
+        }
+        vfs
+    }
+
+    fn mount_memory(vfs: &VfsManager, path: &str, options: MountOptions) -> u64 {
+        block_on(vfs.mount(path, memory_filesystem(), "memory", "none", options)).unwrap()
+    }
+
+    fn names(vfs: &VfsManager, path: &str) -> Vec<String> {
+        let entries = block_on(vfs.readdir(path, &OperationContext::kernel())).unwrap();
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    fn mock_device(capacity: u64, block_size: u32) -> Arc<MockStorageDevice> {
+        Arc::new(MockStorageDevice::new(capacity, block_size))
+    }
+
+    /// Device that loses every write after the first `writes_left`, as if power was cut
+    struct PowerCutDevice {
+        inner: Arc<MockStorageDevice>,
+        writes_left: AtomicU64,
+    }
+
+    impl StorageDevice for PowerCutDevice {
+        fn read(&self, offset: u64, buffer: &mut [u8]) -> core::result::Result<usize, &'static str> {
+            self.inner.read(offset, buffer)
+        }
+
+        fn write(&self, offset: u64, buffer: &[u8]) -> core::result::Result<usize, &'static str> {
+            match self.writes_left.try_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)) {
+                Ok(_) => self.inner.write(offset, buffer),
+                Err(_) => Err("power cut"),
+            }
+        }
+
+        fn flush(&self) -> core::result::Result<(), &'static str> {
+            self.inner.flush()
+        }
+
+        fn capacity(&self) -> u64 {
+            self.inner.capacity()
+        }
+
+        fn block_size(&self) -> u32 {
+            self.inner.block_size()
+        }
+
+        fn is_read_only(&self) -> bool {
+            false
+        }
+    }
+
+    /// Deterministic source of test values
+    struct Generator(SimpleRng);
+
+    impl Generator {
+        fn new(seed: u64) -> Self {
+            Self(SimpleRng::new(seed))
+        }
+
+        fn next(&mut self) -> u64 {
+            // The low bits of the LCG are weak; mix two outputs
+            (self.0.next_u64() >> 32) << 32 | self.0.next_u64() >> 32
+        }
+
+        fn below(&mut self, bound: u64) -> u64 {
+            self.next() % bound
+        }
+
+        fn chance(&mut self) -> bool {
+            self.below(2) == 0
+        }
+
+        /// A value whose magnitude is spread over the full range of varint widths
+        fn wide(&mut self) -> u64 {
+            self.next() >> self.below(64)
+        }
+
+        fn string(&mut self) -> String {
+            let len = self.below(12);
+            (0..len).map(|_| match self.below(4) {
+                0 => 'é',
+                1 => '/',
+                _ => (b'a' + self.below(26) as u8) as char,
+            }).collect()
+        }
+
+        fn bytes(&mut self) -> Vec<u8> {
+            let len = self.below(40);
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        fn timestamp(&mut self) -> Timestamp {
+            Timestamp { seconds: self.wide(), nanoseconds: self.below(1_000_000_000) as u32 }
+        }
+
+        fn hash(&mut self) -> [u8; 32] {
+            let mut hash = [0u8; 32];
+            self.0.fill_bytes(&mut hash);
+            hash
+        }
+    }
+
+    const INODE_TYPES: [InodeType; 9] = [
+        InodeType::RegularFile, InodeType::Directory, InodeType::SymbolicLink,
+        InodeType::BlockDevice, InodeType::CharacterDevice, InodeType::Fifo,
+        InodeType::Socket, InodeType::Snapshot, InodeType::HardLink,
+    ];
+
+    fn random_inode(g: &mut Generator) -> Inode {
+        let permissions = Permissions::new(g.below(0o10000) as u32, g.next() as u32, g.next() as u32);
+        let inode_type = INODE_TYPES[g.below(9) as usize];
+        let mut inode = Inode::new(ObjectId(g.next()), inode_type, permissions, g.wide());
+
+        for _ in 0..g.below(3) {
+            inode.increment_link_count();
+        }
+        for _ in 0..g.below(20) {
+            inode.add_block(g.wide());
+        }
+        for i in 0..g.below(6) {
+            let value = match g.below(4) {
+                0 => ExtendedAttributeValue::String(g.string()),
+                1 => ExtendedAttributeValue::Binary(g.bytes()),
+                2 => ExtendedAttributeValue::Integer(g.next() as i64),
+                _ => ExtendedAttributeValue::Boolean(g.chance()),
+            };
+            inode.set_extended_attribute(format!("user.{}{}", g.string(), i), value);
+        }
+        if g.chance() {
+            let types = [AclEntryType::User, AclEntryType::Group, AclEntryType::Other, AclEntryType::Mask];
+            let acl = (0..g.below(5)).map(|_| AclEntry {
+                entry_type: types[g.below(4) as usize],
+                principal: g.next() as u32,
+                permissions: g.below(8) as u32,
+            }).collect();
+            inode.set_acl(acl);
+        }
+        if g.chance() {
+            inode.set_version_info(VersionInfo {
+                version_number: g.wide(),
+                parent_version: if g.chance() { Some(g.wide()) } else { None },
+                created_at: g.timestamp(),
+                created_by: g.next() as u32,
+                description: g.string(),
+                checksum: if g.chance() { Some(g.hash()) } else { None },
+            });
+        }
+        if g.chance() {
+            let algorithms = [
+                CompressionAlgorithm::None, CompressionAlgorithm::Lz4, CompressionAlgorithm::Zstd,
+                CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli,
+            ];
+            inode.set_compression(CompressionInfo {
+                algorithm: algorithms[g.below(5) as usize],
+                compressed_size: g.wide(),
+                uncompressed_size: g.wide(),
+                compression_ratio: g.below(1000) as f32 / 7.0,
+            });
+        }
+        if g.chance() {
+            let algorithms = [
+                EncryptionAlgorithm::None, EncryptionAlgorithm::Aes256Gcm,
+                EncryptionAlgorithm::ChaCha20Poly1305, EncryptionAlgorithm::Aes256Ctr,
+            ];
+            inode.set_encryption(EncryptionInfo {
+                algorithm: algorithms[g.below(4) as usize],
+                key_id: g.next(),
+                iv: g.bytes(),
+                authenticated: g.chance(),
+            });
+        }
+        if g.chance() {
+            inode.set_dedup_hash(g.hash());
+        }
+        if g.chance() {
+            inode.set_chunked_size(g.next());
+        }
+        if g.chance() {
+            inode.set_replication_meta(ReplicationMetadata {
+                replica_count: g.below(8) as u32,
+                replicas: (0..g.below(4)).map(|_| g.string()).collect(),
+                consistency_level: g.string(),
+                last_synchronized: g.timestamp(),
+                conflict_version: if g.chance() { Some(g.wide()) } else { None },
+            });
+        }
+        for _ in 0..g.below(4) {
+            inode.set_custom_metadata(g.string(), g.bytes());
+        }
+        inode
+    }
+
+    #[test]
+    fn test_varint_encoding() {
+        let values = [0u64, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u64::MAX - 1, u64::MAX];
+        let mut encoder = Encoder::new();
+        for &value in &values {
+            encoder.put_varint(value);
+        }
+        for value in [0i64, -1, 1, i64::MIN, i64::MAX, -64, 64] {
+            encoder.put_signed_varint(value);
+        }
+        let bytes = encoder.into_bytes();
+
+        let mut decoder = Decoder::new(&bytes);
+        for &value in &values {
+            assert_eq!(decoder.get_varint().unwrap(), value);
+        }
+        for value in [0i64, -1, 1, i64::MIN, i64::MAX, -64, 64] {
+            assert_eq!(decoder.get_signed_varint().unwrap(), value);
+        }
+        assert!(decoder.is_empty());
+
+        // Single byte for small values, ten for the largest
+        let mut small = Encoder::new();
+        small.put_varint(127);
+        assert_eq!(small.len(), 1);
+        let mut large = Encoder::new();
+        large.put_varint(u64::MAX);
+        assert_eq!(large.len(), 10);
+
+        // An eleventh byte, or a tenth byte above 1, cannot fit in 64 bits
+        let overlong = [0xffu8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02];
+        assert!(Decoder::new(&overlong).get_varint().is_err());
+        assert!(Decoder::new(&[0x80]).get_varint().is_err());
+    }
+
+    #[test]
+    fn test_inode_round_trip() {
+        let mut g = Generator::new(0x5eed);
+        for _ in 0..500 {
+            let inode = random_inode(&mut g);
+            let bytes = inode.serialize().unwrap();
+            let decoded = Inode::deserialize(&bytes).unwrap();
+            assert_eq!(decoded, inode);
+            assert_eq!(decoded.serialize().unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_inode_encoding_is_compact() {
+        let inode = Inode::new(ObjectId(42), InodeType::RegularFile, Permissions::default_file(), 4096);
+        let bytes = inode.serialize().unwrap();
+        assert!(bytes.len() < 64, "plain inode took {} bytes", bytes.len());
+        assert_eq!(&bytes[..4], &INODE_MAGIC);
+    }
+
+    #[test]
+    fn test_inode_trailing_padding_ignored() {
+        let mut g = Generator::new(7);
+        let inode = random_inode(&mut g);
+        let mut slot = inode.serialize().unwrap();
+        slot.resize(slot.len() + 512, 0);
+        assert_eq!(Inode::deserialize(&slot).unwrap(), inode);
+    }
+
+    #[test]
+    fn test_inode_unknown_fields_skipped() {
+        let mut g = Generator::new(11);
+        let inode = random_inode(&mut g);
+        let bytes = inode.serialize().unwrap();
+
+        // Splice in fields from a hypothetical newer writer, including an
+        // extension of a known field that an older reader has never seen
+        let mut fields = Encoder::new();
+        fields.put_field(200, |e| e.put_str("from the future"));
+        fields.put_raw(&bytes[10..]);
+        fields.put_field(255, |e| e.put_bytes(&[0xaa; 300]));
+        fields.put_field(4, |e| {
+            e.put_varint(inode.size());
+            e.put_u64(0xdead_beef);
+        });
+        fields.put_field(201, |_| {});
+        let fields = fields.into_bytes();
+
+        let mut extended = Vec::new();
+        extended.extend_from_slice(&bytes[..6]);
+        extended.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        extended.extend_from_slice(&fields);
+        assert_eq!(Inode::deserialize(&extended).unwrap(), inode);
+    }
+
+    #[test]
+    fn test_inode_rejects_corruption() {
+        let mut g = Generator::new(13);
+        let inode = random_inode(&mut g);
+        let bytes = inode.serialize().unwrap();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(matches!(Inode::deserialize(&bad_magic), Err(GalleonError::Corruption(_))));
+
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(matches!(Inode::deserialize(&newer), Err(GalleonError::CorruptionDynamic(_))));
+
+        // Every truncation is an error, never a panic or a partial inode
+        for len in 0..bytes.len() {
+            assert!(Inode::deserialize(&bytes[..len]).is_err(), "truncated to {} bytes", len);
+        }
+
+        // Arbitrary damage must not panic
+        for _ in 0..2000 {
+            let mut damaged = bytes.clone();
+            for _ in 0..=g.below(4) {
+                let index = g.below(damaged.len() as u64) as usize;
+                damaged[index] = g.next() as u8;
+            }
+            let _ = Inode::deserialize(&damaged);
+        }
+
+        // Missing required fields
+        let mut fields = Encoder::new();
+        fields.put_field(2, |e| e.put_u8(0));
+        let fields = fields.into_bytes();
+        let mut no_id = bytes[..6].to_vec();
+        no_id.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        no_id.extend_from_slice(&fields);
+        assert!(matches!(Inode::deserialize(&no_id), Err(GalleonError::Corruption("inode has no id"))));
+    }
+
+    #[test]
+    fn test_inode_metadata_preserved() {
+        let mut inode = Inode::new(ObjectId(9), InodeType::Directory, Permissions::new(0o1777, 1000, 100), 0);
+        inode.set_extended_attribute("security.label".to_string(), ExtendedAttributeValue::String("system_u".to_string()));
+        inode.set_acl(vec![AclEntry { entry_type: AclEntryType::Group, principal: 100, permissions: 0o7 }]);
+        inode.create_new_version("initial".to_string(), 1000);
+        inode.create_new_version("second".to_string(), 1000);
+
+        let decoded = Inode::deserialize(&inode.serialize().unwrap()).unwrap();
+        assert!(decoded.is_directory());
+        assert_eq!(decoded.permissions().mode, 0o1777);
+        assert_eq!(decoded.link_count(), 1);
+        assert_eq!(decoded.modified_at(), inode.modified_at());
+        let version = decoded.version_info().unwrap();
+        assert_eq!(version.version_number, 2);
+        assert_eq!(version.parent_version, Some(1));
+        assert_eq!(version.description, "second");
+        assert!(decoded.can_write(1234, 100));
+        assert!(matches!(
+            decoded.get_extended_attribute("security.label"),
+            Some(ExtendedAttributeValue::String(label)) if label == "system_u"
+        ));
+        // An empty ACL is still an ACL
+        inode.set_acl(Vec::new());
+        assert_eq!(Inode::deserialize(&inode.serialize().unwrap()).unwrap().acl().map(|acl| acl.len()), Some(0));
+    }
+
+    #[test]
+    fn test_extent_allocator() {
+        let super_block = SuperBlock::for_device(1024 * 1024, 512).unwrap();
+        let mut allocator = ExtentAllocator::new(&super_block);
+        let total = allocator.free_blocks();
+
+        let first = allocator.allocate(10, None).unwrap();
+        assert_eq!(first, vec![Extent::new(0, 10)]);
+        // Continues the previous extent when the following blocks are free
+        let next = allocator.allocate(5, Some(first[0])).unwrap();
+        assert_eq!(next, vec![Extent::new(10, 5)]);
+
+        // A hole too small for the request is skipped in favour of a whole run
+        allocator.free(Extent::new(2, 3));
+        assert_eq!(allocator.allocate(4, None).unwrap(), vec![Extent::new(15, 4)]);
+        // ... but used when nothing else fits
+        let rest = allocator.free_blocks() - 3;
+        let spread = allocator.allocate(rest + 3, None).unwrap();
+        assert_eq!(spread[0], Extent::new(2, 3));
+        assert_eq!(allocator.free_blocks(), 0);
+        assert!(matches!(allocator.allocate(1, None), Err(GalleonError::NoSpace)));
+
+        allocator.free(Extent::new(0, total));
+        assert_eq!(allocator.free_blocks(), total);
+        // Blocks freed below where the last search ended are found again
+        assert_eq!(allocator.allocate(6, None).unwrap(), vec![Extent::new(0, 6)]);
+        allocator.free(Extent::new(1, 2));
+        assert_eq!(allocator.allocate(2, None).unwrap(), vec![Extent::new(1, 2)]);
+        assert_eq!(allocator.free_runs(), vec![Extent::new(6, total - 6)]);
+        allocator.free(Extent::new(0, 6));
+
+        let mut extents = vec![Extent::new(0, 4), Extent::new(10, 4)];
+        assert_eq!(split_extents(&mut extents, 6), vec![Extent::new(12, 2)]);
+        assert_eq!(extents, vec![Extent::new(0, 4), Extent::new(10, 2)]);
+        assert_eq!(split_extents(&mut extents, 0), vec![Extent::new(0, 4), Extent::new(10, 2)]);
+        assert!(extents.is_empty());
+    }
+
+    #[test]
+    fn test_platform_storage_persists_inodes() {
+        let device = mock_device(2 * 1024 * 1024, 512);
+        let mut g = Generator::new(21);
+        let inodes: Vec<Inode> = (0..40).map(|_| random_inode(&mut g)).collect();
+        {
+            let storage = PlatformStorage::format(Box::new(device.clone()), 16).unwrap();
+            commit(&storage, |t| {
+                inodes.iter().try_for_each(|inode| block_on(storage.write_inode(inode, t)))
+            }).unwrap();
+            assert_eq!(block_on(storage.read_inode(inodes[3].id())).unwrap(), inodes[3]);
+            // Rewriting in place does not take another slot
+            commit(&storage, |t| block_on(storage.write_inode(&inodes[3], t))).unwrap();
+            let stats = block_on(storage.stats()).unwrap();
+            assert_eq!(stats.total_inodes - stats.free_inodes, 40);
+            commit(&storage, |t| block_on(storage.delete_inode(inodes[5].id(), t))).unwrap();
+        }
+
+        let storage = PlatformStorage::open(Box::new(device.clone()), 16).unwrap();
+        for (index, inode) in inodes.iter().enumerate() {
+            if index == 5 {
+                assert!(!block_on(storage.exists(inode.id())).unwrap());
+                assert!(matches!(block_on(storage.read_inode(inode.id())), Err(GalleonError::NotFound)));
+            } else {
+                assert_eq!(block_on(storage.read_inode(inode.id())).unwrap(), *inode);
+            }
+        }
+        assert!(block_on(storage.check_integrity()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_platform_storage_object_ids_after_remount() {
+        let device = mock_device(512 * 1024, 512);
+        let far = ObjectId(ObjectId::new().as_u64() + (1 << 40));
+        {
+            let storage = PlatformStorage::format(Box::new(device.clone()), 8).unwrap();
+            commit(&storage, |t| block_on(storage.write_inode(&file_inode(far.as_u64()), t))).unwrap();
+        }
+        let mut header = vec![0u8; 512];
+        device.read(0, &mut header).unwrap();
+        let super_block = SuperBlock::deserialize(&header).unwrap();
+        assert!(super_block.next_object_id > far.as_u64());
+
+        // Ids handed out after mounting skip everything the volume holds
+        let storage = PlatformStorage::open(Box::new(device.clone()), 8).unwrap();
+        let id = ObjectId::new();
+        assert!(id > far);
+        commit(&storage, |t| block_on(storage.write_inode(&file_inode(id.as_u64()), t))).unwrap();
+        assert!(block_on(storage.exists(far)).unwrap());
+        assert!(block_on(storage.exists(id)).unwrap());
+        assert!(block_on(storage.check_integrity()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_platform_storage_inode_slot_overflow() {
+        let device = mock_device(2 * 1024 * 1024, 512);
+        let storage = PlatformStorage::format(Box::new(device.clone()), 8).unwrap();
+
+        // An inode too large for a slot is refused when it is written, not at commit
+        let mut inode = file_inode(1);
+        inode.set_extended_attribute("user.blob".to_string(), ExtendedAttributeValue::Binary(vec![7; 2000]));
+        let transaction = Transaction::new();
+        let result = block_on(storage.write_inode(&inode, &transaction));
+        assert!(matches!(&result, Err(GalleonError::InvalidArgumentDynamic(message)) if message.contains("inode slot")), "{:?}", result);
+        assert_eq!(transaction.operation_count(), 0);
+
+        // Interleaved appends leave one extent per block until the list no longer fits
+        commit(&storage, |t| {
+            block_on(storage.write_inode(&file_inode(1), t))?;
+            block_on(storage.write_inode(&file_inode(2), t))
+        }).unwrap();
+        let mut length = 0;
+        let error = loop {
+            let result = commit(&storage, |t| {
+                block_on(storage.write_data(ObjectId(1), length, &[1; 512], t))?;
+                block_on(storage.write_data(ObjectId(2), length, &[2; 512], t)).map(drop)
+            });
+            match result {
+                Ok(()) => length += 512,
+                Err(error) => break error,
+            }
+        };
+        assert!(matches!(&error, GalleonError::InvalidArgumentDynamic(message) if message.contains("extents")), "{:?}", error);
+        assert!(length > 100 * 512);
+        let stats = block_on(storage.stats()).unwrap();
+        assert_eq!(stats.used_space, 2 * length);
+        assert_eq!(block_on(storage.read_data(ObjectId(1), 0, u64::MAX)).unwrap(), vec![1; length as usize]);
+        assert!(block_on(storage.check_integrity()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_platform_storage_data_model() {
+        // Random writes and truncates checked against an in-memory model, across remounts
+        let device = mock_device(4 * 1024 * 1024, 512);
+        let ids = [ObjectId(1), ObjectId(2), ObjectId(3)];
+        let mut model: Vec<Vec<u8>> = vec![Vec::new(); ids.len()];
+        let mut storage = PlatformStorage::format(Box::new(device.clone()), 8).unwrap();
+        commit(&storage, |t| {
+            ids.iter().try_for_each(|&id| {
+                let inode = Inode::new(id, InodeType::RegularFile, Permissions::default_file(), 0);
+                block_on(storage.write_inode(&inode, t))
+            })
+        }).unwrap();
+
+        let mut g = Generator::new(99);
+        for round in 0..300 {
+            let file = g.below(ids.len() as u64) as usize;
+            let id = ids[file];
+            if g.below(5) == 0 {
+                let size = g.below(20_000);
+                commit(&storage, |t| block_on(storage.truncate(id, size, t))).unwrap();
+                model[file].resize(size as usize, 0);
+            } else {
+                let offset = g.below(24_000);
+                let data: Vec<u8> = (0..g.below(3000)).map(|_| g.next() as u8).collect();
+                let transaction = Transaction::new();
+                assert_eq!(block_on(storage.write_data(id, offset, &data, &transaction)).unwrap(), data.len() as u64);
+                block_on(transaction.commit(&storage)).unwrap();
+                let end = offset as usize + data.len();
+                if model[file].len() < end {
+                    model[file].resize(end, 0);
+                }
+                model[file][offset as usize..end].copy_from_slice(&data);
+            }
+
+            if round % 50 == 49 {
+                drop(storage);
+                storage = PlatformStorage::open(Box::new(device.clone()), 8).unwrap();
+            }
+            let offset = g.below(model[file].len() as u64 + 1);
+            let length = g.below(5000);
+            let expected_end = (offset + length).min(model[file].len() as u64) as usize;
+            assert_eq!(
+                block_on(storage.read_data(id, offset, length)).unwrap(),
+                model[file][offset as usize..expected_end.max(offset as usize)]
+            );
+        }
+        for (file, &id) in ids.iter().enumerate() {
+            assert_eq!(block_on(storage.read_data(id, 0, u64::MAX)).unwrap(), model[file]);
+        }
+        assert!(block_on(storage.check_integrity()).unwrap().is_empty());
+
+        // Space used follows the data, and deleting returns it
+        let stats = block_on(storage.stats()).unwrap();
+        let blocks: u64 = model.iter().map(|data| (data.len() as u64).div_ceil(512)).sum();
+        assert_eq!(stats.used_space, blocks * 512);
+        commit(&storage, |t| ids.iter().try_for_each(|&id| block_on(storage.delete_inode(id, t)))).unwrap();
+        let stats = block_on(storage.stats()).unwrap();
+        assert_eq!(stats.used_space, 0);
+        assert_eq!(stats.free_inodes, stats.total_inodes);
+    }
+
+    #[test]
+    fn test_platform_storage_failures() {
+        // Unformatted and undersized devices
+        let device = mock_device(256 * 1024, 512);
+        assert!(matches!(PlatformStorage::open(Box::new(device.clone()), 8), Err(GalleonError::Corruption(_))));
+        assert!(matches!(PlatformStorage::format(Box::new(mock_device(16 * 1024, 512)), 8), Err(GalleonError::NoSpace)));
+
+        let storage = PlatformStorage::format(Box::new(device.clone()), 8).unwrap();
+        let id = ObjectId(7);
+        let result = commit(&storage, |t| block_on(storage.write_data(id, 0, b"data", t)).map(drop));
+        assert!(matches!(result, Err(GalleonError::NotFound)));
+        commit(&storage, |t| {
+            block_on(storage.write_inode(&Inode::new(id, InodeType::RegularFile, Permissions::default_file(), 0), t))?;
+            block_on(storage.write_data(id, 0, b"kept", t)).map(drop)
+        }).unwrap();
+
+        // A write larger than the volume fails without leaking blocks
+        let before = block_on(storage.stats()).unwrap();
+        let huge = vec![0x55u8; before.total_space as usize + 512];
+        let result = commit(&storage, |t| block_on(storage.write_data(id, 0, &huge, t)).map(drop));
+        assert!(matches!(result, Err(GalleonError::NoSpace)));
+        assert_eq!(block_on(storage.stats()).unwrap().free_space, before.free_space);
+        assert_eq!(block_on(storage.read_data(id, 0, 100)).unwrap(), b"kept");
+        drop(storage);
+        let storage = PlatformStorage::open(Box::new(device.clone()), 8).unwrap();
+        assert_eq!(block_on(storage.stats()).unwrap().free_space, before.free_space);
+
+        // Raw allocations come back as device offsets in the data area
+        let offset = block_on(storage.allocate(3000, &Transaction::new())).unwrap();
+        assert_eq!(block_on(storage.stats()).unwrap().free_space, before.free_space - 3072);
+        commit(&storage, |t| block_on(storage.deallocate(offset, 3000, t))).unwrap();
+        assert_eq!(block_on(storage.stats()).unwrap().free_space, before.free_space);
+        assert!(commit(&storage, |t| block_on(storage.deallocate(1, 512, t))).is_err());
+
+        // Clearing the bitmap under a live extent is reported
+        let super_block = SuperBlock::for_device(device.capacity(), 512).unwrap();
+        device.write(super_block.bitmap_start * 512, &[0u8]).unwrap();
+        drop(storage);
+        let storage = PlatformStorage::open(Box::new(device.clone()), 8).unwrap();
+        let issues = block_on(storage.check_integrity()).unwrap();
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert!(issues[0].contains("marked free"));
+    }
+
+    #[test]
+    fn test_memory_storage_failed_transaction() {
+        let storage = MemoryStorage::new(64 * 1024);
+        check_failed_transaction_undone(&storage);
+    }
+
+    #[test]
+    fn test_platform_storage_crash_recovery() {
+        // Cut power after every number of device writes a commit makes; on
+        // reopening the transaction has either fully happened or not at all
+        let ids = [1, 2, 3, 10];
+        // Inodes carry creation times, so runs are compared by data and free space
+        let state = |storage: &PlatformStorage| {
+            let data: Vec<Option<Vec<u8>>> = snapshot(storage, &ids).into_iter().map(|file| file.map(|(_, data)| data)).collect();
+            (data, block_on(storage.stats()).unwrap().free_space)
+        };
+        let run = |cut: u64| {
+            let device = mock_device(512 * 1024, 512);
+            let storage = PlatformStorage::format(Box::new(device.clone()), 8).unwrap();
+            commit(&storage, |t| {
+                for (id, len) in [(1, 3000), (2, 5000), (3, 2000)] {
+                    block_on(storage.write_inode(&file_inode(id), t))?;
+                    block_on(storage.write_data(ObjectId(id), 0, &vec![id as u8; len], t))?;
+                }
+                Ok(())
+            }).unwrap();
+            let before = state(&storage);
+            drop(storage);
+
+            let device_with_cut = PowerCutDevice { inner: device.clone(), writes_left: AtomicU64::new(cut) };
+            let storage = PlatformStorage::open(Box::new(device_with_cut), 8).unwrap();
+            let result = commit(&storage, |t| {
+                block_on(storage.write_inode(&file_inode(10), t))?;
+                block_on(storage.write_data(ObjectId(10), 0, &[0xaa; 4000], t))?;
+                block_on(storage.write_data(ObjectId(1), 100, &[0xbb; 1500], t))?;
+                block_on(storage.truncate(ObjectId(2), 100, t))?;
+                block_on(storage.delete_inode(ObjectId(3), t))?;
+                block_on(storage.allocate(3000, t)).map(drop)
+            });
+            drop(storage);
+
+            let storage = PlatformStorage::open(Box::new(device.clone()), 8).unwrap();
+            assert!(block_on(storage.check_integrity()).unwrap().is_empty(), "power cut after {} writes", cut);
+            let after = state(&storage);
+            (result.is_ok(), before, after)
+        };
+
+        let (committed, before, expected) = run(u64::MAX);
+        assert!(committed);
+        assert_ne!(before, expected);
+        let mut cut = 0;
+        loop {
+            let (committed, before, after) = run(cut);
+            if committed {
+                assert_eq!(after, expected);
+                break;
+            }
+            assert_eq!(after, before, "power cut after {} writes", cut);
+            cut += 1;
+        }
+        assert!(cut > 20, "{}", cut);
+
+        // A run reserved by a transaction that never commits is free after remounting
+        let device = mock_device(512 * 1024, 512);
+        let storage = PlatformStorage::format(Box::new(device.clone()), 8).unwrap();
+        let free = block_on(storage.stats()).unwrap().free_space;
+        let transaction = Transaction::new();
+        let offset = block_on(storage.allocate(1024, &transaction)).unwrap();
+        assert_eq!(block_on(storage.stats()).unwrap().free_space, free - 1024);
+        let other = block_on(storage.allocate(512, &Transaction::new())).unwrap();
+        assert_ne!(other, offset);
+        drop(storage);
+        let storage = PlatformStorage::open(Box::new(device.clone()), 8).unwrap();
+        assert_eq!(block_on(storage.stats()).unwrap().free_space, free);
+    }
+
+    #[test]
+    fn test_platform_storage_failed_transaction() {
+        let device = mock_device(512 * 1024, 512);
+        let storage = PlatformStorage::format(Box::new(device.clone()), 8).unwrap();
+        let before = check_failed_transaction_undone(&storage);
+        let stats = block_on(storage.stats()).unwrap();
+        drop(storage);
+
+        // The undone writes and the restored bitmap reached the device
+        let storage = PlatformStorage::open(Box::new(device.clone()), 8).unwrap();
+        assert_eq!(snapshot(&storage, &[1, 2, 3, 10, 11]), before);
+        assert_eq!(block_on(storage.stats()).unwrap().free_space, stats.free_space);
+        assert!(block_on(storage.check_integrity()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_transaction_buffers_operations() {
+        let storage = MemoryStorage::new(64 * 1024);
+
+        // Nothing is visible before commit, and abort discards it all
+        let transaction = Transaction::new();
+        block_on(storage.write_inode(&file_inode(1), &transaction)).unwrap();
+        block_on(storage.write_data(ObjectId(1), 0, b"pending", &transaction)).unwrap();
+        assert_eq!(transaction.operation_count(), 2);
+        assert!(!block_on(storage.exists(ObjectId(1))).unwrap());
+        let handle = transaction.clone();
+        block_on(transaction.abort()).unwrap();
+        assert_eq!(handle.state(), TransactionState::Aborted);
+        assert_eq!(handle.operation_count(), 0);
+        assert!(handle.add_operation(TransactionOperation::CreateInode { id: ObjectId(1), inode: file_inode(1) }).is_err());
+        assert!(!block_on(storage.exists(ObjectId(1))).unwrap());
+
+        // Later operations see the ones recorded before them
+        let transaction = Transaction::new();
+        block_on(storage.write_inode(&file_inode(1), &transaction)).unwrap();
+        block_on(storage.write_inode(&file_inode(1), &transaction)).unwrap();
+        assert!(matches!(transaction.operations()[1], TransactionOperation::UpdateInode { .. }));
+        let handle = transaction.clone();
+        block_on(transaction.commit(&storage)).unwrap();
+        assert_eq!(handle.state(), TransactionState::Committed);
+        assert!(block_on(storage.exists(ObjectId(1))).unwrap());
+        assert!(block_on(handle.clone().commit(&storage)).is_err());
+        assert!(block_on(handle.abort()).is_err());
+
+        // Malformed operations are refused before anything is applied
+        let transaction = Transaction::new();
+        block_on(storage.write_inode(&file_inode(2), &transaction)).unwrap();
+        transaction.add_operation(TransactionOperation::WriteData { id: ObjectId(2), offset: u64::MAX, data: vec![1] }).unwrap();
+        assert!(matches!(block_on(transaction.commit(&storage)), Err(GalleonError::TransactionError(_))));
+        assert!(!block_on(storage.exists(ObjectId(2))).unwrap());
+    }
+
+    #[test]
+    fn test_transaction_locks_released() {
+        let storage = Arc::new(MemoryStorage::new(64 * 1024));
+        let manager = TransactionManager::new(storage.clone());
+        let object = ObjectId(5);
+        let holders = |manager: &TransactionManager| manager.lock_manager().get_lock_holders(object);
+
+        let transaction = manager.begin_transaction();
+        block_on(manager.acquire_lock(object, transaction.id(), LockType::Exclusive)).unwrap();
+        assert_eq!(holders(&manager), vec![transaction.id()]);
+        block_on(storage.write_inode(&file_inode(5), &transaction)).unwrap();
+        block_on(manager.commit_transaction(transaction)).unwrap();
+        assert!(holders(&manager).is_empty());
+
+        let transaction = manager.begin_transaction();
+        block_on(manager.acquire_lock(object, transaction.id(), LockType::Shared)).unwrap();
+        block_on(manager.abort_transaction(transaction)).unwrap();
+        assert!(holders(&manager).is_empty());
+
+        // A failed commit releases its locks too
+        let transaction = manager.begin_transaction();
+        block_on(manager.acquire_lock(object, transaction.id(), LockType::Exclusive)).unwrap();
+        block_on(storage.write_inode(&file_inode(5), &transaction)).unwrap();
+        block_on(storage.delete_inode(ObjectId(5), &transaction)).unwrap();
+        transaction.add_operation(TransactionOperation::DeleteInode { id: ObjectId(5), inode: file_inode(5) }).unwrap();
+        assert!(matches!(block_on(manager.commit_transaction(transaction)), Err(GalleonError::NotFound)));
+        assert!(holders(&manager).is_empty());
+        assert!(block_on(storage.exists(ObjectId(5))).unwrap());
+
+        // Locks can't be taken for a transaction that has finished
+        let transaction = manager.begin_transaction();
+        let id = transaction.id();
+        block_on(manager.commit_transaction(transaction)).unwrap();
+        assert!(block_on(manager.acquire_lock(object, id, LockType::Shared)).is_err());
+    }
+
+    #[test]
+    fn test_lock_waits_and_upgrades() {
+        let locks = LockManager::with_config(LockConfig { timeout: None, ..LockConfig::default() });
+        let object = ObjectId(9);
+        let [t1, t2, t3, t4] = [TransactionId(1), TransactionId(2), TransactionId(3), TransactionId(4)];
+        block_on(locks.acquire_lock(object, t1, LockType::Shared)).unwrap();
+        block_on(locks.acquire_lock(object, t2, LockType::Shared)).unwrap();
+        block_on(locks.acquire_lock(object, t2, LockType::Shared)).unwrap();
+
+        let woken3 = Arc::new(WakeFlag::default());
+        let mut exclusive3 = Box::pin(locks.acquire_lock(object, t3, LockType::Exclusive));
+        assert!(poll_once(exclusive3.as_mut(), &woken3).is_pending());
+
+        // An upgrade waits for the other shared holder only, and goes first
+        let woken1 = Arc::new(WakeFlag::default());
+        let mut upgrade1 = Box::pin(locks.acquire_lock(object, t1, LockType::Exclusive));
+        assert!(poll_once(upgrade1.as_mut(), &woken1).is_pending());
+        assert_eq!(locks.get_lock_waiters(object), vec![t1, t3]);
+        block_on(locks.release_lock(object, t2)).unwrap();
+        assert!(woken1.take());
+        assert!(!woken3.take());
+        assert!(matches!(poll_once(upgrade1.as_mut(), &woken1), Poll::Ready(Ok(()))));
+        assert_eq!(locks.get_lock_holders(object), vec![t1]);
+
+        // Requests are granted in arrival order even when compatible with the holders
+        let woken4 = Arc::new(WakeFlag::default());
+        let mut shared4 = Box::pin(locks.acquire_lock(object, t4, LockType::Shared));
+        assert!(poll_once(shared4.as_mut(), &woken4).is_pending());
+        locks.release_all(t1, &[object]);
+        assert!(woken3.take());
+        assert!(!woken4.take());
+        assert!(matches!(poll_once(exclusive3.as_mut(), &woken3), Poll::Ready(Ok(()))));
+        block_on(locks.release_lock(object, t3)).unwrap();
+        assert!(matches!(poll_once(shared4.as_mut(), &woken4), Poll::Ready(Ok(()))));
+        assert_eq!(locks.get_lock_holders(object), vec![t4]);
+
+        let stats = locks.lock_stats(object);
+        assert_eq!((stats.acquisitions, stats.contended, stats.max_waiters), (6, 3, 2));
+        assert_eq!(locks.contention_stats(), vec![(object, stats)]);
+    }
+
+    #[test]
+    fn test_lock_timeouts() {
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        let config = LockConfig { timeout: Some(Duration::from_nanos(100)), clock: || NOW.load(Ordering::SeqCst) };
+        let locks = LockManager::with_config(config);
+        let object = ObjectId(4);
+        block_on(locks.acquire_lock(object, TransactionId(1), LockType::Exclusive)).unwrap();
+
+        let woken = Arc::new(WakeFlag::default());
+        let mut waiting = Box::pin(locks.acquire_lock(object, TransactionId(2), LockType::Shared));
+        assert!(poll_once(waiting.as_mut(), &woken).is_pending());
+        NOW.store(60, Ordering::SeqCst);
+        assert_eq!(locks.expire_waits(), 0);
+        assert!(poll_once(waiting.as_mut(), &woken).is_pending());
+        NOW.store(100, Ordering::SeqCst);
+        assert_eq!(locks.expire_waits(), 1);
+        assert!(woken.take());
+        assert!(matches!(poll_once(waiting.as_mut(), &woken), Poll::Ready(Err(GalleonError::Timeout))));
+        assert!(locks.get_lock_waiters(object).is_empty());
+
+        // Dropping a waiting request withdraws it
+        let mut dropped = Box::pin(locks.acquire_lock(object, TransactionId(3), LockType::Exclusive));
+        assert!(poll_once(dropped.as_mut(), &woken).is_pending());
+        drop(dropped);
+        assert!(locks.get_lock_waiters(object).is_empty());
+        block_on(locks.release_lock(object, TransactionId(1))).unwrap();
+        assert!(locks.get_lock_holders(object).is_empty());
+        assert_eq!(locks.lock_stats(object).timeouts, 1);
+    }
+
+    #[test]
+    fn test_lock_waits_dont_spin() {
+        assert!(LockConfig::default().timeout.is_none());
+
+        // Waiting requests are only polled again when something wakes them
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        let config = LockConfig { timeout: Some(Duration::from_nanos(100)), clock: || NOW.load(Ordering::SeqCst) };
+        let locks = LockManager::with_config(config);
+        let (object, other) = (ObjectId(4), ObjectId(5));
+        block_on(locks.acquire_lock(object, TransactionId(1), LockType::Exclusive)).unwrap();
+        block_on(locks.acquire_lock(other, TransactionId(4), LockType::Exclusive)).unwrap();
+
+        let (woken2, woken3) = (Arc::new(WakeFlag::default()), Arc::new(WakeFlag::default()));
+        let mut waiting2 = Box::pin(locks.acquire_lock(object, TransactionId(2), LockType::Shared));
+        let mut waiting3 = Box::pin(locks.acquire_lock(object, TransactionId(3), LockType::Shared));
+        assert!(poll_once(waiting2.as_mut(), &woken2).is_pending());
+        assert!(poll_once(waiting3.as_mut(), &woken3).is_pending());
+        NOW.store(150, Ordering::SeqCst);
+        assert!(!woken2.take() && !woken3.take());
+
+        // Releasing any lock wakes the overdue requests, which then fail
+        block_on(locks.release_lock(other, TransactionId(4))).unwrap();
+        assert!(woken2.take() && woken3.take());
+        assert!(matches!(poll_once(waiting2.as_mut(), &woken2), Poll::Ready(Err(GalleonError::Timeout))));
+        assert!(matches!(poll_once(waiting3.as_mut(), &woken3), Poll::Ready(Err(GalleonError::Timeout))));
+        assert!(!woken2.take() && !woken3.take());
+        assert!(locks.get_lock_waiters(object).is_empty());
+        assert_eq!(locks.lock_stats(object).timeouts, 2);
+    }
+
+    #[test]
+    fn test_lock_dropped_after_grant() {
+        let locks = LockManager::with_config(LockConfig { timeout: None, ..LockConfig::default() });
+        let object = ObjectId(7);
+        let [t1, t2, t3, t4] = [TransactionId(1), TransactionId(2), TransactionId(3), TransactionId(4)];
+        block_on(locks.acquire_lock(object, t1, LockType::Shared)).unwrap();
+        block_on(locks.acquire_lock(object, t2, LockType::Shared)).unwrap();
+
+        // An upgrade granted after its last poll falls back to the shared lock held before
+        let woken = Arc::new(WakeFlag::default());
+        let mut upgrade = Box::pin(locks.acquire_lock(object, t1, LockType::Exclusive));
+        assert!(poll_once(upgrade.as_mut(), &woken).is_pending());
+        block_on(locks.release_lock(object, t2)).unwrap();
+        assert!(woken.take());
+        drop(upgrade);
+        assert_eq!(locks.get_lock_holders(object), vec![t1]);
+        block_on(locks.acquire_lock(object, t3, LockType::Shared)).unwrap();
+        assert_eq!(locks.get_lock_holders(object), vec![t1, t3]);
+
+        // A plain request granted after its last poll is released
+        let mut exclusive = Box::pin(locks.acquire_lock(object, t4, LockType::Exclusive));
+        assert!(poll_once(exclusive.as_mut(), &woken).is_pending());
+        locks.release_all(t1, &[object]);
+        locks.release_all(t3, &[object]);
+        assert!(woken.take());
+        assert_eq!(locks.get_lock_holders(object), vec![t4]);
+        drop(exclusive);
+        assert!(locks.get_lock_holders(object).is_empty());
+    }
+
+    #[test]
+    fn test_lock_deadlock_aborts_youngest() {
+        let runtime = luminal::Runtime::new().unwrap();
+        let manager = Arc::new(TransactionManager::new(Arc::new(MemoryStorage::new(64 * 1024))));
+        let (a, b) = (ObjectId(1), ObjectId(2));
+        let older = manager.begin_transaction();
+        let younger = manager.begin_transaction();
+        block_on(manager.acquire_lock(a, older.id(), LockType::Exclusive)).unwrap();
+        block_on(manager.acquire_lock(b, younger.id(), LockType::Shared)).unwrap();
+
+        // Each waits for the other's lock; the younger one gives way
+        let first = {
+            let (manager, older) = (manager.clone(), older.clone());
+            runtime.spawn(async move {
+                manager.acquire_lock(b, older.id(), LockType::Exclusive).await?;
+                manager.commit_transaction(older).await
+            })
+        };
+        let second = {
+            let (manager, younger) = (manager.clone(), younger.clone());
+            runtime.spawn(async move { manager.acquire_lock(a, younger.id(), LockType::Shared).await })
+        };
+        let results = runtime.block_on(async move { (first.await, second.await) });
+        assert!(results.0.is_ok(), "{:?}", results.0);
+        assert!(matches!(results.1, Err(GalleonError::Deadlock)));
+        assert_eq!(older.state(), TransactionState::Committed);
+        assert_eq!(younger.state(), TransactionState::Aborted);
+        assert!(manager.lock_manager().get_lock_holders(a).is_empty());
+        assert!(manager.lock_manager().get_lock_holders(b).is_empty());
+        assert_eq!(manager.lock_manager().lock_stats(a).deadlocks, 1);
+        assert_eq!(manager.lock_manager().lock_stats(b).contended, 1);
+    }
+
+    #[test]
+    fn test_galleonfs_on_platform_storage() {
+        let device = mock_device(1024 * 1024, 4096);
+        let storage = PlatformStorage::format(Box::new(device.clone()), 32).unwrap();
+        let fs = block_on(GalleonFS::new(Box::new(storage))).unwrap();
+        drop(fs);
+
+        // The root directory created on first use is found again after a remount
+        let storage = PlatformStorage::open(Box::new(device.clone()), 32).unwrap();
+        let root = block_on(storage.read_inode(ObjectId::root())).unwrap();
+        assert!(root.is_directory());
+        let data = block_on(storage.read_data(ObjectId::root(), 0, u64::MAX)).unwrap();
+        assert_eq!(Directory::deserialize(&data).unwrap().entry_count(), 0);
+        let fs = block_on(GalleonFS::new(Box::new(storage))).unwrap();
+        drop(fs);
+    }
+
+    #[test]
+    fn test_vfs_mount_crossing() {
+        let kernel = OperationContext::kernel();
+        let vfs = vfs_with_dirs(&["/mnt", "/private"]);
+        block_on(vfs.create("/mnt/hidden", Permissions::default_file(), &kernel)).unwrap();
+
+        let mount_id = mount_memory(&vfs, "/mnt", MountOptions::default());
+        assert!(matches!(block_on(vfs.mount("/mnt", memory_filesystem(), "memory", "none", MountOptions::default())), Err(GalleonError::AlreadyExists)));
+        assert!(matches!(block_on(vfs.mount("/missing", memory_filesystem(), "memory", "none", MountOptions::default())), Err(GalleonError::NotFound)));
+        assert_eq!(vfs.get_mount_info("/mnt/").unwrap().unwrap().parent_mount_id, Some(1));
+
+        // The mounted filesystem hides what was in the directory below it
+        assert!(names(&vfs, "/mnt").is_empty());
+        block_on(vfs.mkdir("/mnt/dir", Permissions::default_dir(), &kernel)).unwrap();
+        let handle = block_on(vfs.open("/mnt/dir/file", file_flags::O_CREAT | file_flags::O_RDWR, &kernel)).unwrap();
+        assert_eq!(block_on(vfs.write(&handle, 0, b"across")).unwrap(), 6);
+        assert_eq!(block_on(vfs.read(&handle, 0, 64)).unwrap(), b"across");
+        block_on(vfs.close(handle)).unwrap();
+        assert_eq!(block_on(vfs.stat("/mnt/dir/../dir/file", &kernel, true)).unwrap().size(), 6);
+        assert!(matches!(block_on(vfs.rename("/mnt/dir/file", "/file", &kernel)), Err(GalleonError::CrossDevice)));
+        assert!(matches!(block_on(vfs.rmdir("/mnt", &kernel)), Err(GalleonError::InvalidState(_))));
+
+        // Symbolic links are followed into the mount
+        block_on(vfs.symlink("mnt/dir", "/link", &kernel)).unwrap();
+        assert_eq!(block_on(vfs.readlink("/link", &kernel)).unwrap(), "mnt/dir");
+        let (filesystem_id, _) = block_on(vfs.resolve_path("/link/file", &kernel, true)).unwrap();
+        assert_eq!(filesystem_id, vfs.get_mount_info("/mnt").unwrap().unwrap().filesystem);
+        assert!(block_on(vfs.stat("/link", &kernel, false)).unwrap().is_symlink());
+        block_on(vfs.symlink("/loop", "/loop", &kernel)).unwrap();
+        assert!(matches!(block_on(vfs.stat("/loop", &kernel, true)), Err(GalleonError::TooManyLinks)));
+
+        // Search permission is needed on every directory passed through
+        block_on(vfs.chmod("/private", Permissions::new(0o700, 0, 0), &kernel)).unwrap();
+        block_on(vfs.create("/private/file", Permissions::default_file(), &kernel)).unwrap();
+        let user = OperationContext::new(1000, 1000, 1);
+        assert!(matches!(block_on(vfs.stat("/private/file", &user, true)), Err(GalleonError::PermissionDenied)));
+        assert!(block_on(vfs.stat("/mnt/dir/file", &user, true)).is_ok());
+
+        block_on(vfs.unmount("/mnt", false)).unwrap();
+        assert!(vfs.list_mounts().iter().all(|mount| mount.mount_id != mount_id));
+        assert_eq!(names(&vfs, "/mnt"), ["hidden"]);
+        assert!(matches!(block_on(vfs.unmount("/", false)), Err(GalleonError::InvalidState(_))));
+    }
+
+    #[test]
+    fn test_vfs_bind_mounts() {
+        let kernel = OperationContext::kernel();
+        let vfs = vfs_with_dirs(&["/src", "/src/sub", "/plain", "/tree"]);
+        block_on(vfs.create("/src/top", Permissions::default_file(), &kernel)).unwrap();
+        mount_memory(&vfs, "/src/sub", MountOptions::default());
+        block_on(vfs.create("/src/sub/inner", Permissions::default_file(), &kernel)).unwrap();
+
+        // A plain bind shows the directory itself but not what is mounted below it
+        let plain = block_on(vfs.bind_mount("/src", "/plain", false)).unwrap();
+        let info = vfs.get_mount_info("/plain").unwrap().unwrap();
+        assert!(info.options.bind);
+        assert_eq!(info.filesystem, vfs.root_filesystem());
+        assert_eq!(info.mount_id, plain);
+        assert_eq!(names(&vfs, "/plain"), ["sub", "top"]);
+        assert!(names(&vfs, "/plain/sub").is_empty());
+
+        // Both paths name the same objects
+        block_on(vfs.create("/plain/added", Permissions::default_file(), &kernel)).unwrap();
+        assert_eq!(names(&vfs, "/src"), ["added", "sub", "top"]);
+
+        // A recursive bind repeats the submounts
+        let tree = block_on(vfs.bind_mount("/src", "/tree", true)).unwrap();
+        assert_eq!(names(&vfs, "/tree/sub"), ["inner"]);
+        let submount = vfs.get_mount_info("/tree/sub").unwrap().unwrap();
+        assert_eq!(submount.parent_mount_id, Some(tree));
+        assert_eq!(submount.filesystem, vfs.get_mount_info("/src/sub").unwrap().unwrap().filesystem);
+
+        assert!(matches!(block_on(vfs.bind_mount("/src", "/tree", false)), Err(GalleonError::AlreadyExists)));
+        assert!(matches!(block_on(vfs.bind_mount("/src/top", "/plain/sub", false)), Err(GalleonError::NotADirectory)));
+
+        // The shared filesystem stays while any mount uses it
+        block_on(vfs.unmount("/tree/sub", false)).unwrap();
+        block_on(vfs.unmount("/tree", false)).unwrap();
+        assert_eq!(names(&vfs, "/src/sub"), ["inner"]);
+        block_on(vfs.unmount("/plain", false)).unwrap();
+        assert_eq!(names(&vfs, "/src"), ["added", "sub", "top"]);
+    }
+
+    #[test]
+    fn test_vfs_mount_flags() {
+        let kernel = OperationContext::kernel();
+        let vfs = vfs_with_dirs(&["/ro", "/noexec"]);
+
+        mount_memory(&vfs, "/ro", MountOptions::default());
+        block_on(vfs.create("/ro/file", Permissions::default_file(), &kernel)).unwrap();
+        let handle = block_on(vfs.open("/ro/file", file_flags::O_RDWR, &kernel)).unwrap();
+
+        // Remounting read-only stops writes through handles already open
+        let read_only = MountOptions { read_only: true, remount: true, ..MountOptions::default() };
+        block_on(vfs.mount("/ro", memory_filesystem(), "memory", "none", read_only)).unwrap();
+        assert!(vfs.get_mount_info("/ro").unwrap().unwrap().options.read_only);
+        assert!(matches!(block_on(vfs.write(&handle, 0, b"x")), Err(GalleonError::ReadOnlyFilesystem)));
+        assert!(matches!(block_on(vfs.create("/ro/other", Permissions::default_file(), &kernel)), Err(GalleonError::ReadOnlyFilesystem)));
+        assert!(matches!(block_on(vfs.open("/ro/file", file_flags::O_WRONLY, &kernel)), Err(GalleonError::ReadOnlyFilesystem)));
+        assert!(matches!(block_on(vfs.unlink("/ro/file", &kernel)), Err(GalleonError::ReadOnlyFilesystem)));
+        assert!(matches!(block_on(vfs.chmod("/ro/file", Permissions::new(0o600, 0, 0), &kernel)), Err(GalleonError::ReadOnlyFilesystem)));
+        let reader = block_on(vfs.open("/ro/file", file_flags::O_RDONLY, &kernel)).unwrap();
+        assert!(block_on(vfs.read(&reader, 0, 16)).unwrap().is_empty());
+        block_on(vfs.close(reader)).unwrap();
+        block_on(vfs.close(handle)).unwrap();
+
+        // No file on a noexec mount may be run, whatever its mode
+        mount_memory(&vfs, "/noexec", MountOptions { no_exec: true, ..MountOptions::default() });
+        block_on(vfs.create("/noexec/tool", Permissions::new(0o755, 0, 0), &kernel)).unwrap();
+        block_on(vfs.create("/tool", Permissions::new(0o755, 0, 0), &kernel)).unwrap();
+        block_on(vfs.create("/data", Permissions::new(0o644, 0, 0), &kernel)).unwrap();
+        assert!(matches!(block_on(vfs.open_executable("/noexec/tool", &kernel)), Err(GalleonError::PermissionDenied)));
+        assert!(matches!(block_on(vfs.open_executable("/data", &kernel)), Err(GalleonError::PermissionDenied)));
+        let tool = block_on(vfs.open_executable("/tool", &kernel)).unwrap();
+        block_on(vfs.close(tool)).unwrap();
+    }
+
+    #[test]
+    fn test_vfs_unmount_busy_forced_and_lazy() {
+        let kernel = OperationContext::kernel();
+        let vfs = vfs_with_dirs(&["/mnt"]);
+
+        let mount_id = mount_memory(&vfs, "/mnt", MountOptions::default());
+        block_on(vfs.mkdir("/mnt/nested", Permissions::default_dir(), &kernel)).unwrap();
+        let handle = block_on(vfs.open("/mnt/file", file_flags::O_CREAT | file_flags::O_RDWR, &kernel)).unwrap();
+        assert_eq!(vfs.open_file_count(mount_id), 1);
+
+        // Open files and submounts keep a normal unmount from going ahead
+        assert!(matches!(block_on(vfs.unmount("/mnt", false)), Err(GalleonError::InvalidState(_))));
+        mount_memory(&vfs, "/mnt/nested", MountOptions::default());
+        assert!(matches!(block_on(vfs.unmount("/mnt", true)), Err(GalleonError::InvalidState(_))));
+        block_on(vfs.unmount("/mnt/nested", false)).unwrap();
+
+        // Forcing invalidates the handle
+        block_on(vfs.unmount("/mnt", true)).unwrap();
+        assert_eq!(vfs.open_file_count(mount_id), 0);
+        assert!(matches!(block_on(vfs.read(&handle, 0, 16)), Err(GalleonError::StaleHandle)));
+        assert!(matches!(block_on(vfs.close(handle)), Err(GalleonError::StaleHandle)));
+
+        // A lazy unmount detaches the mount and its submounts at once
+        let mount_id = mount_memory(&vfs, "/mnt", MountOptions::default());
+        block_on(vfs.mkdir("/mnt/nested", Permissions::default_dir(), &kernel)).unwrap();
+        mount_memory(&vfs, "/mnt/nested", MountOptions::default());
+        let handle = block_on(vfs.open("/mnt/file", file_flags::O_CREAT | file_flags::O_RDWR, &kernel)).unwrap();
+        block_on(vfs.unmount_with("/mnt", UnmountMode::Lazy)).unwrap();
+        assert_eq!(vfs.list_mounts().len(), 1);
+        assert!(matches!(block_on(vfs.stat("/mnt/file", &kernel, true)), Err(GalleonError::NotFound)));
+
+        // but files already open keep working until closed
+        assert_eq!(block_on(vfs.write(&handle, 0, b"still here")).unwrap(), 10);
+        assert_eq!(block_on(vfs.read(&handle, 0, 64)).unwrap(), b"still here");
+        block_on(vfs.close(handle)).unwrap();
+        assert_eq!(vfs.open_file_count(mount_id), 0);
+
+        // Unlinked files live on until their last handle closes
+        block_on(vfs.create("/gone", Permissions::default_file(), &kernel)).unwrap();
+        let handle = block_on(vfs.open("/gone", file_flags::O_RDWR, &kernel)).unwrap();
+        block_on(vfs.unlink("/gone", &kernel)).unwrap();
+        assert_eq!(block_on(vfs.write(&handle, 0, b"tmp")).unwrap(), 3);
+        block_on(vfs.close(handle)).unwrap();
+        let (filesystem_id, _) = block_on(vfs.resolve_path("/", &kernel, true)).unwrap();
+        assert_eq!(filesystem_id, vfs.root_filesystem());
+        assert!(matches!(block_on(vfs.stat("/gone", &kernel, true)), Err(GalleonError::NotFound)));
+    }
+
+    /// Overlay of a fresh upper layer on a base image, with the base's storage
+    fn container() -> (VfsManager, Arc<MemoryStorage>) {
+        let base = populated_storage(&["/etc", "/bin", "/data"], &[
+            ("/etc/config", b"base"),
+            ("/etc/hosts", b"localhost"),
+            ("/bin/tool", b"#!"),
+            ("/data/old", b"old"),
+        ]);
+        let mut overlay = OverlayFilesystem::new();
+        overlay.add_lower_dir(Box::new(StorageFilesystem::new(base.clone())));
+        overlay.set_upper_dir(memory_filesystem());
+        (VfsManager::new(Box::new(overlay)), base)
+    }
+
+    #[test]
+    fn test_overlay_copy_up_on_write() {
+        let kernel = OperationContext::kernel();
+        let (vfs, base) = container();
+        let image = VfsManager::new(Box::new(StorageFilesystem::new(base)));
+
+        // Lookups fall through to the base
+        assert_eq!(read_file(&vfs, "/etc/config").unwrap(), b"base");
+        assert_eq!(names(&vfs, "/"), ["bin", "data", "etc"]);
+
+        // Writing copies the file up and leaves the base alone
+        let handle = block_on(vfs.open("/etc/config", file_flags::O_RDWR, &kernel)).unwrap();
+        block_on(vfs.write(&handle, 0, b"mine")).unwrap();
+        assert_eq!(block_on(vfs.read(&handle, 0, 64)).unwrap(), b"mine");
+        block_on(vfs.close(handle)).unwrap();
+        assert_eq!(read_file(&vfs, "/etc/config").unwrap(), b"mine");
+        assert_eq!(read_file(&image, "/etc/config").unwrap(), b"base");
+        assert_eq!(names(&vfs, "/etc"), ["config", "hosts"]);
+
+        // So do metadata changes
+        block_on(vfs.chmod("/bin/tool", Permissions::new(0o755, 0, 0), &kernel)).unwrap();
+        assert_eq!(block_on(vfs.stat("/bin/tool", &kernel, true)).unwrap().permissions().mode, 0o755);
+        assert_eq!(block_on(image.stat("/bin/tool", &kernel, true)).unwrap().permissions().mode, 0o644);
+        assert_eq!(read_file(&vfs, "/bin/tool").unwrap(), b"#!");
+
+        // New files only exist in the upper layer
+        write_file(&vfs, "/etc/extra", b"new");
+        assert_eq!(names(&vfs, "/etc"), ["config", "extra", "hosts"]);
+        assert_eq!(names(&image, "/etc"), ["config", "hosts"]);
+
+        // Files move by copy-up; directories with lower contents can't
+        block_on(vfs.rename("/data/old", "/etc/old", &kernel)).unwrap();
+        assert!(names(&vfs, "/data").is_empty());
+        assert_eq!(read_file(&vfs, "/etc/old").unwrap(), b"old");
+        assert_eq!(names(&image, "/data"), ["old"]);
+        assert!(matches!(block_on(vfs.rename("/etc", "/config", &kernel)), Err(GalleonError::CrossDevice)));
+        block_on(vfs.mkdir("/new", Permissions::default_dir(), &kernel)).unwrap();
+        write_file(&vfs, "/new/file", b"x");
+        block_on(vfs.rename("/new", "/moved", &kernel)).unwrap();
+        assert_eq!(read_file(&vfs, "/moved/file").unwrap(), b"x");
+    }
+
+    #[test]
+    fn test_overlay_whiteouts_and_opaque_directories() {
+        let kernel = OperationContext::kernel();
+        let (vfs, base) = container();
+        let image = VfsManager::new(Box::new(StorageFilesystem::new(base)));
+
+        // Deleting a base file whites it out
+        block_on(vfs.unlink("/etc/hosts", &kernel)).unwrap();
+        assert_eq!(names(&vfs, "/etc"), ["config"]);
+        assert!(matches!(block_on(vfs.stat("/etc/hosts", &kernel, true)), Err(GalleonError::NotFound)));
+        assert_eq!(names(&image, "/etc"), ["config", "hosts"]);
+
+        // and a new file of that name replaces the whiteout
+        write_file(&vfs, "/etc/hosts", b"fresh");
+        assert_eq!(read_file(&vfs, "/etc/hosts").unwrap(), b"fresh");
+
+        // A directory recreated over a removed one doesn't show the old contents
+        block_on(vfs.unlink("/data/old", &kernel)).unwrap();
+        block_on(vfs.rmdir("/data", &kernel)).unwrap();
+        assert_eq!(names(&vfs, "/"), ["bin", "etc"]);
+        block_on(vfs.mkdir("/data", Permissions::default_dir(), &kernel)).unwrap();
+        assert!(names(&vfs, "/data").is_empty());
+        assert!(matches!(read_file(&vfs, "/data/old"), Err(GalleonError::NotFound)));
+        assert_eq!(read_file(&image, "/data/old").unwrap(), b"old");
+
+        // Removing a copied-up file hides the base copy too
+        write_file(&vfs, "/bin/tool", b"patched");
+        block_on(vfs.unlink("/bin/tool", &kernel)).unwrap();
+        assert!(names(&vfs, "/bin").is_empty());
+        assert!(matches!(block_on(vfs.rmdir("/etc", &kernel)), Err(GalleonError::DirectoryNotEmpty)));
+    }
+
+    #[test]
+    fn test_union_layers() {
+        let kernel = OperationContext::kernel();
+        let middle = populated_storage(&["/shared"], &[("/shared/a", b"middle"), ("/only-middle", b"m")]);
+        let bottom = populated_storage(&["/shared"], &[("/shared/a", b"bottom"), ("/shared/b", b"b")]);
+
+        // The topmost layer with a name wins, and directories merge across all of them
+        let mut overlay = OverlayFilesystem::new();
+        overlay.add_lower_dir(Box::new(StorageFilesystem::new(middle.clone())));
+        overlay.add_lower_dir(Box::new(StorageFilesystem::new(bottom.clone())));
+        block_on(overlay.merge_layers()).unwrap();
+        let paths: Vec<&str> = overlay.merged_view().keys().map(String::as_str).collect();
+        assert_eq!(paths, ["/", "/only-middle", "/shared", "/shared/a", "/shared/b"]);
+        let vfs = VfsManager::new(Box::new(overlay));
+        assert_eq!(read_file(&vfs, "/shared/a").unwrap(), b"middle");
+        assert_eq!(read_file(&vfs, "/shared/b").unwrap(), b"b");
+
+        // Without an upper layer nothing can change
+        assert!(matches!(block_on(vfs.create("/new", Permissions::default_file(), &kernel)), Err(GalleonError::ReadOnlyFilesystem)));
+        let handle = block_on(vfs.open("/shared/a", file_flags::O_RDWR, &kernel)).unwrap();
+        assert!(matches!(block_on(vfs.write(&handle, 0, b"x")), Err(GalleonError::ReadOnlyFilesystem)));
+        block_on(vfs.close(handle)).unwrap();
+
+        // Without copy-on-write, lower files stay read-only but new ones can be added on top
+        let mut union = UnionFilesystem::new(false);
+        union.add_layer(memory_filesystem(), false);
+        union.add_layer(Box::new(StorageFilesystem::new(bottom)), true);
+        let vfs = VfsManager::new(Box::new(union));
+        let handle = block_on(vfs.open("/shared/a", file_flags::O_RDWR, &kernel)).unwrap();
+        assert!(matches!(block_on(vfs.write(&handle, 0, b"x")), Err(GalleonError::ReadOnlyFilesystem)));
+        block_on(vfs.close(handle)).unwrap();
+        write_file(&vfs, "/shared/c", b"top");
+        assert_eq!(names(&vfs, "/shared"), ["a", "b", "c"]);
+        assert_eq!(read_file(&vfs, "/shared/a").unwrap(), b"bottom");
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Text that compresses well, but not to nothing
+    fn records(len: usize) -> Vec<u8> {
+        (0..).flat_map(|i| format!("record {:05}\n", i).into_bytes()).take(len).collect()
+    }
+
+    #[test]
+    fn test_lz4_codec() {
+        // Literals, a match overlapping its own output, then the final literals
+        let block = [&[0x44][..], b"abcd", &[0x04, 0x00, 0x50], b"efghi"].concat();
+        assert_eq!(codec::lz4_decompress(&block, 64).unwrap(), b"abcdabcdabcdefghi");
+        assert!(codec::lz4_decompress(&block, 16).is_err());
+        assert!(codec::lz4_decompress(&block[..6], 64).is_err());
+        // Offset reaching back before the start
+        assert!(codec::lz4_decompress(&[0x14, b'a', 0x05, 0x00], 64).is_err());
+
+        let mut g = Generator::new(22);
+        let noise: Vec<u8> = (0..5000).map(|_| g.next() as u8).collect();
+        let runs: Vec<u8> = (0..20000).map(|i| (i / 700) as u8).collect();
+        for input in [Vec::new(), b"tiny".to_vec(), records(100_000), noise.clone(), runs, [records(3000), noise].concat()] {
+            let compressed = codec::lz4_compress(&input);
+            assert_eq!(codec::lz4_decompress(&compressed, input.len()).unwrap(), input);
+        }
+        assert!(codec::lz4_compress(&records(100_000)).len() < 40_000);
+    }
+
+    #[test]
+    fn test_gzip_decoding() {
+        // Written by zlib: a fixed Huffman block, a dynamic one and a stored one
+        let fixed = unhex("1f8b0800000000000203cb48cdc9c9d751c840a214d213737252f3f3b80078f292d81c000000");
+        assert_eq!(codec::gunzip(&fixed, 1024).unwrap(), b"hello, hello, hello galleon\n");
+        let dynamic = unhex(concat!(
+            "1f8b0800000000000203b58cc919823010465bf9bdfbd9804d78a08144268b860cd921d53b14e1f92d8b23a4e6df5f",
+            "e8cc23c2f0814fdbf602ee94510507354fac6c1f58fe26bf9478db092dd2f0d5c1f84e822645049f1a67696d79a2ec",
+            "cec7036ca0c3d5a4a6729d77a1aba5ebd079dc7e292a74ead5000000",
+        ));
+        let text = [b"The quick brown fox jumps over the lazy dog. ".repeat(3), b"Pack my box with five dozen liquor jugs; sphinx of black quartz, judge my vow!".to_vec()].concat();
+        assert_eq!(codec::gunzip(&dynamic, 1024).unwrap(), text);
+        let stored = unhex("1f8b0800000000000403010600f9ff73746f7265640bf9435606000000");
+        assert_eq!(codec::gunzip(&stored, 1024).unwrap(), b"stored");
+        assert_eq!(block_on(CompressionManager::new().decompress_data(&stored, CompressionAlgorithm::Gzip)).unwrap(), b"stored");
+
+        // Damage is caught by the checksum or the decoder
+        let mut damaged = dynamic.clone();
+        damaged[40] ^= 0x10;
+        assert!(codec::gunzip(&damaged, 1024).is_err());
+        assert!(codec::gunzip(&dynamic[..dynamic.len() - 4], 1024).is_err());
+        assert!(codec::gunzip(&dynamic, 100).is_err());
+        assert_eq!(codec::crc32(b"123456789"), 0xcbf43926);
+    }
+
+    /// Features whose encryption manager wraps keys under `master_key` and draws them from a seeded generator
+    fn keyed_features(master_key: u8) -> GalleonAdvancedFeatures {
+        let encryption = EncryptionManager::with_master_key([master_key; 32], Box::new(SimpleRng::new(master_key as u64)));
+        GalleonAdvancedFeatures::new().with_encryption_manager(encryption)
+    }
+
+    /// Namespace over chunked memory storage, with the manager of its policies
+    fn chunked_vfs() -> (VfsManager, Arc<ChunkedStorage>, GalleonAdvancedFeatures) {
+        let features = keyed_features(7);
+        let storage = Arc::new(ChunkedStorage::new(memory_storage(), &features));
+        block_on(storage.load_keys()).unwrap();
+        (VfsManager::new(Box::new(StorageFilesystem::new(storage.clone()))), storage, features)
+    }
+
+    #[test]
+    fn test_chunked_compression() {
+        let kernel = OperationContext::kernel();
+        let (vfs, storage, features) = chunked_vfs();
+        let compression = features.compression_manager();
+        let create = |path| {
+            let id = block_on(vfs.create(path, Permissions::default_file(), &kernel)).unwrap();
+            block_on(compression.set_compression_policy(id, CompressionPolicy::default())).unwrap();
+            id
+        };
+        let text = records(40 * 1024);
+
+        // Without a policy, or below its minimum size, files are stored whole
+        write_file(&vfs, "/plain", &text);
+        create("/small");
+        write_file(&vfs, "/small", &text[..100]);
+        for path in ["/plain", "/small"] {
+            let inode = block_on(vfs.stat(path, &kernel, true)).unwrap();
+            assert!(inode.compression().is_none() && inode.chunked_size().is_none() && inode.blocks().is_empty());
+        }
+
+        // With one, each chunk is compressed on its own
+        let id = create("/log");
+        write_file(&vfs, "/log", &text);
+        assert_eq!(read_file(&vfs, "/log").unwrap(), text);
+        let inode = block_on(vfs.stat("/log", &kernel, true)).unwrap();
+        assert_eq!(inode.blocks().len(), 3);
+        let info = inode.compression().unwrap();
+        assert_eq!((info.algorithm, info.uncompressed_size), (CompressionAlgorithm::Lz4, text.len() as u64));
+        assert!(info.compressed_size < text.len() as u64 / 2);
+        assert_eq!(inode.chunked_size(), Some(info.compressed_size));
+        assert!(block_on(storage.inner().read_data(id, 0, u64::MAX)).unwrap_or_default().is_empty());
+
+        // A stored size that undercounts the blocks doesn't underflow when they are released
+        create("/spare");
+        write_file(&vfs, "/spare", &text);
+        let mut understated = block_on(vfs.stat("/spare", &kernel, true)).unwrap();
+        understated.set_chunked_size(0);
+        let transaction = Transaction::new();
+        block_on(storage.inner().write_inode(&understated, &transaction)).unwrap();
+        block_on(transaction.commit(storage.inner())).unwrap();
+        write_file(&vfs, "/spare", &text);
+        assert_eq!(read_file(&vfs, "/spare").unwrap(), text);
+
+        // A write re-encodes only the chunks it touches
+        let mut expected = text.clone();
+        expected[CHUNK_SIZE as usize - 2..CHUNK_SIZE as usize + 2].copy_from_slice(b"1234");
+        let handle = block_on(vfs.open("/log", file_flags::O_RDWR, &kernel)).unwrap();
+        block_on(vfs.write(&handle, CHUNK_SIZE - 2, b"1234")).unwrap();
+        assert_eq!(block_on(vfs.read(&handle, CHUNK_SIZE - 4, 8)).unwrap(), expected[CHUNK_SIZE as usize - 4..CHUNK_SIZE as usize + 4]);
+        let blocks = block_on(vfs.stat("/log", &kernel, true)).unwrap().blocks().clone();
+        assert!(blocks[0] != inode.blocks()[0] && blocks[1] != inode.blocks()[1] && blocks[2] == inode.blocks()[2]);
+        for old in &inode.blocks()[..2] {
+            assert!(!block_on(storage.inner().exists(ObjectId(*old))).unwrap());
+        }
+
+        // Incompressible chunks are kept as they are; gaps read as zeros
+        let mut g = Generator::new(22);
+        let noise: Vec<u8> = (0..CHUNK_SIZE).map(|_| g.next() as u8).collect();
+        block_on(vfs.write(&handle, 4 * CHUNK_SIZE, &noise)).unwrap();
+        block_on(vfs.close(handle)).unwrap();
+        expected.resize(4 * CHUNK_SIZE as usize, 0);
+        expected.extend_from_slice(&noise);
+        assert_eq!(read_file(&vfs, "/log").unwrap(), expected);
+        let inode = block_on(vfs.stat("/log", &kernel, true)).unwrap();
+        assert_eq!(inode.blocks()[3], 0);
+        assert!(inode.compression().unwrap().compressed_size > CHUNK_SIZE);
+
+        // Truncating cuts the boundary chunk and releases the rest
+        commit(storage.as_ref(), |t| block_on(storage.truncate(id, CHUNK_SIZE + 10, t))).unwrap();
+        expected.truncate(CHUNK_SIZE as usize + 10);
+        assert_eq!(read_file(&vfs, "/log").unwrap(), expected);
+        let truncated = block_on(vfs.stat("/log", &kernel, true)).unwrap();
+        assert_eq!(truncated.blocks().len(), 2);
+        assert!(!block_on(storage.inner().exists(ObjectId(inode.blocks()[4]))).unwrap());
+
+        // Data stored whole moves into chunks once a policy applies
+        block_on(compression.set_compression_policy(block_on(vfs.stat("/plain", &kernel, true)).unwrap().id(), CompressionPolicy::default())).unwrap();
+        let handle = block_on(vfs.open("/plain", file_flags::O_WRONLY | file_flags::O_APPEND, &kernel)).unwrap();
+        block_on(vfs.write(&handle, 0, b"tail")).unwrap();
+        block_on(vfs.close(handle)).unwrap();
+        assert_eq!(read_file(&vfs, "/plain").unwrap(), [text.clone(), b"tail".to_vec()].concat());
+        assert_eq!(block_on(vfs.stat("/plain", &kernel, true)).unwrap().blocks().len(), 3);
+
+        // Deleting a file releases its blocks
+        block_on(vfs.unlink("/log", &kernel)).unwrap();
+        for block in truncated.blocks() {
+            assert!(!block_on(storage.inner().exists(ObjectId(*block))).unwrap());
+        }
+        let zstd = CompressionPolicy { algorithm: CompressionAlgorithm::Zstd, ..CompressionPolicy::default() };
+        assert!(matches!(block_on(compression.set_compression_policy(id, zstd)), Err(GalleonError::NotSupported)));
+    }
+
+    #[test]
+    fn test_chacha20_poly1305() {
+        // RFC 8439, sections 2.5.2 and 2.8.2
+        let key: [u8; 32] = unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b").try_into().unwrap();
+        assert_eq!(crypto::poly1305(&key, b"Cryptographic Forum Research Group").to_vec(), unhex("a8061dc1305136c6c22b8baf0c0127a9"));
+
+        let key: [u8; 32] = core::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 12] = unhex("070000004041424344454647").try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let sealed = crypto::seal(&key, &nonce, &aad, plaintext);
+        assert_eq!(sealed, unhex(concat!(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca9671282fafb69da92728b",
+            "1a71de0a9e060b2905d6a5b67ecd3b3692ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc",
+            "3ff4def08e4b7a9de576d26586cec64b6116",
+            "1ae10b594f09e26a7e902ecbd0600691",
+        )));
+        assert_eq!(crypto::open(&key, &nonce, &aad, &sealed).unwrap(), plaintext);
+
+        // Any change to the ciphertext, the tag or the associated data is refused
+        for position in [0, 50, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[position] ^= 1;
+            assert!(matches!(crypto::open(&key, &nonce, &aad, &tampered), Err(GalleonError::CryptoError(_))));
+        }
+        assert!(crypto::open(&key, &nonce, b"other", &sealed).is_err());
+        assert!(crypto::open(&key, &nonce, &aad, &sealed[..10]).is_err());
+        assert_eq!(crypto::open(&key, &nonce, &[], &crypto::seal(&key, &nonce, &[], &[])).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_encryption_keys() {
+        // Without a master key and key source nothing can be encrypted
+        let keyless = EncryptionManager::new();
+        assert!(matches!(block_on(keyless.generate_key(EncryptionAlgorithm::ChaCha20Poly1305)), Err(GalleonError::InvalidState(_))));
+
+        let manager = EncryptionManager::with_master_key([3; 32], Box::new(SimpleRng::new(3)));
+        assert!(matches!(block_on(manager.generate_key(EncryptionAlgorithm::Aes256Gcm)), Err(GalleonError::NotSupported)));
+        let key = block_on(manager.generate_key(EncryptionAlgorithm::ChaCha20Poly1305)).unwrap();
+        let encrypted = block_on(manager.encrypt_data(b"secret", key)).unwrap();
+        assert_eq!(encrypted.len(), 12 + 6 + 16);
+        assert_ne!(block_on(manager.encrypt_data(b"secret", key)).unwrap(), encrypted);
+        assert_eq!(block_on(manager.decrypt_data(&encrypted, key)).unwrap(), b"secret");
+        let mut tampered = encrypted.clone();
+        tampered[14] ^= 1;
+        assert!(matches!(block_on(manager.decrypt_data(&tampered, key)), Err(GalleonError::CryptoError(_))));
+        assert!(block_on(manager.decrypt_data(&encrypted, key + 1)).is_err());
+
+        // A new master key rewraps the data keys without changing them
+        block_on(manager.rotate_master_key([9; 32])).unwrap();
+        assert_eq!(block_on(manager.decrypt_data(&encrypted, key)).unwrap(), b"secret");
+
+        // Policies must name an existing key of their algorithm
+        let policy = |key_id| EncryptionPolicy { algorithm: EncryptionAlgorithm::ChaCha20Poly1305, key_id, auto_encrypt: true, require_authentication: true };
+        assert!(block_on(manager.set_encryption_policy(ObjectId(5), policy(key + 1))).is_err());
+        block_on(manager.set_encryption_policy(ObjectId(5), policy(key))).unwrap();
+        let rotated = block_on(manager.rotate_key(key)).unwrap();
+        assert_eq!(block_on(manager.get_encryption_policy(ObjectId(5))).unwrap().unwrap().key_id, rotated);
+        assert_eq!(block_on(manager.decrypt_data(&encrypted, key)).unwrap(), b"secret");
+
+        // An exported table carries the wrapped keys and policies, but not the master key
+        let table = manager.export_keys();
+        let restored = EncryptionManager::with_master_key([9; 32], Box::new(SimpleRng::new(4)));
+        restored.import_keys(&table).unwrap();
+        assert_eq!(block_on(restored.decrypt_data(&encrypted, key)).unwrap(), b"secret");
+        assert_eq!(block_on(restored.get_encryption_policy(ObjectId(5))).unwrap().unwrap().key_id, rotated);
+        keyless.import_keys(&table).unwrap();
+        assert!(matches!(block_on(keyless.decrypt_data(&encrypted, key)), Err(GalleonError::InvalidState(_))));
+        let other = EncryptionManager::with_master_key([3; 32], Box::new(SimpleRng::new(3)));
+        other.import_keys(&table).unwrap();
+        assert!(matches!(block_on(other.decrypt_data(&encrypted, key)), Err(GalleonError::CryptoError(_))));
+        assert!(other.import_keys(&table[..table.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_chunked_encryption_after_remount() {
+        let kernel = OperationContext::kernel();
+        let device = mock_device(1024 * 1024, 4096);
+        drop(PlatformStorage::format(Box::new(device.clone()), 32).unwrap());
+        let mount = |master_key| {
+            let storage = PlatformStorage::open(Box::new(device.clone()), 32).unwrap();
+            let features = keyed_features(master_key);
+            let fs = block_on(block_on(GalleonFS::new(Box::new(storage))).unwrap().with_chunked_storage(&features)).unwrap();
+            (fs, features)
+        };
+        let text = records(40 * 1024);
+
+        let (fs, features) = mount(7);
+        let encryption = features.encryption_manager();
+        let key = block_on(encryption.generate_key(EncryptionAlgorithm::ChaCha20Poly1305)).unwrap();
+        let policy = EncryptionPolicy { algorithm: EncryptionAlgorithm::ChaCha20Poly1305, key_id: key, auto_encrypt: true, require_authentication: true };
+        let id = block_on(fs.vfs().create("/vault", Permissions::default_file(), &kernel)).unwrap();
+        block_on(encryption.set_encryption_policy(id, policy)).unwrap();
+        write_file(fs.vfs(), "/vault", &text);
+        drop(fs);
+
+        // The wrapped keys and the policy are found on the volume again
+        let (fs, features) = mount(7);
+        assert_eq!(read_file(fs.vfs(), "/vault").unwrap(), text);
+        assert_eq!(block_on(features.encryption_manager().get_encryption_policy(id)).unwrap().unwrap().key_id, key);
+        write_file(fs.vfs(), "/vault", &text[..100]);
+        drop(fs);
+
+        // The master key is not
+        let (fs, _) = mount(8);
+        assert!(matches!(read_file(fs.vfs(), "/vault"), Err(GalleonError::CryptoError(_))));
+        let (fs, _) = mount(7);
+        assert_eq!(read_file(fs.vfs(), "/vault").unwrap(), text[..100]);
+    }
+
+    #[test]
+    fn test_chunked_encryption() {
+        let kernel = OperationContext::kernel();
+        let (vfs, storage, features) = chunked_vfs();
+        let (compression, encryption) = (features.compression_manager(), features.encryption_manager());
+        let key = block_on(encryption.generate_key(EncryptionAlgorithm::ChaCha20Poly1305)).unwrap();
+        let policy = EncryptionPolicy { algorithm: EncryptionAlgorithm::ChaCha20Poly1305, key_id: key, auto_encrypt: true, require_authentication: true };
+        let text = records(40 * 1024);
+        block_on(storage.sync()).unwrap();
+        assert!(!block_on(storage.inner().read_data(ObjectId::key_table(), 0, u64::MAX)).unwrap().is_empty());
+        let stored = |block: u64| block_on(storage.inner().read_data(ObjectId(block), 0, u64::MAX)).unwrap();
+        let overwrite = |block: u64, data: &[u8]| {
+            let transaction = Transaction::new();
+            block_on(storage.inner().write_data(ObjectId(block), 0, data, &transaction)).unwrap();
+            block_on(transaction.commit(storage.inner())).unwrap();
+        };
+
+        // Files of any size are sealed, compressed first if that is asked for too
+        write_file(&vfs, "/notes", &text[..100]);
+        let notes = block_on(vfs.stat("/notes", &kernel, true)).unwrap().id();
+        let id = block_on(vfs.create("/vault", Permissions::default_file(), &kernel)).unwrap();
+        for object in [notes, id] {
+            block_on(encryption.set_encryption_policy(object, policy.clone())).unwrap();
+        }
+        block_on(compression.set_compression_policy(id, CompressionPolicy::default())).unwrap();
+        write_file(&vfs, "/vault", &text);
+        let handle = block_on(vfs.open("/notes", file_flags::O_WRONLY | file_flags::O_APPEND, &kernel)).unwrap();
+        block_on(vfs.write(&handle, 0, b"!")).unwrap();
+        block_on(vfs.close(handle)).unwrap();
+        assert_eq!(read_file(&vfs, "/vault").unwrap(), text);
+        assert_eq!(read_file(&vfs, "/notes").unwrap(), [&text[..100], b"!"].concat());
+
+        let inode = block_on(vfs.stat("/vault", &kernel, true)).unwrap();
+        let info = inode.encryption().unwrap().clone();
+        assert_eq!((info.algorithm, info.key_id, info.iv.len(), info.authenticated), (EncryptionAlgorithm::ChaCha20Poly1305, key, 16, true));
+        assert!(inode.compression().unwrap().compressed_size < text.len() as u64 / 2);
+        let notes_inode = block_on(vfs.stat("/notes", &kernel, true)).unwrap();
+        assert!(notes_inode.compression().is_none() && notes_inode.chunked_size().is_some());
+        let blocks = inode.blocks().clone();
+        for &block in blocks.iter().chain(notes_inode.blocks()) {
+            assert!(!stored(block).windows(12).any(|window| window == &text[..12]));
+        }
+        assert!(block_on(storage.inner().read_data(notes, 0, u64::MAX)).unwrap_or_default().is_empty());
+
+        // Tampered or rearranged blocks are refused
+        let original = stored(blocks[1]);
+        let mut tampered = original.clone();
+        tampered[20] ^= 1;
+        overwrite(blocks[1], &tampered);
+        assert!(matches!(read_file(&vfs, "/vault"), Err(GalleonError::CryptoError(_))));
+        let handle = block_on(vfs.open("/vault", file_flags::O_RDONLY, &kernel)).unwrap();
+        assert_eq!(block_on(vfs.read(&handle, 0, 100)).unwrap(), text[..100]);
+        block_on(vfs.close(handle)).unwrap();
+        overwrite(blocks[1], &stored(blocks[0]));
+        assert!(matches!(read_file(&vfs, "/vault"), Err(GalleonError::CryptoError(_))));
+        overwrite(blocks[1], &original);
+        assert_eq!(read_file(&vfs, "/vault").unwrap(), text);
+
+        // After rotation new chunks use the new key and old ones stay readable
+        let rotated = block_on(encryption.rotate_key(key)).unwrap();
+        let handle = block_on(vfs.open("/vault", file_flags::O_WRONLY, &kernel)).unwrap();
+        block_on(vfs.write(&handle, 0, b"rotated")).unwrap();
+        block_on(vfs.close(handle)).unwrap();
+        let mut expected = text.clone();
+        expected[..7].copy_from_slice(b"rotated");
+        assert_eq!(read_file(&vfs, "/vault").unwrap(), expected);
+        let inode = block_on(vfs.stat("/vault", &kernel, true)).unwrap();
+        assert_eq!(inode.encryption().unwrap().key_id, rotated);
+        assert_eq!(stored(inode.blocks()[0])[1..9], rotated.to_le_bytes());
+        assert_eq!(stored(inode.blocks()[1])[1..9], key.to_le_bytes());
+        block_on(encryption.rotate_master_key([3; 32])).unwrap();
+        assert_eq!(read_file(&vfs, "/vault").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(crypto::sha256(b"").to_vec(), unhex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+        assert_eq!(crypto::sha256(b"abc").to_vec(), unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+        // Padding spills into a second block
+        let two_blocks = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(crypto::sha256(two_blocks).to_vec(), unhex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"));
+        let bytes: Vec<u8> = (0..768).map(|i| i as u8).collect();
+        assert_eq!(DeduplicationManager::calculate_hash(&bytes).to_vec(), unhex("f3a25aa93aa2fbba28d79260535bbd6a5eb0fc1c24a8b0f04e12b484c1dfe363"));
+    }
+
+    #[test]
+    fn test_chunked_deduplication() {
+        let kernel = OperationContext::kernel();
+        let (vfs, storage, features) = chunked_vfs();
+        let deduplication = features.deduplication_manager();
+        let create = |path| {
+            let id = block_on(vfs.create(path, Permissions::default_file(), &kernel)).unwrap();
+            block_on(deduplication.enable_deduplication(id)).unwrap();
+            id
+        };
+        let blocks = |path| block_on(vfs.stat(path, &kernel, true)).unwrap().blocks().clone();
+        let stats = || {
+            let stats = block_on(storage.stats()).unwrap();
+            (stats.shared_blocks, stats.dedup_saved_space)
+        };
+        let text = records(40 * 1024);
+
+        // Identical chunks are stored once, whatever else differs between the files
+        let a = create("/a");
+        block_on(features.compression_manager().set_compression_policy(a, CompressionPolicy::default())).unwrap();
+        write_file(&vfs, "/a", &text);
+        let b = create("/b");
+        write_file(&vfs, "/b", &text);
+        assert_eq!(blocks("/b"), blocks("/a"));
+        assert_eq!(read_file(&vfs, "/b").unwrap(), text);
+        let stored = block_on(vfs.stat("/a", &kernel, true)).unwrap().chunked_size().unwrap();
+        // Only the file with a compression policy claims to be compressed
+        assert!(block_on(vfs.stat("/b", &kernel, true)).unwrap().compression().is_none());
+        assert_eq!(stats(), (3, stored));
+        let hash = DeduplicationManager::calculate_hash(&text[..CHUNK_SIZE as usize]);
+        assert_eq!(block_on(deduplication.find_duplicates(&hash)).unwrap(), [a, b]);
+
+        // So are repeats within one write; files without deduplication keep their own
+        let mut g = Generator::new(24);
+        let noise: Vec<u8> = (0..CHUNK_SIZE).map(|_| g.next() as u8).collect();
+        create("/c");
+        write_file(&vfs, "/c", &[noise.clone(), noise.clone()].concat());
+        let c = blocks("/c");
+        assert_eq!(c[0], c[1]);
+        assert_eq!(stats(), (4, stored + CHUNK_SIZE + 1));
+        let encrypted = create("/encrypted");
+        let key = block_on(features.encryption_manager().generate_key(EncryptionAlgorithm::ChaCha20Poly1305)).unwrap();
+        let policy = EncryptionPolicy { algorithm: EncryptionAlgorithm::ChaCha20Poly1305, key_id: key, auto_encrypt: true, require_authentication: true };
+        block_on(features.encryption_manager().set_encryption_policy(encrypted, policy)).unwrap();
+        write_file(&vfs, "/encrypted", &text);
+        assert!(blocks("/encrypted").iter().all(|block| !blocks("/a").contains(block)));
+        assert_eq!(stats().0, 4);
+
+        // Writing to a shared block copies it
+        let handle = block_on(vfs.open("/b", file_flags::O_WRONLY, &kernel)).unwrap();
+        block_on(vfs.write(&handle, CHUNK_SIZE, b"changed")).unwrap();
+        block_on(vfs.close(handle)).unwrap();
+        let (old, new) = (blocks("/a"), blocks("/b"));
+        assert!(old[0] == new[0] && old[1] != new[1] && old[2] == new[2]);
+        assert_eq!(read_file(&vfs, "/a").unwrap(), text);
+        assert_eq!(&read_file(&vfs, "/b").unwrap()[CHUNK_SIZE as usize..][..7], b"changed");
+        assert_eq!(stats().0, 3);
+        assert_eq!(block_on(deduplication.find_duplicates(&hash)).unwrap(), [a, b]);
+
+        // Blocks go when their last reference does
+        block_on(vfs.unlink("/a", &kernel)).unwrap();
+        assert!(!block_on(storage.inner().exists(ObjectId(old[1]))).unwrap());
+        assert_eq!(read_file(&vfs, "/b").unwrap()[..CHUNK_SIZE as usize], text[..CHUNK_SIZE as usize]);
+        assert_eq!(stats(), (1, CHUNK_SIZE + 1));
+        assert_eq!(block_on(deduplication.find_duplicates(&hash)).unwrap(), [b]);
+    }
+
+    #[test]
+    fn test_snapshots_after_remount() {
+        let kernel = OperationContext::kernel();
+        let device = mock_device(1024 * 1024, 4096);
+        drop(PlatformStorage::format(Box::new(device.clone()), 32).unwrap());
+        let mount = || {
+            let storage = PlatformStorage::open(Box::new(device.clone()), 32).unwrap();
+            let features = keyed_features(7);
+            let fs = block_on(block_on(GalleonFS::new(Box::new(storage))).unwrap().with_chunked_storage(&features)).unwrap();
+            (fs, features.snapshot_manager())
+        };
+        let text = records(40 * 1024);
+
+        let (fs, snapshots) = mount();
+        let project = block_on(fs.vfs().mkdir("/project", Permissions::default_dir(), &kernel)).unwrap();
+        write_file(fs.vfs(), "/project/data", &text);
+        let snapshot = block_on(snapshots.create_snapshot(project, "before".to_string())).unwrap();
+        let kept = block_on(snapshots.create_snapshot(project, "kept".to_string())).unwrap();
+        block_on(snapshots.delete_snapshot(kept)).unwrap();
+        drop(fs);
+
+        // The registry is found on the volume again, and the snapshot can be restored
+        let (fs, snapshots) = mount();
+        let listed = block_on(snapshots.list_snapshots(project)).unwrap();
+        assert!(listed.len() == 1 && listed[0].id == snapshot && listed[0].name == "before");
+        write_file(fs.vfs(), "/project/data", b"replaced");
+        block_on(snapshots.restore_from_snapshot(snapshot, project)).unwrap();
+        assert_eq!(read_file(fs.vfs(), "/project/data").unwrap(), text);
+        let blocks = block_on(fs.vfs().stat("/project/data", &kernel, true)).unwrap().blocks().clone();
+        drop(fs);
+
+        // Deleting it after another remount frees what only it used
+        let (fs, snapshots) = mount();
+        block_on(snapshots.delete_snapshot(snapshot)).unwrap();
+        assert!(block_on(snapshots.list_snapshots(project)).unwrap().is_empty());
+        drop(fs);
+        let (fs, snapshots) = mount();
+        assert!(block_on(snapshots.list_snapshots(project)).unwrap().is_empty());
+        assert!(matches!(block_on(snapshots.usage(snapshot)), Err(GalleonError::NotFound)));
+        assert_eq!(read_file(fs.vfs(), "/project/data").unwrap(), text);
+        assert_eq!(block_on(fs.vfs().stat("/project/data", &kernel, true)).unwrap().blocks(), &blocks);
+        drop(fs);
+        let storage = PlatformStorage::open(Box::new(device.clone()), 32).unwrap();
+        assert!(!block_on(storage.exists(snapshot)).unwrap());
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let kernel = OperationContext::kernel();
+        let (vfs, storage, features) = chunked_vfs();
+        let snapshots = features.snapshot_manager();
+        block_on(snapshots.attach_storage(storage.clone())).unwrap();
+        let blocks = |path| block_on(vfs.stat(path, &kernel, true)).unwrap().blocks().clone();
+        let usage = |snapshot| block_on(snapshots.usage(snapshot)).unwrap();
+        let overwrite = |path, offset, data: &[u8]| {
+            let handle = block_on(vfs.open(path, file_flags::O_WRONLY, &kernel)).unwrap();
+            block_on(vfs.write(&handle, offset, data)).unwrap();
+            block_on(vfs.close(handle)).unwrap();
+        };
+        let text = records(40 * 1024);
+
+        let project = block_on(vfs.mkdir("/project", Permissions::default_dir(), &kernel)).unwrap();
+        block_on(vfs.mkdir("/project/docs", Permissions::default_dir(), &kernel)).unwrap();
+        write_file(&vfs, "/project/data", &text);
+        write_file(&vfs, "/project/docs/notes", b"first draft");
+        block_on(vfs.symlink("data", "/project/latest", &kernel)).unwrap();
+
+        // A snapshot shares the blocks of its files and can be mounted read-only
+        let snapshot = block_on(snapshots.create_snapshot(project, "before".to_string())).unwrap();
+        block_on(vfs.mkdir("/snapshot", Permissions::default_dir(), &kernel)).unwrap();
+        let options = MountOptions { read_only: true, ..Default::default() };
+        block_on(vfs.mount("/snapshot", Box::new(snapshots.filesystem(snapshot).unwrap()), "galleonfs", "snapshot", options)).unwrap();
+        assert_eq!(blocks("/project/data").len(), 3);
+        assert_eq!(blocks("/snapshot/data"), blocks("/project/data"));
+        let initial = usage(snapshot);
+        assert!(initial.referenced > text.len() as u64 && initial.exclusive < 1024);
+        assert!(matches!(block_on(vfs.open("/snapshot/data", file_flags::O_WRONLY, &kernel)), Err(GalleonError::ReadOnlyFilesystem)));
+        // The filesystem refuses writes even where its mount doesn't
+        block_on(vfs.mkdir("/writable", Permissions::default_dir(), &kernel)).unwrap();
+        block_on(vfs.mount("/writable", Box::new(snapshots.filesystem(snapshot).unwrap()), "galleonfs", "snapshot", MountOptions::default())).unwrap();
+        assert!(matches!(block_on(vfs.create("/writable/new", Permissions::default_file(), &kernel)), Err(GalleonError::ReadOnlyFilesystem)));
+        block_on(vfs.unmount("/writable", false)).unwrap();
+
+        // Changing the live tree leaves the snapshot as it was, which then holds the old blocks alone
+        overwrite("/project/data", CHUNK_SIZE, b"changed");
+        write_file(&vfs, "/project/docs/notes", b"second draft");
+        block_on(vfs.unlink("/project/latest", &kernel)).unwrap();
+        assert_eq!(read_file(&vfs, "/snapshot/data").unwrap(), text);
+        assert_eq!(read_file(&vfs, "/snapshot/docs/notes").unwrap(), b"first draft");
+        assert_eq!(block_on(vfs.readlink("/snapshot/latest", &kernel)).unwrap(), "data");
+        let diverged = usage(snapshot);
+        assert_eq!(diverged.referenced, initial.referenced);
+        assert_eq!(diverged.exclusive, initial.exclusive + (CHUNK_SIZE + 1) + (1 + 11));
+        let listed = block_on(snapshots.list_snapshots(project)).unwrap();
+        assert!(listed.len() == 1 && listed[0].name == "before" && listed[0].size == diverged.exclusive);
+
+        // Restoring rolls the tree back and shares the snapshot's blocks again
+        write_file(&vfs, "/project/docs/extra", b"added later");
+        block_on(snapshots.restore_from_snapshot(snapshot, project)).unwrap();
+        assert_eq!(read_file(&vfs, "/project/data").unwrap(), text);
+        assert_eq!(read_file(&vfs, "/project/docs/notes").unwrap(), b"first draft");
+        assert_eq!(block_on(vfs.readlink("/project/latest", &kernel)).unwrap(), "data");
+        assert!(matches!(block_on(vfs.stat("/project/docs/extra", &kernel, true)), Err(GalleonError::NotFound)));
+        assert_eq!(blocks("/project/data"), blocks("/snapshot/data"));
+        assert_eq!(usage(snapshot), initial);
+
+        // Single files and the whole filesystem can be snapshotted too
+        let data = block_on(vfs.stat("/project/data", &kernel, true)).unwrap().id();
+        let file_snapshot = block_on(snapshots.create_snapshot(data, "data".to_string())).unwrap();
+        write_file(&vfs, "/project/data", b"replaced");
+        assert!(matches!(block_on(snapshots.restore_from_snapshot(file_snapshot, project)), Err(GalleonError::InvalidArgument(_))));
+        block_on(snapshots.restore_from_snapshot(file_snapshot, data)).unwrap();
+        assert_eq!(read_file(&vfs, "/project/data").unwrap(), text);
+        let everything = block_on(snapshots.create_snapshot(ObjectId::root(), "all".to_string())).unwrap();
+        block_on(vfs.mount("/writable", Box::new(snapshots.filesystem(everything).unwrap()), "galleonfs", "snapshot", MountOptions::default())).unwrap();
+        assert_eq!(read_file(&vfs, "/writable/project/docs/notes").unwrap(), b"first draft");
+        block_on(vfs.unmount("/writable", false)).unwrap();
+        block_on(snapshots.delete_snapshot(everything)).unwrap();
+        block_on(snapshots.delete_snapshot(file_snapshot)).unwrap();
+
+        // Deleting a snapshot releases the blocks only it used
+        overwrite("/project/data", 0, b"changed");
+        let old = blocks("/snapshot/data")[0];
+        block_on(vfs.unmount("/snapshot", false)).unwrap();
+        block_on(snapshots.delete_snapshot(snapshot)).unwrap();
+        assert!(!block_on(storage.inner().exists(ObjectId(old))).unwrap());
+        assert!(!block_on(storage.exists(snapshot)).unwrap());
+        assert!(blocks("/project/data").iter().all(|&block| block_on(storage.inner().exists(ObjectId(block))).unwrap()));
+        assert_eq!(read_file(&vfs, "/project/data").unwrap()[7..], text[7..]);
+        assert!(block_on(snapshots.list_snapshots(project)).unwrap().is_empty());
+        assert!(matches!(block_on(snapshots.usage(snapshot)), Err(GalleonError::NotFound)));
+    }
+}
@@ -2,10 +2,11 @@
 //! 
 //! Provides atomic operations and consistency guarantees
 
-use alloc::{vec::Vec, collections::BTreeMap};
+use alloc::{vec::Vec, collections::{BTreeMap, VecDeque}, sync::Arc};
 use alloc::format;
-use core::sync::atomic::{AtomicU64, Ordering};
-use super::{Result, ObjectId, Inode};
+use core::{fmt, future::Future, pin::Pin, task::{Context, Poll, Waker}, time::Duration, sync::atomic::{AtomicU64, Ordering}};
+use super::{Result, ObjectId, Inode, GalleonError, StorageBackend};
+use super::platform::get_monotonic_time;
 
 /// Transaction identifier
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -43,21 +44,74 @@ pub enum TransactionOperation {
 }
 
 /// Transaction implementation
-#[derive(Debug, Clone)]
+///
+/// Storage backends do not apply changes made under a transaction; they
+/// record them as [`TransactionOperation`]s, and [`Transaction::commit`] hands
+/// the whole list to [`StorageBackend::apply_transaction`], which applies all
+/// of it or none. Reads see committed state only.
+///
+/// Clones are handles to the same transaction, so an operation recorded
+/// through a clone, e.g. inside a spawned task, is part of the original. Locks
+/// are released when the transaction commits or aborts, or when the last
+/// handle to an unfinished transaction is dropped.
+#[derive(Clone)]
 pub struct Transaction {
+    id: TransactionId,
+    inner: Arc<spin::Mutex<TransactionInner>>,
+}
+
+struct TransactionInner {
     id: TransactionId,
     state: TransactionState,
     operations: Vec<TransactionOperation>,
     locks: Vec<ObjectId>,
+    lock_manager: Option<Arc<LockManager>>,
+}
+
+impl TransactionInner {
+    fn release_locks(&mut self) {
+        if let Some(lock_manager) = &self.lock_manager {
+            lock_manager.release_all(self.id, &self.locks);
+        }
+        self.locks.clear();
+    }
+}
+
+impl Drop for TransactionInner {
+    fn drop(&mut self) {
+        // Abandoned without commit or abort, e.g. by an early return on error
+        self.release_locks();
+    }
+}
+
+impl fmt::Debug for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_struct("Transaction")
+            .field("id", &self.id)
+            .field("state", &inner.state)
+            .field("operations", &inner.operations.len())
+            .field("locks", &inner.locks)
+            .finish()
+    }
 }
 
 impl Transaction {
     pub fn new() -> Self {
+        Self::with_lock_manager(None)
+    }
+
+    fn with_lock_manager(lock_manager: Option<Arc<LockManager>>) -> Self {
+        let id = TransactionId::new();
         Self {
-            id: TransactionId::new(),
-            state: TransactionState::Active,
-            operations: Vec::new(),
-            locks: Vec::new(),
+            id,
+            inner: Arc::new(spin::Mutex::new(TransactionInner {
+                id,
+                state: TransactionState::Active,
+                operations: Vec::new(),
+                locks: Vec::new(),
+                lock_manager,
+            })),
         }
     }
 
@@ -66,147 +120,208 @@ impl Transaction {
     }
 
     pub fn state(&self) -> TransactionState {
-        self.state
+        self.inner.lock().state
     }
 
     pub fn is_active(&self) -> bool {
-        matches!(self.state, TransactionState::Active)
+        matches!(self.state(), TransactionState::Active)
     }
 
-    pub fn add_operation(&mut self, operation: TransactionOperation) {
-        if self.is_active() {
-            self.operations.push(operation);
+    /// Record an operation to be applied on commit
+    pub fn add_operation(&self, operation: TransactionOperation) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.state != TransactionState::Active {
+            return Err(GalleonError::InvalidState("Transaction is not active"));
         }
+        inner.operations.push(operation);
+        Ok(())
     }
 
-    pub fn add_lock(&mut self, object_id: ObjectId) {
-        if self.is_active() && !self.locks.contains(&object_id) {
-            self.locks.push(object_id);
+    pub fn add_lock(&self, object_id: ObjectId) {
+        let mut inner = self.inner.lock();
+        if inner.state == TransactionState::Active && !inner.locks.contains(&object_id) {
+            inner.locks.push(object_id);
         }
     }
 
-    pub fn operations(&self) -> &[TransactionOperation] {
-        &self.operations
+    pub fn operations(&self) -> Vec<TransactionOperation> {
+        self.inner.lock().operations.clone()
     }
 
-    pub fn locks(&self) -> &[ObjectId] {
-        &self.locks
+    pub fn operation_count(&self) -> usize {
+        self.inner.lock().operations.len()
+    }
+
+    pub fn locks(&self) -> Vec<ObjectId> {
+        self.inner.lock().locks.clone()
+    }
+
+    /// Inode state of `id` after the operations recorded so far
+    ///
+    /// `None` if no recorded operation touches the inode, `Some(None)` if the
+    /// last one deletes it.
+    pub fn pending_inode(&self, id: ObjectId) -> Option<Option<Inode>> {
+        let inner = self.inner.lock();
+        inner.operations.iter().rev().find_map(|operation| match operation {
+            TransactionOperation::CreateInode { id: target, inode }
+            | TransactionOperation::UpdateInode { id: target, new_inode: inode, .. } if *target == id => {
+                Some(Some(inode.clone()))
+            }
+            TransactionOperation::DeleteInode { id: target, .. } if *target == id => Some(None),
+            _ => None,
+        })
+    }
+
+    /// Forget operations recorded after the first `count`
+    pub fn rollback_to(&self, count: usize) {
+        self.inner.lock().operations.truncate(count);
     }
 
     /// Prepare the transaction for commit
-    pub async fn prepare(&mut self) -> Result<()> {
-        if !self.is_active() {
-            return Err(super::GalleonError::InvalidState(
-                "Transaction is not active".into()
-            ));
+    ///
+    /// Checks that the recorded operations are well formed; whether they can
+    /// be applied is up to the storage backend.
+    pub async fn prepare(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.state != TransactionState::Active {
+            return Err(GalleonError::InvalidState("Transaction is not active"));
         }
-
-        self.state = TransactionState::Preparing;
-        
-        // TODO: Implement two-phase commit preparation
-        // - Validate all operations
-        // - Acquire all necessary locks
-        // - Prepare storage backends
-        
+        for operation in &inner.operations {
+            validate_operation(operation)?;
+        }
+        inner.state = TransactionState::Preparing;
         Ok(())
     }
 
-    /// Commit the transaction
-    pub async fn commit(mut self) -> Result<()> {
-        match self.state {
-            TransactionState::Active => {
-                self.prepare().await?;
-            }
-            TransactionState::Preparing => {
-                // Already prepared
-            }
-            _ => {
-                return Err(super::GalleonError::InvalidStateDynamic(
-                    format!("Cannot commit transaction in state {:?}", self.state)
+    /// Commit the transaction, applying its operations to `storage`
+    ///
+    /// If an operation is malformed or the backend fails part way, nothing
+    /// is applied and the transaction ends aborted.
+    pub async fn commit(self, storage: &dyn StorageBackend) -> Result<()> {
+        let prepared = match self.state() {
+            TransactionState::Active => self.prepare().await,
+            TransactionState::Preparing => Ok(()),
+            state => {
+                return Err(GalleonError::InvalidStateDynamic(
+                    format!("Cannot commit transaction in state {:?}", state)
                 ));
             }
+        };
+
+        let operations = core::mem::take(&mut self.inner.lock().operations);
+        let result = match prepared {
+            Ok(()) if operations.is_empty() => Ok(()),
+            Ok(()) => storage.apply_transaction(&operations).await,
+            Err(error) => Err(error),
+        };
+
+        let mut inner = self.inner.lock();
+        inner.state = if result.is_ok() { TransactionState::Committed } else { TransactionState::Aborted };
+        inner.release_locks();
+        result
+    }
+
+    /// Abort the transaction, discarding its operations
+    pub async fn abort(self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if matches!(inner.state, TransactionState::Committed) {
+            return Err(GalleonError::InvalidState("Cannot abort committed transaction"));
         }
 
-        self.state = TransactionState::Committed;
-        
-        // TODO: Implement actual commit logic
-        // - Apply all operations atomically
-        // - Update storage backends
-        // - Release locks
-        
+        inner.state = TransactionState::Aborted;
+        inner.operations.clear();
+        inner.release_locks();
         Ok(())
     }
+}
 
-    /// Abort the transaction
-    pub async fn abort(mut self) -> Result<()> {
-        if matches!(self.state, TransactionState::Committed) {
-            return Err(super::GalleonError::InvalidState(
-                "Cannot abort committed transaction".into()
-            ));
+/// Reject operations that no backend could apply
+fn validate_operation(operation: &TransactionOperation) -> Result<()> {
+    match operation {
+        TransactionOperation::CreateInode { id, inode }
+        | TransactionOperation::DeleteInode { id, inode } if inode.id() != *id => {
+            Err(GalleonError::TransactionError("operation names a different inode than it carries"))
         }
-
-        self.state = TransactionState::Aborted;
-        
-        // TODO: Implement rollback logic
-        // - Undo all operations
-        // - Release locks
-        // - Clean up resources
-        
-        Ok(())
+        TransactionOperation::UpdateInode { id, old_inode, new_inode }
+            if old_inode.id() != *id || new_inode.id() != *id => {
+            Err(GalleonError::TransactionError("operation names a different inode than it carries"))
+        }
+        TransactionOperation::WriteData { offset, data, .. }
+            if offset.checked_add(data.len() as u64).is_none() => {
+            Err(GalleonError::TransactionError("write extends past the maximum file size"))
+        }
+        TransactionOperation::AllocateSpace { offset, size }
+        | TransactionOperation::DeallocateSpace { offset, size }
+            if offset.checked_add(*size).is_none() => {
+            Err(GalleonError::TransactionError("space range overflows"))
+        }
+        _ => Ok(()),
     }
 }
 
 /// Transaction manager for coordinating transactions
 pub struct TransactionManager {
+    storage: Arc<dyn StorageBackend>,
     active_transactions: spin::Mutex<BTreeMap<TransactionId, Transaction>>,
-    lock_manager: LockManager,
+    lock_manager: Arc<LockManager>,
 }
 
 impl TransactionManager {
-    pub fn new() -> Self {
+    pub fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        Self::with_lock_config(storage, LockConfig::default())
+    }
+
+    pub fn with_lock_config(storage: Arc<dyn StorageBackend>, config: LockConfig) -> Self {
         Self {
+            storage,
             active_transactions: spin::Mutex::new(BTreeMap::new()),
-            lock_manager: LockManager::new(),
+            lock_manager: Arc::new(LockManager::with_config(config)),
         }
     }
 
     pub fn begin_transaction(&self) -> Transaction {
-        let transaction = Transaction::new();
+        let transaction = Transaction::with_lock_manager(Some(self.lock_manager.clone()));
         let mut active = self.active_transactions.lock();
-    active.insert(transaction.id(), transaction.clone());
+        active.insert(transaction.id(), transaction.clone());
         transaction
     }
 
     pub async fn commit_transaction(&self, transaction: Transaction) -> Result<()> {
-        let tid = transaction.id();
-        let locks = transaction.locks().to_vec();
-        let result = transaction.commit().await;
-        // Remove from active transactions
-        let mut active = self.active_transactions.lock();
-        active.remove(&tid);
-        // Release locks
-        for object_id in locks {
-            self.lock_manager.release_lock(object_id, tid).await?;
-        }
-        result
+        self.active_transactions.lock().remove(&transaction.id());
+        transaction.commit(self.storage.as_ref()).await
     }
 
     pub async fn abort_transaction(&self, transaction: Transaction) -> Result<()> {
-        let tid = transaction.id();
-        let locks = transaction.locks().to_vec();
-        let result = transaction.abort().await;
-        // Remove from active transactions
-        let mut active = self.active_transactions.lock();
-        active.remove(&tid);
-        // Release locks
-        for object_id in locks {
-            self.lock_manager.release_lock(object_id, tid).await?;
-        }
-        result
+        self.active_transactions.lock().remove(&transaction.id());
+        transaction.abort().await
     }
 
+    /// Lock `object_id` for an active transaction until it finishes
+    ///
+    /// A transaction picked to break a deadlock is aborted, releasing the
+    /// locks the others in the cycle are waiting for.
     pub async fn acquire_lock(&self, object_id: ObjectId, transaction_id: TransactionId, lock_type: LockType) -> Result<()> {
-        self.lock_manager.acquire_lock(object_id, transaction_id, lock_type).await
+        let transaction = self.active_transactions.lock().get(&transaction_id).cloned()
+            .ok_or(GalleonError::InvalidState("Transaction is not active"))?;
+        match self.lock_manager.acquire_lock(object_id, transaction_id, lock_type).await {
+            Ok(()) => {}
+            Err(GalleonError::Deadlock) => {
+                self.abort_transaction(transaction).await?;
+                return Err(GalleonError::Deadlock);
+            }
+            Err(error) => return Err(error),
+        }
+        transaction.add_lock(object_id);
+        if !transaction.is_active() {
+            // Finished while waiting, so nothing would release the lock
+            self.lock_manager.release_all(transaction_id, &[object_id]);
+            return Err(GalleonError::InvalidState("Transaction is not active"));
+        }
+        Ok(())
+    }
+
+    pub fn lock_manager(&self) -> &LockManager {
+        &self.lock_manager
     }
 }
 
@@ -217,6 +332,51 @@ pub enum LockType {
     Exclusive,
 }
 
+impl LockType {
+    fn conflicts_with(self, other: LockType) -> bool {
+        self == LockType::Exclusive || other == LockType::Exclusive
+    }
+}
+
+/// Lock acquisition settings
+#[derive(Debug, Clone, Copy)]
+pub struct LockConfig {
+    /// How long a request may wait before failing with `Timeout`; `None` waits indefinitely
+    ///
+    /// Deadlines are measured with `clock`, so a timeout needs a clock that
+    /// follows real time.
+    pub timeout: Option<Duration>,
+    /// Monotonic clock in nanoseconds, for timeouts and wait statistics
+    pub clock: fn() -> u64,
+}
+
+/// Waits without a timeout, as the platform clock only counts calls until a
+/// real time source is installed
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            clock: get_monotonic_time,
+        }
+    }
+}
+
+/// Contention counters for one object
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Locks granted, including upgrades and re-acquisitions
+    pub acquisitions: u64,
+    /// Requests that had to wait
+    pub contended: u64,
+    pub timeouts: u64,
+    /// Requests failed to break a deadlock
+    pub deadlocks: u64,
+    /// Total time granted requests spent waiting, in nanoseconds
+    pub total_wait_ns: u64,
+    /// Longest wait queue seen
+    pub max_waiters: u64,
+}
+
 /// Lock information
 #[derive(Debug, Clone)]
 struct LockInfo {
@@ -224,83 +384,405 @@ struct LockInfo {
     lock_type: LockType,
 }
 
+/// A request waiting for a lock
+struct Waiter {
+    request: u64,
+    transaction_id: TransactionId,
+    lock_type: LockType,
+    /// Turns a shared lock the transaction holds into an exclusive one
+    upgrade: bool,
+    since: u64,
+    deadline: Option<u64>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct ObjectLocks {
+    holders: Vec<LockInfo>,
+    /// Upgrades first, then other requests in arrival order
+    waiters: VecDeque<Waiter>,
+}
+
+impl ObjectLocks {
+    fn held_by(&self, transaction_id: TransactionId) -> Option<LockType> {
+        self.holders.iter()
+            .find(|lock| lock.transaction_id == transaction_id)
+            .map(|lock| lock.lock_type)
+    }
+
+    /// Whether the holders other than `transaction_id` allow it `lock_type`
+    fn compatible(&self, transaction_id: TransactionId, lock_type: LockType) -> bool {
+        self.holders.iter()
+            .filter(|lock| lock.transaction_id != transaction_id)
+            .all(|lock| !lock.lock_type.conflicts_with(lock_type))
+    }
+
+    fn grant(&mut self, transaction_id: TransactionId, lock_type: LockType) {
+        match self.holders.iter_mut().find(|lock| lock.transaction_id == transaction_id) {
+            Some(lock) => lock.lock_type = lock_type,
+            None => self.holders.push(LockInfo { transaction_id, lock_type }),
+        }
+    }
+
+    /// Transactions the waiter at `index` is waiting for
+    fn blockers(&self, index: usize) -> impl Iterator<Item = TransactionId> + '_ {
+        let waiter = &self.waiters[index];
+        let holders = self.holders.iter()
+            .filter(move |lock| lock.lock_type.conflicts_with(waiter.lock_type))
+            .map(|lock| lock.transaction_id);
+        let ahead = self.waiters.iter()
+            .take(index)
+            .filter(move |other| other.lock_type.conflicts_with(waiter.lock_type))
+            .map(|other| other.transaction_id);
+        holders.chain(ahead).filter(move |&id| id != waiter.transaction_id)
+    }
+}
+
+/// How a waiting request ended
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    /// `upgrade` when it turned a shared lock into an exclusive one
+    Granted { upgrade: bool },
+    Deadlock,
+}
+
+#[derive(Default)]
+struct LockTable {
+    objects: BTreeMap<ObjectId, ObjectLocks>,
+    stats: BTreeMap<ObjectId, LockStats>,
+    /// Ended requests whose futures have not seen the outcome yet
+    outcomes: BTreeMap<u64, Outcome>,
+    next_request: u64,
+}
+
+impl LockTable {
+    /// Grant queued requests on `object_id` in order until one has to keep waiting
+    fn grant_waiters(&mut self, object_id: ObjectId, now: u64) {
+        let Some(object) = self.objects.get_mut(&object_id) else {
+            return;
+        };
+        let stats = self.stats.entry(object_id).or_default();
+        while let Some(waiter) = object.waiters.front() {
+            if !object.compatible(waiter.transaction_id, waiter.lock_type) {
+                break;
+            }
+            let waiter = object.waiters.pop_front().unwrap();
+            object.grant(waiter.transaction_id, waiter.lock_type);
+            stats.acquisitions += 1;
+            stats.total_wait_ns += now.saturating_sub(waiter.since);
+            self.outcomes.insert(waiter.request, Outcome::Granted { upgrade: waiter.upgrade });
+            if let Some(waker) = waiter.waker {
+                waker.wake();
+            }
+        }
+        if object.holders.is_empty() && object.waiters.is_empty() {
+            self.objects.remove(&object_id);
+        }
+    }
+
+    /// Take `request` out of the queue on `object_id`, if it is still there
+    fn cancel(&mut self, object_id: ObjectId, request: u64, now: u64) -> bool {
+        let Some(object) = self.objects.get_mut(&object_id) else {
+            return false;
+        };
+        let Some(index) = object.waiters.iter().position(|waiter| waiter.request == request) else {
+            return false;
+        };
+        object.waiters.remove(index);
+        // Requests queued behind it may now be grantable
+        self.grant_waiters(object_id, now);
+        true
+    }
+
+    fn release(&mut self, object_id: ObjectId, transaction_id: TransactionId, now: u64) {
+        if let Some(object) = self.objects.get_mut(&object_id) {
+            object.holders.retain(|lock| lock.transaction_id != transaction_id);
+            self.grant_waiters(object_id, now);
+        }
+    }
+
+    /// Turn the exclusive lock `transaction_id` holds back into a shared one
+    fn downgrade(&mut self, object_id: ObjectId, transaction_id: TransactionId, now: u64) {
+        if let Some(object) = self.objects.get_mut(&object_id) {
+            object.grant(transaction_id, LockType::Shared);
+            self.grant_waiters(object_id, now);
+        }
+    }
+
+    /// Wake the queued requests whose deadline has passed, so they fail when polled
+    fn expire(&mut self, now: u64) -> usize {
+        let mut expired = 0;
+        for object in self.objects.values_mut() {
+            for waiter in object.waiters.iter_mut() {
+                if waiter.deadline.is_some_and(|deadline| now >= deadline) {
+                    if let Some(waker) = waiter.waker.take() {
+                        waker.wake();
+                    }
+                    expired += 1;
+                }
+            }
+        }
+        expired
+    }
+
+    /// Rebuild `detector` as the wait-for graph of the queued requests
+    fn wait_for_graph(&self, detector: &DeadlockDetector) {
+        detector.clear();
+        for object in self.objects.values() {
+            for index in 0..object.waiters.len() {
+                let waiting = object.waiters[index].transaction_id;
+                for holding in object.blockers(index) {
+                    detector.add_wait_edge(waiting, holding);
+                }
+            }
+        }
+    }
+
+    /// Fail the request `transaction_id` is waiting on, so the cycle it closes is broken
+    fn fail_waiter(&mut self, transaction_id: TransactionId, now: u64) {
+        let found = self.objects.iter().find_map(|(&object_id, object)| {
+            object.waiters.iter()
+                .find(|waiter| waiter.transaction_id == transaction_id)
+                .map(|waiter| (object_id, waiter.request))
+        });
+        let Some((object_id, request)) = found else {
+            return;
+        };
+        let object = self.objects.get_mut(&object_id).unwrap();
+        let index = object.waiters.iter().position(|waiter| waiter.request == request).unwrap();
+        let waiter = object.waiters.remove(index).unwrap();
+        self.stats.entry(object_id).or_default().deadlocks += 1;
+        self.outcomes.insert(request, Outcome::Deadlock);
+        if let Some(waker) = waiter.waker {
+            waker.wake();
+        }
+        self.grant_waiters(object_id, now);
+    }
+}
+
 /// Lock manager for handling concurrent access
+///
+/// Requests that conflict with the current holders wait in a queue per
+/// object and are granted in order as locks are released, waking the task
+/// that awaits them; nothing spins. A request past its deadline is woken
+/// to fail when a lock is requested or released, or by
+/// [`expire_waits`](Self::expire_waits). Each time a request starts waiting the
+/// wait-for graph is checked, and while it has a cycle the youngest
+/// transaction in the cycle has its request failed with `Deadlock`.
 pub struct LockManager {
-    locks: spin::Mutex<BTreeMap<ObjectId, Vec<LockInfo>>>,
+    table: spin::Mutex<LockTable>,
+    detector: DeadlockDetector,
+    config: LockConfig,
 }
 
 impl LockManager {
     pub fn new() -> Self {
+        Self::with_config(LockConfig::default())
+    }
+
+    pub fn with_config(config: LockConfig) -> Self {
         Self {
-            locks: spin::Mutex::new(BTreeMap::new()),
+            table: spin::Mutex::new(LockTable::default()),
+            detector: DeadlockDetector::new(),
+            config,
         }
     }
 
-    pub async fn acquire_lock(&self, object_id: ObjectId, transaction_id: TransactionId, lock_type: LockType) -> Result<()> {
-        loop {
-            {
-                let mut locks = self.locks.lock();
-                let object_locks = locks.entry(object_id).or_insert_with(Vec::new);
-                
-                // Check if lock can be acquired
-                if self.can_acquire_lock(object_locks, lock_type) {
-                    object_locks.push(LockInfo {
-                        transaction_id,
-                        lock_type,
-                    });
-                    return Ok(());
-                }
-            }
-            
-            // TODO: Implement proper waiting mechanism instead of busy loop
-            // In a real implementation, this would use async waiting
-            core::future::ready(()).await;
-        }
+    pub fn config(&self) -> LockConfig {
+        self.config
+    }
+
+    /// Acquire `lock_type` on `object_id` for `transaction_id`
+    ///
+    /// A transaction holding a shared lock that asks for an exclusive one is
+    /// upgraded once it is the only holder, ahead of other waiting requests.
+    /// Asking again for a lock already held succeeds at once. The request
+    /// fails with `Timeout` if not granted within the configured timeout, or
+    /// `Deadlock` if it was picked to break a cycle; either way nothing is
+    /// held on its behalf. Dropping the future withdraws the request.
+    pub fn acquire_lock(&self, object_id: ObjectId, transaction_id: TransactionId, lock_type: LockType) -> LockAcquire<'_> {
+        LockAcquire { manager: self, object_id, transaction_id, lock_type, request: None }
     }
 
     pub async fn release_lock(&self, object_id: ObjectId, transaction_id: TransactionId) -> Result<()> {
-        let mut locks = self.locks.lock();
-        
-        if let Some(object_locks) = locks.get_mut(&object_id) {
-            object_locks.retain(|lock| lock.transaction_id != transaction_id);
-            
-            // Remove empty entries
-            if object_locks.is_empty() {
-                locks.remove(&object_id);
-            }
-        }
-        
+        let now = (self.config.clock)();
+        let mut table = self.table.lock();
+        table.release(object_id, transaction_id, now);
+        table.expire(now);
         Ok(())
     }
 
-    fn can_acquire_lock(&self, existing_locks: &[LockInfo], requested_type: LockType) -> bool {
-        if existing_locks.is_empty() {
-            return true;
+    /// Release every lock `transaction_id` holds on `object_ids`
+    pub fn release_all(&self, transaction_id: TransactionId, object_ids: &[ObjectId]) {
+        let now = (self.config.clock)();
+        let mut table = self.table.lock();
+        for &object_id in object_ids {
+            table.release(object_id, transaction_id, now);
         }
+        table.expire(now);
+    }
 
-        match requested_type {
-            LockType::Shared => {
-                // Shared locks are compatible with other shared locks
-                existing_locks.iter().all(|lock| matches!(lock.lock_type, LockType::Shared))
-            }
-            LockType::Exclusive => {
-                // Exclusive locks are incompatible with any other locks
-                false
-            }
-        }
+    /// Wake requests whose timeout has passed, so they fail
+    ///
+    /// Requesting and releasing locks does this too. A platform timer should
+    /// call it so requests expire while nothing else happens.
+    pub fn expire_waits(&self) -> usize {
+        let now = (self.config.clock)();
+        self.table.lock().expire(now)
     }
 
     pub fn get_lock_holders(&self, object_id: ObjectId) -> Vec<TransactionId> {
-        let locks = self.locks.lock();
-        
-        locks.get(&object_id)
-            .map(|object_locks| {
-                object_locks.iter()
-                    .map(|lock| lock.transaction_id)
-                    .collect()
-            })
+        let table = self.table.lock();
+        table.objects.get(&object_id)
+            .map(|object| object.holders.iter().map(|lock| lock.transaction_id).collect())
             .unwrap_or_default()
     }
+
+    /// Transactions waiting for a lock on `object_id`, in the order they will be granted
+    pub fn get_lock_waiters(&self, object_id: ObjectId) -> Vec<TransactionId> {
+        let table = self.table.lock();
+        table.objects.get(&object_id)
+            .map(|object| object.waiters.iter().map(|waiter| waiter.transaction_id).collect())
+            .unwrap_or_default()
+    }
+
+    /// Contention counters for `object_id`
+    pub fn lock_stats(&self, object_id: ObjectId) -> LockStats {
+        self.table.lock().stats.get(&object_id).copied().unwrap_or_default()
+    }
+
+    /// Counters of every object that has been locked, most contended first
+    pub fn contention_stats(&self) -> Vec<(ObjectId, LockStats)> {
+        let table = self.table.lock();
+        let mut stats: Vec<(ObjectId, LockStats)> = table.stats.iter().map(|(&id, &stats)| (id, stats)).collect();
+        stats.sort_by(|a, b| b.1.contended.cmp(&a.1.contended).then(a.0.cmp(&b.0)));
+        stats
+    }
+
+    /// Grant at once if possible, otherwise queue a request and return its number
+    fn request(&self, table: &mut LockTable, object_id: ObjectId, transaction_id: TransactionId, lock_type: LockType, now: u64) -> Option<u64> {
+        let object = table.objects.entry(object_id).or_default();
+        let stats = table.stats.entry(object_id).or_default();
+        let held = object.held_by(transaction_id);
+        if held == Some(LockType::Exclusive) || (held.is_some() && lock_type == LockType::Shared) {
+            stats.acquisitions += 1;
+            return None;
+        }
+        let upgrade = held.is_some();
+        if object.compatible(transaction_id, lock_type) && (upgrade || object.waiters.is_empty()) {
+            object.grant(transaction_id, lock_type);
+            stats.acquisitions += 1;
+            return None;
+        }
+
+        let request = table.next_request;
+        table.next_request += 1;
+        let waiter = Waiter {
+            request,
+            transaction_id,
+            lock_type,
+            upgrade,
+            since: now,
+            deadline: self.config.timeout.map(|timeout| now.saturating_add(timeout.as_nanos() as u64)),
+            waker: None,
+        };
+        if upgrade {
+            let index = object.waiters.iter().take_while(|waiter| waiter.upgrade).count();
+            object.waiters.insert(index, waiter);
+        } else {
+            object.waiters.push_back(waiter);
+        }
+        stats.contended += 1;
+        stats.max_waiters = stats.max_waiters.max(object.waiters.len() as u64);
+        Some(request)
+    }
+
+    /// Fail requests until the wait-for graph has no cycles
+    fn break_deadlocks(&self, table: &mut LockTable, now: u64) {
+        loop {
+            table.wait_for_graph(&self.detector);
+            let Some(cycle) = self.detector.detect_deadlock() else {
+                break;
+            };
+            // Transaction ids increase, so the youngest has done the least work
+            let youngest = cycle.into_iter().max().unwrap();
+            table.fail_waiter(youngest, now);
+        }
+        self.detector.clear();
+    }
+}
+
+/// Future returned by [`LockManager::acquire_lock`]
+pub struct LockAcquire<'a> {
+    manager: &'a LockManager,
+    object_id: ObjectId,
+    transaction_id: TransactionId,
+    lock_type: LockType,
+    /// Set while queued
+    request: Option<u64>,
+}
+
+impl Future for LockAcquire<'_> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let manager = self.manager;
+        let now = (manager.config.clock)();
+        let mut table = manager.table.lock();
+        let request = match self.request {
+            Some(request) => request,
+            None => match manager.request(&mut table, self.object_id, self.transaction_id, self.lock_type, now) {
+                None => return Poll::Ready(Ok(())),
+                Some(request) => {
+                    self.request = Some(request);
+                    manager.break_deadlocks(&mut table, now);
+                    request
+                }
+            },
+        };
+
+        if let Some(outcome) = table.outcomes.remove(&request) {
+            self.request = None;
+            return Poll::Ready(match outcome {
+                Outcome::Granted { .. } => Ok(()),
+                Outcome::Deadlock => Err(GalleonError::Deadlock),
+            });
+        }
+        table.expire(now);
+
+        let object = table.objects.get_mut(&self.object_id)
+            .expect("a queued request keeps its object entry");
+        let waiter = object.waiters.iter_mut()
+            .find(|waiter| waiter.request == request)
+            .expect("a request without an outcome is still queued");
+        if waiter.deadline.is_some_and(|deadline| now >= deadline) {
+            table.stats.entry(self.object_id).or_default().timeouts += 1;
+            table.cancel(self.object_id, request, now);
+            self.request = None;
+            return Poll::Ready(Err(GalleonError::Timeout));
+        }
+        waiter.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for LockAcquire<'_> {
+    fn drop(&mut self) {
+        let Some(request) = self.request else {
+            return;
+        };
+        let now = (self.manager.config.clock)();
+        let mut table = self.manager.table.lock();
+        if !table.cancel(self.object_id, request, now) {
+            // Granted after the last poll, so nobody knows it is held. An
+            // upgrade goes back to the shared lock held before it.
+            match table.outcomes.remove(&request) {
+                Some(Outcome::Granted { upgrade: true }) => table.downgrade(self.object_id, self.transaction_id, now),
+                Some(Outcome::Granted { upgrade: false }) => table.release(self.object_id, self.transaction_id, now),
+                _ => {}
+            }
+        }
+    }
 }
 
 /// Savepoint for nested transactions
@@ -351,7 +833,7 @@ impl ExtendedTransaction {
     pub fn create_savepoint(&mut self) -> Savepoint {
         let savepoint = Savepoint::new(
             self.base_transaction.id(),
-            self.base_transaction.operations().len()
+            self.base_transaction.operation_count()
         );
         self.savepoints.push(savepoint.clone());
         savepoint
@@ -366,7 +848,7 @@ impl ExtendedTransaction {
         let savepoint = &self.savepoints[savepoint_index];
         
         // Truncate operations to savepoint
-        self.base_transaction.operations.truncate(savepoint.operation_count());
+        self.base_transaction.rollback_to(savepoint.operation_count());
         
         // Remove newer savepoints
         self.savepoints.truncate(savepoint_index + 1);
@@ -409,6 +891,10 @@ impl DeadlockDetector {
         graph.entry(waiting).or_insert_with(Vec::new).push(holding);
     }
 
+    pub fn clear(&self) {
+        self.wait_graph.lock().clear();
+    }
+
     pub fn remove_wait_edge(&self, waiting: TransactionId, holding: TransactionId) {
         let mut graph = self.wait_graph.lock();
         if let Some(waiters) = graph.get_mut(&waiting) {
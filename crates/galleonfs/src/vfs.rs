@@ -6,9 +6,15 @@
 //! - Path resolution across filesystems
 //! - Union mounts and overlays
 
-use alloc::{vec::Vec, collections::BTreeMap, string::String, boxed::Box, string::ToString};
-use core::{future::Future, pin::Pin};
-use super::{Result, ObjectId, Inode, Permissions, OperationContext, Transaction, Filesystem, Path, DirectoryEntry, GalleonError};
+use alloc::{vec::Vec, collections::BTreeMap, string::String, boxed::Box, string::ToString, sync::Arc};
+use core::{future::Future, pin::Pin, sync::atomic::{AtomicU64, Ordering}};
+use super::{
+    Result, ObjectId, Inode, InodeType, Permissions, OperationContext, Transaction, Filesystem, FilesystemStats,
+    StorageBackend, Directory, DirectoryEntry, FileHandle, GalleonError, ExtendedAttributeValue, file_flags,
+};
+
+/// Longest chain of symbolic links followed while resolving one path
+const MAX_SYMLINK_HOPS: usize = 40;
 
 /// Mount options for filesystems
 #[derive(Debug, Clone)]
@@ -56,32 +62,94 @@ pub struct MountPoint {
     pub filesystem: ObjectId, // Reference to the mounted filesystem
     pub mount_id: u64,
     pub parent_mount_id: Option<u64>,
+    /// Directory of the filesystem shown at `path`; not its root for bind mounts of a subtree
     pub root_inode: ObjectId,
 }
 
+/// How [`VfsManager::unmount_with`] treats a busy mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmountMode {
+    /// Fail while files are open on the mount
+    Normal,
+    /// Invalidate the open files; using their handles fails with `StaleHandle`
+    Force,
+    /// Detach the mount and those below it now, and release each one's
+    /// filesystem when its last open file is closed
+    Lazy,
+}
+
+/// A file opened through the VFS
+#[derive(Debug, Clone)]
+struct OpenFile {
+    mount_id: u64,
+    filesystem: ObjectId,
+    object_id: ObjectId,
+    flags: u32,
+}
+
+/// Result of walking a path
+struct Resolved {
+    mount: MountPoint,
+    filesystem: Arc<dyn Filesystem>,
+    object_id: ObjectId,
+    /// The path with symbolic links replaced
+    path: String,
+}
+
 /// Virtual File System manager
+///
+/// Paths are walked one component at a time through the directories of each
+/// filesystem, switching to the mounted filesystem whenever the walk reaches
+/// a mount point. Bind mounts share the source mount's filesystem instance.
 pub struct VfsManager {
     mount_points: spin::Mutex<BTreeMap<String, MountPoint>>,
-    filesystems: spin::Mutex<BTreeMap<ObjectId, Box<dyn Filesystem>>>,
-    next_mount_id: core::sync::atomic::AtomicU64,
+    filesystems: spin::Mutex<BTreeMap<ObjectId, Arc<dyn Filesystem>>>,
+    /// Lazily unmounted mounts that still have open files, by mount id
+    detached: spin::Mutex<BTreeMap<u64, MountPoint>>,
+    /// Open files by handle id
+    open_files: spin::Mutex<BTreeMap<u64, OpenFile>>,
+    next_mount_id: AtomicU64,
     root_filesystem: ObjectId,
 }
 
 impl VfsManager {
     pub fn new(root_filesystem: Box<dyn Filesystem>) -> Self {
         let root_fs_id = ObjectId::new();
-        let mut filesystems = BTreeMap::new();
-        filesystems.insert(root_fs_id, root_filesystem);
+        let mut filesystems: BTreeMap<ObjectId, Arc<dyn Filesystem>> = BTreeMap::new();
+        filesystems.insert(root_fs_id, Arc::from(root_filesystem));
+
+        let root_mount = MountPoint {
+            path: "/".to_string(),
+            filesystem_type: "rootfs".to_string(),
+            device: "none".to_string(),
+            options: MountOptions::default(),
+            filesystem: root_fs_id,
+            mount_id: 1,
+            parent_mount_id: None,
+            root_inode: ObjectId::root(),
+        };
+        let mut mount_points = BTreeMap::new();
+        mount_points.insert(root_mount.path.clone(), root_mount);
 
         Self {
-            mount_points: spin::Mutex::new(BTreeMap::new()),
+            mount_points: spin::Mutex::new(mount_points),
             filesystems: spin::Mutex::new(filesystems),
-            next_mount_id: core::sync::atomic::AtomicU64::new(1),
+            detached: spin::Mutex::new(BTreeMap::new()),
+            open_files: spin::Mutex::new(BTreeMap::new()),
+            next_mount_id: AtomicU64::new(2),
             root_filesystem: root_fs_id,
         }
     }
 
+    pub fn root_filesystem(&self) -> ObjectId {
+        self.root_filesystem
+    }
+
     /// Mount a filesystem at the specified path
+    ///
+    /// The path must name an existing directory that is not already a mount
+    /// point. With `options.remount` the options of the mount already at
+    /// `path` are replaced instead, and `filesystem` is dropped.
     pub async fn mount(
         &self,
         path: &str,
@@ -90,81 +158,103 @@ impl VfsManager {
         device: &str,
         options: MountOptions,
     ) -> Result<u64> {
-        use core::sync::atomic::Ordering;
-
-        // Validate mount path
-        let normalized_path = self.normalize_path(path)?;
-        
-        // Check if path is already mounted
-        {
-            let mount_points = self.mount_points.lock();
-            if mount_points.contains_key(&normalized_path) {
-                if !options.remount {
-                    return Err(GalleonError::AlreadyExists);
-                }
-            }
+        if options.remount {
+            return self.remount(path, options);
         }
 
-        // Generate mount ID
-        let mount_id = self.next_mount_id.fetch_add(1, Ordering::Relaxed);
-        let filesystem_id = ObjectId::new();
-
-        // Get root inode of the filesystem
+        let target = self.walk(path, &OperationContext::kernel(), true).await?;
+        if !target.filesystem.read_inode(target.object_id).await?.is_directory() {
+            return Err(GalleonError::NotADirectory);
+        }
         let root_inode = filesystem.read_inode(ObjectId::root()).await?.id();
 
-        // Create mount point
+        let filesystem_id = ObjectId::new();
         let mount_point = MountPoint {
-            path: normalized_path.clone(),
+            path: target.path.clone(),
             filesystem_type: filesystem_type.to_string(),
             device: device.to_string(),
             options,
             filesystem: filesystem_id,
-            mount_id,
-            parent_mount_id: None, // TODO: Determine parent mount
+            mount_id: self.next_mount_id.fetch_add(1, Ordering::Relaxed),
+            parent_mount_id: Some(target.mount.mount_id),
             root_inode,
         };
+        let mount_id = mount_point.mount_id;
 
-        // Store filesystem and mount point
-        {
-            let mut filesystems = self.filesystems.lock();
-            filesystems.insert(filesystem_id, filesystem);
-        }
-
-        {
-            let mut mount_points = self.mount_points.lock();
-            mount_points.insert(normalized_path, mount_point);
+        let mut mount_points = self.mount_points.lock();
+        if mount_points.contains_key(&target.path) {
+            return Err(GalleonError::AlreadyExists);
         }
-
+        self.filesystems.lock().insert(filesystem_id, Arc::from(filesystem));
+        mount_points.insert(target.path, mount_point);
         Ok(mount_id)
     }
 
+    /// Replace the options of the mount at `path`
+    pub fn remount(&self, path: &str, mut options: MountOptions) -> Result<u64> {
+        let normalized_path = self.normalize_path(path)?;
+        let mut mount_points = self.mount_points.lock();
+        let mount_point = mount_points.get_mut(&normalized_path).ok_or(GalleonError::NotFound)?;
+        options.remount = false;
+        options.bind = mount_point.options.bind;
+        mount_point.options = options;
+        Ok(mount_point.mount_id)
+    }
+
     /// Unmount a filesystem
+    ///
+    /// Fails while files are open on it unless `force` is set, in which case
+    /// they are invalidated.
     pub async fn unmount(&self, path: &str, force: bool) -> Result<()> {
+        self.unmount_with(path, if force { UnmountMode::Force } else { UnmountMode::Normal }).await
+    }
+
+    /// Unmount a filesystem, treating open files as `mode` says
+    ///
+    /// Only a lazy unmount removes a mount that has others mounted below it.
+    pub async fn unmount_with(&self, path: &str, mode: UnmountMode) -> Result<()> {
         let normalized_path = self.normalize_path(path)?;
+        if normalized_path == "/" {
+            return Err(GalleonError::InvalidState("Cannot unmount the root filesystem"));
+        }
+        let prefix = Self::child_prefix(&normalized_path);
 
-        // Find and remove mount point
-        let mount_point = {
+        let removed: Vec<MountPoint> = {
             let mut mount_points = self.mount_points.lock();
-            mount_points.remove(&normalized_path)
-                .ok_or(GalleonError::NotFound)?
+            if !mount_points.contains_key(&normalized_path) {
+                return Err(GalleonError::NotFound);
+            }
+            let below: Vec<String> = mount_points.keys()
+                .filter(|mount_path| mount_path.starts_with(&prefix))
+                .cloned()
+                .collect();
+
+            let mount_id = mount_points[&normalized_path].mount_id;
+            match mode {
+                UnmountMode::Lazy => {}
+                _ if !below.is_empty() => return Err(GalleonError::InvalidState("Filesystem is busy")),
+                UnmountMode::Normal if self.has_open_files(mount_id) => {
+                    return Err(GalleonError::InvalidState("Filesystem is busy"));
+                }
+                UnmountMode::Normal => {}
+                UnmountMode::Force => {
+                    self.open_files.lock().retain(|_, open_file| open_file.mount_id != mount_id);
+                }
+            }
+            let mut removed: Vec<MountPoint> = below.iter()
+                .filter_map(|mount_path| mount_points.remove(mount_path))
+                .collect();
+            removed.extend(mount_points.remove(&normalized_path));
+            removed
         };
 
-        // Check if filesystem is busy (has open files, etc.)
-        if !force {
-            if self.is_filesystem_busy(&mount_point.filesystem).await? {
-                // Restore mount point
-                let mut mount_points = self.mount_points.lock();
-                mount_points.insert(normalized_path, mount_point);
-                return Err(GalleonError::InvalidState("Filesystem is busy".into()));
+        for mount_point in removed {
+            if self.has_open_files(mount_point.mount_id) {
+                self.detached.lock().insert(mount_point.mount_id, mount_point);
+            } else {
+                self.release_filesystem(mount_point.filesystem);
             }
         }
-
-        // Remove filesystem
-        {
-            let mut filesystems = self.filesystems.lock();
-            filesystems.remove(&mount_point.filesystem);
-        }
-
         Ok(())
     }
 
@@ -175,22 +265,8 @@ impl VfsManager {
         context: &OperationContext,
         follow_symlinks: bool,
     ) -> Result<(ObjectId, ObjectId)> { // (filesystem_id, object_id)
-        let normalized_path = self.normalize_path(path)?;
-        let path_obj = Path::parse(&normalized_path)?;
-
-        // Find the appropriate mount point
-        let (mount_point, relative_path) = self.find_mount_point(&normalized_path)?;
-        
-        // Get the filesystem
-        let filesystem = {
-            let filesystems = self.filesystems.lock();
-            // We can't return a reference here due to lock lifetime, 
-            // so we'll need a different approach in a real implementation
-            return Err(GalleonError::NotSupported);
-        };
-
-        // TODO: Resolve the path within the filesystem
-        // This would involve calling the filesystem's resolve_path method
+        let resolved = self.walk(path, context, follow_symlinks).await?;
+        Ok((resolved.mount.filesystem, resolved.object_id))
     }
 
     /// List all mount points
@@ -206,49 +282,310 @@ impl VfsManager {
         Ok(mount_points.get(&normalized_path).cloned())
     }
 
-    /// Check if a filesystem is busy (has open files, processes, etc.)
-    async fn is_filesystem_busy(&self, _filesystem_id: &ObjectId) -> Result<bool> {
-        // TODO: Implement busy check
-        // - Check for open file handles
-        // - Check for processes with working directory in filesystem
-        // - Check for memory mapped files
-        Ok(false)
+    /// Number of files open on a mount, including one that was lazily unmounted
+    pub fn open_file_count(&self, mount_id: u64) -> usize {
+        self.open_files.lock().values().filter(|open_file| open_file.mount_id == mount_id).count()
     }
 
-    /// Find the mount point for a given path
-    fn find_mount_point(&self, path: &str) -> Result<(MountPoint, String)> {
-        let mount_points = self.mount_points.lock();
-        
-        // Find the longest matching mount point
-        let mut best_match: Option<(String, MountPoint)> = None;
-        
-        for (mount_path, mount_point) in mount_points.iter() {
-            if path.starts_with(mount_path) {
-                if let Some((ref current_best_path, _)) = best_match {
-                    if mount_path.len() > current_best_path.len() {
-                        best_match = Some((mount_path.clone(), mount_point.clone()));
+    fn has_open_files(&self, mount_id: u64) -> bool {
+        self.open_file_count(mount_id) > 0
+    }
+
+    /// `path` followed by a separator, to match the paths below it
+    fn child_prefix(path: &str) -> String {
+        if path.ends_with('/') {
+            path.to_string()
+        } else {
+            alloc::format!("{}/", path)
+        }
+    }
+
+    /// Drop a filesystem once no mount, attached or detached, refers to it
+    fn release_filesystem(&self, filesystem_id: ObjectId) {
+        let attached = self.mount_points.lock().values().any(|mount| mount.filesystem == filesystem_id);
+        let detached = self.detached.lock().values().any(|mount| mount.filesystem == filesystem_id);
+        if !attached && !detached {
+            self.filesystems.lock().remove(&filesystem_id);
+        }
+    }
+
+    fn filesystem(&self, filesystem_id: ObjectId) -> Result<Arc<dyn Filesystem>> {
+        self.filesystems.lock().get(&filesystem_id).cloned().ok_or(GalleonError::StaleHandle)
+    }
+
+    /// The mount an open file was opened through, while it is still usable
+    fn mount_by_id(&self, mount_id: u64) -> Option<MountPoint> {
+        let attached = self.mount_points.lock().values().find(|mount| mount.mount_id == mount_id).cloned();
+        attached.or_else(|| self.detached.lock().get(&mount_id).cloned())
+    }
+
+    /// Walk `path` from the root, crossing mount points and following symbolic links
+    ///
+    /// Each directory passed through needs search permission. The last
+    /// component is only followed if it is a link and `follow_last` is set.
+    async fn walk(&self, path: &str, context: &OperationContext, follow_last: bool) -> Result<Resolved> {
+        let mut path = self.normalize_path(path)?;
+        if !path.starts_with('/') {
+            return Err(GalleonError::InvalidPath("VFS paths must be absolute"));
+        }
+        let mut hops = 0;
+
+        'restart: loop {
+            let root = self.mount_points.lock().get("/").cloned().ok_or(GalleonError::NotFound)?;
+            let mut filesystem = self.filesystem(root.filesystem)?;
+            let mut object_id = root.root_inode;
+            let mut mount = root;
+            let mut walked = String::new();
+
+            let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+            for (index, name) in components.iter().enumerate() {
+                let directory = filesystem.read_inode(object_id).await?;
+                if !directory.is_directory() {
+                    return Err(GalleonError::NotADirectory);
+                }
+                if !directory.can_execute(context.uid, context.gid) {
+                    return Err(GalleonError::PermissionDenied);
+                }
+                let entry = read_directory(filesystem.as_ref(), object_id).await?
+                    .get_entry(name)
+                    .cloned()
+                    .ok_or(GalleonError::NotFound)?;
+                let parent = walked.clone();
+                walked.push('/');
+                walked.push_str(name);
+
+                // Whatever is mounted here hides the directory underneath
+                let covering = self.mount_points.lock().get(&walked).cloned();
+                if let Some(covering) = covering {
+                    filesystem = self.filesystem(covering.filesystem)?;
+                    object_id = covering.root_inode;
+                    mount = covering;
+                    continue;
+                }
+
+                let last = index + 1 == components.len();
+                if entry.inode_type == InodeType::SymbolicLink && (!last || follow_last) {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(GalleonError::TooManyLinks);
+                    }
+                    let target = read_symlink(filesystem.as_ref(), entry.object_id).await?;
+                    let mut expanded = if target.starts_with('/') {
+                        target
+                    } else {
+                        alloc::format!("{}/{}", parent, target)
+                    };
+                    for rest in &components[index + 1..] {
+                        expanded.push('/');
+                        expanded.push_str(rest);
                     }
-                } else {
-                    best_match = Some((mount_path.clone(), mount_point.clone()));
+                    path = self.normalize_path(&expanded)?;
+                    continue 'restart;
                 }
+                object_id = entry.object_id;
             }
+
+            if walked.is_empty() {
+                walked.push('/');
+            }
+            return Ok(Resolved { mount, filesystem, object_id, path: walked });
         }
+    }
 
-        if let Some((mount_path, mount_point)) = best_match {
-            let relative_path = if path.len() > mount_path.len() {
-                path[mount_path.len()..].to_string()
-            } else {
-                String::new()
-            };
-            Ok((mount_point, relative_path))
+    /// Walk to the directory that holds the last component of `path`
+    async fn walk_parent(&self, path: &str, context: &OperationContext) -> Result<(Resolved, String)> {
+        let normalized_path = self.normalize_path(path)?;
+        let (parent, name) = normalized_path.rsplit_once('/')
+            .ok_or(GalleonError::InvalidPath("VFS paths must be absolute"))?;
+        if name.is_empty() {
+            return Err(GalleonError::InvalidPath("Path has no final component"));
+        }
+        let parent = self.walk(if parent.is_empty() { "/" } else { parent }, context, true).await?;
+        if !parent.filesystem.read_inode(parent.object_id).await?.is_directory() {
+            return Err(GalleonError::NotADirectory);
+        }
+        Ok((parent, name.to_string()))
+    }
+
+    /// Walk to the directory that will hold a new entry and check it may be added
+    async fn prepare_entry(&self, path: &str, context: &OperationContext) -> Result<(Resolved, Directory, String)> {
+        let (parent, name) = self.walk_parent(path, context).await?;
+        check_writable(&parent.mount)?;
+        let parent_inode = parent.filesystem.read_inode(parent.object_id).await?;
+        if !parent_inode.can_write(context.uid, context.gid) {
+            return Err(GalleonError::PermissionDenied);
+        }
+        let directory = read_directory(parent.filesystem.as_ref(), parent.object_id).await?;
+        if directory.contains(&name) {
+            return Err(GalleonError::AlreadyExists);
+        }
+        Ok((parent, directory, name))
+    }
+
+    /// Create an object of `inode_type` named by `path`, with `data` as its contents
+    async fn create_entry(
+        &self,
+        path: &str,
+        inode_type: InodeType,
+        permissions: Permissions,
+        data: &[u8],
+        context: &OperationContext,
+    ) -> Result<ObjectId> {
+        let (parent, mut directory, name) = self.prepare_entry(path, context).await?;
+        let filesystem = parent.filesystem.as_ref();
+        let transaction = Transaction::new();
+        let id = filesystem.create_inode(inode_type, permissions, &transaction).await?;
+        if !data.is_empty() {
+            let mut inode = Inode::new(id, inode_type, permissions, 0);
+            if inode_type != InodeType::Directory {
+                inode.set_size(data.len() as u64);
+            }
+            filesystem.write_inode(&inode, &transaction).await?;
+            filesystem.write_data(id, 0, data, &transaction).await?;
+        }
+        directory.add_entry(DirectoryEntry::new(name, id, inode_type))?;
+        write_directory(filesystem, parent.object_id, &directory, &transaction).await?;
+        commit(&parent, transaction).await?;
+        Ok(id)
+    }
+
+    /// Walk to an existing entry for removal, returning its directory and inode
+    async fn prepare_removal(&self, path: &str, context: &OperationContext) -> Result<(Resolved, Directory, DirectoryEntry, Inode)> {
+        let (parent, name) = self.walk_parent(path, context).await?;
+        check_writable(&parent.mount)?;
+        let parent_inode = parent.filesystem.read_inode(parent.object_id).await?;
+        if !parent_inode.can_write(context.uid, context.gid) {
+            return Err(GalleonError::PermissionDenied);
+        }
+        let directory = read_directory(parent.filesystem.as_ref(), parent.object_id).await?;
+        let entry = directory.get_entry(&name).cloned().ok_or(GalleonError::NotFound)?;
+        let full_path = if parent.path == "/" { alloc::format!("/{}", name) } else { alloc::format!("{}/{}", parent.path, name) };
+        if self.mount_points.lock().contains_key(&full_path) {
+            return Err(GalleonError::InvalidState("Mount point is busy"));
+        }
+        let inode = parent.filesystem.read_inode(entry.object_id).await?;
+        Ok((parent, directory, entry, inode))
+    }
+
+    /// Drop one link to `inode`, deleting it once unlinked and no longer open
+    async fn drop_link(&self, filesystem: &dyn Filesystem, filesystem_id: ObjectId, mut inode: Inode, transaction: &Transaction) -> Result<()> {
+        inode.decrement_link_count();
+        let open = self.open_files.lock().values()
+            .any(|open_file| open_file.filesystem == filesystem_id && open_file.object_id == inode.id());
+        if inode.link_count() == 0 && !open {
+            filesystem.delete_inode(inode.id(), transaction).await
         } else {
-            // Default to root filesystem
-            if let Some(root_mount) = mount_points.get("/") {
-                Ok((root_mount.clone(), path.to_string()))
-            } else {
-                Err(GalleonError::NotFound)
+            filesystem.write_inode(&inode, transaction).await
+        }
+    }
+
+    /// Walk to an object whose metadata `context` changes
+    async fn prepare_change(&self, path: &str, context: &OperationContext) -> Result<(Resolved, Inode)> {
+        let resolved = self.walk(path, context, true).await?;
+        check_writable(&resolved.mount)?;
+        let inode = resolved.filesystem.read_inode(resolved.object_id).await?;
+        Ok((resolved, inode))
+    }
+
+    /// Open a file to run it
+    ///
+    /// Refused on `no_exec` mounts and for files without an execute bit.
+    pub async fn open_executable(&self, path: &str, context: &OperationContext) -> Result<FileHandle> {
+        let resolved = self.walk(path, context, true).await?;
+        if resolved.mount.options.no_exec {
+            return Err(GalleonError::PermissionDenied);
+        }
+        let inode = resolved.filesystem.read_inode(resolved.object_id).await?;
+        if !inode.is_file() {
+            return Err(GalleonError::PermissionDenied);
+        }
+        if inode.permissions().mode & 0o111 == 0 || !inode.can_execute(context.uid, context.gid) {
+            return Err(GalleonError::PermissionDenied);
+        }
+        Ok(self.register(&resolved, file_flags::O_RDONLY))
+    }
+
+    fn register(&self, resolved: &Resolved, flags: u32) -> FileHandle {
+        let handle = FileHandle::new(resolved.object_id, flags);
+        self.open_files.lock().insert(handle.handle_id, OpenFile {
+            mount_id: resolved.mount.mount_id,
+            filesystem: resolved.mount.filesystem,
+            object_id: resolved.object_id,
+            flags,
+        });
+        handle
+    }
+
+    /// The open file behind `handle`, with its mount and filesystem
+    fn open_file(&self, handle: &FileHandle) -> Result<(OpenFile, MountPoint, Arc<dyn Filesystem>)> {
+        let open_file = self.open_files.lock().get(&handle.handle_id).cloned().ok_or(GalleonError::StaleHandle)?;
+        let mount = self.mount_by_id(open_file.mount_id).ok_or(GalleonError::StaleHandle)?;
+        let filesystem = self.filesystem(open_file.filesystem)?;
+        Ok((open_file, mount, filesystem))
+    }
+
+    /// Read from an open file
+    pub async fn read(&self, handle: &FileHandle, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let (open_file, _, filesystem) = self.open_file(handle)?;
+        if open_file.flags & file_flags::O_WRONLY != 0 {
+            return Err(GalleonError::PermissionDenied);
+        }
+        match filesystem.read_data(open_file.object_id, offset, length).await {
+            // Nothing has been written yet
+            Err(GalleonError::NotFound) => Ok(Vec::new()),
+            result => result,
+        }
+    }
+
+    /// Write to an open file, which must have been opened for writing
+    pub async fn write(&self, handle: &FileHandle, offset: u64, data: &[u8]) -> Result<u64> {
+        let (open_file, mount, filesystem) = self.open_file(handle)?;
+        if open_file.flags & (file_flags::O_WRONLY | file_flags::O_RDWR) == 0 {
+            return Err(GalleonError::PermissionDenied);
+        }
+        // Checked on every write, so a remount read-only takes effect at once
+        check_writable(&mount)?;
+        let mut inode = filesystem.read_inode(open_file.object_id).await?;
+        let offset = if open_file.flags & file_flags::O_APPEND != 0 { inode.size() } else { offset };
+
+        let transaction = Transaction::new();
+        let written = filesystem.write_data(open_file.object_id, offset, data, &transaction).await?;
+        inode.set_size(inode.size().max(offset + written));
+        filesystem.write_inode(&inode, &transaction).await?;
+        transaction.commit(filesystem.storage()).await?;
+        if mount.options.sync {
+            filesystem.sync().await?;
+        }
+        Ok(written)
+    }
+
+    /// Close an open file
+    ///
+    /// Closing the last file of a lazily unmounted mount releases it, and
+    /// closing the last handle of an unlinked file deletes it.
+    pub async fn close(&self, handle: FileHandle) -> Result<()> {
+        let open_file = self.open_files.lock().remove(&handle.handle_id).ok_or(GalleonError::StaleHandle)?;
+        let filesystem = self.filesystem(open_file.filesystem)?;
+
+        let still_open = self.open_files.lock().values()
+            .any(|other| other.filesystem == open_file.filesystem && other.object_id == open_file.object_id);
+        if !still_open {
+            if let Ok(inode) = filesystem.read_inode(open_file.object_id).await {
+                if inode.link_count() == 0 {
+                    let transaction = Transaction::new();
+                    filesystem.delete_inode(inode.id(), &transaction).await?;
+                    transaction.commit(filesystem.storage()).await?;
+                }
             }
         }
+
+        if !self.has_open_files(open_file.mount_id) {
+            let detached = self.detached.lock().remove(&open_file.mount_id);
+            if let Some(mount_point) = detached {
+                self.release_filesystem(mount_point.filesystem);
+            }
+        }
+        Ok(())
     }
 
     /// Normalize a path (resolve .., ., remove duplicate slashes, etc.)
@@ -294,30 +631,71 @@ impl VfsManager {
     }
 
     /// Create a bind mount
+    ///
+    /// Makes the directory at `source` visible at `target` as well, sharing
+    /// the filesystem and the source mount's options. A `recursive` bind also
+    /// repeats the mounts found below `source` under `target`.
     pub async fn bind_mount(&self, source: &str, target: &str, recursive: bool) -> Result<u64> {
-        let mut options = MountOptions::default();
-        options.bind = true;
+        let context = OperationContext::kernel();
+        let source = self.walk(source, &context, true).await?;
+        if !source.filesystem.read_inode(source.object_id).await?.is_directory() {
+            return Err(GalleonError::NotADirectory);
+        }
+        let target = self.walk(target, &context, true).await?;
+        if !target.filesystem.read_inode(target.object_id).await?.is_directory() {
+            return Err(GalleonError::NotADirectory);
+        }
+        if source.mount.options.unbindable {
+            return Err(GalleonError::InvalidState("Mount is unbindable"));
+        }
 
-        // For bind mounts, we don't create a new filesystem instance,
-        // we just create another mount point that refers to the same filesystem
-        let (source_mount, _) = self.find_mount_point(source)?;
-        
-        let filesystem_id = source_mount.filesystem;
-        let filesystem = {
-            let filesystems = self.filesystems.lock();
-            // In a real implementation, we'd need to handle this differently
-            // since we can't clone the filesystem easily
-            return Err(GalleonError::NotSupported);
+        let mut mount_points = self.mount_points.lock();
+        if mount_points.contains_key(&target.path) {
+            return Err(GalleonError::AlreadyExists);
+        }
+
+        let bind = |mount: &MountPoint, path: String, parent_mount_id: u64, root_inode: ObjectId| {
+            let mut options = mount.options.clone();
+            options.bind = true;
+            MountPoint {
+                path,
+                filesystem_type: mount.filesystem_type.clone(),
+                device: mount.device.clone(),
+                options,
+                filesystem: mount.filesystem,
+                mount_id: self.next_mount_id.fetch_add(1, Ordering::Relaxed),
+                parent_mount_id: Some(parent_mount_id),
+                root_inode,
+            }
         };
+        let top = bind(&source.mount, target.path.clone(), target.mount.mount_id, source.object_id);
+        let top_id = top.mount_id;
 
-        // TODO: Handle recursive bind mounts
+        let mut binds = Vec::new();
         if recursive {
-            // Would need to bind all submounts as well
+            // Shallowest first, so each copy's parent has been copied already
+            let prefix = Self::child_prefix(&source.path);
+            let mut below: Vec<&MountPoint> = mount_points.values()
+                .filter(|mount| mount.path.starts_with(&prefix) && !mount.options.unbindable)
+                .collect();
+            below.sort_by_key(|mount| mount.path.len());
+
+            let mut copies: BTreeMap<u64, u64> = BTreeMap::new();
+            copies.insert(source.mount.mount_id, top_id);
+            for mount in below {
+                let path = alloc::format!("{}{}", Self::child_prefix(&target.path), &mount.path[prefix.len()..]);
+                let parent = mount.parent_mount_id.and_then(|id| copies.get(&id).copied()).unwrap_or(top_id);
+                let copy = bind(mount, path, parent, mount.root_inode);
+                copies.insert(mount.mount_id, copy.mount_id);
+                binds.push(copy);
+            }
         }
 
-        // Create the bind mount (this is simplified)
-        // self.mount(target, filesystem, "bind", source, options).await
-        todo!("Implement bind mount creation")
+        mount_points.insert(top.path.clone(), top);
+        for copy in binds {
+            mount_points.insert(copy.path.clone(), copy);
+        }
+        Ok(top_id)
     }
 
     /// Move a mount point
@@ -348,15 +726,16 @@ impl VfsManager {
     }
 
     /// Get statistics for all mounted filesystems
-    pub async fn get_filesystem_stats(&self) -> Result<Vec<(String, crate::FilesystemStats)>> {
-        let mount_points = self.mount_points.lock();
+    pub async fn get_filesystem_stats(&self) -> Result<Vec<(String, FilesystemStats)>> {
+        let mounted: Vec<(String, ObjectId)> = self.mount_points.lock().iter()
+            .map(|(path, mount_point)| (path.clone(), mount_point.filesystem))
+            .collect();
         let mut stats = Vec::new();
 
-        for (path, mount_point) in mount_points.iter() {
-            let filesystems = self.filesystems.lock();
-            if let Some(filesystem) = filesystems.get(&mount_point.filesystem) {
+        for (path, filesystem_id) in mounted {
+            if let Ok(filesystem) = self.filesystem(filesystem_id) {
                 match filesystem.stats().await {
-                    Ok(fs_stats) => stats.push((path.clone(), fs_stats)),
+                    Ok(fs_stats) => stats.push((path, fs_stats)),
                     Err(_) => continue, // Skip filesystems that can't provide stats
                 }
             }
@@ -366,10 +745,133 @@ impl VfsManager {
     }
 }
 
+fn check_writable(mount: &MountPoint) -> Result<()> {
+    if mount.options.read_only {
+        Err(GalleonError::ReadOnlyFilesystem)
+    } else {
+        Ok(())
+    }
+}
+
+/// Commit `transaction` to the filesystem `resolved` is on, honouring its `sync` option
+async fn commit(resolved: &Resolved, transaction: Transaction) -> Result<()> {
+    transaction.commit(resolved.filesystem.storage()).await?;
+    if resolved.mount.options.sync {
+        resolved.filesystem.sync().await?;
+    }
+    Ok(())
+}
+
+async fn read_directory(filesystem: &dyn Filesystem, id: ObjectId) -> Result<Directory> {
+    let data = filesystem.read_data(id, 0, u64::MAX).await?;
+    Directory::deserialize(&data)
+}
+
+async fn write_directory(filesystem: &dyn Filesystem, id: ObjectId, directory: &Directory, transaction: &Transaction) -> Result<()> {
+    let data = directory.serialize()?;
+    filesystem.write_data(id, 0, &data, transaction).await?;
+    filesystem.truncate(id, data.len() as u64, transaction).await
+}
+
+async fn read_symlink(filesystem: &dyn Filesystem, id: ObjectId) -> Result<String> {
+    let data = filesystem.read_data(id, 0, u64::MAX).await?;
+    String::from_utf8(data).map_err(|_| GalleonError::Corruption("symbolic link target is not UTF-8"))
+}
+
+/// Filesystem over a bare storage backend
+///
+/// Runs each operation on the caller's task. [`GalleonFS`](crate::GalleonFS)
+/// uses it as the root of its mount namespace.
+pub struct StorageFilesystem {
+    storage: Arc<dyn StorageBackend>,
+    root: ObjectId,
+    read_only: bool,
+}
+
+impl StorageFilesystem {
+    pub fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        Self { storage, root: ObjectId::root(), read_only: false }
+    }
+
+    /// Read-only view of the tree under the directory `root`, which it
+    /// presents as its root, such as a snapshot
+    pub fn read_only(storage: Arc<dyn StorageBackend>, root: ObjectId) -> Self {
+        Self { storage, root, read_only: true }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(GalleonError::ReadOnlyFilesystem);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for StorageFilesystem {
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<FilesystemStats>> + Send + '_>> {
+        self.storage.stats()
+    }
+
+    fn create_inode(&self, inode_type: InodeType, permissions: Permissions, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<ObjectId>> + Send + '_>> {
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            self.check_writable()?;
+            let id = ObjectId::new();
+            let inode = Inode::new(id, inode_type, permissions, 0);
+            self.storage.write_inode(&inode, &transaction).await?;
+            Ok(id)
+        })
+    }
+
+    fn read_inode(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>> {
+        self.storage.read_inode(if id == ObjectId::root() { self.root } else { id })
+    }
+
+    fn write_inode(&self, inode: &Inode, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        if let Err(error) = self.check_writable() {
+            return Box::pin(async move { Err(error) });
+        }
+        self.storage.write_inode(inode, transaction)
+    }
+
+    fn delete_inode(&self, id: ObjectId, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        if let Err(error) = self.check_writable() {
+            return Box::pin(async move { Err(error) });
+        }
+        self.storage.delete_inode(id, transaction)
+    }
+
+    fn read_data(&self, id: ObjectId, offset: u64, length: u64) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + '_>> {
+        self.storage.read_data(id, offset, length)
+    }
+
+    fn write_data(&self, id: ObjectId, offset: u64, data: &[u8], transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+        if let Err(error) = self.check_writable() {
+            return Box::pin(async move { Err(error) });
+        }
+        self.storage.write_data(id, offset, data, transaction)
+    }
+
+    fn truncate(&self, id: ObjectId, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        if let Err(error) = self.check_writable() {
+            return Box::pin(async move { Err(error) });
+        }
+        self.storage.truncate(id, size, transaction)
+    }
+
+    fn sync(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.storage.sync()
+    }
+
+    fn storage(&self) -> &dyn StorageBackend {
+        self.storage.as_ref()
+    }
+}
+
 /// VFS operations trait - high-level filesystem operations
 pub trait VfsOperations {
     /// Open a file by path
-    fn open(&self, path: &str, flags: u32, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<crate::FileHandle>> + Send + '_>>;
+    fn open(&self, path: &str, flags: u32, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<FileHandle>> + Send + '_>>;
 
     /// Create a file
     fn create(&self, path: &str, permissions: Permissions, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<ObjectId>> + Send + '_>>;
@@ -408,22 +910,367 @@ pub trait VfsOperations {
     fn sync(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 }
 
+impl VfsOperations for VfsManager {
+    fn open(&self, path: &str, flags: u32, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<FileHandle>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let writing = flags & (file_flags::O_WRONLY | file_flags::O_RDWR | file_flags::O_TRUNC) != 0;
+            let resolved = match self.walk(&path, &context, true).await {
+                Ok(_) if flags & file_flags::O_CREAT != 0 && flags & file_flags::O_EXCL != 0 => {
+                    return Err(GalleonError::AlreadyExists);
+                }
+                Err(GalleonError::NotFound) if flags & file_flags::O_CREAT != 0 => {
+                    let permissions = Permissions::new(0o644, context.uid, context.gid);
+                    self.create_entry(&path, InodeType::RegularFile, permissions, &[], &context).await?;
+                    self.walk(&path, &context, true).await?
+                }
+                result => result?,
+            };
+
+            let mut inode = resolved.filesystem.read_inode(resolved.object_id).await?;
+            if writing {
+                check_writable(&resolved.mount)?;
+                if inode.is_directory() {
+                    return Err(GalleonError::IsADirectory);
+                }
+                if !inode.can_write(context.uid, context.gid) {
+                    return Err(GalleonError::PermissionDenied);
+                }
+            }
+            if flags & file_flags::O_WRONLY == 0 && !inode.can_read(context.uid, context.gid) {
+                return Err(GalleonError::PermissionDenied);
+            }
+
+            if flags & file_flags::O_TRUNC != 0 && inode.size() > 0 {
+                let transaction = Transaction::new();
+                resolved.filesystem.truncate(resolved.object_id, 0, &transaction).await?;
+                inode.set_size(0);
+                resolved.filesystem.write_inode(&inode, &transaction).await?;
+                commit(&resolved, transaction).await?;
+            }
+            Ok(self.register(&resolved, flags))
+        })
+    }
+
+    fn create(&self, path: &str, permissions: Permissions, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<ObjectId>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            self.create_entry(&path, InodeType::RegularFile, permissions, &[], &context).await
+        })
+    }
+
+    fn mkdir(&self, path: &str, permissions: Permissions, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<ObjectId>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let data = Directory::new().serialize()?;
+            self.create_entry(&path, InodeType::Directory, permissions, &data, &context).await
+        })
+    }
+
+    fn unlink(&self, path: &str, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let (parent, mut directory, entry, inode) = self.prepare_removal(&path, &context).await?;
+            if inode.is_directory() {
+                return Err(GalleonError::IsADirectory);
+            }
+            let filesystem = parent.filesystem.as_ref();
+            let transaction = Transaction::new();
+            directory.remove_entry(&entry.name);
+            write_directory(filesystem, parent.object_id, &directory, &transaction).await?;
+            self.drop_link(filesystem, parent.mount.filesystem, inode, &transaction).await?;
+            commit(&parent, transaction).await
+        })
+    }
+
+    fn rmdir(&self, path: &str, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let (parent, mut directory, entry, inode) = self.prepare_removal(&path, &context).await?;
+            if !inode.is_directory() {
+                return Err(GalleonError::NotADirectory);
+            }
+            let filesystem = parent.filesystem.as_ref();
+            if !read_directory(filesystem, inode.id()).await?.is_empty() {
+                return Err(GalleonError::DirectoryNotEmpty);
+            }
+            let transaction = Transaction::new();
+            directory.remove_entry(&entry.name);
+            write_directory(filesystem, parent.object_id, &directory, &transaction).await?;
+            filesystem.delete_inode(inode.id(), &transaction).await?;
+            commit(&parent, transaction).await
+        })
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let old_path = old_path.to_string();
+        let new_path = new_path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let (source, mut source_directory, entry, inode) = self.prepare_removal(&old_path, &context).await?;
+            let (target, name) = self.walk_parent(&new_path, &context).await?;
+            // A rename never moves data between mounts, even of one filesystem
+            if target.mount.mount_id != source.mount.mount_id {
+                return Err(GalleonError::CrossDevice);
+            }
+            if !target.filesystem.read_inode(target.object_id).await?.can_write(context.uid, context.gid) {
+                return Err(GalleonError::PermissionDenied);
+            }
+            let old_full = self.normalize_path(&old_path)?;
+            let new_full = self.normalize_path(&new_path)?;
+            if inode.is_directory() && new_full.starts_with(&Self::child_prefix(&old_full)) {
+                return Err(GalleonError::InvalidArgument("Cannot move a directory into itself"));
+            }
+            if self.mount_points.lock().contains_key(&new_full) {
+                return Err(GalleonError::InvalidState("Mount point is busy"));
+            }
+
+            let filesystem = source.filesystem.as_ref();
+            let same_directory = source.object_id == target.object_id;
+            let mut target_directory = if same_directory {
+                None
+            } else {
+                Some(read_directory(filesystem, target.object_id).await?)
+            };
+            let transaction = Transaction::new();
+
+            let existing = target_directory.as_ref().unwrap_or(&source_directory).get_entry(&name).cloned();
+            if let Some(existing) = existing {
+                if existing.object_id == entry.object_id {
+                    return Ok(());
+                }
+                let replaced = filesystem.read_inode(existing.object_id).await?;
+                match (inode.is_directory(), replaced.is_directory()) {
+                    (false, true) => return Err(GalleonError::IsADirectory),
+                    (true, false) => return Err(GalleonError::NotADirectory),
+                    (true, true) => {
+                        if !read_directory(filesystem, replaced.id()).await?.is_empty() {
+                            return Err(GalleonError::DirectoryNotEmpty);
+                        }
+                        filesystem.delete_inode(replaced.id(), &transaction).await?;
+                    }
+                    (false, false) => {
+                        self.drop_link(filesystem, source.mount.filesystem, replaced, &transaction).await?;
+                    }
+                }
+                target_directory.as_mut().unwrap_or(&mut source_directory).remove_entry(&name);
+            }
+
+            source_directory.remove_entry(&entry.name);
+            let moved = DirectoryEntry::new(name, entry.object_id, entry.inode_type);
+            match target_directory {
+                Some(mut target_directory) => {
+                    target_directory.add_entry(moved)?;
+                    write_directory(filesystem, target.object_id, &target_directory, &transaction).await?;
+                }
+                None => source_directory.add_entry(moved)?,
+            }
+            write_directory(filesystem, source.object_id, &source_directory, &transaction).await?;
+            commit(&source, transaction).await
+        })
+    }
+
+    fn symlink(&self, target: &str, link_path: &str, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<ObjectId>> + Send + '_>> {
+        let target = target.to_string();
+        let link_path = link_path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let permissions = Permissions::new(0o777, context.uid, context.gid);
+            self.create_entry(&link_path, InodeType::SymbolicLink, permissions, target.as_bytes(), &context).await
+        })
+    }
+
+    fn readlink(&self, path: &str, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<String>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let resolved = self.walk(&path, &context, false).await?;
+            if !resolved.filesystem.read_inode(resolved.object_id).await?.is_symlink() {
+                return Err(GalleonError::InvalidArgument("Not a symbolic link"));
+            }
+            read_symlink(resolved.filesystem.as_ref(), resolved.object_id).await
+        })
+    }
+
+    fn stat(&self, path: &str, context: &OperationContext, follow_symlinks: bool) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let resolved = self.walk(&path, &context, follow_symlinks).await?;
+            resolved.filesystem.read_inode(resolved.object_id).await
+        })
+    }
+
+    fn readdir(&self, path: &str, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<Vec<DirectoryEntry>>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let resolved = self.walk(&path, &context, true).await?;
+            let inode = resolved.filesystem.read_inode(resolved.object_id).await?;
+            if !inode.is_directory() {
+                return Err(GalleonError::NotADirectory);
+            }
+            if !inode.can_read(context.uid, context.gid) {
+                return Err(GalleonError::PermissionDenied);
+            }
+            let directory = read_directory(resolved.filesystem.as_ref(), resolved.object_id).await?;
+            Ok(directory.entries().cloned().collect())
+        })
+    }
+
+    fn chmod(&self, path: &str, permissions: Permissions, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let (resolved, mut inode) = self.prepare_change(&path, &context).await?;
+            let current = *inode.permissions();
+            if context.uid != 0 && context.uid != current.uid {
+                return Err(GalleonError::PermissionDenied);
+            }
+            // Only the mode changes; ownership goes through chown
+            inode.set_permissions(Permissions::new(permissions.mode, current.uid, current.gid));
+            let transaction = Transaction::new();
+            resolved.filesystem.write_inode(&inode, &transaction).await?;
+            commit(&resolved, transaction).await
+        })
+    }
+
+    fn chown(&self, path: &str, uid: u32, gid: u32, context: &OperationContext) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let path = path.to_string();
+        let context = context.clone();
+        Box::pin(async move {
+            let (resolved, mut inode) = self.prepare_change(&path, &context).await?;
+            if context.uid != 0 {
+                return Err(GalleonError::PermissionDenied);
+            }
+            let mode = inode.permissions().mode;
+            inode.set_permissions(Permissions::new(mode, uid, gid));
+            let transaction = Transaction::new();
+            resolved.filesystem.write_inode(&inode, &transaction).await?;
+            commit(&resolved, transaction).await
+        })
+    }
+
+    fn sync(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let filesystems: Vec<Arc<dyn Filesystem>> = self.filesystems.lock().values().cloned().collect();
+            for filesystem in filesystems {
+                filesystem.sync().await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Extended attribute marking a directory in a writable layer as opaque,
+/// hiding whatever the layers below have at the same path
+pub const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// Object named by whiteout entries
+///
+/// A whiteout is a character device entry pointing here; it hides the same
+/// name in the layers below without needing an inode of its own.
+pub const WHITEOUT: ObjectId = ObjectId(u64::MAX);
+
+fn is_whiteout(entry: &DirectoryEntry) -> bool {
+    entry.inode_type == InodeType::CharacterDevice && entry.object_id == WHITEOUT
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        alloc::format!("/{}", name)
+    } else {
+        alloc::format!("{}/{}", parent, name)
+    }
+}
+
+fn split_path(path: &str) -> Result<(&str, &str)> {
+    match path.rsplit_once('/') {
+        Some((_, "")) | None => Err(GalleonError::InvalidPath("Path has no final component")),
+        Some(("", name)) => Ok(("/", name)),
+        Some((parent, name)) => Ok((parent, name)),
+    }
+}
+
+/// Whole contents of an object, empty if nothing was ever written
+async fn read_all(filesystem: &dyn Filesystem, id: ObjectId) -> Result<Vec<u8>> {
+    match filesystem.read_data(id, 0, u64::MAX).await {
+        Err(GalleonError::NotFound) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Identifiers a union hands out for the objects of its merged view
+#[derive(Default)]
+struct UnionNodes {
+    paths: BTreeMap<ObjectId, String>,
+    ids: BTreeMap<String, ObjectId>,
+    /// Objects created in the top layer that no directory names yet
+    pending: BTreeMap<ObjectId, Inode>,
+}
+
+impl UnionNodes {
+    fn bind(&mut self, id: ObjectId, path: String) {
+        if let Some(old) = self.paths.insert(id, path.clone()) {
+            if old != path {
+                self.ids.remove(&old);
+                // Everything below a renamed directory moves with it
+                let prefix = alloc::format!("{}/", old);
+                let moved: Vec<(String, ObjectId)> = self.ids.range(prefix.clone()..)
+                    .take_while(|(child, _)| child.starts_with(&prefix))
+                    .map(|(child, &child_id)| (child.clone(), child_id))
+                    .collect();
+                for (child, child_id) in moved {
+                    let renamed = alloc::format!("{}/{}", path, &child[prefix.len()..]);
+                    self.ids.remove(&child);
+                    self.ids.insert(renamed.clone(), child_id);
+                    self.paths.insert(child_id, renamed);
+                }
+            }
+        }
+        self.ids.insert(path, id);
+    }
+
+    fn forget(&mut self, id: ObjectId) {
+        if let Some(path) = self.paths.remove(&id) {
+            self.ids.remove(&path);
+        }
+    }
+}
+
 /// Union filesystem for combining multiple filesystems
+///
+/// Layers are stacked with the first on top. A name resolves to the topmost
+/// layer that has it, and directories present in several layers show the
+/// merged contents. Every change is made in the top layer, which must be
+/// writable: with copy-on-write, changing a file from a lower layer first
+/// copies it up; removing a name that a lower layer has leaves a whiteout.
+///
+/// Objects are identified by their merged path, so an object keeps its id
+/// when copied up.
 pub struct UnionFilesystem {
     layers: Vec<(Box<dyn Filesystem>, bool)>, // (filesystem, read_only)
     copy_on_write: bool,
-    whiteouts: BTreeMap<String, bool>, // Files that have been deleted
+    nodes: spin::Mutex<UnionNodes>,
 }
 
 impl UnionFilesystem {
     pub fn new(copy_on_write: bool) -> Self {
+        let mut nodes = UnionNodes::default();
+        nodes.bind(ObjectId::root(), "/".to_string());
         Self {
             layers: Vec::new(),
             copy_on_write,
-            whiteouts: BTreeMap::new(),
+            nodes: spin::Mutex::new(nodes),
         }
     }
 
+    /// Add a layer below those already present
     pub fn add_layer(&mut self, filesystem: Box<dyn Filesystem>, read_only: bool) {
         self.layers.push((filesystem, read_only));
     }
@@ -436,33 +1283,413 @@ impl UnionFilesystem {
         }
     }
 
+    /// The layer all changes go to
+    fn top(&self) -> Result<&dyn Filesystem> {
+        match self.layers.first() {
+            Some((filesystem, false)) => Ok(filesystem.as_ref()),
+            _ => Err(GalleonError::ReadOnlyFilesystem),
+        }
+    }
+
+    fn id_for(&self, path: &str) -> ObjectId {
+        let mut nodes = self.nodes.lock();
+        if let Some(&id) = nodes.ids.get(path) {
+            return id;
+        }
+        let id = ObjectId::new();
+        nodes.bind(id, path.to_string());
+        id
+    }
+
+    fn path_of(&self, id: ObjectId) -> Result<String> {
+        self.nodes.lock().paths.get(&id).cloned().ok_or(GalleonError::NotFound)
+    }
+
+    async fn is_opaque(&self, layer: usize, id: ObjectId) -> Result<bool> {
+        let inode = self.layers[layer].0.read_inode(id).await?;
+        Ok(matches!(inode.get_extended_attribute(OPAQUE_XATTR), Some(ExtendedAttributeValue::Boolean(true))))
+    }
+
+    /// The directories that make up the merged directory at `path`, topmost first
+    ///
+    /// The stack ends at the first opaque directory, and at a whiteout or
+    /// non-directory in the way.
+    async fn layer_stack(&self, path: &str) -> Result<Vec<(usize, ObjectId)>> {
+        let mut stack = Vec::new();
+        for layer in 0..self.layers.len() {
+            stack.push((layer, ObjectId::root()));
+            if self.is_opaque(layer, ObjectId::root()).await? {
+                break;
+            }
+        }
+
+        for name in path.split('/').filter(|component| !component.is_empty()) {
+            let mut next = Vec::new();
+            for (layer, directory) in stack {
+                let entry = read_directory(self.layers[layer].0.as_ref(), directory).await?.get_entry(name).cloned();
+                match entry {
+                    None => continue,
+                    Some(entry) if is_whiteout(&entry) => break,
+                    Some(entry) if entry.inode_type == InodeType::Directory => {
+                        next.push((layer, entry.object_id));
+                        if self.is_opaque(layer, entry.object_id).await? {
+                            break;
+                        }
+                    }
+                    Some(_) if next.is_empty() => return Err(GalleonError::NotADirectory),
+                    Some(_) => break,
+                }
+            }
+            if next.is_empty() {
+                return Err(GalleonError::NotFound);
+            }
+            stack = next;
+        }
+        Ok(stack)
+    }
+
     /// Find a file in the union, checking layers from top to bottom
     async fn find_in_union(&self, path: &str) -> Result<Option<(usize, ObjectId)>> {
-        // Check whiteouts first
-        if self.whiteouts.get(path).copied().unwrap_or(false) {
-            return Ok(None);
+        if path == "/" {
+            return Ok(self.layer_stack(path).await?.first().copied());
+        }
+        let (parent, name) = split_path(path)?;
+        let stack = match self.layer_stack(parent).await {
+            Err(GalleonError::NotFound) | Err(GalleonError::NotADirectory) => return Ok(None),
+            result => result?,
+        };
+        for (layer, directory) in stack {
+            if let Some(entry) = read_directory(self.layers[layer].0.as_ref(), directory).await?.get_entry(name) {
+                return Ok((!is_whiteout(entry)).then_some((layer, entry.object_id)));
+            }
         }
+        Ok(None)
+    }
 
-        // Search through layers from top to bottom
-        for (layer_index, (filesystem, _)) in self.layers.iter().enumerate() {
-            // TODO: Resolve path in this layer
-            // This would require implementing path resolution within each filesystem
+    /// Visible entries of the merged directory at `path`, with the layer each comes from
+    async fn merged_entries(&self, path: &str) -> Result<BTreeMap<String, (usize, DirectoryEntry)>> {
+        let mut seen: BTreeMap<String, Option<(usize, DirectoryEntry)>> = BTreeMap::new();
+        for (layer, directory) in self.layer_stack(path).await? {
+            for entry in read_directory(self.layers[layer].0.as_ref(), directory).await?.entries() {
+                seen.entry(entry.name.clone())
+                    .or_insert_with(|| (!is_whiteout(entry)).then(|| (layer, entry.clone())));
+            }
         }
+        Ok(seen.into_iter().filter_map(|(name, entry)| Some((name, entry?))).collect())
+    }
 
-        Ok(None)
+    /// List the merged directory at `path`
+    pub async fn readdir(&self, path: &str) -> Result<Vec<DirectoryEntry>> {
+        let entries = self.merged_entries(path).await?;
+        Ok(entries.into_iter()
+            .map(|(name, (_, entry))| {
+                let id = self.id_for(&join_path(path, &name));
+                DirectoryEntry::new(name, id, entry.inode_type)
+            })
+            .collect())
+    }
+
+    /// Whether a layer below the top shows `name` in the merged directory `stack` describes
+    async fn lower_has(&self, stack: &[(usize, ObjectId)], name: &str) -> Result<bool> {
+        for &(layer, directory) in stack.iter().filter(|(layer, _)| *layer != 0) {
+            if let Some(entry) = read_directory(self.layers[layer].0.as_ref(), directory).await?.get_entry(name) {
+                return Ok(!is_whiteout(entry));
+            }
+        }
+        Ok(false)
+    }
+
+    /// The top layer's copy of the directory at `path`, copying it and its parents up as needed
+    async fn upper_directory(&self, path: &str) -> Result<ObjectId> {
+        let top = self.top()?;
+        let mut id = ObjectId::root();
+        let mut walked = String::new();
+        for name in path.split('/').filter(|component| !component.is_empty()) {
+            walked.push('/');
+            walked.push_str(name);
+            let entry = read_directory(top, id).await?.get_entry(name).cloned();
+            id = match entry {
+                Some(entry) if entry.inode_type == InodeType::Directory => entry.object_id,
+                _ => match self.find_in_union(&walked).await? {
+                    Some((0, _)) => return Err(GalleonError::NotADirectory),
+                    Some((layer, source)) => self.copy_up_into(&walked, id, layer, source).await?,
+                    None => return Err(GalleonError::NotFound),
+                },
+            };
+        }
+        Ok(id)
     }
 
     /// Copy a file from a lower layer to the top writable layer (copy-on-write)
-    async fn copy_up(&self, _path: &str, _source_layer: usize) -> Result<ObjectId> {
-        // TODO: Implement copy-up operation for copy-on-write
-        Err(GalleonError::NotSupported)
+    async fn copy_up(&self, path: &str, source_layer: usize, source: ObjectId) -> Result<ObjectId> {
+        let (parent, _) = split_path(path)?;
+        let parent = self.upper_directory(parent).await?;
+        self.copy_up_into(path, parent, source_layer, source).await
+    }
+
+    /// Copy `source` into the top layer's directory `parent`
+    ///
+    /// Directories are copied empty, as the merged view still shows what the
+    /// lower layers hold. The copy is committed on its own, before the change
+    /// that needed it.
+    async fn copy_up_into(&self, path: &str, parent: ObjectId, source_layer: usize, source: ObjectId) -> Result<ObjectId> {
+        let top = self.top()?;
+        let lower = self.layers[source_layer].0.as_ref();
+        let inode = lower.read_inode(source).await?;
+        let data = if inode.is_directory() {
+            Directory::new().serialize()?
+        } else {
+            read_all(lower, source).await?
+        };
+
+        let id = ObjectId::new();
+        let transaction = Transaction::new();
+        top.write_inode(&inode.with_id(id), &transaction).await?;
+        if !data.is_empty() {
+            top.write_data(id, 0, &data, &transaction).await?;
+        }
+        let (_, name) = split_path(path)?;
+        let mut directory = read_directory(top, parent).await?;
+        directory.remove_entry(name);
+        directory.add_entry(DirectoryEntry::new(name.to_string(), id, inode.inode_type()))?;
+        write_directory(top, parent, &directory, &transaction).await?;
+        transaction.commit(top.storage()).await?;
+        Ok(id)
+    }
+
+    /// The top layer's copy of the object at `path`, copied up if it lives lower down
+    async fn upper_object(&self, path: &str) -> Result<ObjectId> {
+        self.top()?;
+        match self.find_in_union(path).await? {
+            Some((0, id)) => Ok(id),
+            Some(_) if !self.copy_on_write => Err(GalleonError::ReadOnlyFilesystem),
+            Some((layer, id)) => self.copy_up(path, layer, id).await,
+            None => Err(GalleonError::NotFound),
+        }
+    }
+
+    /// The layer holding the object at `path`, with its id there
+    async fn locate(&self, id: ObjectId) -> Result<(String, usize, ObjectId)> {
+        let path = self.path_of(id)?;
+        let (layer, object) = self.find_in_union(&path).await?.ok_or(GalleonError::NotFound)?;
+        Ok((path, layer, object))
+    }
+
+    /// Make the merged directory at `path` list what `data` holds
+    ///
+    /// Names that disappear are removed from the top layer and whited out if
+    /// a lower layer has them. Names that appear are either objects created
+    /// since, or objects renamed from elsewhere in the union, which are
+    /// copied up first. A directory put where a lower layer has something is
+    /// made opaque.
+    async fn write_merged_directory(&self, path: &str, data: &[u8], transaction: &Transaction) -> Result<()> {
+        let top = self.top()?;
+        let wanted = Directory::deserialize(data)?;
+        let stack = self.layer_stack(path).await?;
+        let current = self.merged_entries(path).await?;
+        let directory = self.upper_directory(path).await?;
+
+        let unchanged = |name: &str| {
+            let id = self.nodes.lock().ids.get(&join_path(path, name)).copied();
+            wanted.get_entry(name).is_some_and(|entry| id.is_none_or(|id| entry.object_id == id))
+        };
+
+        // Settle where each new name's object lives before touching the directory,
+        // as copying up may rewrite it
+        let mut added = Vec::new();
+        for entry in wanted.entries().filter(|entry| !current.contains_key(&entry.name) || !unchanged(&entry.name)) {
+            let pending = self.nodes.lock().pending.remove(&entry.object_id);
+            let (upper, inode) = match pending {
+                Some(inode) => (entry.object_id, inode),
+                None => {
+                    let from = self.path_of(entry.object_id)?;
+                    if entry.inode_type == InodeType::Directory
+                        && self.layer_stack(&from).await?.iter().any(|&(layer, _)| layer != 0)
+                    {
+                        // Its lower contents can't follow it to the new name
+                        return Err(GalleonError::CrossDevice);
+                    }
+                    let upper = self.upper_object(&from).await?;
+                    (upper, top.read_inode(upper).await?)
+                }
+            };
+            if inode.is_directory() && self.lower_has(&stack, &entry.name).await? {
+                let mut inode = inode;
+                inode.set_extended_attribute(OPAQUE_XATTR.to_string(), ExtendedAttributeValue::Boolean(true));
+                top.write_inode(&inode, transaction).await?;
+            }
+            added.push((entry.clone(), upper));
+        }
+
+        let mut upper = read_directory(top, directory).await?;
+        for name in current.keys().filter(|name| !unchanged(name)) {
+            upper.remove_entry(name);
+            if self.lower_has(&stack, name).await? {
+                upper.add_entry(DirectoryEntry::new(name.clone(), WHITEOUT, InodeType::CharacterDevice))?;
+            }
+        }
+        for (entry, id) in &added {
+            upper.remove_entry(&entry.name);
+            upper.add_entry(DirectoryEntry::new(entry.name.clone(), *id, entry.inode_type))?;
+        }
+        write_directory(top, directory, &upper, transaction).await?;
+
+        let mut nodes = self.nodes.lock();
+        for (entry, _) in added {
+            nodes.bind(entry.object_id, join_path(path, &entry.name));
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for UnionFilesystem {
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<FilesystemStats>> + Send + '_>> {
+        Box::pin(async move {
+            let (filesystem, _) = self.layers.first().ok_or(GalleonError::InvalidState("Union has no layers"))?;
+            filesystem.stats().await
+        })
+    }
+
+    fn create_inode(&self, inode_type: InodeType, permissions: Permissions, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<ObjectId>> + Send + '_>> {
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            let id = ObjectId::new();
+            let inode = Inode::new(id, inode_type, permissions, 0);
+            self.top()?.write_inode(&inode, &transaction).await?;
+            self.nodes.lock().pending.insert(id, inode);
+            Ok(id)
+        })
+    }
+
+    fn read_inode(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>> {
+        Box::pin(async move {
+            if let Some(inode) = self.nodes.lock().pending.get(&id) {
+                return Ok(inode.clone());
+            }
+            let (_, layer, object) = self.locate(id).await?;
+            Ok(self.layers[layer].0.read_inode(object).await?.with_id(id))
+        })
+    }
+
+    fn write_inode(&self, inode: &Inode, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let inode = inode.clone();
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            let top = self.top()?;
+            if let Some(pending) = self.nodes.lock().pending.get_mut(&inode.id()) {
+                *pending = inode.clone();
+            }
+            let upper = match self.path_of(inode.id()) {
+                Ok(path) => self.upper_object(&path).await?,
+                Err(_) => inode.id(),
+            };
+            top.write_inode(&inode.with_id(upper), &transaction).await
+        })
+    }
+
+    fn delete_inode(&self, id: ObjectId, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            let top = self.top()?;
+            if self.nodes.lock().pending.remove(&id).is_some() {
+                return top.delete_inode(id, &transaction).await;
+            }
+            // Lower layers keep their copy; the whiteout left in its directory hides it
+            let (_, layer, object) = self.locate(id).await?;
+            if layer == 0 {
+                top.delete_inode(object, &transaction).await?;
+            }
+            self.nodes.lock().forget(id);
+            Ok(())
+        })
+    }
+
+    fn read_data(&self, id: ObjectId, offset: u64, length: u64) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + '_>> {
+        Box::pin(async move {
+            if self.nodes.lock().pending.contains_key(&id) {
+                return self.top()?.read_data(id, offset, length).await;
+            }
+            let (path, layer, object) = self.locate(id).await?;
+            let filesystem = self.layers[layer].0.as_ref();
+            if !filesystem.read_inode(object).await?.is_directory() {
+                return filesystem.read_data(object, offset, length).await;
+            }
+
+            let mut directory = Directory::new();
+            for entry in self.readdir(&path).await? {
+                directory.add_entry(entry)?;
+            }
+            let data = directory.serialize()?;
+            let start = (offset as usize).min(data.len());
+            let end = start.saturating_add(length.min(usize::MAX as u64) as usize).min(data.len());
+            Ok(data[start..end].to_vec())
+        })
+    }
+
+    fn write_data(&self, id: ObjectId, offset: u64, data: &[u8], transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+        let data = data.to_vec();
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            let top = self.top()?;
+            if self.nodes.lock().pending.contains_key(&id) {
+                return top.write_data(id, offset, &data, &transaction).await;
+            }
+            let (path, layer, object) = self.locate(id).await?;
+            if self.layers[layer].0.read_inode(object).await?.is_directory() {
+                if offset != 0 {
+                    return Err(GalleonError::InvalidArgument("Directories are written whole"));
+                }
+                self.write_merged_directory(&path, &data, &transaction).await?;
+                return Ok(data.len() as u64);
+            }
+            let upper = self.upper_object(&path).await?;
+            top.write_data(upper, offset, &data, &transaction).await
+        })
+    }
+
+    fn truncate(&self, id: ObjectId, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let transaction = transaction.clone();
+        Box::pin(async move {
+            let top = self.top()?;
+            if self.nodes.lock().pending.contains_key(&id) {
+                return top.truncate(id, size, &transaction).await;
+            }
+            let (path, layer, object) = self.locate(id).await?;
+            if self.layers[layer].0.read_inode(object).await?.is_directory() {
+                // Already rewritten in full by write_data
+                return Ok(());
+            }
+            let upper = self.upper_object(&path).await?;
+            top.truncate(upper, size, &transaction).await
+        })
+    }
+
+    fn sync(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            for (filesystem, _) in &self.layers {
+                filesystem.sync().await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The top layer's storage, which every change is committed to
+    ///
+    /// # Panics
+    ///
+    /// If the union has no layers.
+    fn storage(&self) -> &dyn StorageBackend {
+        self.layers.first().expect("union has no layers").0.storage()
     }
 }
 
 /// Overlay filesystem implementation
+///
+/// A writable upper directory over read-only lower ones, built on
+/// [`UnionFilesystem`]. Without an upper directory the overlay is read-only.
 pub struct OverlayFilesystem {
-    lower_dirs: Vec<Box<dyn Filesystem>>,
-    upper_dir: Option<Box<dyn Filesystem>>,
+    union: UnionFilesystem,
+    has_upper: bool,
     work_dir: Option<Box<dyn Filesystem>>,
     merged_view: BTreeMap<String, ObjectId>,
 }
@@ -470,41 +1697,98 @@ pub struct OverlayFilesystem {
 impl OverlayFilesystem {
     pub fn new() -> Self {
         Self {
-            lower_dirs: Vec::new(),
-            upper_dir: None,
+            union: UnionFilesystem::new(true),
+            has_upper: false,
             work_dir: None,
             merged_view: BTreeMap::new(),
         }
     }
 
     pub fn set_upper_dir(&mut self, filesystem: Box<dyn Filesystem>) {
-        self.upper_dir = Some(filesystem);
+        if self.has_upper {
+            self.union.layers[0] = (filesystem, false);
+        } else {
+            self.union.layers.insert(0, (filesystem, false));
+            self.has_upper = true;
+        }
     }
 
     pub fn set_work_dir(&mut self, filesystem: Box<dyn Filesystem>) {
         self.work_dir = Some(filesystem);
     }
 
+    /// Add a lower directory below those already present
     pub fn add_lower_dir(&mut self, filesystem: Box<dyn Filesystem>) {
-        self.lower_dirs.push(filesystem);
+        self.union.add_layer(filesystem, true);
     }
 
     /// Merge all layers into a unified view
-    async fn merge_layers(&mut self) -> Result<()> {
+    ///
+    /// Records every path of the merged tree with its object, as of this call.
+    pub async fn merge_layers(&mut self) -> Result<()> {
         self.merged_view.clear();
-
-        // Start with lower directories (in reverse order, bottom to top)
-        for filesystem in self.lower_dirs.iter().rev() {
-            // TODO: Enumerate all files in this layer and add to merged view
-        }
-
-        // Apply upper directory changes
-        if let Some(ref upper_dir) = self.upper_dir {
-            // TODO: Apply upper directory files and whiteouts
+        self.merged_view.insert("/".to_string(), ObjectId::root());
+
+        let mut directories = alloc::vec!["/".to_string()];
+        while let Some(directory) = directories.pop() {
+            for entry in self.union.readdir(&directory).await? {
+                let path = join_path(&directory, &entry.name);
+                if entry.inode_type == InodeType::Directory {
+                    directories.push(path.clone());
+                }
+                self.merged_view.insert(path, entry.object_id);
+            }
         }
 
         Ok(())
     }
+
+    /// Paths of the merged tree as of the last [`merge_layers`](Self::merge_layers)
+    pub fn merged_view(&self) -> &BTreeMap<String, ObjectId> {
+        &self.merged_view
+    }
+}
+
+impl Filesystem for OverlayFilesystem {
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<FilesystemStats>> + Send + '_>> {
+        self.union.stats()
+    }
+
+    fn create_inode(&self, inode_type: InodeType, permissions: Permissions, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<ObjectId>> + Send + '_>> {
+        self.union.create_inode(inode_type, permissions, transaction)
+    }
+
+    fn read_inode(&self, id: ObjectId) -> Pin<Box<dyn Future<Output = Result<Inode>> + Send + '_>> {
+        self.union.read_inode(id)
+    }
+
+    fn write_inode(&self, inode: &Inode, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.union.write_inode(inode, transaction)
+    }
+
+    fn delete_inode(&self, id: ObjectId, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.union.delete_inode(id, transaction)
+    }
+
+    fn read_data(&self, id: ObjectId, offset: u64, length: u64) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + '_>> {
+        self.union.read_data(id, offset, length)
+    }
+
+    fn write_data(&self, id: ObjectId, offset: u64, data: &[u8], transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<u64>> + Send + '_>> {
+        self.union.write_data(id, offset, data, transaction)
+    }
+
+    fn truncate(&self, id: ObjectId, size: u64, transaction: &Transaction) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.union.truncate(id, size, transaction)
+    }
+
+    fn sync(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.union.sync()
+    }
+
+    fn storage(&self) -> &dyn StorageBackend {
+        self.union.storage()
+    }
 }
 
 /// Mount namespace for process isolation
@@ -19,7 +19,16 @@ pub fn ms_to_ticks(ms: u64) -> u64 {
     ms
 }
 
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    ticks_to_ms(ticks) * 1_000_000
+}
+
 /// Get current timestamp in milliseconds since boot
 pub fn get_timestamp() -> u64 {
     ticks_to_ms(current_tick())
+}
+
+/// Get current timestamp in nanoseconds since boot
+pub fn get_timestamp_ns() -> u64 {
+    ticks_to_ns(current_tick())
 }
\ No newline at end of file
@@ -0,0 +1,162 @@
+//! Galleon2 Block Device Adapter
+//!
+//! Exposes an AHCI device as a [`galleon2::BlockDevice`] so a Galleon2 volume
+//! can be formatted and mounted on a SATA disk. Transfers are staged through a
+//! DMA buffer that the controller reads from or writes into.
+
+extern crate alloc;
+
+use galleon2::{BlockDevice, FilesystemError, FilesystemResult};
+use lib_kernel::kprintln;
+use lib_kernel::memory::dma::{create_dma_buffer, dma_registry, get_dma_buffer, BufferId};
+use super::{AhciError, AHCI_DRIVER};
+
+/// Offset of the higher-half direct map used to reach DMA pages from the kernel
+const HHDM_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+
+/// Size of one page of a DMA buffer
+const PAGE_SIZE: usize = 0x1000;
+
+/// Sectors moved per AHCI command; keeps each DMA buffer at 64 KiB
+const MAX_SECTORS_PER_COMMAND: u64 = 128;
+
+/// Block device backed by a disk on the AHCI controller
+pub struct AhciBlockDevice {
+    device_id: u32,
+    sector_size: usize,
+    sector_count: u64,
+}
+
+impl AhciBlockDevice {
+    /// Open an AHCI device by its driver-assigned id
+    pub fn new(device_id: u32) -> Result<Self, AhciError> {
+        let driver = AHCI_DRIVER.read();
+        let driver = driver.as_ref().ok_or(AhciError::DeviceNotReady)?;
+        let info = driver.get_device_info(device_id).ok_or(AhciError::DeviceNotFound)?;
+
+        kprintln!("AHCI: Opening device {} as block device ({} sectors of {} bytes)",
+                 device_id, info.total_sectors, info.sector_size);
+
+        Ok(Self {
+            device_id,
+            sector_size: info.sector_size as usize,
+            sector_count: info.total_sectors,
+        })
+    }
+
+    /// Driver-assigned id of the underlying device
+    pub fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    /// Validate a transfer against the device geometry
+    fn check_transfer(&self, start_sector: u64, len: usize) -> FilesystemResult<()> {
+        if len == 0 || !len.is_multiple_of(self.sector_size) {
+            return Err(FilesystemError::InvalidParameter);
+        }
+
+        let sectors = (len / self.sector_size) as u64;
+        match start_sector.checked_add(sectors) {
+            Some(end) if end <= self.sector_count => Ok(()),
+            _ => Err(FilesystemError::OutOfRange),
+        }
+    }
+
+    /// Run `f` with a freshly allocated DMA buffer of at least `len` bytes
+    fn with_dma_buffer<T>(
+        len: usize,
+        f: impl FnOnce(BufferId) -> FilesystemResult<T>,
+    ) -> FilesystemResult<T> {
+        let id = create_dma_buffer(len).map_err(|_| FilesystemError::DeviceError)?;
+        let result = f(id);
+        dma_registry().unregister_buffer(id);
+        result
+    }
+
+    /// Kernel-visible address of each page of a DMA buffer
+    fn dma_pages(id: BufferId) -> FilesystemResult<alloc::vec::Vec<*mut u8>> {
+        let buffer = get_dma_buffer(id).ok_or(FilesystemError::DeviceError)?;
+        Ok(buffer.physical_pages().iter()
+            .map(|page| (page.start_address().as_u64() + HHDM_OFFSET) as *mut u8)
+            .collect())
+    }
+
+    /// Copy `data` into a DMA buffer ahead of a write
+    fn copy_to_dma(id: BufferId, data: &[u8]) -> FilesystemResult<()> {
+        for (virt, chunk) in Self::dma_pages(id)?.into_iter().zip(data.chunks(PAGE_SIZE)) {
+            // SAFETY: the page belongs to a pinned DMA buffer owned by this
+            // transfer and is reachable through the direct map
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), virt, chunk.len()) };
+        }
+        Ok(())
+    }
+
+    /// Copy a completed read out of a DMA buffer into `data`
+    fn copy_from_dma(id: BufferId, data: &mut [u8]) -> FilesystemResult<()> {
+        for (virt, chunk) in Self::dma_pages(id)?.into_iter().zip(data.chunks_mut(PAGE_SIZE)) {
+            // SAFETY: as in `copy_to_dma`
+            unsafe { core::ptr::copy_nonoverlapping(virt, chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciBlockDevice {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> FilesystemResult<()> {
+        self.check_transfer(start_sector, buffer.len())?;
+
+        let chunk_size = MAX_SECTORS_PER_COMMAND as usize * self.sector_size;
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let lba = start_sector + i as u64 * MAX_SECTORS_PER_COMMAND;
+            let sectors = (chunk.len() / self.sector_size) as u32;
+
+            Self::with_dma_buffer(chunk.len(), |id| {
+                {
+                    let driver = AHCI_DRIVER.read();
+                    let driver = driver.as_ref().ok_or(FilesystemError::DeviceError)?;
+                    driver.read(self.device_id, lba, sectors, id).map_err(|e| {
+                        kprintln!("AHCI: Read of {} sectors at LBA {} failed: {:?}", sectors, lba, e);
+                        FilesystemError::DeviceError
+                    })?;
+                }
+                Self::copy_from_dma(id, chunk)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> FilesystemResult<()> {
+        self.check_transfer(start_sector, buffer.len())?;
+
+        let chunk_size = MAX_SECTORS_PER_COMMAND as usize * self.sector_size;
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+            let lba = start_sector + i as u64 * MAX_SECTORS_PER_COMMAND;
+            let sectors = (chunk.len() / self.sector_size) as u32;
+
+            Self::with_dma_buffer(chunk.len(), |id| {
+                Self::copy_to_dma(id, chunk)?;
+
+                let driver = AHCI_DRIVER.read();
+                let driver = driver.as_ref().ok_or(FilesystemError::DeviceError)?;
+                driver.write(self.device_id, lba, sectors, id).map_err(|e| {
+                    kprintln!("AHCI: Write of {} sectors at LBA {} failed: {:?}", sectors, lba, e);
+                    FilesystemError::DeviceError
+                })
+            })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> FilesystemResult<()> {
+        // Commands complete synchronously; the driver has no cache flush command yet
+        Ok(())
+    }
+}
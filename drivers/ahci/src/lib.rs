@@ -32,6 +32,7 @@ pub mod device;
 pub mod port;
 pub mod command;
 pub mod error;
+pub mod block_device;
 
 use consts::*;
 use device::*;
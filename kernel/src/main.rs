@@ -26,6 +26,7 @@ use alloc::string::{String, ToString};
 use limine::request::{MemoryMapRequest, RequestsEndMarker, RequestsStartMarker};
 use core::panic::PanicInfo;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use lib_kernel::{ consts::BASE_REVISION, kprintln, scrolling_text };
 
 extern crate alloc;
@@ -44,7 +45,7 @@ mod utils;
 mod shell;
 
 use ahci;
-use galleon2::{ GalleonFilesystem, FilesystemStats };
+use galleon2::{ GalleonFilesystem, FilesystemStats, IdeBlockDevice, SharedBlockDevice };
 use luminal;
 use pci;
 use usb::init_usb;
@@ -60,6 +61,14 @@ where
     let guard = GLOBAL_FILESYSTEM.lock();
     guard.as_ref().map(|fs| f(*fs))
 }
+
+pub fn with_filesystem_mut<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut GalleonFilesystem) -> R,
+{
+    let mut guard = GLOBAL_FILESYSTEM.lock();
+    guard.as_mut().map(|fs| f(*fs))
+}
 /// 
 // NOTE: speaker and other modules are available as `crate::speaker` if
 /// needed; avoid glob imports here to keep the top-level clean.
@@ -196,9 +205,19 @@ unsafe extern "C" fn kmain() -> ! {
     kprintln!("Proceeding with Galleon2 filesystem initialization...");
 
     // Try to mount existing filesystem, or format a new one
-    kprintln!("About to call GalleonFilesystem::mount(0)...");
+    kprintln!("About to mount Galleon2 from IDE drive 0...");
+
+    let boot_device: SharedBlockDevice = match IdeBlockDevice::new(0) {
+        Ok(device) => Arc::new(device),
+        Err(e) => {
+            kprintln!("✗ Failed to open IDE drive 0 as a block device: {:?}", e);
+            loop {
+                core::arch::asm!("hlt");
+            }
+        }
+    };
 
-    let mut filesystem = match GalleonFilesystem::mount(0) {
+    let mut filesystem = match GalleonFilesystem::mount(boot_device.clone()) {
         Ok(fs) => {
             kprintln!("✓ Mounted existing Galleon2 filesystem on drive 0");
             fs
@@ -206,7 +225,7 @@ unsafe extern "C" fn kmain() -> ! {
         Err(mount_error) => {
             kprintln!("No existing filesystem found: {:?}", mount_error);
             kprintln!("Attempting to format drive 0...");
-            match GalleonFilesystem::format(0) {
+            match GalleonFilesystem::format(boot_device) {
                 Ok(fs) => {
                     kprintln!("✓ Successfully formatted drive 0 with Galleon2 filesystem");
                     fs
@@ -244,6 +263,7 @@ unsafe extern "C" fn kmain() -> ! {
                 kprintln!("   Used space:  {} KB", stats.used_space / 1024);
                 kprintln!("   Cluster size: {} bytes", stats.cluster_size);
                 kprintln!("   Total clusters: {}", stats.total_clusters);
+                kprintln!("   Cache hits/misses: {}/{}", stats.cache_hits, stats.cache_misses);
                 
                 // Print heap usage - test if we can even call the function
                 kprintln!("About to get heap stats...");
@@ -381,14 +401,14 @@ unsafe extern "C" fn kmain() -> ! {
             }
         }
 
-        // Demonstrate file search
+        // Demonstrate path lookup
         kprintln!("File search demonstration:");
-        let search_files = ["README.txt", "config.ini", "demo.rs", "nonexistent.txt"];
-        for filename in &search_files {
-            match filesystem.find_file(filename) {
-                Ok(Some(record)) => kprintln!("✓ Found '{}' at record #{}", filename, record),
-                Ok(None) => kprintln!("✗ File '{}' not found", filename),
-                Err(e) => kprintln!("✗ Error searching for '{}': {:?}", filename, e),
+        let search_paths = ["/README.txt", "/home/documents/config.ini", "/home/projects/demo.rs", "/nonexistent.txt"];
+        for path in &search_paths {
+            match filesystem.resolve_path(galleon2::mft::MFT_RECORD_ROOT, &galleon2::types::pathbuf::PathBuf::from(*path)) {
+                Ok(record) => kprintln!("✓ Found '{}' at record #{}", path, record),
+                Err(galleon2::FilesystemError::NotFound) => kprintln!("✗ File '{}' not found", path),
+                Err(e) => kprintln!("✗ Error searching for '{}': {:?}", path, e),
             }
         }
 
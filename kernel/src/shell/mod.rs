@@ -357,8 +357,11 @@ fn cmd_ls(args: &[&str]) -> CommandResult {
     // Try to get the global filesystem
     if let Some(result) = crate::with_filesystem(|fs| {
         kprintln!("Listing directory: {}", path);
-        
-        match fs.list_directory() {
+
+        let target = galleon2::types::pathbuf::PathBuf::from(path);
+        let listing = fs.resolve_path(galleon2::mft::MFT_RECORD_ROOT, &target)
+            .and_then(|dir| fs.list_directory_at(dir));
+        match listing {
             Ok(entries) => {
                 if entries.is_empty() {
                     kprintln!("  (empty directory)");
@@ -484,6 +487,49 @@ fn cmd_btree_diag(_args: &[&str]) -> CommandResult {
     }
 }
 
+fn cmd_fsck(args: &[&str]) -> CommandResult {
+    let repair = match args {
+        [] => false,
+        ["--repair"] => true,
+        _ => {
+            kprintln!("Usage: fsck [--repair]");
+            return CommandResult::Error;
+        }
+    };
+
+    match crate::with_filesystem_mut(|fs| fs.check(repair)) {
+        Some(Ok(report)) => {
+            kprintln!("Checked {} records in {} directories, {} clusters in use",
+                      report.records_checked, report.directories_checked, report.clusters_in_use);
+            for issue in &report.issues {
+                kprintln!("  {:?}", issue);
+            }
+            if repair {
+                kprintln!("Applied {} repairs, {} issues remaining", report.repaired, report.remaining.len());
+                for issue in &report.remaining {
+                    kprintln!("  still: {:?}", issue);
+                }
+            }
+
+            if report.is_clean() {
+                kprintln!("Filesystem is clean");
+                CommandResult::Success
+            } else {
+                kprintln!("Filesystem has {} issues; run 'fsck --repair' to fix them", report.remaining.len());
+                CommandResult::Error
+            }
+        }
+        Some(Err(e)) => {
+            kprintln!("fsck: check failed: {:?}", e);
+            CommandResult::Error
+        }
+        None => {
+            kprintln!("Filesystem not available!");
+            CommandResult::Error
+        }
+    }
+}
+
 /// All built-in commands (static, compile-time constant)
 const BUILTIN_COMMANDS: &[Command] = &[
     Command {
@@ -564,4 +610,10 @@ const BUILTIN_COMMANDS: &[Command] = &[
         usage: "btree-diag",
         handler: cmd_btree_diag,
     },
+    Command {
+        name: "fsck",
+        description: "Check the filesystem and optionally repair it",
+        usage: "fsck [--repair]",
+        handler: cmd_fsck,
+    },
 ];